@@ -0,0 +1,105 @@
+//! Compares decoding large `MessagePayload::File` packets with the zero-copy `Decoder` against the
+//! previous approach, which copied the payload once in `RawPacket::decode` and again in `read_bytes`.
+//!
+//! Besides timing, the number of bytes allocated per decoded packet is printed for both approaches.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rustchat::{
+    coding::varint::decode_varint32,
+    networking::{
+        packet::Packet,
+        packet_type::{DestinationType, MessagePacket, MessagePayload},
+        raw_packet::{RawPacket, MAX_PACKET_SIZE, PACKET_HEADER_SIZE},
+    },
+};
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const FILE_SIZES: [usize; 3] = [64 * 1024, 1024 * 1024, MAX_PACKET_SIZE - 64];
+
+fn file_frame(size: usize) -> Bytes {
+    let packet = Packet::Message(MessagePacket {
+        destination: 1,
+        destination_type: DestinationType::User,
+        message_payload: MessagePayload::File(Bytes::from(vec![0xAB; size])),
+    });
+
+    RawPacket::from(packet).encode()
+}
+
+fn decode_zero_copy(frame: Bytes) -> Bytes {
+    let raw_packet = RawPacket::decode(frame).unwrap();
+    let mut packet = MessagePacket::default();
+    raw_packet.receive_payload(&mut packet).unwrap();
+    match packet.message_payload {
+        MessagePayload::File(buffer) => buffer,
+        _ => unreachable!(),
+    }
+}
+
+/// The decoding path before `Decoder` was built on `Bytes`.
+fn decode_copying(frame: Bytes) -> Bytes {
+    let payload = Bytes::copy_from_slice(&frame[PACKET_HEADER_SIZE..]);
+
+    // destination (4) + destination type (1) + payload kind (1)
+    let cursor = &payload[6..];
+    let (len, read) = decode_varint32(cursor).unwrap();
+    Bytes::copy_from_slice(&cursor[read..read + len as usize])
+}
+
+fn allocated_by(f: impl FnOnce() -> Bytes) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
+
+fn decode_file_packets(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode_file_packet");
+
+    for size in FILE_SIZES {
+        let frame = file_frame(size);
+
+        println!(
+            "file payload of {} bytes: copying allocates {} bytes, zero-copy allocates {} bytes",
+            size,
+            allocated_by(|| decode_copying(frame.clone())),
+            allocated_by(|| decode_zero_copy(frame.clone())),
+        );
+
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_with_input(BenchmarkId::new("copying", size), &frame, |b, frame| {
+            b.iter(|| decode_copying(black_box(frame.clone())))
+        });
+        group.bench_with_input(BenchmarkId::new("zero_copy", size), &frame, |b, frame| {
+            b.iter(|| decode_zero_copy(black_box(frame.clone())))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, decode_file_packets);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+//! Generates the protocol types from `protocol.toml`, they are included by `networking::packet_type`.
+
+use std::{env, fs, path::PathBuf};
+
+fn main() {
+    println!("cargo:rerun-if-changed=protocol.toml");
+
+    let schema =
+        rustchat_codegen::load("protocol.toml".as_ref()).unwrap_or_else(|err| panic!("{err}"));
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("protocol.rs");
+    fs::write(out, rustchat_codegen::rust::generate(&schema))
+        .expect("could not write the generated protocol");
+}
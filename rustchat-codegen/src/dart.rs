@@ -0,0 +1,695 @@
+//! Emits a self-contained Dart library with the protocol types, their encoders and decoders and the envelope framing.
+
+use std::fmt::Write;
+
+use crate::{
+    naming::{camel_case, words, write_doc},
+    schema::{EnumDef, FlagsDef, NamedKind, PacketDef, Schema, Type, UnionDef},
+};
+
+const RUNTIME: &str = r#"import 'dart:convert';
+import 'dart:typed_data';
+
+const kEnvelopeVersion = 1;
+const kFlagRequestId = 0x01;
+const kMaxPacketSize = 1024 * 1024 * 12;
+
+class ProtocolException implements Exception {
+  final String message;
+
+  ProtocolException(this.message);
+
+  @override
+  String toString() => 'ProtocolException: $message';
+}
+
+class ProtocolWriter {
+  final BytesBuilder _builder = BytesBuilder(copy: false);
+  final ByteData _scratch = ByteData(8);
+
+  Uint8List takeBytes() => _builder.takeBytes();
+
+  void writeBool(bool value) => _builder.addByte(value ? 1 : 0);
+  void writeU8(int value) => _builder.addByte(value & 0xFF);
+  void writeI8(int value) => _builder.addByte(value & 0xFF);
+
+  void writeU16(int value) => _add(2, () => _scratch.setUint16(0, value));
+  void writeI16(int value) => _add(2, () => _scratch.setInt16(0, value));
+  void writeU32(int value) => _add(4, () => _scratch.setUint32(0, value));
+  void writeI32(int value) => _add(4, () => _scratch.setInt32(0, value));
+  void writeU64(int value) => _add(8, () => _scratch.setUint64(0, value));
+  void writeI64(int value) => _add(8, () => _scratch.setInt64(0, value));
+  void writeF32(double value) => _add(4, () => _scratch.setFloat32(0, value));
+  void writeF64(double value) => _add(8, () => _scratch.setFloat64(0, value));
+
+  void writeVarint(int value) => writeVarlong(value & 0xFFFFFFFF);
+
+  void writeVarlong(int value) {
+    while ((value & ~0x7F) != 0) {
+      _builder.addByte((value & 0x7F) | 0x80);
+      value = value >>> 7;
+    }
+    _builder.addByte(value);
+  }
+
+  void writeSvarint(int value) => writeVarint((value << 1) ^ (value >> 31));
+  void writeSvarlong(int value) => writeVarlong((value << 1) ^ (value >> 63));
+
+  void writeString(String value) => writeBytes(utf8.encode(value));
+
+  void writeBytes(Uint8List value) {
+    writeVarint(value.length);
+    _builder.add(value);
+  }
+
+  void writeUuid(String value) {
+    final hex = value.replaceAll('-', '');
+    if (hex.length != 32) {
+      throw ProtocolException('invalid uuid $value');
+    }
+    for (var i = 0; i < 32; i += 2) {
+      _builder.addByte(int.parse(hex.substring(i, i + 2), radix: 16));
+    }
+  }
+
+  void writeOption<T>(T? value, void Function(T) write) {
+    writeBool(value != null);
+    if (value != null) {
+      write(value);
+    }
+  }
+
+  void writeList<T>(List<T> values, void Function(T) write) {
+    writeVarint(values.length);
+    values.forEach(write);
+  }
+
+  void writeMap<K, V>(Map<K, V> values, void Function(K) writeKey, void Function(V) writeValue) {
+    writeVarint(values.length);
+    values.forEach((key, value) {
+      writeKey(key);
+      writeValue(value);
+    });
+  }
+
+  void _add(int length, void Function() write) {
+    write();
+    _builder.add(Uint8List.fromList(_scratch.buffer.asUint8List(0, length)));
+  }
+}
+
+class ProtocolReader {
+  final Uint8List _bytes;
+  final ByteData _data;
+  int _offset = 0;
+
+  ProtocolReader(Uint8List bytes)
+      : _bytes = bytes,
+        _data = ByteData.sublistView(bytes);
+
+  int get offset => _offset;
+  int get remaining => _bytes.length - _offset;
+
+  bool readBool() {
+    final offset = _offset;
+    final value = readU8();
+    if (value > 1) {
+      throw ProtocolException('invalid boolean byte $value at offset $offset');
+    }
+    return value == 1;
+  }
+
+  int readU8() => _read(1, () => _data.getUint8(_offset));
+  int readI8() => _read(1, () => _data.getInt8(_offset));
+  int readU16() => _read(2, () => _data.getUint16(_offset));
+  int readI16() => _read(2, () => _data.getInt16(_offset));
+  int readU32() => _read(4, () => _data.getUint32(_offset));
+  int readI32() => _read(4, () => _data.getInt32(_offset));
+  int readU64() => _read(8, () => _data.getUint64(_offset));
+  int readI64() => _read(8, () => _data.getInt64(_offset));
+  double readF32() => _read(4, () => _data.getFloat32(_offset));
+  double readF64() => _read(8, () => _data.getFloat64(_offset));
+
+  int readVarint() => _readVarint(32);
+  int readVarlong() => _readVarint(64);
+
+  int readSvarint() {
+    final value = readVarint();
+    return (value >>> 1) ^ -(value & 1);
+  }
+
+  int readSvarlong() {
+    final value = readVarlong();
+    return (value >>> 1) ^ -(value & 1);
+  }
+
+  String readString() {
+    final offset = _offset;
+    final bytes = readBytes();
+    try {
+      return utf8.decode(bytes);
+    } on FormatException {
+      throw ProtocolException('invalid utf-8 string at offset $offset');
+    }
+  }
+
+  Uint8List readBytes() {
+    final length = readVarint();
+    _ensure(length);
+    final bytes = Uint8List.sublistView(_bytes, _offset, _offset + length);
+    _offset += length;
+    return bytes;
+  }
+
+  String readUuid() {
+    _ensure(16);
+    final hex = StringBuffer();
+    for (var i = 0; i < 16; i++) {
+      if (i == 4 || i == 6 || i == 8 || i == 10) {
+        hex.write('-');
+      }
+      hex.write(_bytes[_offset + i].toRadixString(16).padLeft(2, '0'));
+    }
+    _offset += 16;
+    return hex.toString();
+  }
+
+  T? readOption<T>(T Function() read) => readBool() ? read() : null;
+
+  List<T> readList<T>(int maxLength, T Function() read) {
+    final length = _readLength(maxLength);
+    return List.generate(length, (_) => read());
+  }
+
+  Map<K, V> readMap<K, V>(int maxLength, K Function() readKey, V Function() readValue) {
+    final length = _readLength(maxLength);
+    final values = <K, V>{};
+    for (var i = 0; i < length; i++) {
+      final key = readKey();
+      values[key] = readValue();
+    }
+    return values;
+  }
+
+  /// Every element takes at least one byte, so a length larger than the remaining data is rejected early.
+  int _readLength(int maxLength) {
+    final offset = _offset;
+    final length = readVarint();
+    if (length > maxLength) {
+      throw ProtocolException(
+          'collection at offset $offset has $length elements, the maximum is $maxLength');
+    }
+    _ensure(length);
+    return length;
+  }
+
+  int _readVarint(int bits) {
+    final offset = _offset;
+    var result = 0;
+    for (var shift = 0; shift < bits; shift += 7) {
+      final byte = readU8();
+      result |= (byte & 0x7F) << shift;
+      if ((byte & 0x80) == 0) {
+        return result;
+      }
+    }
+    throw ProtocolException('varint at offset $offset does not fit in $bits bits');
+  }
+
+  T _read<T>(int length, T Function() read) {
+    _ensure(length);
+    final value = read();
+    _offset += length;
+    return value;
+  }
+
+  void _ensure(int needed) {
+    if (remaining < needed) {
+      throw ProtocolException(
+          'unexpected end of data at offset $_offset: needed $needed bytes but only $remaining remain');
+    }
+  }
+}
+
+abstract interface class PacketData {
+  int get packetId;
+
+  void encode(ProtocolWriter writer);
+}
+
+/// A packet envelope, without the length prefix
+class Frame {
+  final int packetType;
+
+  /// Set on requests that expect an answer, the server echoes it on the response.
+  final int? requestId;
+  final Uint8List payload;
+
+  const Frame({required this.packetType, this.requestId, required this.payload});
+
+  /// Decodes the payload, returns null when the packet type is unknown
+  PacketData? decode() => decodePacket(packetType, ProtocolReader(payload));
+
+  @override
+  String toString() => 'Frame(packetType: $packetType, requestId: $requestId, payload: ${payload.length} bytes)';
+}
+
+/// Encodes a packet with its length prefix and envelope header
+Uint8List encodeFrame(PacketData packet, {int? requestId}) {
+  final writer = ProtocolWriter();
+  writer.writeU8(kEnvelopeVersion);
+  writer.writeU8(requestId == null ? 0 : kFlagRequestId);
+  writer.writeU8(packet.packetId);
+  if (requestId != null) {
+    writer.writeVarint(requestId);
+  }
+  packet.encode(writer);
+
+  final body = writer.takeBytes();
+  if (body.length > kMaxPacketSize) {
+    throw ProtocolException('packet of ${body.length} bytes exceeds the maximum packet size');
+  }
+
+  final frame = Uint8List(4 + body.length);
+  ByteData.sublistView(frame).setUint32(0, body.length);
+  frame.setRange(4, frame.length, body);
+  return frame;
+}
+
+/// Decodes an envelope, the length prefix must already be stripped
+Frame decodeFrame(Uint8List body) {
+  final reader = ProtocolReader(body);
+  final version = reader.readU8();
+  if (version != kEnvelopeVersion) {
+    throw ProtocolException('unsupported envelope version $version');
+  }
+
+  final flags = reader.readU8();
+  if ((flags & ~kFlagRequestId) != 0) {
+    throw ProtocolException('unsupported envelope flags $flags');
+  }
+
+  final packetType = reader.readU8();
+  final requestId = (flags & kFlagRequestId) != 0 ? reader.readVarint() : null;
+  return Frame(
+    packetType: packetType,
+    requestId: requestId,
+    payload: Uint8List.sublistView(body, reader.offset),
+  );
+}
+
+/// Splits a stream of bytes into frames
+class FrameBuffer {
+  final BytesBuilder _pending = BytesBuilder();
+
+  /// Adds received bytes, returning every frame they complete
+  List<Frame> add(Uint8List bytes) {
+    _pending.add(bytes);
+    var buffer = _pending.takeBytes();
+
+    final frames = <Frame>[];
+    while (buffer.length >= 4) {
+      final length = ByteData.sublistView(buffer).getUint32(0);
+      if (buffer.length < 4 + length) {
+        break;
+      }
+      frames.add(decodeFrame(Uint8List.sublistView(buffer, 4, 4 + length)));
+      buffer = Uint8List.sublistView(buffer, 4 + length);
+    }
+
+    _pending.add(buffer);
+    return frames;
+  }
+}
+"#;
+
+/// Generates the Dart library for the schema
+pub fn generate(schema: &Schema) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "// @generated by rustchat-codegen from protocol.toml, do not edit."
+    )
+    .unwrap();
+    writeln!(out).unwrap();
+    out.push_str(RUNTIME);
+
+    writeln!(out).unwrap();
+    writeln!(out, "abstract final class PacketType {{").unwrap();
+    for packet in &schema.packets {
+        write_doc(&mut out, &packet.doc, "  ", "///");
+        writeln!(
+            out,
+            "  static const {} = {};",
+            camel_case(&packet.constant),
+            packet.id
+        )
+        .unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "/// Decodes the payload of a packet, returns null when the packet type is unknown"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "PacketData? decodePacket(int packetType, ProtocolReader reader) {{"
+    )
+    .unwrap();
+    writeln!(out, "  return switch (packetType) {{").unwrap();
+    for packet in &schema.packets {
+        writeln!(
+            out,
+            "    PacketType.{} => {}.decode(reader),",
+            camel_case(&packet.constant),
+            packet.name
+        )
+        .unwrap();
+    }
+    writeln!(out, "    _ => null,").unwrap();
+    writeln!(out, "  }};").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    for def in &schema.enums {
+        write_enum(&mut out, def);
+    }
+    for def in &schema.flags {
+        write_flags(&mut out, def);
+    }
+    for def in &schema.unions {
+        write_union(&mut out, schema, def);
+    }
+    for def in &schema.packets {
+        write_packet(&mut out, schema, def);
+    }
+
+    out
+}
+
+fn write_enum(out: &mut String, def: &EnumDef) {
+    writeln!(out).unwrap();
+    write_doc(out, &def.doc, "", "///");
+    writeln!(out, "enum {} {{", def.name).unwrap();
+    for (i, variant) in def.variants.iter().enumerate() {
+        write_doc(out, &variant.doc, "  ", "///");
+        let separator = if i + 1 == def.variants.len() {
+            ";"
+        } else {
+            ","
+        };
+        writeln!(out, "  {}{separator}", camel_case(&variant.name)).unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "  int get code => index;").unwrap();
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "  static {} decode(ProtocolReader reader) {{",
+        def.name
+    )
+    .unwrap();
+    writeln!(out, "    final offset = reader.offset;").unwrap();
+    writeln!(out, "    final code = reader.readU8();").unwrap();
+    writeln!(out, "    if (code >= values.length) {{").unwrap();
+    writeln!(
+        out,
+        "      throw ProtocolException('unknown {} discriminant $code at offset $offset');",
+        words(&def.name)
+    )
+    .unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "    return values[code];").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn write_flags(out: &mut String, def: &FlagsDef) {
+    writeln!(out).unwrap();
+    write_doc(out, &def.doc, "", "///");
+    writeln!(out, "abstract final class {} {{", def.name).unwrap();
+    for bit in &def.bits {
+        write_doc(out, &bit.doc, "  ", "///");
+        writeln!(
+            out,
+            "  static const {} = 1 << {};",
+            camel_case(&bit.name),
+            bit.bit
+        )
+        .unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+}
+
+fn write_union(out: &mut String, schema: &Schema, def: &UnionDef) {
+    let name = &def.name;
+    writeln!(out).unwrap();
+    write_doc(out, &def.doc, "", "///");
+    writeln!(out, "sealed class {name} {{").unwrap();
+    writeln!(out, "  const {name}();").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "  void encode(ProtocolWriter writer);").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "  static {name} decode(ProtocolReader reader) {{").unwrap();
+    writeln!(out, "    final offset = reader.offset;").unwrap();
+    writeln!(out, "    final tag = reader.readI8();").unwrap();
+    writeln!(out, "    return switch (tag) {{").unwrap();
+    for variant in &def.variants {
+        writeln!(
+            out,
+            "      {} => {name}{}({}),",
+            variant.tag,
+            variant.name,
+            read_value(schema, &variant.ty)
+        )
+        .unwrap();
+    }
+    writeln!(
+        out,
+        "      _ => throw ProtocolException('unknown {} discriminant $tag at offset $offset'),",
+        words(name)
+    )
+    .unwrap();
+    writeln!(out, "    }};").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    for variant in &def.variants {
+        let class = format!("{name}{}", variant.name);
+        writeln!(out).unwrap();
+        writeln!(out, "final class {class} extends {name} {{").unwrap();
+        writeln!(out, "  final {} value;", dart_type(schema, &variant.ty)).unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "  const {class}(this.value);").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "  @override").unwrap();
+        writeln!(out, "  void encode(ProtocolWriter writer) {{").unwrap();
+        writeln!(out, "    writer.writeI8({});", variant.tag).unwrap();
+        writeln!(out, "    {};", write_value(schema, &variant.ty, "value", 0)).unwrap();
+        writeln!(out, "  }}").unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "  @override").unwrap();
+        writeln!(out, "  String toString() => '{class}($value)';").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+}
+
+fn write_packet(out: &mut String, schema: &Schema, def: &PacketDef) {
+    let name = &def.name;
+    writeln!(out).unwrap();
+    write_doc(out, &def.doc, "", "///");
+    writeln!(out, "class {name} implements PacketData {{").unwrap();
+    writeln!(
+        out,
+        "  static const id = PacketType.{};",
+        camel_case(&def.constant)
+    )
+    .unwrap();
+    for field in &def.fields {
+        writeln!(out).unwrap();
+        write_doc(out, &field.doc, "  ", "///");
+        writeln!(
+            out,
+            "  final {} {};",
+            dart_type(schema, &field.ty),
+            camel_case(&field.name)
+        )
+        .unwrap();
+    }
+    writeln!(out).unwrap();
+
+    if def.fields.is_empty() {
+        writeln!(out, "  const {name}();").unwrap();
+    } else {
+        writeln!(out, "  const {name}({{").unwrap();
+        for field in &def.fields {
+            writeln!(out, "    required this.{},", camel_case(&field.name)).unwrap();
+        }
+        writeln!(out, "  }});").unwrap();
+    }
+    writeln!(out).unwrap();
+
+    writeln!(out, "  factory {name}.decode(ProtocolReader reader) {{").unwrap();
+    writeln!(out, "    return {name}(").unwrap();
+    for field in &def.fields {
+        writeln!(
+            out,
+            "      {}: {},",
+            camel_case(&field.name),
+            read_field(schema, &field.ty, field.max_len)
+        )
+        .unwrap();
+    }
+    writeln!(out, "    );").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "  @override").unwrap();
+    writeln!(out, "  int get packetId => id;").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "  @override").unwrap();
+    writeln!(out, "  void encode(ProtocolWriter writer) {{").unwrap();
+    for field in &def.fields {
+        writeln!(
+            out,
+            "    {};",
+            write_value(schema, &field.ty, &camel_case(&field.name), 0)
+        )
+        .unwrap();
+    }
+    writeln!(out, "  }}").unwrap();
+    writeln!(out).unwrap();
+
+    let fields = def
+        .fields
+        .iter()
+        .map(|field| {
+            let field = camel_case(&field.name);
+            format!("{field}: ${field}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    writeln!(out, "  @override").unwrap();
+    writeln!(out, "  String toString() => '{name}({fields})';").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn dart_type(schema: &Schema, ty: &Type) -> String {
+    match ty {
+        Type::Bool => "bool".into(),
+        Type::U8
+        | Type::I8
+        | Type::U16
+        | Type::I16
+        | Type::U32
+        | Type::I32
+        | Type::U64
+        | Type::I64
+        | Type::Varint
+        | Type::Varlong
+        | Type::Svarint
+        | Type::Svarlong => "int".into(),
+        Type::F32 | Type::F64 => "double".into(),
+        Type::String | Type::Uuid => "String".into(),
+        Type::Bytes => "Uint8List".into(),
+        Type::Option(inner) => format!("{}?", dart_type(schema, inner)),
+        Type::List(inner) => format!("List<{}>", dart_type(schema, inner)),
+        Type::Map(key, value) => format!(
+            "Map<{}, {}>",
+            dart_type(schema, key),
+            dart_type(schema, value)
+        ),
+        Type::Named(name) => match schema.kind_of(name) {
+            Some(NamedKind::Flags) => "int".into(),
+            _ => name.clone(),
+        },
+    }
+}
+
+/// The writer method of a scalar type, or `None` for the types encoding themselves
+fn scalar_method(ty: &Type) -> Option<&'static str> {
+    Some(match ty {
+        Type::Bool => "Bool",
+        Type::U8 => "U8",
+        Type::I8 => "I8",
+        Type::U16 => "U16",
+        Type::I16 => "I16",
+        Type::U32 => "U32",
+        Type::I32 => "I32",
+        Type::U64 => "U64",
+        Type::I64 => "I64",
+        Type::F32 => "F32",
+        Type::F64 => "F64",
+        Type::Varint => "Varint",
+        Type::Varlong => "Varlong",
+        Type::Svarint => "Svarint",
+        Type::Svarlong => "Svarlong",
+        Type::String => "String",
+        Type::Bytes => "Bytes",
+        Type::Uuid => "Uuid",
+        _ => return None,
+    })
+}
+
+/// Writes `value`, `depth` keeps the closure parameters of nested types apart
+fn write_value(schema: &Schema, ty: &Type, value: &str, depth: usize) -> String {
+    if let Some(method) = scalar_method(ty) {
+        return format!("writer.write{method}({value})");
+    }
+
+    let v = format!("v{depth}");
+    let k = format!("k{depth}");
+    match ty {
+        Type::Option(inner) => format!(
+            "writer.writeOption({value}, ({v}) => {})",
+            write_value(schema, inner, &v, depth + 1)
+        ),
+        Type::List(inner) => format!(
+            "writer.writeList({value}, ({v}) => {})",
+            write_value(schema, inner, &v, depth + 1)
+        ),
+        Type::Map(key, inner) => format!(
+            "writer.writeMap({value}, ({k}) => {}, ({v}) => {})",
+            write_value(schema, key, &k, depth + 1),
+            write_value(schema, inner, &v, depth + 1)
+        ),
+        Type::Named(name) => match schema.kind_of(name) {
+            Some(NamedKind::Enum) => format!("writer.writeU8({value}.code)"),
+            Some(NamedKind::Flags) => format!("writer.writeVarint({value})"),
+            _ => format!("{value}.encode(writer)"),
+        },
+        _ => unreachable!("scalar types are handled above"),
+    }
+}
+
+fn read_field(schema: &Schema, ty: &Type, max_len: Option<usize>) -> String {
+    match (ty, max_len) {
+        (Type::List(inner), Some(max_len)) => format!(
+            "reader.readList({max_len}, () => {})",
+            read_value(schema, inner)
+        ),
+        (Type::Map(key, value), Some(max_len)) => format!(
+            "reader.readMap({max_len}, () => {}, () => {})",
+            read_value(schema, key),
+            read_value(schema, value)
+        ),
+        _ => read_value(schema, ty),
+    }
+}
+
+fn read_value(schema: &Schema, ty: &Type) -> String {
+    if let Some(method) = scalar_method(ty) {
+        return format!("reader.read{method}()");
+    }
+
+    match ty {
+        Type::Option(inner) => format!("reader.readOption(() => {})", read_value(schema, inner)),
+        Type::Named(name) => match schema.kind_of(name) {
+            Some(NamedKind::Flags) => "reader.readVarint()".into(),
+            _ => format!("{name}.decode(reader)"),
+        },
+        _ => unreachable!("collections only appear as fields and are read by read_field"),
+    }
+}
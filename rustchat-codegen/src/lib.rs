@@ -0,0 +1,51 @@
+//! Generates the rustchat protocol types from the packet schema in `protocol.toml`.
+//!
+//! The server uses [`rust::generate`] from its build script, the Dart and TypeScript clients are written by the
+//! `rustchat-codegen` binary.
+
+use std::{fs, path::Path};
+
+use snafu::ResultExt;
+
+pub mod dart;
+mod naming;
+pub mod rust;
+pub mod schema;
+pub mod typescript;
+
+pub use schema::{Schema, SchemaError};
+
+/// Reads and validates a schema file
+pub fn load(path: &Path) -> Result<Schema, SchemaError> {
+    let source = fs::read_to_string(path).context(schema::ReadSnafu {
+        path: path.display().to_string(),
+    })?;
+
+    Schema::parse(&source)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn tester_clients_are_up_to_date() {
+        let workspace = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("..");
+        let schema = load(&workspace.join("protocol.toml")).unwrap();
+
+        let clients = [
+            ("protocol.dart", dart::generate(&schema)),
+            ("protocol.ts", typescript::generate(&schema)),
+        ];
+        for (name, source) in clients {
+            let current =
+                fs::read_to_string(workspace.join("tester").join(name)).unwrap_or_default();
+            assert!(
+                current == source,
+                "tester/{name} is out of date with protocol.toml, run `cargo run -p rustchat-codegen`"
+            );
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Regenerates the Dart and TypeScript clients in `tester/` from `protocol.toml`.
+//!
+//! Usage: `cargo run -p rustchat-codegen [schema] [output directory]`, both default to the workspace files.
+
+use std::{env, fs, path::PathBuf, process::ExitCode};
+
+use rustchat_codegen::{dart, typescript};
+
+fn main() -> ExitCode {
+    let workspace = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("..");
+    let mut args = env::args_os().skip(1);
+    let schema_path = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| workspace.join("protocol.toml"));
+    let output = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| workspace.join("tester"));
+
+    let schema = match rustchat_codegen::load(&schema_path) {
+        Ok(schema) => schema,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let files = [
+        ("protocol.dart", dart::generate(&schema)),
+        ("protocol.ts", typescript::generate(&schema)),
+    ];
+    for (name, source) in files {
+        let path = output.join(name);
+        if let Err(err) = fs::write(&path, source) {
+            eprintln!("could not write {}: {err}", path.display());
+            return ExitCode::FAILURE;
+        }
+        println!("wrote {}", path.display());
+    }
+
+    ExitCode::SUCCESS
+}
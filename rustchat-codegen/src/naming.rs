@@ -0,0 +1,84 @@
+use std::fmt::Write;
+
+/// `client_name` or `FILE_CHUNKING` to `clientName` or `fileChunking`
+pub fn camel_case(name: &str) -> String {
+    let pascal = pascal_case(name);
+    let mut chars = pascal.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => pascal,
+    }
+}
+
+/// `client_name`, `SIGN_IN` or `HelloExpected` to `ClientName`, `SignIn` or `HelloExpected`
+pub fn pascal_case(name: &str) -> String {
+    let screaming = name.chars().all(|c| !c.is_lowercase());
+    name.split('_')
+        .filter(|word| !word.is_empty())
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap().to_uppercase();
+            let rest: String = match screaming {
+                true => chars.as_str().to_lowercase(),
+                false => chars.as_str().to_string(),
+            };
+            first.chain(rest.chars().collect::<Vec<_>>())
+        })
+        .collect()
+}
+
+/// `MessagePayload` to `message payload`
+pub fn words(name: &str) -> String {
+    let mut words = String::with_capacity(name.len() + 4);
+    for (i, ch) in name.char_indices() {
+        if ch.is_uppercase() && i > 0 {
+            words.push(' ');
+        }
+        words.extend(ch.to_lowercase());
+    }
+
+    words
+}
+
+/// Writes a doc comment, one `prefix` line per line of `doc`
+pub fn write_doc(out: &mut String, doc: &Option<String>, indent: &str, prefix: &str) {
+    if let Some(doc) = doc {
+        for line in doc.trim().lines() {
+            writeln!(out, "{indent}{prefix} {}", line.trim_end()).unwrap();
+        }
+    }
+}
+
+/// Writes a JSDoc comment
+pub fn write_jsdoc(out: &mut String, doc: &Option<String>, indent: &str) {
+    let Some(doc) = doc else {
+        return;
+    };
+
+    let lines = doc.trim().lines().collect::<Vec<_>>();
+    if let [line] = lines.as_slice() {
+        writeln!(out, "{indent}/** {line} */").unwrap();
+        return;
+    }
+
+    writeln!(out, "{indent}/**").unwrap();
+    for line in lines {
+        writeln!(out, "{indent} * {}", line.trim_end()).unwrap();
+    }
+    writeln!(out, "{indent} */").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_case() {
+        assert_eq!(camel_case("client_name"), "clientName");
+        assert_eq!(camel_case("FILE_CHUNKING"), "fileChunking");
+        assert_eq!(camel_case("HelloExpected"), "helloExpected");
+        assert_eq!(pascal_case("SIGN_IN"), "SignIn");
+        assert_eq!(pascal_case("message_payload"), "MessagePayload");
+        assert_eq!(words("MessagePayload"), "message payload");
+    }
+}
@@ -0,0 +1,373 @@
+//! Emits the server side protocol types. Packets derive `PacketData`, so the wire format of a packet is defined
+//! by the derive macro and this module only has to pick the right field attributes.
+
+use std::fmt::Write;
+
+use crate::{
+    naming::{words, write_doc},
+    schema::{EnumDef, FlagsDef, NamedKind, PacketDef, Schema, Type, UnionDef},
+};
+
+/// Generates the Rust source for every type in the schema. The output is meant to be `include!`d by
+/// `networking::packet_type`.
+pub fn generate(schema: &Schema) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "// @generated by rustchat-codegen from protocol.toml, do not edit."
+    )
+    .unwrap();
+
+    for packet in &schema.packets {
+        writeln!(out).unwrap();
+        write_doc(&mut out, &packet.doc, "", "///");
+        writeln!(out, "pub const {}: u8 = {};", packet.constant, packet.id).unwrap();
+    }
+
+    for def in &schema.enums {
+        write_enum(&mut out, def);
+    }
+    for def in &schema.flags {
+        write_flags(&mut out, def);
+    }
+    for def in &schema.unions {
+        write_union(&mut out, schema, def);
+    }
+    for def in &schema.packets {
+        write_packet(&mut out, schema, def);
+    }
+
+    out
+}
+
+fn write_enum(out: &mut String, def: &EnumDef) {
+    let name = &def.name;
+    writeln!(out).unwrap();
+    write_doc(out, &def.doc, "", "///");
+    writeln!(
+        out,
+        "#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]"
+    )
+    .unwrap();
+    writeln!(out, "pub enum {name} {{").unwrap();
+    for (i, variant) in def.variants.iter().enumerate() {
+        if i == 0 {
+            writeln!(out, "    #[default]").unwrap();
+        } else if variant.doc.is_some() {
+            writeln!(out).unwrap();
+        }
+        write_doc(out, &variant.doc, "    ", "///");
+        writeln!(out, "    {},", variant.name).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out).unwrap();
+    writeln!(out, "impl {name} {{").unwrap();
+    writeln!(out, "    pub fn from_code(code: u8) -> Option<Self> {{").unwrap();
+    writeln!(out, "        match code {{").unwrap();
+    for (code, variant) in def.variants.iter().enumerate() {
+        writeln!(out, "            {code} => Some(Self::{}),", variant.name).unwrap();
+    }
+    writeln!(out, "            _ => None,").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "    pub fn to_code(&self) -> u8 {{").unwrap();
+    writeln!(out, "        match self {{").unwrap();
+    for (code, variant) in def.variants.iter().enumerate() {
+        writeln!(out, "            {name}::{} => {code},", variant.name).unwrap();
+    }
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    let decode = format!(
+        "let offset = decoder.offset();\n        let code = decoder.read_u8()?;\n        Self::from_code(code).ok_or(crate::coding::CodingError::UnknownDiscriminant {{\n            kind: \"{}\",\n            value: code as u64,\n            offset,\n        }})",
+        words(name)
+    );
+    write_codable(out, name, "encoder.write_u8(self.to_code());", &decode);
+
+    // JSON uses the same codes as the binary encoding
+    writeln!(
+        out,
+        r#"
+impl ::serde::Serialize for {name} {{
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error> {{
+        serializer.serialize_u8(self.to_code())
+    }}
+}}
+
+impl<'de> ::serde::Deserialize<'de> for {name} {{
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::core::result::Result<Self, D::Error> {{
+        let code = <u8 as ::serde::Deserialize>::deserialize(deserializer)?;
+        Self::from_code(code).ok_or_else(|| {{
+            ::serde::de::Error::custom(::core::format_args!("unknown {words} discriminant {{code}}"))
+        }})
+    }}
+}}"#,
+        words = words(name)
+    )
+    .unwrap();
+}
+
+fn write_flags(out: &mut String, def: &FlagsDef) {
+    let name = &def.name;
+    writeln!(out).unwrap();
+    write_doc(out, &def.doc, "", "///");
+    writeln!(
+        out,
+        "#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash, ::serde::Serialize, ::serde::Deserialize)]"
+    )
+    .unwrap();
+    writeln!(out, "#[serde(transparent)]").unwrap();
+    writeln!(out, "pub struct {name}(u32);").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "impl {name} {{").unwrap();
+    for bit in &def.bits {
+        write_doc(out, &bit.doc, "    ", "///");
+        writeln!(
+            out,
+            "    pub const {}: {name} = {name}(1 << {});",
+            bit.name, bit.bit
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+    }
+    writeln!(
+        out,
+        r#"    pub const fn empty() -> Self {{
+        {name}(0)
+    }}
+
+    pub const fn from_bits(bits: u32) -> Self {{
+        {name}(bits)
+    }}
+
+    pub const fn bits(&self) -> u32 {{
+        self.0
+    }}
+
+    pub const fn contains(&self, other: {name}) -> bool {{
+        self.0 & other.0 == other.0
+    }}
+
+    pub const fn intersection(&self, other: {name}) -> {name} {{
+        {name}(self.0 & other.0)
+    }}
+}}
+
+impl ::std::ops::BitOr for {name} {{
+    type Output = {name};
+
+    fn bitor(self, rhs: Self) -> Self::Output {{
+        {name}(self.0 | rhs.0)
+    }}
+}}"#
+    )
+    .unwrap();
+
+    write_codable(
+        out,
+        name,
+        "encoder.write_varint(self.0);",
+        &format!("Ok({name}(decoder.read_varint()?))"),
+    );
+}
+
+fn write_union(out: &mut String, schema: &Schema, def: &UnionDef) {
+    let name = &def.name;
+    writeln!(out).unwrap();
+    write_doc(out, &def.doc, "", "///");
+    writeln!(
+        out,
+        "#[derive(Debug, Clone, PartialEq, ::serde::Serialize, ::serde::Deserialize)]"
+    )
+    .unwrap();
+    writeln!(out, "#[serde(tag = \"kind\", content = \"value\")]").unwrap();
+    writeln!(out, "pub enum {name} {{").unwrap();
+    for variant in &def.variants {
+        if let Some(attribute) = serde_attribute(&variant.ty) {
+            writeln!(out, "    #[serde({attribute})]").unwrap();
+        }
+        writeln!(out, "    {}({}),", variant.name, rust_type(&variant.ty)).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    // packets are decoded into their default, so every union needs one that can be encoded as well
+    writeln!(
+        out,
+        r#"
+impl Default for {name} {{
+    fn default() -> Self {{
+        {name}::{}(Default::default())
+    }}
+}}"#,
+        def.variants[0].name
+    )
+    .unwrap();
+
+    let mut encode = String::from("match self {");
+    for variant in &def.variants {
+        write!(
+            encode,
+            "\n            {name}::{}(value) => {{\n                encoder.write_i8({});\n                {};\n            }}",
+            variant.name,
+            variant.tag,
+            write_value(schema, &variant.ty, "value"),
+        )
+        .unwrap();
+    }
+    encode.push_str("\n        }");
+
+    let mut decode =
+        String::from("let offset = decoder.offset();\n        match decoder.read_i8()? {");
+    for variant in &def.variants {
+        write!(
+            decode,
+            "\n            {} => Ok({name}::{}({})),",
+            variant.tag,
+            variant.name,
+            read_value(&variant.ty)
+        )
+        .unwrap();
+    }
+    write!(
+        decode,
+        "\n            value => Err(crate::coding::CodingError::UnknownDiscriminant {{\n                kind: \"{}\",\n                value: value as u64,\n                offset,\n            }}),\n        }}",
+        words(name)
+    )
+    .unwrap();
+
+    write_codable(out, name, &encode, &decode);
+}
+
+fn write_packet(out: &mut String, schema: &Schema, def: &PacketDef) {
+    writeln!(out).unwrap();
+    write_doc(out, &def.doc, "", "///");
+    writeln!(
+        out,
+        "#[derive(Debug, Default, PartialEq, rustchat_derive::PacketData, ::serde::Serialize, ::serde::Deserialize)]"
+    )
+    .unwrap();
+    match &def.sample {
+        Some(sample) => writeln!(
+            out,
+            "#[packet(id = {}, sample = {})]",
+            def.constant,
+            sample.trim()
+        )
+        .unwrap(),
+        None => writeln!(out, "#[packet(id = {})]", def.constant).unwrap(),
+    }
+    writeln!(out, "#[serde(rename_all = \"camelCase\")]").unwrap();
+    writeln!(out, "pub struct {} {{", def.name).unwrap();
+    for (i, field) in def.fields.iter().enumerate() {
+        if i > 0 && (field.doc.is_some() || def.fields[i - 1].doc.is_some()) {
+            writeln!(out).unwrap();
+        }
+        write_doc(out, &field.doc, "    ", "///");
+        if let Some(attribute) = field_attribute(schema, &field.ty, field.max_len) {
+            writeln!(out, "    #[packet({attribute})]").unwrap();
+        }
+        if let Some(attribute) = serde_attribute(&field.ty) {
+            writeln!(out, "    #[serde({attribute})]").unwrap();
+        }
+        writeln!(out, "    pub {}: {},", field.name, rust_type(&field.ty)).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+}
+
+fn write_codable(out: &mut String, name: &str, encode: &str, decode: &str) {
+    writeln!(
+        out,
+        r#"
+impl crate::coding::Codable for {name} {{
+    fn encode(&self, encoder: &mut crate::coding::Encoder) {{
+        {encode}
+    }}
+
+    fn decode(decoder: &mut crate::coding::Decoder) -> ::core::result::Result<Self, crate::coding::CodingError> {{
+        {decode}
+    }}
+}}"#
+    )
+    .unwrap();
+}
+
+/// The `#[packet(...)]` attribute of a field, `None` when it is written through `Codable`
+fn field_attribute(schema: &Schema, ty: &Type, max_len: Option<usize>) -> Option<String> {
+    let attribute = match ty {
+        Type::Varint => "varint".to_string(),
+        Type::Varlong => "varlong".to_string(),
+        Type::Svarint => "svarint".to_string(),
+        Type::Svarlong => "svarlong".to_string(),
+        Type::Bytes => "bytes".to_string(),
+        Type::List(_) => format!("seq, max_len = {}", max_len.unwrap()),
+        Type::Map(..) => format!("map, max_len = {}", max_len.unwrap()),
+        Type::Named(name) if schema.kind_of(name) == Some(NamedKind::Enum) => "enum".to_string(),
+        _ => return None,
+    };
+
+    Some(attribute)
+}
+
+/// The `#[serde(...)]` attribute of a field or union variant, `None` when the derived encoding is used
+fn serde_attribute(ty: &Type) -> Option<&'static str> {
+    match ty {
+        Type::Bytes => Some("with = \"crate::networking::json::base64\""),
+        _ => None,
+    }
+}
+
+fn rust_type(ty: &Type) -> String {
+    match ty {
+        Type::Bool => "bool".into(),
+        Type::U8 => "u8".into(),
+        Type::I8 => "i8".into(),
+        Type::U16 => "u16".into(),
+        Type::I16 => "i16".into(),
+        Type::U32 | Type::Varint => "u32".into(),
+        Type::I32 | Type::Svarint => "i32".into(),
+        Type::U64 | Type::Varlong => "u64".into(),
+        Type::I64 | Type::Svarlong => "i64".into(),
+        Type::F32 => "f32".into(),
+        Type::F64 => "f64".into(),
+        Type::String => "String".into(),
+        Type::Bytes => "::bytes::Bytes".into(),
+        Type::Uuid => "::uuid::Uuid".into(),
+        Type::Option(inner) => format!("Option<{}>", rust_type(inner)),
+        Type::List(inner) => format!("Vec<{}>", rust_type(inner)),
+        Type::Map(key, value) => format!(
+            "::std::collections::HashMap<{}, {}>",
+            rust_type(key),
+            rust_type(value)
+        ),
+        Type::Named(name) => name.clone(),
+    }
+}
+
+/// Writes a union variant value, `value` is a reference
+fn write_value(schema: &Schema, ty: &Type, value: &str) -> String {
+    match ty {
+        Type::Varint => format!("encoder.write_varint(*{value})"),
+        Type::Varlong => format!("encoder.write_varlong(*{value})"),
+        Type::Svarint => format!("encoder.write_svarint(*{value})"),
+        Type::Svarlong => format!("encoder.write_svarlong(*{value})"),
+        Type::Bytes => format!("encoder.write_bytes({value})"),
+        Type::Named(name) if schema.kind_of(name) == Some(NamedKind::Enum) => {
+            format!("encoder.write_u8({value}.to_code())")
+        }
+        _ => format!("crate::coding::Codable::encode({value}, encoder)"),
+    }
+}
+
+fn read_value(ty: &Type) -> String {
+    match ty {
+        Type::Varint => "decoder.read_varint()?".into(),
+        Type::Varlong => "decoder.read_varlong()?".into(),
+        Type::Svarint => "decoder.read_svarint()?".into(),
+        Type::Svarlong => "decoder.read_svarlong()?".into(),
+        Type::Bytes => "decoder.read_bytes()?".into(),
+        _ => "crate::coding::Codable::decode(decoder)?".into(),
+    }
+}
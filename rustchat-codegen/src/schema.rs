@@ -0,0 +1,477 @@
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use serde::Deserialize;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
+pub enum SchemaError {
+    #[snafu(display("could not read {path}: {source}"))]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("invalid schema: {source}"))]
+    Parse { source: toml::de::Error },
+
+    #[snafu(display("invalid type `{ty}` on {field}"))]
+    InvalidType { ty: String, field: String },
+
+    #[snafu(display("unknown type `{ty}` on {field}"))]
+    UnknownType { ty: String, field: String },
+
+    #[snafu(display("`{ty}` on {field} can not be nested inside an option, list or map"))]
+    NotNestable { ty: String, field: String },
+
+    #[snafu(display("union `{name}` has no variants"))]
+    EmptyUnion { name: String },
+
+    #[snafu(display("union variant {field} can not be a list or map"))]
+    CollectionVariant { field: String },
+
+    #[snafu(display("{field} is a list or map and requires `max_len`"))]
+    MissingMaxLen { field: String },
+
+    #[snafu(display("{field} has `max_len` but is not a list or map"))]
+    UnexpectedMaxLen { field: String },
+
+    #[snafu(display("`{name}` is declared more than once"))]
+    DuplicateName { name: String },
+
+    #[snafu(display("packet id {id} is used by more than one packet"))]
+    DuplicatePacketId { id: u8 },
+
+    #[snafu(display("flags `{name}` uses bit {bit}, bits must be between 0 and 31"))]
+    InvalidBit { name: String, bit: u8 },
+}
+
+/// A parsed and validated `protocol.toml`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    #[serde(default)]
+    pub enums: Vec<EnumDef>,
+
+    #[serde(default)]
+    pub flags: Vec<FlagsDef>,
+
+    #[serde(default)]
+    pub unions: Vec<UnionDef>,
+
+    #[serde(default)]
+    pub packets: Vec<PacketDef>,
+}
+
+/// Written as a single byte with the index of the variant. The first variant is the default, unknown
+/// values fail to decode.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnumDef {
+    pub name: String,
+    pub doc: Option<String>,
+    pub variants: Vec<VariantDef>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VariantDef {
+    pub name: String,
+    pub doc: Option<String>,
+}
+
+/// A bitset written as a varint
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlagsDef {
+    pub name: String,
+    pub doc: Option<String>,
+    pub bits: Vec<BitDef>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BitDef {
+    pub name: String,
+    pub bit: u8,
+    pub doc: Option<String>,
+}
+
+/// One of several values, written as an i8 tag followed by the value of the variant
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnionDef {
+    pub name: String,
+    pub doc: Option<String>,
+    pub variants: Vec<UnionVariantDef>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UnionVariantDef {
+    pub name: String,
+    pub tag: i8,
+    #[serde(rename = "type")]
+    pub ty: Type,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PacketDef {
+    pub name: String,
+    pub id: u8,
+
+    /// The name of the Rust constant holding the packet id
+    pub constant: String,
+    pub doc: Option<String>,
+
+    /// A Rust expression used as the value of the generated round-trip test
+    pub sample: Option<String>,
+    pub fields: Vec<FieldDef>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldDef {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: Type,
+    pub max_len: Option<usize>,
+    pub doc: Option<String>,
+}
+
+/// The type of a field or union variant, see the top of `protocol.toml`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Type {
+    Bool,
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    Varint,
+    Varlong,
+    Svarint,
+    Svarlong,
+    String,
+    Bytes,
+    Uuid,
+    Option(Box<Type>),
+    List(Box<Type>),
+    Map(Box<Type>, Box<Type>),
+    Named(String),
+}
+
+/// What a named type refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamedKind {
+    Enum,
+    Flags,
+    Union,
+}
+
+impl Schema {
+    pub fn parse(source: &str) -> Result<Schema, SchemaError> {
+        let schema: Schema = toml::from_str(source).context(ParseSnafu)?;
+        schema.validate()?;
+        Ok(schema)
+    }
+
+    /// Resolves a type name declared in the schema
+    pub fn kind_of(&self, name: &str) -> Option<NamedKind> {
+        if self.enums.iter().any(|e| e.name == name) {
+            Some(NamedKind::Enum)
+        } else if self.flags.iter().any(|f| f.name == name) {
+            Some(NamedKind::Flags)
+        } else if self.unions.iter().any(|u| u.name == name) {
+            Some(NamedKind::Union)
+        } else {
+            None
+        }
+    }
+
+    fn validate(&self) -> Result<(), SchemaError> {
+        let mut names = HashSet::new();
+        let declared = self
+            .enums
+            .iter()
+            .map(|e| &e.name)
+            .chain(self.flags.iter().map(|f| &f.name))
+            .chain(self.unions.iter().map(|u| &u.name))
+            .chain(self.packets.iter().map(|p| &p.name));
+        for name in declared {
+            ensure!(
+                names.insert(name.as_str()),
+                DuplicateNameSnafu { name: name.clone() }
+            );
+        }
+
+        for flags in &self.flags {
+            for bit in &flags.bits {
+                ensure!(
+                    bit.bit < 32,
+                    InvalidBitSnafu {
+                        name: flags.name.clone(),
+                        bit: bit.bit,
+                    }
+                );
+            }
+        }
+
+        for union in &self.unions {
+            ensure!(
+                !union.variants.is_empty(),
+                EmptyUnionSnafu {
+                    name: union.name.clone()
+                }
+            );
+            for variant in &union.variants {
+                let field = format!("{}::{}", union.name, variant.name);
+                ensure!(
+                    !matches!(variant.ty, Type::List(_) | Type::Map(..)),
+                    CollectionVariantSnafu { field }
+                );
+                self.validate_type(&variant.ty, &field, false)?;
+            }
+        }
+
+        let mut ids = HashSet::new();
+        for packet in &self.packets {
+            ensure!(
+                ids.insert(packet.id),
+                DuplicatePacketIdSnafu { id: packet.id }
+            );
+
+            for def in &packet.fields {
+                let field = format!("{}.{}", packet.name, def.name);
+                self.validate_type(&def.ty, &field, false)?;
+
+                let collection = matches!(def.ty, Type::List(_) | Type::Map(..));
+                match def.max_len {
+                    Some(_) => ensure!(collection, UnexpectedMaxLenSnafu { field }),
+                    None => ensure!(!collection, MissingMaxLenSnafu { field }),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks named types exist and that nested types can be encoded on their own. Nested values are written
+    /// through `Codable` on the server, which has no varint or length prefixed bytes implementation.
+    fn validate_type(&self, ty: &Type, field: &str, nested: bool) -> Result<(), SchemaError> {
+        let not_nestable = || NotNestableSnafu {
+            ty: ty.to_string(),
+            field,
+        };
+
+        match ty {
+            Type::Varint | Type::Varlong | Type::Svarint | Type::Svarlong | Type::Bytes => {
+                ensure!(!nested, not_nestable());
+            }
+            Type::List(_) | Type::Map(..) if nested => return not_nestable().fail(),
+            Type::Option(inner) | Type::List(inner) => self.validate_type(inner, field, true)?,
+            Type::Map(key, value) => {
+                ensure!(
+                    !matches!(**key, Type::F32 | Type::F64 | Type::Option(_)),
+                    InvalidTypeSnafu {
+                        ty: key.to_string(),
+                        field,
+                    }
+                );
+                self.validate_type(key, field, true)?;
+                self.validate_type(value, field, true)?;
+            }
+            Type::Named(name) => {
+                self.kind_of(name).context(UnknownTypeSnafu {
+                    ty: name.clone(),
+                    field,
+                })?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for Type {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl FromStr for Type {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some((outer, inner)) = s.strip_suffix('>').and_then(|s| s.split_once('<')) {
+            let invalid = || format!("invalid type `{s}`");
+            return match outer.trim() {
+                "option" => Ok(Type::Option(Box::new(inner.parse()?))),
+                "list" => Ok(Type::List(Box::new(inner.parse()?))),
+                "map" => {
+                    // keys can not be generic, so the first comma always ends the key
+                    let (key, value) = inner.split_once(',').ok_or_else(invalid)?;
+                    Ok(Type::Map(Box::new(key.parse()?), Box::new(value.parse()?)))
+                }
+                _ => Err(invalid()),
+            };
+        }
+
+        Ok(match s {
+            "bool" => Type::Bool,
+            "u8" => Type::U8,
+            "i8" => Type::I8,
+            "u16" => Type::U16,
+            "i16" => Type::I16,
+            "u32" => Type::U32,
+            "i32" => Type::I32,
+            "u64" => Type::U64,
+            "i64" => Type::I64,
+            "f32" => Type::F32,
+            "f64" => Type::F64,
+            "varint" => Type::Varint,
+            "varlong" => Type::Varlong,
+            "svarint" => Type::Svarint,
+            "svarlong" => Type::Svarlong,
+            "string" => Type::String,
+            "bytes" => Type::Bytes,
+            "uuid" => Type::Uuid,
+            name if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') => {
+                Type::Named(name.to_string())
+            }
+            _ => return Err(format!("invalid type `{s}`")),
+        })
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::Bool => "bool",
+            Type::U8 => "u8",
+            Type::I8 => "i8",
+            Type::U16 => "u16",
+            Type::I16 => "i16",
+            Type::U32 => "u32",
+            Type::I32 => "i32",
+            Type::U64 => "u64",
+            Type::I64 => "i64",
+            Type::F32 => "f32",
+            Type::F64 => "f64",
+            Type::Varint => "varint",
+            Type::Varlong => "varlong",
+            Type::Svarint => "svarint",
+            Type::Svarlong => "svarlong",
+            Type::String => "string",
+            Type::Bytes => "bytes",
+            Type::Uuid => "uuid",
+            Type::Option(inner) => return write!(f, "option<{inner}>"),
+            Type::List(inner) => return write!(f, "list<{inner}>"),
+            Type::Map(key, value) => return write!(f, "map<{key}, {value}>"),
+            Type::Named(name) => name,
+        };
+
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_types() {
+        assert_eq!("svarlong".parse(), Ok(Type::Svarlong));
+        assert_eq!(
+            "option<list<uuid>>".parse(),
+            Ok(Type::Option(Box::new(Type::List(Box::new(Type::Uuid)))))
+        );
+        assert_eq!(
+            "map<u32, option<string>>".parse(),
+            Ok(Type::Map(
+                Box::new(Type::U32),
+                Box::new(Type::Option(Box::new(Type::String)))
+            ))
+        );
+        assert!("map<u32>".parse::<Type>().is_err());
+        assert!("list<".parse::<Type>().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_invalid_schemas() {
+        let missing_max_len = r#"
+            [[packets]]
+            name = "A"
+            id = 1
+            constant = "A"
+            fields = [{ name = "a", type = "list<string>" }]
+        "#;
+        assert!(matches!(
+            Schema::parse(missing_max_len),
+            Err(SchemaError::MissingMaxLen { .. })
+        ));
+
+        let nested_varint = r#"
+            [[packets]]
+            name = "A"
+            id = 1
+            constant = "A"
+            fields = [{ name = "a", type = "list<varint>", max_len = 4 }]
+        "#;
+        assert!(matches!(
+            Schema::parse(nested_varint),
+            Err(SchemaError::NotNestable { .. })
+        ));
+
+        let unknown_type = r#"
+            [[packets]]
+            name = "A"
+            id = 1
+            constant = "A"
+            fields = [{ name = "a", type = "Missing" }]
+        "#;
+        assert!(matches!(
+            Schema::parse(unknown_type),
+            Err(SchemaError::UnknownType { .. })
+        ));
+
+        let duplicate_id = r#"
+            [[packets]]
+            name = "A"
+            id = 1
+            constant = "A"
+            fields = []
+
+            [[packets]]
+            name = "B"
+            id = 1
+            constant = "B"
+            fields = []
+        "#;
+        assert!(matches!(
+            Schema::parse(duplicate_id),
+            Err(SchemaError::DuplicatePacketId { id: 1 })
+        ));
+
+        let empty_union = r#"
+            [[unions]]
+            name = "A"
+            variants = []
+        "#;
+        assert!(matches!(
+            Schema::parse(empty_union),
+            Err(SchemaError::EmptyUnion { .. })
+        ));
+    }
+}
@@ -0,0 +1,786 @@
+//! Emits a self-contained TypeScript module with the protocol types, their encoders and decoders and the envelope
+//! framing. 64 bit integers are represented as `bigint`, uuids as their hyphenated string.
+
+use std::fmt::Write;
+
+use crate::{
+    naming::{camel_case, pascal_case, words, write_jsdoc},
+    schema::{EnumDef, FlagsDef, NamedKind, PacketDef, Schema, Type, UnionDef},
+};
+
+const RUNTIME: &str = r#"export const ENVELOPE_VERSION = 1;
+export const FLAG_REQUEST_ID = 0x01;
+export const MAX_PACKET_SIZE = 1024 * 1024 * 12;
+
+const textEncoder = new TextEncoder();
+const textDecoder = new TextDecoder("utf-8", { fatal: true });
+
+export class ProtocolError extends Error {}
+
+export class ProtocolWriter {
+  private buffer = new Uint8Array(256);
+  private view = new DataView(this.buffer.buffer);
+  private length = 0;
+
+  takeBytes(): Uint8Array {
+    const bytes = this.buffer.slice(0, this.length);
+    this.length = 0;
+    return bytes;
+  }
+
+  writeBool(value: boolean): void {
+    this.writeU8(value ? 1 : 0);
+  }
+
+  writeU8(value: number): void {
+    this.view.setUint8(this.reserve(1), value);
+  }
+
+  writeI8(value: number): void {
+    this.view.setInt8(this.reserve(1), value);
+  }
+
+  writeU16(value: number): void {
+    this.view.setUint16(this.reserve(2), value);
+  }
+
+  writeI16(value: number): void {
+    this.view.setInt16(this.reserve(2), value);
+  }
+
+  writeU32(value: number): void {
+    this.view.setUint32(this.reserve(4), value);
+  }
+
+  writeI32(value: number): void {
+    this.view.setInt32(this.reserve(4), value);
+  }
+
+  writeU64(value: bigint): void {
+    this.view.setBigUint64(this.reserve(8), value);
+  }
+
+  writeI64(value: bigint): void {
+    this.view.setBigInt64(this.reserve(8), value);
+  }
+
+  writeF32(value: number): void {
+    this.view.setFloat32(this.reserve(4), value);
+  }
+
+  writeF64(value: number): void {
+    this.view.setFloat64(this.reserve(8), value);
+  }
+
+  writeVarint(value: number): void {
+    value >>>= 0;
+    while (value >= 0x80) {
+      this.writeU8((value & 0x7f) | 0x80);
+      value >>>= 7;
+    }
+    this.writeU8(value);
+  }
+
+  writeVarlong(value: bigint): void {
+    value = BigInt.asUintN(64, value);
+    while (value >= 0x80n) {
+      this.writeU8(Number(value & 0x7fn) | 0x80);
+      value >>= 7n;
+    }
+    this.writeU8(Number(value));
+  }
+
+  writeSvarint(value: number): void {
+    this.writeVarint((value << 1) ^ (value >> 31));
+  }
+
+  writeSvarlong(value: bigint): void {
+    value = BigInt.asIntN(64, value);
+    this.writeVarlong((value << 1n) ^ (value >> 63n));
+  }
+
+  writeString(value: string): void {
+    this.writeBytes(textEncoder.encode(value));
+  }
+
+  writeBytes(value: Uint8Array): void {
+    this.writeVarint(value.length);
+    this.buffer.set(value, this.reserve(value.length));
+  }
+
+  writeUuid(value: string): void {
+    const hex = value.replace(/-/g, "");
+    if (!/^[0-9a-fA-F]{32}$/.test(hex)) {
+      throw new ProtocolError(`invalid uuid ${value}`);
+    }
+    for (let i = 0; i < 32; i += 2) {
+      this.writeU8(parseInt(hex.substring(i, i + 2), 16));
+    }
+  }
+
+  writeOption<T>(value: T | null, write: (value: T) => void): void {
+    this.writeBool(value !== null);
+    if (value !== null) {
+      write(value);
+    }
+  }
+
+  writeList<T>(values: T[], write: (value: T) => void): void {
+    this.writeVarint(values.length);
+    values.forEach((value) => write(value));
+  }
+
+  writeMap<K, V>(values: Map<K, V>, writeKey: (key: K) => void, writeValue: (value: V) => void): void {
+    this.writeVarint(values.size);
+    values.forEach((value, key) => {
+      writeKey(key);
+      writeValue(value);
+    });
+  }
+
+  /** Grows the buffer when needed, returning the offset of the reserved bytes */
+  private reserve(length: number): number {
+    if (this.length + length > this.buffer.length) {
+      const buffer = new Uint8Array(Math.max(this.buffer.length * 2, this.length + length));
+      buffer.set(this.buffer.subarray(0, this.length));
+      this.buffer = buffer;
+      this.view = new DataView(buffer.buffer);
+    }
+
+    const offset = this.length;
+    this.length += length;
+    return offset;
+  }
+}
+
+export class ProtocolReader {
+  private readonly view: DataView;
+  offset = 0;
+
+  constructor(private readonly bytes: Uint8Array) {
+    this.view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+  }
+
+  get remaining(): number {
+    return this.bytes.length - this.offset;
+  }
+
+  readBool(): boolean {
+    const offset = this.offset;
+    const value = this.readU8();
+    if (value > 1) {
+      throw new ProtocolError(`invalid boolean byte ${value} at offset ${offset}`);
+    }
+    return value === 1;
+  }
+
+  readU8(): number {
+    return this.view.getUint8(this.advance(1));
+  }
+
+  readI8(): number {
+    return this.view.getInt8(this.advance(1));
+  }
+
+  readU16(): number {
+    return this.view.getUint16(this.advance(2));
+  }
+
+  readI16(): number {
+    return this.view.getInt16(this.advance(2));
+  }
+
+  readU32(): number {
+    return this.view.getUint32(this.advance(4));
+  }
+
+  readI32(): number {
+    return this.view.getInt32(this.advance(4));
+  }
+
+  readU64(): bigint {
+    return this.view.getBigUint64(this.advance(8));
+  }
+
+  readI64(): bigint {
+    return this.view.getBigInt64(this.advance(8));
+  }
+
+  readF32(): number {
+    return this.view.getFloat32(this.advance(4));
+  }
+
+  readF64(): number {
+    return this.view.getFloat64(this.advance(8));
+  }
+
+  readVarint(): number {
+    const offset = this.offset;
+    let result = 0;
+    for (let shift = 0; shift < 32; shift += 7) {
+      const byte = this.readU8();
+      result |= (byte & 0x7f) << shift;
+      if ((byte & 0x80) === 0) {
+        return result >>> 0;
+      }
+    }
+    throw new ProtocolError(`varint at offset ${offset} does not fit in 32 bits`);
+  }
+
+  readVarlong(): bigint {
+    const offset = this.offset;
+    let result = 0n;
+    for (let shift = 0n; shift < 64n; shift += 7n) {
+      const byte = this.readU8();
+      result |= BigInt(byte & 0x7f) << shift;
+      if ((byte & 0x80) === 0) {
+        return BigInt.asUintN(64, result);
+      }
+    }
+    throw new ProtocolError(`varint at offset ${offset} does not fit in 64 bits`);
+  }
+
+  readSvarint(): number {
+    const value = this.readVarint();
+    return (value >>> 1) ^ -(value & 1);
+  }
+
+  readSvarlong(): bigint {
+    const value = this.readVarlong();
+    return (value >> 1n) ^ -(value & 1n);
+  }
+
+  readString(): string {
+    const offset = this.offset;
+    const bytes = this.readBytes();
+    try {
+      return textDecoder.decode(bytes);
+    } catch {
+      throw new ProtocolError(`invalid utf-8 string at offset ${offset}`);
+    }
+  }
+
+  readBytes(): Uint8Array {
+    const length = this.readVarint();
+    const offset = this.advance(length);
+    return this.bytes.subarray(offset, offset + length);
+  }
+
+  readUuid(): string {
+    const offset = this.advance(16);
+    const hex = Array.from(this.bytes.subarray(offset, offset + 16), (byte) =>
+      byte.toString(16).padStart(2, "0"),
+    ).join("");
+    return `${hex.slice(0, 8)}-${hex.slice(8, 12)}-${hex.slice(12, 16)}-${hex.slice(16, 20)}-${hex.slice(20)}`;
+  }
+
+  readOption<T>(read: () => T): T | null {
+    return this.readBool() ? read() : null;
+  }
+
+  readList<T>(maxLength: number, read: () => T): T[] {
+    const length = this.readLength(maxLength);
+    const values: T[] = [];
+    for (let i = 0; i < length; i++) {
+      values.push(read());
+    }
+    return values;
+  }
+
+  readMap<K, V>(maxLength: number, readKey: () => K, readValue: () => V): Map<K, V> {
+    const length = this.readLength(maxLength);
+    const values = new Map<K, V>();
+    for (let i = 0; i < length; i++) {
+      const key = readKey();
+      values.set(key, readValue());
+    }
+    return values;
+  }
+
+  /** Every element takes at least one byte, so a length larger than the remaining data is rejected early. */
+  private readLength(maxLength: number): number {
+    const offset = this.offset;
+    const length = this.readVarint();
+    if (length > maxLength) {
+      throw new ProtocolError(`collection at offset ${offset} has ${length} elements, the maximum is ${maxLength}`);
+    }
+    this.ensure(length);
+    return length;
+  }
+
+  /** Consumes `length` bytes, returning the offset they start at */
+  private advance(length: number): number {
+    this.ensure(length);
+    const offset = this.offset;
+    this.offset += length;
+    return offset;
+  }
+
+  private ensure(needed: number): void {
+    if (this.remaining < needed) {
+      throw new ProtocolError(
+        `unexpected end of data at offset ${this.offset}: needed ${needed} bytes but only ${this.remaining} remain`,
+      );
+    }
+  }
+}
+
+/** A packet envelope, without the length prefix */
+export interface Frame {
+  packetType: number;
+
+  /** Set on requests that expect an answer, the server echoes it on the response. */
+  requestId: number | null;
+  payload: Uint8Array;
+}
+
+/** Encodes a packet with its length prefix and envelope header */
+export function encodeFrame(packet: Packet, requestId: number | null = null): Uint8Array {
+  const writer = new ProtocolWriter();
+  writer.writeU32(0); // length prefix, filled once the body is written
+  writer.writeU8(ENVELOPE_VERSION);
+  writer.writeU8(requestId === null ? 0 : FLAG_REQUEST_ID);
+  writer.writeU8(packet.type);
+  if (requestId !== null) {
+    writer.writeVarint(requestId);
+  }
+  writePacket(writer, packet);
+
+  const frame = writer.takeBytes();
+  const length = frame.length - 4;
+  if (length > MAX_PACKET_SIZE) {
+    throw new ProtocolError(`packet of ${length} bytes exceeds the maximum packet size`);
+  }
+  new DataView(frame.buffer).setUint32(0, length);
+  return frame;
+}
+
+/** Decodes an envelope, the length prefix must already be stripped */
+export function decodeFrame(body: Uint8Array): Frame {
+  const reader = new ProtocolReader(body);
+  const version = reader.readU8();
+  if (version !== ENVELOPE_VERSION) {
+    throw new ProtocolError(`unsupported envelope version ${version}`);
+  }
+
+  const flags = reader.readU8();
+  if ((flags & ~FLAG_REQUEST_ID) !== 0) {
+    throw new ProtocolError(`unsupported envelope flags ${flags}`);
+  }
+
+  const packetType = reader.readU8();
+  const requestId = (flags & FLAG_REQUEST_ID) !== 0 ? reader.readVarint() : null;
+  return { packetType, requestId, payload: body.subarray(reader.offset) };
+}
+
+/** Splits a stream of bytes into frames */
+export class FrameBuffer {
+  private pending = new Uint8Array(0);
+
+  /** Adds received bytes, returning every frame they complete */
+  push(bytes: Uint8Array): Frame[] {
+    const buffer = new Uint8Array(this.pending.length + bytes.length);
+    buffer.set(this.pending);
+    buffer.set(bytes, this.pending.length);
+
+    const frames: Frame[] = [];
+    let offset = 0;
+    while (buffer.length - offset >= 4) {
+      const length = new DataView(buffer.buffer, offset).getUint32(0);
+      if (buffer.length - offset < 4 + length) {
+        break;
+      }
+      frames.push(decodeFrame(buffer.subarray(offset + 4, offset + 4 + length)));
+      offset += 4 + length;
+    }
+
+    this.pending = buffer.slice(offset);
+    return frames;
+  }
+}
+"#;
+
+/// Generates the TypeScript module for the schema
+pub fn generate(schema: &Schema) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "// @generated by rustchat-codegen from protocol.toml, do not edit."
+    )
+    .unwrap();
+    writeln!(out).unwrap();
+    out.push_str(RUNTIME);
+
+    writeln!(out).unwrap();
+    writeln!(out, "export enum PacketType {{").unwrap();
+    for packet in &schema.packets {
+        write_jsdoc(&mut out, &packet.doc, "  ");
+        writeln!(out, "  {} = {},", pascal_case(&packet.constant), packet.id).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out).unwrap();
+    writeln!(out, "export type Packet =").unwrap();
+    for packet in &schema.packets {
+        writeln!(
+            out,
+            "  | {{ type: PacketType.{}; data: {} }}",
+            pascal_case(&packet.constant),
+            packet.name
+        )
+        .unwrap();
+    }
+    if schema.packets.is_empty() {
+        writeln!(out, "  never").unwrap();
+    }
+    out.truncate(out.trim_end().len());
+    writeln!(out, ";").unwrap();
+
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "export function writePacket(writer: ProtocolWriter, packet: Packet): void {{"
+    )
+    .unwrap();
+    writeln!(out, "  switch (packet.type) {{").unwrap();
+    for packet in &schema.packets {
+        writeln!(
+            out,
+            "    case PacketType.{}:",
+            pascal_case(&packet.constant)
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "      return write{}(writer, packet.data);",
+            packet.name
+        )
+        .unwrap();
+    }
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "/** Decodes the payload of a frame, returns null when the packet type is unknown */"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "export function decodePacket(frame: Frame): Packet | null {{"
+    )
+    .unwrap();
+    writeln!(out, "  const reader = new ProtocolReader(frame.payload);").unwrap();
+    writeln!(out, "  switch (frame.packetType) {{").unwrap();
+    for packet in &schema.packets {
+        let ty = pascal_case(&packet.constant);
+        writeln!(out, "    case PacketType.{ty}:").unwrap();
+        writeln!(
+            out,
+            "      return {{ type: PacketType.{ty}, data: read{}(reader) }};",
+            packet.name
+        )
+        .unwrap();
+    }
+    writeln!(out, "    default:").unwrap();
+    writeln!(out, "      return null;").unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    for def in &schema.enums {
+        write_enum(&mut out, def);
+    }
+    for def in &schema.flags {
+        write_flags(&mut out, def);
+    }
+    for def in &schema.unions {
+        write_union(&mut out, schema, def);
+    }
+    for def in &schema.packets {
+        write_packet(&mut out, schema, def);
+    }
+
+    out
+}
+
+fn write_enum(out: &mut String, def: &EnumDef) {
+    let name = &def.name;
+    writeln!(out).unwrap();
+    write_jsdoc(out, &def.doc, "");
+    writeln!(out, "export enum {name} {{").unwrap();
+    for (code, variant) in def.variants.iter().enumerate() {
+        write_jsdoc(out, &variant.doc, "  ");
+        writeln!(out, "  {} = {code},", variant.name).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "function read{name}(reader: ProtocolReader): {name} {{"
+    )
+    .unwrap();
+    writeln!(out, "  const offset = reader.offset;").unwrap();
+    writeln!(out, "  const code = reader.readU8();").unwrap();
+    writeln!(out, "  if (code >= {}) {{", def.variants.len()).unwrap();
+    writeln!(
+        out,
+        "    throw new ProtocolError(`unknown {} discriminant ${{code}} at offset ${{offset}}`);",
+        words(name)
+    )
+    .unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "  return code;").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn write_flags(out: &mut String, def: &FlagsDef) {
+    writeln!(out).unwrap();
+    write_jsdoc(out, &def.doc, "");
+    writeln!(out, "export const {} = {{", def.name).unwrap();
+    for bit in &def.bits {
+        write_jsdoc(out, &bit.doc, "  ");
+        writeln!(out, "  {}: 1 << {},", bit.name, bit.bit).unwrap();
+    }
+    writeln!(out, "}} as const;").unwrap();
+}
+
+fn write_union(out: &mut String, schema: &Schema, def: &UnionDef) {
+    let name = &def.name;
+    writeln!(out).unwrap();
+    write_jsdoc(out, &def.doc, "");
+    writeln!(out, "export type {name} =").unwrap();
+    for (i, variant) in def.variants.iter().enumerate() {
+        let end = if i + 1 == def.variants.len() { ";" } else { "" };
+        writeln!(
+            out,
+            "  | {{ kind: \"{}\"; value: {} }}{end}",
+            variant.name,
+            ts_type(schema, &variant.ty)
+        )
+        .unwrap();
+    }
+
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "export function write{name}(writer: ProtocolWriter, value: {name}): void {{"
+    )
+    .unwrap();
+    writeln!(out, "  switch (value.kind) {{").unwrap();
+    for variant in &def.variants {
+        writeln!(out, "    case \"{}\":", variant.name).unwrap();
+        writeln!(out, "      writer.writeI8({});", variant.tag).unwrap();
+        writeln!(
+            out,
+            "      {};",
+            write_value(schema, &variant.ty, "value.value", 0)
+        )
+        .unwrap();
+        writeln!(out, "      return;").unwrap();
+    }
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "export function read{name}(reader: ProtocolReader): {name} {{"
+    )
+    .unwrap();
+    writeln!(out, "  const offset = reader.offset;").unwrap();
+    writeln!(out, "  const tag = reader.readI8();").unwrap();
+    writeln!(out, "  switch (tag) {{").unwrap();
+    for variant in &def.variants {
+        writeln!(out, "    case {}:", variant.tag).unwrap();
+        writeln!(
+            out,
+            "      return {{ kind: \"{}\", value: {} }};",
+            variant.name,
+            read_value(schema, &variant.ty)
+        )
+        .unwrap();
+    }
+    writeln!(out, "    default:").unwrap();
+    writeln!(
+        out,
+        "      throw new ProtocolError(`unknown {} discriminant ${{tag}} at offset ${{offset}}`);",
+        words(name)
+    )
+    .unwrap();
+    writeln!(out, "  }}").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn write_packet(out: &mut String, schema: &Schema, def: &PacketDef) {
+    let name = &def.name;
+    writeln!(out).unwrap();
+    write_jsdoc(out, &def.doc, "");
+    writeln!(out, "export interface {name} {{").unwrap();
+    for field in &def.fields {
+        write_jsdoc(out, &field.doc, "  ");
+        writeln!(
+            out,
+            "  {}: {};",
+            camel_case(&field.name),
+            ts_type(schema, &field.ty)
+        )
+        .unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out).unwrap();
+    let packet = if def.fields.is_empty() {
+        "_packet"
+    } else {
+        "packet"
+    };
+    writeln!(
+        out,
+        "export function write{name}(writer: ProtocolWriter, {packet}: {name}): void {{"
+    )
+    .unwrap();
+    for field in &def.fields {
+        let value = format!("packet.{}", camel_case(&field.name));
+        writeln!(out, "  {};", write_value(schema, &field.ty, &value, 0)).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out).unwrap();
+    let reader = if def.fields.is_empty() {
+        "_reader"
+    } else {
+        "reader"
+    };
+    writeln!(
+        out,
+        "export function read{name}({reader}: ProtocolReader): {name} {{"
+    )
+    .unwrap();
+    writeln!(out, "  return {{").unwrap();
+    for field in &def.fields {
+        writeln!(
+            out,
+            "    {}: {},",
+            camel_case(&field.name),
+            read_field(schema, &field.ty, field.max_len)
+        )
+        .unwrap();
+    }
+    writeln!(out, "  }};").unwrap();
+    writeln!(out, "}}").unwrap();
+}
+
+fn ts_type(schema: &Schema, ty: &Type) -> String {
+    match ty {
+        Type::Bool => "boolean".into(),
+        Type::U8
+        | Type::I8
+        | Type::U16
+        | Type::I16
+        | Type::U32
+        | Type::I32
+        | Type::Varint
+        | Type::Svarint
+        | Type::F32
+        | Type::F64 => "number".into(),
+        Type::U64 | Type::I64 | Type::Varlong | Type::Svarlong => "bigint".into(),
+        Type::String | Type::Uuid => "string".into(),
+        Type::Bytes => "Uint8Array".into(),
+        Type::Option(inner) => format!("{} | null", ts_type(schema, inner)),
+        Type::List(inner) => format!("Array<{}>", ts_type(schema, inner)),
+        Type::Map(key, value) => {
+            format!("Map<{}, {}>", ts_type(schema, key), ts_type(schema, value))
+        }
+        Type::Named(name) => match schema.kind_of(name) {
+            Some(NamedKind::Flags) => "number".into(),
+            _ => name.clone(),
+        },
+    }
+}
+
+/// The writer method of a scalar type, or `None` for the types encoding themselves
+fn scalar_method(ty: &Type) -> Option<&'static str> {
+    Some(match ty {
+        Type::Bool => "Bool",
+        Type::U8 => "U8",
+        Type::I8 => "I8",
+        Type::U16 => "U16",
+        Type::I16 => "I16",
+        Type::U32 => "U32",
+        Type::I32 => "I32",
+        Type::U64 => "U64",
+        Type::I64 => "I64",
+        Type::F32 => "F32",
+        Type::F64 => "F64",
+        Type::Varint => "Varint",
+        Type::Varlong => "Varlong",
+        Type::Svarint => "Svarint",
+        Type::Svarlong => "Svarlong",
+        Type::String => "String",
+        Type::Bytes => "Bytes",
+        Type::Uuid => "Uuid",
+        _ => return None,
+    })
+}
+
+/// Writes `value`, `depth` keeps the closure parameters of nested types apart
+fn write_value(schema: &Schema, ty: &Type, value: &str, depth: usize) -> String {
+    if let Some(method) = scalar_method(ty) {
+        return format!("writer.write{method}({value})");
+    }
+
+    let v = format!("v{depth}");
+    let k = format!("k{depth}");
+    match ty {
+        Type::Option(inner) => format!(
+            "writer.writeOption({value}, ({v}) => {})",
+            write_value(schema, inner, &v, depth + 1)
+        ),
+        Type::List(inner) => format!(
+            "writer.writeList({value}, ({v}) => {})",
+            write_value(schema, inner, &v, depth + 1)
+        ),
+        Type::Map(key, inner) => format!(
+            "writer.writeMap({value}, ({k}) => {}, ({v}) => {})",
+            write_value(schema, key, &k, depth + 1),
+            write_value(schema, inner, &v, depth + 1)
+        ),
+        Type::Named(name) => match schema.kind_of(name) {
+            Some(NamedKind::Enum) => format!("writer.writeU8({value})"),
+            Some(NamedKind::Flags) => format!("writer.writeVarint({value})"),
+            _ => format!("write{name}(writer, {value})"),
+        },
+        _ => unreachable!("scalar types are handled above"),
+    }
+}
+
+fn read_field(schema: &Schema, ty: &Type, max_len: Option<usize>) -> String {
+    match (ty, max_len) {
+        (Type::List(inner), Some(max_len)) => format!(
+            "reader.readList({max_len}, () => {})",
+            read_value(schema, inner)
+        ),
+        (Type::Map(key, value), Some(max_len)) => format!(
+            "reader.readMap({max_len}, () => {}, () => {})",
+            read_value(schema, key),
+            read_value(schema, value)
+        ),
+        _ => read_value(schema, ty),
+    }
+}
+
+fn read_value(schema: &Schema, ty: &Type) -> String {
+    if let Some(method) = scalar_method(ty) {
+        return format!("reader.read{method}()");
+    }
+
+    match ty {
+        Type::Option(inner) => format!("reader.readOption(() => {})", read_value(schema, inner)),
+        Type::Named(name) => match schema.kind_of(name) {
+            Some(NamedKind::Flags) => "reader.readVarint()".into(),
+            _ => format!("read{name}(reader)"),
+        },
+        _ => unreachable!("collections only appear as fields and are read by read_field"),
+    }
+}
@@ -0,0 +1,275 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Expr, Fields, Ident};
+
+/// Derives `networking::packet_type::PacketData` for a struct with named fields.
+///
+/// The struct must carry a `#[packet(id = ...)]` attribute with the packet type id. An optional
+/// `sample = ...` expression is used as the value for the generated round-trip test, otherwise
+/// `Default::default()` is used.
+///
+/// Fields are written in declaration order. By default a field is encoded through its
+/// `coding::Codable` implementation, which can be overridden with:
+/// - `#[packet(varint)]` for `u32` values written as a varint
+/// - `#[packet(varlong)]` for `u64` values written as a varint
+/// - `#[packet(svarint)]` and `#[packet(svarlong)]` for `i32`/`i64` values written as zigzag varints
+/// - `#[packet(bytes)]` for `Bytes` values written with a varint length prefix
+/// - `#[packet(enum)]` for enums exposing `to_code()` and decoded through `Codable`, like `DestinationType`
+/// - `#[packet(seq, max_len = ...)]` for `Vec` values whose elements are `Codable`
+/// - `#[packet(map, max_len = ...)]` for `HashMap` values whose keys and values are `Codable`
+///
+/// `max_len` is required for sequences and maps, decoding fails with `CodingError::TooManyElements`
+/// when a peer sends more elements than that.
+///
+/// The generated code refers to `crate::coding` and `crate::networking`, so it
+/// is only meant to be used inside rustchat.
+#[proc_macro_derive(PacketData, attributes(packet))]
+pub fn derive_packet_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+enum FieldKind {
+    Codable,
+    Varint,
+    Varlong,
+    Svarint,
+    Svarlong,
+    Bytes,
+    Enum,
+    Seq(Expr),
+    Map(Expr),
+}
+
+struct PacketAttributes {
+    id: Expr,
+    sample: Option<Expr>,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let attributes = parse_packet_attributes(&input)?;
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => fields.named.iter().collect::<Vec<_>>(),
+            Fields::Unit => Vec::new(),
+            Fields::Unnamed(_) => {
+                return Err(syn::Error::new(
+                    input.span(),
+                    "PacketData can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new(
+                input.span(),
+                "PacketData can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut serialize = Vec::with_capacity(fields.len());
+    let mut deserialize = Vec::with_capacity(fields.len());
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let (write, read) = match parse_field_kind(&field.attrs)? {
+            FieldKind::Codable => (
+                quote! { crate::coding::Codable::encode(&self.#ident, encoder); },
+                quote! { crate::coding::Codable::decode(data)? },
+            ),
+            FieldKind::Varint => (
+                quote! { encoder.write_varint(self.#ident); },
+                quote! { data.read_varint()? },
+            ),
+            FieldKind::Varlong => (
+                quote! { encoder.write_varlong(self.#ident); },
+                quote! { data.read_varlong()? },
+            ),
+            FieldKind::Svarint => (
+                quote! { encoder.write_svarint(self.#ident); },
+                quote! { data.read_svarint()? },
+            ),
+            FieldKind::Svarlong => (
+                quote! { encoder.write_svarlong(self.#ident); },
+                quote! { data.read_svarlong()? },
+            ),
+            FieldKind::Bytes => (
+                quote! { encoder.write_bytes(&self.#ident); },
+                quote! { data.read_bytes()? },
+            ),
+            FieldKind::Enum => (
+                quote! { encoder.write_u8(self.#ident.to_code()); },
+                quote! { crate::coding::Codable::decode(data)? },
+            ),
+            FieldKind::Seq(max_len) => (
+                quote! {
+                    encoder.write_seq(&self.#ident, |encoder, value| {
+                        crate::coding::Codable::encode(value, encoder)
+                    });
+                },
+                quote! { data.read_seq(#max_len, crate::coding::Codable::decode)? },
+            ),
+            FieldKind::Map(max_len) => (
+                quote! {
+                    encoder.write_map(
+                        &self.#ident,
+                        |encoder, key| crate::coding::Codable::encode(key, encoder),
+                        |encoder, value| crate::coding::Codable::encode(value, encoder),
+                    );
+                },
+                quote! {
+                    data.read_map(
+                        #max_len,
+                        crate::coding::Codable::decode,
+                        crate::coding::Codable::decode,
+                    )?
+                },
+            ),
+        };
+
+        serialize.push(write);
+        deserialize.push(quote! { self.#ident = #read; });
+    }
+
+    let id = &attributes.id;
+    let sample = attributes
+        .sample
+        .map(|sample| quote! { #sample })
+        .unwrap_or_else(|| quote! { <#name as ::core::default::Default>::default() });
+    let test_module = format_ident!("{}_round_trip", to_snake_case(&name.to_string()));
+
+    Ok(quote! {
+        impl crate::networking::packet_type::PacketData for #name {
+            fn packet_id(&self) -> u8 {
+                #id
+            }
+
+            fn deserialize(&mut self, data: &mut crate::coding::Decoder) -> ::core::result::Result<(), crate::coding::CodingError> {
+                #(#deserialize)*
+                Ok(())
+            }
+
+            fn serialize(&self, encoder: &mut crate::coding::Encoder) {
+                #(#serialize)*
+            }
+        }
+
+        #[cfg(test)]
+        mod #test_module {
+            use super::*;
+            use crate::networking::packet_type::PacketData;
+
+            #[test]
+            fn round_trip() {
+                let packet: #name = #sample;
+                let mut encoder = crate::coding::Encoder::new();
+                packet.serialize(&mut encoder);
+                let buffer = encoder.take_bytes();
+
+                let mut decoder = crate::coding::Decoder::new(buffer);
+                let mut decoded = <#name as ::core::default::Default>::default();
+                decoded.deserialize(&mut decoder).unwrap();
+
+                assert_eq!(packet, decoded);
+                assert_eq!(decoder.remaining(), 0);
+                assert_eq!(decoded.packet_id(), #id);
+            }
+        }
+    })
+}
+
+fn parse_packet_attributes(input: &DeriveInput) -> syn::Result<PacketAttributes> {
+    let mut id = None;
+    let mut sample = None;
+
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                id = Some(meta.value()?.parse::<Expr>()?);
+                Ok(())
+            } else if meta.path.is_ident("sample") {
+                sample = Some(meta.value()?.parse::<Expr>()?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported packet attribute, expected `id` or `sample`"))
+            }
+        })?;
+    }
+
+    let id = id.ok_or_else(|| {
+        syn::Error::new(
+            Span::call_site(),
+            "missing `#[packet(id = ...)]` attribute on PacketData struct",
+        )
+    })?;
+
+    Ok(PacketAttributes { id, sample })
+}
+
+fn parse_field_kind(attrs: &[syn::Attribute]) -> syn::Result<FieldKind> {
+    let mut kind = FieldKind::Codable;
+    let mut collection: Option<fn(Expr) -> FieldKind> = None;
+    let mut max_len = None;
+
+    for attr in attrs.iter().filter(|a| a.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("varint") {
+                kind = FieldKind::Varint;
+            } else if meta.path.is_ident("varlong") {
+                kind = FieldKind::Varlong;
+            } else if meta.path.is_ident("svarint") {
+                kind = FieldKind::Svarint;
+            } else if meta.path.is_ident("svarlong") {
+                kind = FieldKind::Svarlong;
+            } else if meta.path.is_ident("bytes") {
+                kind = FieldKind::Bytes;
+            } else if meta.path.is_ident("enum") {
+                kind = FieldKind::Enum;
+            } else if meta.path.is_ident("seq") {
+                collection = Some(FieldKind::Seq);
+            } else if meta.path.is_ident("map") {
+                collection = Some(FieldKind::Map);
+            } else if meta.path.is_ident("max_len") {
+                max_len = Some(meta.value()?.parse::<Expr>()?);
+            } else {
+                return Err(meta.error(
+                    "unsupported field attribute, expected `varint`, `varlong`, `svarint`, `svarlong`, `bytes`, `enum`, `seq`, `map` or `max_len`",
+                ));
+            }
+            Ok(())
+        })?;
+    }
+
+    match (collection, max_len) {
+        (Some(collection), Some(max_len)) => Ok(collection(max_len)),
+        (Some(_), None) => Err(syn::Error::new(
+            Span::call_site(),
+            "`seq` and `map` fields require a `max_len = ...` attribute",
+        )),
+        (None, Some(max_len)) => Err(syn::Error::new(
+            max_len.span(),
+            "`max_len` is only supported on `seq` and `map` fields",
+        )),
+        (None, None) => Ok(kind),
+    }
+}
+
+fn to_snake_case(name: &str) -> Ident {
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, ch) in name.char_indices() {
+        if ch.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(ch.to_lowercase());
+        } else {
+            snake.push(ch);
+        }
+    }
+
+    Ident::new(&snake, Span::call_site())
+}
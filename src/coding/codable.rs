@@ -0,0 +1,83 @@
+use uuid::Uuid;
+
+use super::{ByteStr, CodingError, Decoder, Encoder};
+
+/// A value that knows how to write itself to an [`Encoder`] and read itself back from a [`Decoder`].
+///
+/// This is what `#[derive(PacketData)]` uses for fields without an explicit encoding attribute.
+pub trait Codable: Sized {
+    fn encode(&self, encoder: &mut Encoder);
+    fn decode(decoder: &mut Decoder) -> Result<Self, CodingError>;
+}
+
+macro_rules! impl_codable {
+    ($($ty:ty => $write:ident, $read:ident;)*) => {
+        $(
+            impl Codable for $ty {
+                #[inline]
+                fn encode(&self, encoder: &mut Encoder) {
+                    encoder.$write(*self);
+                }
+
+                #[inline]
+                fn decode(decoder: &mut Decoder) -> Result<Self, CodingError> {
+                    decoder.$read()
+                }
+            }
+        )*
+    };
+}
+
+impl_codable! {
+    bool => write_bool, read_bool;
+    u8 => write_u8, read_u8;
+    u16 => write_u16, read_u16;
+    u32 => write_u32, read_u32;
+    u64 => write_u64, read_u64;
+    i8 => write_i8, read_i8;
+    i16 => write_i16, read_i16;
+    i32 => write_i32, read_i32;
+    i64 => write_i64, read_i64;
+    f32 => write_f32, read_f32;
+    f64 => write_f64, read_f64;
+}
+
+impl Codable for String {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.write_string_ref(self);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, CodingError> {
+        decoder.read_string()
+    }
+}
+
+impl Codable for ByteStr {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.write_str(self);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, CodingError> {
+        decoder.read_str()
+    }
+}
+
+impl Codable for Uuid {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.write_uuid(self);
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, CodingError> {
+        decoder.read_uuid()
+    }
+}
+
+impl<T: Codable> Codable for Option<T> {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.write_option(self, |encoder, value| value.encode(encoder));
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, CodingError> {
+        decoder.read_option(T::decode)
+    }
+}
@@ -1,148 +1,397 @@
+use std::{collections::HashMap, hash::Hash};
+
 use bytes::{Buf, Bytes};
+use snafu::ResultExt;
+use uuid::Uuid;
+
+use super::{
+    byte_str::ByteStr,
+    error::{
+        CodingError, InvalidBoolSnafu, InvalidUtf8Snafu, TooManyElementsSnafu, UnexpectedEofSnafu,
+    },
+    varint::{decode_svarint32, decode_svarint64, decode_varint32, decode_varint64},
+};
+
+/// This is simply a wrapper around a Bytes buffer to safely read data, and also add method to read varint and varlong.
+///
+/// Reading bytes or strings does not copy, the returned values share the buffer the decoder was created with.
+#[derive(Debug)]
+pub struct Decoder {
+    cursor: Bytes,
+    len: usize,
+}
 
-use crate::types::types;
+impl Decoder {
+    pub fn new(cursor: Bytes) -> Self {
+        Decoder {
+            len: cursor.len(),
+            cursor,
+        }
+    }
 
-use super::varint::decode_varint32;
+    pub fn read_u8(&mut self) -> Result<u8, CodingError> {
+        self.ensure(1)?;
+        Ok(self.cursor.get_u8())
+    }
 
-/// This is simply a wrapper around a u8 slice to safely read data, and also add method to read varint and varlong
-#[derive(Debug)]
-pub struct Decoder<'a> {
-    cursor: &'a [u8],
-}
+    pub fn read_u16(&mut self) -> Result<u16, CodingError> {
+        self.ensure(2)?;
+        Ok(self.cursor.get_u16())
+    }
 
-impl<'a> Decoder<'a> {
-    pub fn new(cursor: &'a [u8]) -> Self {
-        Decoder { cursor }
+    pub fn read_u32(&mut self) -> Result<u32, CodingError> {
+        self.ensure(4)?;
+        Ok(self.cursor.get_u32())
     }
 
-    pub fn read_u8(&mut self) -> types::Result<u8> {
-        if self.cursor.remaining() > 0 {
-            return Ok(self.cursor.get_u8());
-        }
+    pub fn read_u64(&mut self) -> Result<u64, CodingError> {
+        self.ensure(8)?;
+        Ok(self.cursor.get_u64())
+    }
 
-        Err("not enough data to get u8".into())
+    pub fn read_i8(&mut self) -> Result<i8, CodingError> {
+        self.ensure(1)?;
+        Ok(self.cursor.get_i8())
     }
 
-    pub fn read_u16(&mut self) -> types::Result<u16> {
-        if self.cursor.remaining() > 1 {
-            return Ok(self.cursor.get_u16());
-        }
+    pub fn read_i16(&mut self) -> Result<i16, CodingError> {
+        self.ensure(2)?;
+        Ok(self.cursor.get_i16())
+    }
 
-        Err("not enough data to get u16".into())
+    pub fn read_i32(&mut self) -> Result<i32, CodingError> {
+        self.ensure(4)?;
+        Ok(self.cursor.get_i32())
     }
 
-    pub fn read_u32(&mut self) -> types::Result<u32> {
-        if self.cursor.remaining() > 3 {
-            return Ok(self.cursor.get_u32());
-        }
+    pub fn read_i64(&mut self) -> Result<i64, CodingError> {
+        self.ensure(8)?;
+        Ok(self.cursor.get_i64())
+    }
 
-        Err("not enough data to get u32".into())
+    pub fn read_f32(&mut self) -> Result<f32, CodingError> {
+        self.ensure(4)?;
+        Ok(self.cursor.get_f32())
     }
 
-    pub fn read_u64(&mut self) -> types::Result<u64> {
-        if self.cursor.remaining() > 7 {
-            return Ok(self.cursor.get_u64());
-        }
+    pub fn read_f64(&mut self) -> Result<f64, CodingError> {
+        self.ensure(8)?;
+        Ok(self.cursor.get_f64())
+    }
 
-        Err("not enough data to get u64".into())
+    pub fn read_bytes(&mut self) -> Result<Bytes, CodingError> {
+        let bytes_len = self.read_varint()? as usize;
+        self.ensure(bytes_len)?;
+        Ok(self.cursor.split_to(bytes_len))
     }
 
-    pub fn read_i8(&mut self) -> types::Result<i8> {
-        if self.cursor.remaining() > 0 {
-            return Ok(self.cursor.get_i8());
-        }
+    pub fn read_str(&mut self) -> Result<ByteStr, CodingError> {
+        let str_len = self.read_varint()? as usize;
+        self.ensure(str_len)?;
+
+        let offset = self.offset();
+        let str = ByteStr::from_utf8(self.cursor.slice(..str_len))
+            .context(InvalidUtf8Snafu { offset })?;
+        self.cursor.advance(str_len);
+        Ok(str)
+    }
 
-        Err("not enough data to get i8".into())
+    pub fn read_string(&mut self) -> Result<String, CodingError> {
+        Ok(self.read_str()?.to_string())
     }
 
-    pub fn read_i16(&mut self) -> types::Result<i16> {
-        if self.cursor.remaining() > 1 {
-            return Ok(self.cursor.get_i16());
+    pub fn read_varint(&mut self) -> Result<u32, CodingError> {
+        match decode_varint32(&self.cursor) {
+            Some((varint, len)) => {
+                self.cursor.advance(len);
+                Ok(varint)
+            }
+            None => Err(self.varint_error(32)),
         }
+    }
 
-        Err("not enough data to get i16".into())
+    pub fn read_varlong(&mut self) -> Result<u64, CodingError> {
+        match decode_varint64(&self.cursor) {
+            Some((varlong, len)) => {
+                self.cursor.advance(len);
+                Ok(varlong)
+            }
+            None => Err(self.varint_error(64)),
+        }
     }
 
-    pub fn read_i32(&mut self) -> types::Result<i32> {
-        if self.cursor.remaining() > 3 {
-            return Ok(self.cursor.get_i32());
+    /// Reads a zigzag encoded signed varint
+    pub fn read_svarint(&mut self) -> Result<i32, CodingError> {
+        match decode_svarint32(&self.cursor) {
+            Some((svarint, len)) => {
+                self.cursor.advance(len);
+                Ok(svarint)
+            }
+            None => Err(self.varint_error(32)),
         }
+    }
 
-        Err("not enough data to get i32".into())
+    /// Reads a zigzag encoded signed varlong
+    pub fn read_svarlong(&mut self) -> Result<i64, CodingError> {
+        match decode_svarint64(&self.cursor) {
+            Some((svarlong, len)) => {
+                self.cursor.advance(len);
+                Ok(svarlong)
+            }
+            None => Err(self.varint_error(64)),
+        }
     }
 
-    pub fn read_i64(&mut self) -> types::Result<i64> {
-        if self.cursor.remaining() > 7 {
-            return Ok(self.cursor.get_i64());
+    pub fn read_bool(&mut self) -> Result<bool, CodingError> {
+        self.ensure(1)?;
+        let offset = self.offset();
+        match self.cursor.get_u8() {
+            0x01 => Ok(true),
+            0x00 => Ok(false),
+            value => InvalidBoolSnafu { value, offset }.fail(),
         }
+    }
 
-        Err("not enough data to get i64".into())
+    pub fn read_uuid(&mut self) -> Result<Uuid, CodingError> {
+        self.ensure(16)?;
+        let mut bytes = [0; 16];
+        self.cursor.copy_to_slice(&mut bytes);
+        Ok(Uuid::from_bytes(bytes))
     }
 
-    pub fn read_bytes(&mut self) -> types::Result<Bytes> {
-        if !self.cursor.has_remaining() {
-            return Err("not enough data to read bytes".into());
+    /// Reads a presence flag followed, if present, by the value
+    pub fn read_option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, CodingError>,
+    ) -> Result<Option<T>, CodingError> {
+        match self.read_bool()? {
+            true => Ok(Some(read(self)?)),
+            false => Ok(None),
         }
+    }
 
-        if let Ok(bytes_len) = self.read_varint() {
-            let bytes_len = bytes_len as usize;
-            if self.cursor.len() < bytes_len {
-                return Err("not enough data to read bytes, specified by varint".into());
-            }
-
-            let slice = &self.cursor[..bytes_len];
-            let buffer = Bytes::copy_from_slice(slice);
-            return Ok(buffer);
+    /// Reads a sequence written by `Encoder::write_seq`, failing if it has more than `max_len` elements.
+    pub fn read_seq<T>(
+        &mut self,
+        max_len: usize,
+        mut read: impl FnMut(&mut Self) -> Result<T, CodingError>,
+    ) -> Result<Vec<T>, CodingError> {
+        let len = self.read_len(max_len)?;
+
+        let mut values = Vec::with_capacity(len);
+        for _ in 0..len {
+            values.push(read(self)?);
         }
 
-        Err("invalid bytes value".into())
+        Ok(values)
     }
 
-    pub fn read_string(&mut self) -> types::Result<String> {
-        if !self.cursor.has_remaining() {
-            return Err("not enough data to read string".into());
+    /// Reads a map written by `Encoder::write_map`, failing if it has more than `max_len` entries.
+    pub fn read_map<K: Eq + Hash, V>(
+        &mut self,
+        max_len: usize,
+        mut read_key: impl FnMut(&mut Self) -> Result<K, CodingError>,
+        mut read_value: impl FnMut(&mut Self) -> Result<V, CodingError>,
+    ) -> Result<HashMap<K, V>, CodingError> {
+        let len = self.read_len(max_len)?;
+
+        let mut entries = HashMap::with_capacity(len);
+        for _ in 0..len {
+            let key = read_key(self)?;
+            let value = read_value(self)?;
+            entries.insert(key, value);
         }
 
-        if let Ok(str_len) = self.read_varint() {
-            let str_len = str_len as usize;
-            if self.cursor.len() < str_len {
-                return Err("not enough data to read string, specified by varint".into());
-            }
+        Ok(entries)
+    }
 
-            let slice = &self.cursor[..str_len];
-            let str = String::from_utf8(slice.into())?;
-            return Ok(str);
-        }
+    /// Consumes the decoder, returning the bytes that were not read
+    pub fn into_remaining(self) -> Bytes {
+        self.cursor
+    }
 
-        Err("invalid string value".into())
+    pub fn remaining(&self) -> usize {
+        self.cursor.remaining()
     }
 
-    pub fn read_varint(&mut self) -> types::Result<u32> {
-        if !self.cursor.has_remaining() {
-            return Err("not enough data to get varint".into());
+    /// The amount of bytes read so far
+    pub fn offset(&self) -> usize {
+        self.len - self.cursor.remaining()
+    }
+
+    /// Reads the element count of a collection. Every element takes at least one byte, so the count is also
+    /// checked against the remaining data before anything is allocated for it.
+    fn read_len(&mut self, max_len: usize) -> Result<usize, CodingError> {
+        let offset = self.offset();
+        let len = self.read_varint()? as usize;
+        if len > max_len {
+            return TooManyElementsSnafu {
+                len,
+                max_len,
+                offset,
+            }
+            .fail();
         }
 
-        if let Some((varint, len)) = decode_varint32(self.cursor) {
-            self.cursor.advance(len);
-            return Ok(varint);
+        self.ensure(len)?;
+        Ok(len)
+    }
+
+    fn ensure(&self, needed: usize) -> Result<(), CodingError> {
+        let remaining = self.cursor.remaining();
+        if remaining < needed {
+            return UnexpectedEofSnafu {
+                needed,
+                remaining,
+                offset: self.offset(),
+            }
+            .fail();
         }
 
-        Err("invalid varint value".into())
+        Ok(())
     }
 
-    pub fn read_bool(&mut self) -> types::Result<bool> {
-        if self.cursor.remaining() > 0 {
-            return match self.cursor.get_u8() {
-                0x01 => Ok(true),
-                0x00 => Ok(false),
-                _ => Err("not a valid byte representing a boolean".into()),
+    /// A varint fails to decode either because the data ends before its last byte, or because it is longer
+    /// than the maximum amount of bytes for the given bit width.
+    fn varint_error(&self, bits: u8) -> CodingError {
+        let max_len = (bits as usize).div_ceil(7);
+        let remaining = self.cursor.remaining();
+        if remaining >= max_len {
+            return CodingError::VarintOverflow {
+                bits,
+                offset: self.offset(),
             };
         }
 
-        Err("not enough data to get bool".into())
+        CodingError::UnexpectedEof {
+            needed: remaining + 1,
+            remaining,
+            offset: self.offset(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coding::Encoder;
+
+    #[test]
+    fn test_decode_errors() {
+        let mut decoder = Decoder::new(Bytes::from_static(&[0x01, 0x00, 0x05, b'a']));
+        assert_eq!(decoder.read_u8(), Ok(1));
+        assert_eq!(
+            decoder.read_u32(),
+            Err(CodingError::UnexpectedEof {
+                needed: 4,
+                remaining: 3,
+                offset: 1,
+            })
+        );
+        assert_eq!(decoder.read_bool(), Ok(false));
+        assert_eq!(
+            decoder.read_string(),
+            Err(CodingError::UnexpectedEof {
+                needed: 5,
+                remaining: 1,
+                offset: 3,
+            })
+        );
+
+        let mut decoder = Decoder::new(Bytes::from_static(&[0x02]));
+        assert_eq!(
+            decoder.read_bool(),
+            Err(CodingError::InvalidBool {
+                value: 0x02,
+                offset: 0,
+            })
+        );
+
+        let mut decoder = Decoder::new(Bytes::from_static(&[0x02, 0xC3, 0x28]));
+        assert!(matches!(
+            decoder.read_string(),
+            Err(CodingError::InvalidUtf8 { offset: 1, .. })
+        ));
+
+        let mut decoder = Decoder::new(Bytes::from_static(&[
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02,
+        ]));
+        assert_eq!(
+            decoder.read_varlong(),
+            Err(CodingError::VarintOverflow {
+                bits: 64,
+                offset: 0
+            })
+        );
+
+        let mut decoder = Decoder::new(Bytes::from_static(&[0xFF; 6]));
+        assert_eq!(
+            decoder.read_varint(),
+            Err(CodingError::VarintOverflow {
+                bits: 32,
+                offset: 0
+            })
+        );
+        assert_eq!(
+            decoder.read_varlong(),
+            Err(CodingError::UnexpectedEof {
+                needed: 7,
+                remaining: 6,
+                offset: 0,
+            })
+        );
     }
 
-    pub fn remaining(&self) -> usize {
-        return self.cursor.remaining();
+    #[test]
+    fn test_collections() {
+        let id = Uuid::new_v4();
+        let members = vec!["john".to_string(), "jane".to_string()];
+        let roles = HashMap::from([(1u32, true), (2u32, false)]);
+
+        let mut encoder = Encoder::new();
+        encoder.write_uuid(&id);
+        encoder.write_option(&Some(7u8), |e, v| e.write_u8(*v));
+        encoder.write_option::<u8>(&None, |e, v| e.write_u8(*v));
+        encoder.write_seq(&members, |e, v| e.write_str(v));
+        encoder.write_map(&roles, |e, k| e.write_varint(*k), |e, v| e.write_bool(*v));
+
+        let mut decoder = Decoder::new(encoder.take_bytes());
+        assert_eq!(decoder.read_uuid(), Ok(id));
+        assert_eq!(decoder.read_option(Decoder::read_u8), Ok(Some(7)));
+        assert_eq!(decoder.read_option(Decoder::read_u8), Ok(None));
+        assert_eq!(decoder.read_seq(2, Decoder::read_string), Ok(members));
+        assert_eq!(
+            decoder.read_map(2, Decoder::read_varint, Decoder::read_bool),
+            Ok(roles)
+        );
+        assert_eq!(decoder.remaining(), 0);
+    }
+
+    #[test]
+    fn test_collections_enforce_limits() {
+        let mut encoder = Encoder::new();
+        encoder.write_seq(&[1u8, 2, 3], |e, v| e.write_u8(*v));
+        let buffer = encoder.take_bytes();
+
+        let mut decoder = Decoder::new(buffer.clone());
+        assert_eq!(
+            decoder.read_seq(2, Decoder::read_u8),
+            Err(CodingError::TooManyElements {
+                len: 3,
+                max_len: 2,
+                offset: 0,
+            })
+        );
+
+        // a huge length prefix with no data behind it must fail before allocating
+        let mut decoder = Decoder::new(Bytes::from_static(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]));
+        assert_eq!(
+            decoder.read_seq(usize::MAX, Decoder::read_u8),
+            Err(CodingError::UnexpectedEof {
+                needed: u32::MAX as usize,
+                remaining: 0,
+                offset: 5,
+            })
+        );
     }
 }
@@ -1,4 +1,5 @@
 use bytes::{BufMut, Bytes, BytesMut};
+use uuid::Uuid;
 
 use super::varint;
 
@@ -7,6 +8,12 @@ pub struct Encoder {
     buf: BytesMut,
 }
 
+impl Default for Encoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Encoder {
     pub fn new() -> Encoder {
         Encoder {
@@ -91,15 +98,77 @@ impl Encoder {
         self.buf.put_slice(buffer);
     }
 
+    pub fn write_str(&mut self, value: &str) {
+        let buffer = value.as_bytes();
+        if buffer.len() > 32767 {
+            panic!("Maximum string length exceeded");
+        }
+
+        self.write_varint(buffer.len() as u32);
+        self.buf.put_slice(buffer);
+    }
+
     pub fn write_varint(&mut self, value: u32) {
         varint::encode_varint32(value, &mut self.buf);
     }
 
+    pub fn write_varlong(&mut self, value: u64) {
+        varint::encode_varint64(value, &mut self.buf);
+    }
+
+    /// Writes a signed value as a zigzag encoded varint
+    pub fn write_svarint(&mut self, value: i32) {
+        varint::encode_svarint32(value, &mut self.buf);
+    }
+
+    /// Writes a signed value as a zigzag encoded varlong
+    pub fn write_svarlong(&mut self, value: i64) {
+        varint::encode_svarint64(value, &mut self.buf);
+    }
+
     pub fn write_bytes(&mut self, value: &Bytes) {
         self.write_varint(value.len() as u32);
         self.buf.put_slice(value);
     }
 
+    pub fn write_uuid(&mut self, value: &Uuid) {
+        self.buf.put_slice(value.as_bytes());
+    }
+
+    /// Writes a presence flag followed, if present, by the value
+    pub fn write_option<T>(&mut self, value: &Option<T>, write: impl FnOnce(&mut Self, &T)) {
+        match value {
+            Some(value) => {
+                self.write_bool(true);
+                write(self, value);
+            }
+            None => self.write_bool(false),
+        }
+    }
+
+    /// Writes the amount of elements as a varint followed by every element
+    pub fn write_seq<T>(&mut self, values: &[T], mut write: impl FnMut(&mut Self, &T)) {
+        self.write_varint(values.len() as u32);
+        for value in values {
+            write(self, value);
+        }
+    }
+
+    /// Writes the amount of entries as a varint followed by every key and value
+    pub fn write_map<'a, K: 'a, V: 'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = (&'a K, &'a V), IntoIter: ExactSizeIterator>,
+        mut write_key: impl FnMut(&mut Self, &K),
+        mut write_value: impl FnMut(&mut Self, &V),
+    ) {
+        let entries = entries.into_iter();
+        self.write_varint(entries.len() as u32);
+        for (key, value) in entries {
+            write_key(self, key);
+            write_value(self, value);
+        }
+    }
+
     pub fn take_bytes(self) -> Bytes {
         self.buf.freeze()
     }
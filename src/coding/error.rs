@@ -0,0 +1,43 @@
+use std::str::Utf8Error;
+
+use snafu::Snafu;
+
+/// Errors produced while decoding data. Every variant carries the offset, relative to the start
+/// of the decoded buffer, where the problem was found.
+#[derive(Debug, Snafu, PartialEq, Eq)]
+#[snafu(visibility(pub))]
+pub enum CodingError {
+    #[snafu(display(
+        "unexpected end of data at offset {offset}: needed {needed} bytes but only {remaining} remain"
+    ))]
+    UnexpectedEof {
+        needed: usize,
+        remaining: usize,
+        offset: usize,
+    },
+
+    #[snafu(display("invalid utf-8 string at offset {offset}: {source}"))]
+    InvalidUtf8 { offset: usize, source: Utf8Error },
+
+    #[snafu(display("varint at offset {offset} does not fit in {bits} bits"))]
+    VarintOverflow { bits: u8, offset: usize },
+
+    #[snafu(display("invalid boolean byte {value:#04x} at offset {offset}"))]
+    InvalidBool { value: u8, offset: usize },
+
+    #[snafu(display(
+        "collection at offset {offset} has {len} elements, the maximum is {max_len}"
+    ))]
+    TooManyElements {
+        len: usize,
+        max_len: usize,
+        offset: usize,
+    },
+
+    #[snafu(display("unknown {kind} discriminant {value} at offset {offset}"))]
+    UnknownDiscriminant {
+        kind: &'static str,
+        value: u64,
+        offset: usize,
+    },
+}
@@ -1,6 +1,12 @@
+pub mod byte_str;
+pub mod codable;
 pub mod decoder;
 pub mod encoder;
+pub mod error;
 pub mod varint;
 
+pub use byte_str::ByteStr;
+pub use codable::Codable;
 pub use decoder::Decoder;
 pub use encoder::Encoder;
+pub use error::CodingError;
@@ -19,6 +19,11 @@ pub fn decode_varint32(buf: &[u8]) -> Option<(u32, usize)> {
     let mut len = 0;
 
     for &byte in buf.iter() {
+        // the last byte may only carry the bits left in the value
+        if shift + 7 > 32 && (byte & 0x7F) >> (32 - shift) != 0 {
+            return None;
+        }
+
         value |= ((byte & 0x7F) as u32) << shift;
         len += 1;
 
@@ -36,6 +41,84 @@ pub fn decode_varint32(buf: &[u8]) -> Option<(u32, usize)> {
     None // If we exit the loop, it means the VARINT is incomplete
 }
 
+pub fn encode_varint64(mut value: u64, buf: &mut BytesMut) -> usize {
+    let mut len = 0;
+
+    while value >= 0x80 {
+        buf.put_u8((value as u8) | 0x80);
+        value >>= 7;
+        len += 1;
+    }
+
+    buf.put_u8(value as u8);
+    len + 1
+}
+
+pub fn decode_varint64(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    let mut len = 0;
+
+    for &byte in buf.iter() {
+        // the last byte may only carry the bits left in the value
+        if shift + 7 > 64 && (byte & 0x7F) >> (64 - shift) != 0 {
+            return None;
+        }
+
+        value |= ((byte & 0x7F) as u64) << shift;
+        len += 1;
+
+        if (byte & 0x80) == 0 {
+            return Some((value, len));
+        }
+
+        shift += 7;
+
+        if shift >= 64 {
+            return None; // Too many bytes
+        }
+    }
+
+    None // If we exit the loop, it means the VARINT is incomplete
+}
+
+/// Maps signed integers to unsigned so small negative values stay small: 0, -1, 1, -2 => 0, 1, 2, 3
+#[inline]
+pub fn zigzag_encode32(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+#[inline]
+pub fn zigzag_decode32(value: u32) -> i32 {
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+#[inline]
+pub fn zigzag_encode64(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+#[inline]
+pub fn zigzag_decode64(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+pub fn encode_svarint32(value: i32, buf: &mut BytesMut) -> usize {
+    encode_varint32(zigzag_encode32(value), buf)
+}
+
+pub fn decode_svarint32(buf: &[u8]) -> Option<(i32, usize)> {
+    decode_varint32(buf).map(|(value, len)| (zigzag_decode32(value), len))
+}
+
+pub fn encode_svarint64(value: i64, buf: &mut BytesMut) -> usize {
+    encode_varint64(zigzag_encode64(value), buf)
+}
+
+pub fn decode_svarint64(buf: &[u8]) -> Option<(i64, usize)> {
+    decode_varint64(buf).map(|(value, len)| (zigzag_decode64(value), len))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,10 +164,11 @@ mod tests {
     #[test]
     fn test_decode_varint32_incomplete() {
         let test_cases = vec![
-            vec![0x80],                   // incomplete
-            vec![0x80, 0x80],             // incomplete
-            vec![0x80, 0x80, 0x80],       // incomplete
-            vec![0x80, 0x80, 0x80, 0x80], // incomplete
+            vec![0x80],                         // incomplete
+            vec![0x80, 0x80],                   // incomplete
+            vec![0x80, 0x80, 0x80],             // incomplete
+            vec![0x80, 0x80, 0x80, 0x80],       // incomplete
+            vec![0xFF, 0xFF, 0xFF, 0xFF, 0x1F], // past 32 bits
         ];
 
         for encoded in test_cases {
@@ -92,4 +176,120 @@ mod tests {
             assert_eq!(decoded, None);
         }
     }
+
+    #[test]
+    fn test_encode_varint64() {
+        let test_cases = vec![
+            (0, vec![0x00]),
+            (1, vec![0x01]),
+            (127, vec![0x7F]),
+            (128, vec![0x80, 0x01]),
+            (300, vec![0xAC, 0x02]),
+            (16384, vec![0x80, 0x80, 0x01]),
+            (u32::MAX as u64, vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F]),
+            (
+                u64::MAX,
+                vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01],
+            ),
+        ];
+
+        for (value, expected) in test_cases {
+            let mut buf = BytesMut::with_capacity(10);
+            let size = encode_varint64(value, &mut buf);
+            assert_eq!(&buf[..], &expected[..]);
+            assert_eq!(size, expected.len());
+        }
+    }
+
+    #[test]
+    fn test_decode_varint64() {
+        let test_cases = vec![
+            (vec![0x00], Some((0, 1))),
+            (vec![0x01], Some((1, 1))),
+            (vec![0x7F], Some((127, 1))),
+            (vec![0x80, 0x01], Some((128, 2))),
+            (vec![0xAC, 0x02], Some((300, 2))),
+            (vec![0x80, 0x80, 0x01], Some((16384, 3))),
+            (
+                vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F],
+                Some((u32::MAX as u64, 5)),
+            ),
+            (
+                vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01],
+                Some((u64::MAX, 10)),
+            ),
+        ];
+
+        for (encoded, expected) in test_cases {
+            let decoded = decode_varint64(&encoded);
+            assert_eq!(decoded, expected);
+        }
+    }
+
+    #[test]
+    fn test_decode_varint64_incomplete() {
+        let test_cases = vec![
+            vec![0x80],                                                       // incomplete
+            vec![0x80, 0x80],                                                 // incomplete
+            vec![0x80, 0x80, 0x80, 0x80, 0x80],                               // incomplete
+            vec![0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80],       // incomplete
+            vec![0xFF; 11],                                                   // too many bytes
+            vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02], // past 64 bits
+        ];
+
+        for encoded in test_cases {
+            let decoded = decode_varint64(&encoded);
+            assert_eq!(decoded, None);
+        }
+    }
+
+    #[test]
+    fn test_encode_svarint32() {
+        let test_cases = vec![
+            (0, vec![0x00]),
+            (-1, vec![0x01]),
+            (1, vec![0x02]),
+            (-64, vec![0x7F]),
+            (64, vec![0x80, 0x01]),
+            (-150, vec![0xAB, 0x02]),
+            (i32::MAX, vec![0xFE, 0xFF, 0xFF, 0xFF, 0x0F]),
+            (i32::MIN, vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F]),
+        ];
+
+        for (value, expected) in test_cases {
+            let mut buf = BytesMut::with_capacity(5);
+            let size = encode_svarint32(value, &mut buf);
+            assert_eq!(&buf[..], &expected[..]);
+            assert_eq!(size, expected.len());
+            assert_eq!(decode_svarint32(&expected), Some((value, expected.len())));
+        }
+    }
+
+    #[test]
+    fn test_encode_svarint64() {
+        let test_cases = vec![
+            (0, vec![0x00]),
+            (-1, vec![0x01]),
+            (1, vec![0x02]),
+            (-64, vec![0x7F]),
+            (64, vec![0x80, 0x01]),
+            (-150, vec![0xAB, 0x02]),
+            (
+                i64::MAX,
+                vec![0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01],
+            ),
+            (
+                i64::MIN,
+                vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01],
+            ),
+        ];
+
+        for (value, expected) in test_cases {
+            let mut buf = BytesMut::with_capacity(10);
+            let size = encode_svarint64(value, &mut buf);
+            assert_eq!(&buf[..], &expected[..]);
+            assert_eq!(size, expected.len());
+            assert_eq!(decode_svarint64(&expected), Some((value, expected.len())));
+        }
+    }
 }
@@ -1,5 +1,4 @@
-use redis::Commands;
-use uuid::Uuid;
+use redis::AsyncCommands;
 
 use crate::{server::channel::ServerChannel, types};
 
@@ -14,7 +13,9 @@ impl Repository {
         })
     }
 
-    pub async fn get_channel(&self, id: Uuid) -> types::Result<ServerChannel> {
-        self.client.get(key)
+    pub async fn get_channel(&self, id: i32) -> types::Result<Option<ServerChannel>> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let exists: bool = conn.exists(format!("channel:{}", id)).await?;
+        Ok(exists.then(|| ServerChannel::new(id)))
     }
 }
@@ -0,0 +1,5 @@
+pub mod coding;
+pub mod database;
+pub mod networking;
+pub mod server;
+pub mod types;
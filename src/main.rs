@@ -1,15 +1,83 @@
-use server::server::Server;
+use std::{env, path::Path, time::Duration};
 
-mod coding;
-mod database;
-mod networking;
-mod server;
-mod types;
+use rustchat::server::{
+    proxy::TrustedProxies,
+    quic,
+    server::{Server, ShutdownSettings},
+    tls,
+    user::Heartbeat,
+};
+use tokio::signal;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut server = Server::new("127.0.0.1:7878").await?;
-    let _ = server.run().await;
+    server.listen_udp("127.0.0.1:7879").await?;
+
+    // quiet clients are pinged every RUSTCHAT_HEARTBEAT seconds, and dropped after missing a few pings
+    if let Ok(seconds) = env::var("RUSTCHAT_HEARTBEAT") {
+        server.set_heartbeat(Heartbeat {
+            interval: Duration::from_secs(seconds.parse()?),
+            ..Heartbeat::default()
+        });
+    }
+
+    // local bots and tools connect through the Unix socket instead of the public port
+    if let Ok(path) = env::var("RUSTCHAT_SOCKET") {
+        server.listen_unix(Path::new(&path))?;
+    }
+
+    // behind load balancers, clients are known by the address their PROXY protocol header carries
+    if let Ok(proxies) = env::var("RUSTCHAT_TRUSTED_PROXIES") {
+        server.trust_proxies(TrustedProxies::parse(&proxies)?);
+    }
+
+    // TLS and QUIC need a certificate, so they are only enabled when one is configured
+    if let (Ok(certificate), Ok(key)) = (env::var("RUSTCHAT_CERT"), env::var("RUSTCHAT_KEY")) {
+        let (certificates, key) = tls::load_pem(Path::new(&certificate), Path::new(&key))?;
+
+        // clients may authenticate with a certificate issued by these authorities
+        let client_roots = match env::var("RUSTCHAT_CLIENT_CA") {
+            Ok(path) => Some(tls::load_roots(Path::new(&path))?),
+            Err(_) => None,
+        };
+        server.enable_tls(tls::server_config(
+            certificates.clone(),
+            key.clone_key(),
+            client_roots,
+        )?);
+        server.listen_quic("127.0.0.1:7878", quic::server_config(certificates, key)?)?;
+    }
+
+    // deploys tell clients when to come back, and give them a little longer to receive what is queued for them
+    let mut shutdown = ShutdownSettings::default();
+    if let Ok(seconds) = env::var("RUSTCHAT_RECONNECT_AFTER") {
+        shutdown.reconnect_after = Some(Duration::from_secs(seconds.parse()?));
+    }
+    if let Ok(seconds) = env::var("RUSTCHAT_DRAIN_DEADLINE") {
+        shutdown.drain_deadline = Duration::from_secs(seconds.parse()?);
+    }
+    server.set_shutdown(shutdown);
+
+    if let Err(err) = server.run_until(shutdown_signal()).await {
+        println!("The server stopped with an error: {}", err);
+    }
 
     Ok(())
 }
+
+/// Completes on SIGINT, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("could not listen for SIGTERM");
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = signal::ctrl_c().await;
+}
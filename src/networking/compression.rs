@@ -0,0 +1,161 @@
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder};
+use snafu::{ensure, ResultExt};
+
+use super::{
+    error::{DecompressedTooLargeSnafu, InvalidCompressedPayloadSnafu, NetworkingError},
+    handshake::Capabilities,
+};
+
+/// The envelope payload is deflate compressed
+pub const FLAG_DEFLATE: u8 = 0x02;
+
+/// The envelope payload is zstd compressed
+pub const FLAG_ZSTD: u8 = 0x04;
+
+/// Both compression flags, at most one of them can be set
+pub const COMPRESSION_FLAGS: u8 = FLAG_DEFLATE | FLAG_ZSTD;
+
+/// Payloads smaller than this are sent as they are, compressing them rarely pays off
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// The algorithm a packet payload is compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+
+    /// zlib wrapped deflate, the format of `CompressionStream("deflate")` in browsers
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    /// The best algorithm among the negotiated capabilities
+    pub fn negotiate(capabilities: Capabilities) -> Self {
+        if capabilities.contains(Capabilities::COMPRESSION_ZSTD) {
+            Compression::Zstd
+        } else if capabilities.contains(Capabilities::COMPRESSION_DEFLATE) {
+            Compression::Deflate
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Reads the algorithm from the envelope flags
+    pub fn from_flags(flags: u8) -> Result<Self, NetworkingError> {
+        match flags & COMPRESSION_FLAGS {
+            0 => Ok(Compression::None),
+            FLAG_DEFLATE => Ok(Compression::Deflate),
+            FLAG_ZSTD => Ok(Compression::Zstd),
+            _ => Err(NetworkingError::UnsupportedFlags { flags }),
+        }
+    }
+
+    /// The envelope flag marking a payload compressed with this algorithm
+    pub fn flag(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => FLAG_DEFLATE,
+            Compression::Zstd => FLAG_ZSTD,
+        }
+    }
+
+    pub fn compress(&self, payload: &[u8]) -> Bytes {
+        match self {
+            Compression::None => Bytes::copy_from_slice(payload),
+            Compression::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(payload)
+                    .and_then(|_| encoder.finish())
+                    .map(Bytes::from)
+                    .expect("compressing into memory can not fail")
+            }
+            Compression::Zstd => zstd::bulk::compress(payload, ZSTD_LEVEL)
+                .map(Bytes::from)
+                .expect("compressing into memory can not fail"),
+        }
+    }
+
+    /// Decompresses a payload, failing as soon as it grows past `limit` bytes so a small frame can not expand
+    /// into an arbitrarily large allocation.
+    pub fn decompress(&self, payload: Bytes, limit: usize) -> Result<Bytes, NetworkingError> {
+        match self {
+            Compression::None => Ok(payload),
+            Compression::Deflate => {
+                read_limited(ZlibDecoder::new(&payload[..]), payload.len(), limit)
+            }
+            Compression::Zstd => {
+                let decoder = zstd::stream::read::Decoder::new(&payload[..])
+                    .context(InvalidCompressedPayloadSnafu)?;
+                read_limited(decoder, payload.len(), limit)
+            }
+        }
+    }
+}
+
+/// How a connection compresses the packets it writes, and which compression it accepts on the packets it reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionSettings {
+    pub algorithm: Compression,
+
+    /// Payloads smaller than this are not compressed
+    pub threshold: usize,
+}
+
+impl CompressionSettings {
+    /// Settings that never compress
+    pub const fn disabled() -> Self {
+        Self {
+            algorithm: Compression::None,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    /// Compresses with the best algorithm among the negotiated capabilities
+    pub fn negotiate(capabilities: Capabilities) -> Self {
+        Self {
+            algorithm: Compression::negotiate(capabilities),
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    /// Whether a received payload may be compressed with the given algorithm, uncompressed payloads always are
+    pub fn accepts(&self, compression: Compression) -> bool {
+        compression == Compression::None || compression == self.algorithm
+    }
+
+    /// The algorithm to use for a payload of the given length
+    pub fn algorithm_for(&self, len: usize) -> Compression {
+        match len >= self.threshold {
+            true => self.algorithm,
+            false => Compression::None,
+        }
+    }
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
+fn read_limited(
+    reader: impl Read,
+    compressed_len: usize,
+    limit: usize,
+) -> Result<Bytes, NetworkingError> {
+    let mut buffer = Vec::with_capacity(limit.min(compressed_len.saturating_mul(4)));
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut buffer)
+        .context(InvalidCompressedPayloadSnafu)?;
+
+    ensure!(buffer.len() <= limit, DecompressedTooLargeSnafu { limit });
+    Ok(Bytes::from(buffer))
+}
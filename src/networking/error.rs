@@ -1,7 +1,62 @@
+use std::io;
+
 use snafu::Snafu;
 
+use crate::{coding::CodingError, types::types};
+
+use super::compression::Compression;
+
 #[derive(Debug, Snafu)]
+#[snafu(visibility(pub))]
 pub enum NetworkingError {
     #[snafu(display("invalid packet format"))]
     InvalidPacketFormat,
+
+    #[snafu(display("unsupported envelope version {version}"))]
+    UnsupportedEnvelopeVersion { version: u8 },
+
+    #[snafu(display("unsupported envelope flags {flags:#04x}"))]
+    UnsupportedFlags { flags: u8 },
+
+    #[snafu(display("payload compressed with {compression:?}, which was not negotiated"))]
+    UnsupportedCompression { compression: Compression },
+
+    #[snafu(display("invalid compressed payload: {source}"))]
+    InvalidCompressedPayload { source: io::Error },
+
+    #[snafu(display("decompressed payload exceeds the limit of {limit} bytes"))]
+    DecompressedTooLarge { limit: usize },
+
+    #[snafu(display("invalid JSON packet: {source}"))]
+    InvalidJson { source: serde_json::Error },
+
+    #[snafu(display("unknown packet type {packet_type}"))]
+    UnknownPacketType { packet_type: u8 },
+
+    #[snafu(display("could not decode packet: {source}"))]
+    Coding { source: CodingError },
+
+    #[snafu(display("failed to handle packet type {packet_type}: {source}"))]
+    Handler {
+        packet_type: u8,
+        source: types::Error,
+    },
+
+    #[snafu(display("connection closed by peer"))]
+    ConnectionClosed,
+
+    #[snafu(display("connection error: {source}"))]
+    Io { source: io::Error },
+}
+
+impl From<CodingError> for NetworkingError {
+    fn from(source: CodingError) -> Self {
+        NetworkingError::Coding { source }
+    }
+}
+
+impl From<io::Error> for NetworkingError {
+    fn from(source: io::Error) -> Self {
+        NetworkingError::Io { source }
+    }
 }
@@ -0,0 +1,100 @@
+pub use super::packet_type::{Capabilities, HandshakeStatus};
+use super::packet_type::{HelloPacket, WelcomePacket};
+
+/// The protocol version spoken by this server
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol version this server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+impl Capabilities {
+    /// The capabilities this server implements
+    pub const fn supported() -> Self {
+        Capabilities::from_bits(
+            Capabilities::COMPRESSION_DEFLATE.bits()
+                | Capabilities::COMPRESSION_ZSTD.bits()
+                | Capabilities::FILE_CHUNKING.bits(),
+        )
+    }
+}
+
+/// What the server and a client agreed on during the handshake
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub client_name: String,
+    pub client_version: String,
+    pub capabilities: Capabilities,
+}
+
+impl Handshake {
+    /// Validates a HELLO packet, returning the agreed settings or the WELCOME packet rejecting the client.
+    pub fn negotiate(hello: HelloPacket) -> Result<Handshake, WelcomePacket> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.protocol_version) {
+            return Err(WelcomePacket::rejected(
+                HandshakeStatus::UnsupportedVersion,
+                format!(
+                    "protocol version {} is not supported, expected a version between {} and {}",
+                    hello.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+            ));
+        }
+
+        Ok(Handshake {
+            protocol_version: hello.protocol_version,
+            client_name: hello.client_name,
+            client_version: hello.client_version,
+            capabilities: hello.capabilities.intersection(Capabilities::supported()),
+        })
+    }
+
+    /// The WELCOME packet accepting this handshake
+    pub fn welcome(&self) -> WelcomePacket {
+        WelcomePacket {
+            status: HandshakeStatus::Accepted,
+            protocol_version: self.protocol_version,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            max_protocol_version: PROTOCOL_VERSION,
+            capabilities: self.capabilities,
+            reason: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(protocol_version: u32, capabilities: Capabilities) -> HelloPacket {
+        HelloPacket {
+            protocol_version,
+            client_name: "tester".into(),
+            client_version: "0.1.0".into(),
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn negotiate_accepts_supported_versions() {
+        let capabilities = Capabilities::COMPRESSION_ZSTD | Capabilities::FILE_CHUNKING;
+        let handshake = Handshake::negotiate(hello(PROTOCOL_VERSION, capabilities)).unwrap();
+
+        assert_eq!(handshake.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(handshake.client_name, "tester");
+        assert_eq!(
+            handshake.capabilities,
+            capabilities.intersection(Capabilities::supported())
+        );
+        assert_eq!(handshake.welcome().status, HandshakeStatus::Accepted);
+    }
+
+    #[test]
+    fn negotiate_rejects_unsupported_versions() {
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let welcome = Handshake::negotiate(hello(version, Capabilities::empty())).unwrap_err();
+            assert_eq!(welcome.status, HandshakeStatus::UnsupportedVersion);
+            assert_eq!(welcome.min_protocol_version, MIN_PROTOCOL_VERSION);
+            assert_eq!(welcome.max_protocol_version, PROTOCOL_VERSION);
+        }
+    }
+}
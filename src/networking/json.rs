@@ -0,0 +1,138 @@
+//! The JSON encoding of packets, spoken by WebSocket clients that pick the [`JSON_SUBPROTOCOL`].
+//!
+//! Every text frame carries one packet as `{"type": 3, "requestId": 7, "data": {...}}`. The type is the packet id and
+//! the data mirrors the generated TypeScript interfaces: camelCase fields, enums as their numeric code, flags as a
+//! number, unions as `{"kind": "Text", "value": ...}` and bytes as base64 strings.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use super::{
+    error::{InvalidJsonSnafu, NetworkingError},
+    raw_packet::RawPacket,
+};
+
+/// The `Sec-WebSocket-Protocol` a client requests to speak JSON instead of binary frames
+pub const JSON_SUBPROTOCOL: &str = "rustchat.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonFrame {
+    #[serde(rename = "type")]
+    packet_type: u8,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<u32>,
+    data: serde_json::Value,
+}
+
+/// Converts the data of the packet types it knows between JSON and the binary encoding, implemented by the
+/// [`PacketRegistry`](crate::server::registry::PacketRegistry)
+pub trait JsonPackets: fmt::Debug + Send + Sync {
+    /// Decodes the JSON data of a packet into the packet its binary encoding would produce
+    fn decode_json(
+        &self,
+        packet_type: u8,
+        data: serde_json::Value,
+    ) -> Result<RawPacket, NetworkingError>;
+
+    /// The JSON representation of the data of the packet
+    fn encode_json(&self, raw_packet: RawPacket) -> Result<serde_json::Value, NetworkingError>;
+}
+
+/// Decodes a JSON text frame into the packet its binary encoding would produce
+pub fn decode(packets: &dyn JsonPackets, text: &str) -> Result<RawPacket, NetworkingError> {
+    let frame: JsonFrame = serde_json::from_str(text).context(InvalidJsonSnafu)?;
+    let raw_packet = packets.decode_json(frame.packet_type, frame.data)?;
+    Ok(raw_packet.with_request_id(frame.request_id))
+}
+
+/// Encodes a packet as a JSON text frame
+pub fn encode(packets: &dyn JsonPackets, raw_packet: RawPacket) -> Result<String, NetworkingError> {
+    let packet_type = raw_packet.packet_type;
+    let request_id = raw_packet.request_id;
+    let frame = JsonFrame {
+        packet_type,
+        request_id,
+        data: packets.encode_json(raw_packet)?,
+    };
+
+    serde_json::to_string(&frame).context(InvalidJsonSnafu)
+}
+
+/// Serializes `bytes` fields as standard base64 strings, used through `#[serde(with)]` by the generated packets
+pub mod base64 {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use bytes::Bytes;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        STANDARD
+            .decode(text)
+            .map(Bytes::from)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        networking::{
+            packet::Packet,
+            packet_type::{DestinationType, FileChunkPacket, MessagePacket, MessagePayload},
+        },
+        server::registry::PacketRegistry,
+    };
+
+    #[test]
+    fn json_matches_binary_packets() {
+        let text = r#"{"type":3,"requestId":7,"data":{"destination":42,"destinationType":2,"messagePayload":{"kind":"Text","value":"hello"}}}"#;
+        let packets = PacketRegistry::default();
+        let raw_packet = decode(&packets, text).unwrap();
+        let expected = RawPacket::from(Packet::Message(MessagePacket {
+            destination: 42,
+            destination_type: DestinationType::Channel,
+            message_payload: MessagePayload::Text("hello".into()),
+        }))
+        .with_request_id(Some(7));
+
+        assert_eq!(raw_packet, expected);
+        assert_eq!(encode(&packets, raw_packet).unwrap(), text);
+    }
+
+    #[test]
+    fn json_encodes_bytes_as_base64() {
+        let packets = PacketRegistry::default();
+        let raw_packet = RawPacket::from(Packet::FileChunk(FileChunkPacket {
+            transfer_id: uuid::Uuid::nil(),
+            offset: 5,
+            data: Bytes::from_static(b"chunk"),
+        }));
+
+        let text = encode(&packets, raw_packet).unwrap();
+        assert_eq!(
+            text,
+            r#"{"type":8,"data":{"transferId":"00000000-0000-0000-0000-000000000000","offset":5,"data":"Y2h1bms="}}"#
+        );
+        assert!(decode(&packets, &text).is_ok());
+
+        assert!(matches!(
+            decode(&packets, r#"{"type":200,"data":{}}"#),
+            Err(NetworkingError::UnknownPacketType { packet_type: 200 })
+        ));
+        assert!(matches!(
+            decode(&packets, r#"{"type":3,"data":{"destination":"42"}}"#),
+            Err(NetworkingError::InvalidJson { .. })
+        ));
+    }
+}
@@ -1,5 +1,7 @@
+pub mod compression;
 pub mod error;
-pub mod message_payload;
+pub mod handshake;
+pub mod json;
 pub mod packet;
 pub mod packet_type;
 pub mod raw_packet;
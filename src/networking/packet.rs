@@ -1,26 +1,70 @@
-use crate::{coding::Decoder, types::types};
+#[cfg(test)]
+use crate::coding::Decoder;
 
-use super::{
-    packet_type::{MessagePacket, PacketData, MESSAGE},
-    raw_packet::RawPacket,
+use super::packet_type::{
+    DisconnectPacket, ErrorPacket, FileAbortPacket, FileAckPacket, FileBeginPacket,
+    FileChunkPacket, FileCommitPacket, HelloPacket, JoinPacket, LoginPacket, LogoutPacket,
+    MessagePacket, PacketData, PingPacket, PongPacket, ResumePacket, ServerShutdownPacket,
+    SessionPacket, SignedInPacket, WelcomePacket,
 };
+#[cfg(test)]
+use super::{error::NetworkingError, raw_packet::RawPacket};
 
-#[derive(Debug)]
-pub enum Packet {
-    Message(MessagePacket),
-}
+/// Declares the built in packets. Each entry becomes a variant of [`Packet`], the server sends them through it and
+/// decodes received packets through the [`PacketRegistry`](crate::server::registry::PacketRegistry) instead.
+macro_rules! packets {
+    ($($variant:ident($packet:ty),)*) => {
+        #[derive(Debug)]
+        pub enum Packet {
+            $($variant($packet),)*
+        }
 
-impl Packet {
-    pub fn from(raw_packet: RawPacket) -> types::Result<Self> {
-        let mut decoder = Decoder::new(&raw_packet.payload);
+        impl Packet {
+            /// Decodes a packet by its packet id, used by tests to read what the server sent
+            #[cfg(test)]
+            pub fn from(raw_packet: RawPacket) -> Result<Self, NetworkingError> {
+                let mut decoder = Decoder::new(raw_packet.payload);
 
-        match raw_packet.packet_type {
-            MESSAGE => {
-                let mut packet = MessagePacket::default();
-                packet.deserialize(&mut decoder)?;
-                return Ok(Packet::Message(packet));
+                $(
+                    let mut packet = <$packet>::default();
+                    if packet.packet_id() == raw_packet.packet_type {
+                        packet.deserialize(&mut decoder)?;
+                        return Ok(Packet::$variant(packet));
+                    }
+                )*
+
+                let packet_type = raw_packet.packet_type;
+                Err(NetworkingError::UnknownPacketType { packet_type })
+            }
+
+            /// The data of the packet, regardless of its type
+            pub fn data(&self) -> &dyn PacketData {
+                match self {
+                    $(Packet::$variant(packet) => packet,)*
+                }
             }
-            _ => Err("invalid-packet".into()),
         }
-    }
+    };
+}
+
+packets! {
+    SignIn(LoginPacket),
+    SignOut(LogoutPacket),
+    Message(MessagePacket),
+    Hello(HelloPacket),
+    Welcome(WelcomePacket),
+    Error(ErrorPacket),
+    FileBegin(FileBeginPacket),
+    FileChunk(FileChunkPacket),
+    FileAck(FileAckPacket),
+    FileCommit(FileCommitPacket),
+    FileAbort(FileAbortPacket),
+    Ping(PingPacket),
+    Pong(PongPacket),
+    Disconnect(DisconnectPacket),
+    ServerShutdown(ServerShutdownPacket),
+    Resume(ResumePacket),
+    Session(SessionPacket),
+    SignedIn(SignedInPacket),
+    Join(JoinPacket),
 }
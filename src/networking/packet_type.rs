@@ -1,118 +1,86 @@
-use crate::{
-    coding::{Decoder, Encoder},
-    types::Result,
-};
-
-use super::message_payload::{DestinationType, MessagePayload};
-
-/// A user trying to sign in to the server.
-pub const SIGN_IN: u8 = 1;
-
-/// A user trying to sign out from the server.
-pub const SIGN_OUT: u8 = 2;
-
-/// A message from an user
-pub const MESSAGE: u8 = 3;
+use crate::coding::{CodingError, Decoder, Encoder};
 
+/// Implemented by every packet type. Prefer `#[derive(PacketData)]` over writing it by hand.
 pub trait PacketData: Send {
     fn packet_id(&self) -> u8;
-    fn deserialize(&mut self, data: &mut Decoder) -> Result<()>;
+    fn deserialize(&mut self, data: &mut Decoder) -> Result<(), CodingError>;
     fn serialize(&self, encoder: &mut Encoder);
 }
 
-#[derive(Debug, Default, PartialEq)]
-pub struct LoginPacket {
-    username: String,
-    password: String,
-}
-
-impl PacketData for LoginPacket {
-    fn deserialize(&mut self, data: &mut Decoder) -> Result<()> {
-        self.username = data.read_string()?;
-        self.password = data.read_string()?;
-        Ok(())
-    }
-
-    fn serialize(&self, encoder: &mut Encoder) {
-        encoder.write_string_ref(&self.username);
-        encoder.write_string_ref(&self.password);
-    }
-
-    fn packet_id(&self) -> u8 {
-        SIGN_IN
-    }
-}
-
-#[derive(Debug, Default, PartialEq)]
-pub struct LogoutPacket {
-    session_id: i64,
-}
-
-impl PacketData for LogoutPacket {
-    fn deserialize(&mut self, data: &mut Decoder) -> Result<()> {
-        self.session_id = data.read_i64()?;
-        Ok(())
-    }
-
-    fn serialize(&self, encoder: &mut Encoder) {
-        encoder.write_i64(self.session_id);
-    }
-
-    fn packet_id(&self) -> u8 {
-        SIGN_OUT
+// The packet ids, packets and the types they carry are declared in protocol.toml, see build.rs.
+include!(concat!(env!("OUT_DIR"), "/protocol.rs"));
+
+impl WelcomePacket {
+    pub fn rejected(status: HandshakeStatus, reason: String) -> Self {
+        Self {
+            status,
+            protocol_version: 0,
+            min_protocol_version: super::handshake::MIN_PROTOCOL_VERSION,
+            max_protocol_version: super::handshake::PROTOCOL_VERSION,
+            capabilities: Capabilities::empty(),
+            reason,
+        }
     }
 }
 
-#[derive(Debug, PartialEq, Default)]
-pub struct MessagePacket {
-    /// The id of the destination. Can be an individual user or a channel
-    pub destination: i32,
-
-    /// Indicates the destination type: Channel/User
-    pub destination_type: DestinationType,
-
-    /// The payload of the message
-    pub message_payload: MessagePayload,
-}
-
-impl PacketData for MessagePacket {
-    fn deserialize(&mut self, data: &mut Decoder) -> Result<()> {
-        self.destination = data.read_i32()?;
-        self.destination_type = DestinationType::from(data.read_u8()?);
-
-        match data.read_i8()? {
-            1 => {
-                let message = data.read_string()?;
-                self.message_payload = MessagePayload::Text(message);
-                Ok(())
-            }
-            2 => {
-                let buffer = data.read_bytes()?;
-                self.message_payload = MessagePayload::File(buffer);
-                Ok(())
-            }
-            _ => Err("unknown-message-type".into()),
-        }
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rustchat_derive::PacketData;
+    use uuid::Uuid;
+
+    use super::{HandshakeStatus, MessagePacket, PacketData, WelcomePacket};
+    use crate::coding::{CodingError, Decoder, Encoder};
+
+    /// Exercises the collection attributes of the derive, its round-trip test is generated
+    #[derive(Debug, Default, PartialEq, PacketData)]
+    #[packet(id = 255, sample = CollectionsPacket {
+        id: Uuid::from_u128(0x6f9619ff_8b86_d011_b42d_00c04fc964ff),
+        topic: Some("general".into()),
+        members: vec!["john".into(), "jane".into()],
+        roles: HashMap::from([(1, "owner".into()), (2, "member".into())]),
+    })]
+    struct CollectionsPacket {
+        id: Uuid,
+        topic: Option<String>,
+
+        #[packet(seq, max_len = 8)]
+        members: Vec<String>,
+
+        #[packet(map, max_len = 8)]
+        roles: HashMap<u32, String>,
     }
 
-    fn serialize(&self, encoder: &mut Encoder) {
-        encoder.write_i32(self.destination);
-        encoder.write_u8(self.destination_type.to_code());
-
-        match &self.message_payload {
-            MessagePayload::Text(text) => {
-                encoder.write_i8(1);
-                encoder.write_string_ref(text);
-            }
-            MessagePayload::File(buffer) => {
-                encoder.write_i8(2);
-                encoder.write_bytes(buffer);
-            }
-            MessagePayload::Invalid => todo!(),
-        }
+    #[test]
+    fn default_packets_can_be_encoded() {
+        let packet = MessagePacket::default();
+        let mut encoder = Encoder::new();
+        packet.serialize(&mut encoder);
+
+        let mut decoded = MessagePacket::default();
+        decoded
+            .deserialize(&mut Decoder::new(encoder.take_bytes()))
+            .unwrap();
+        assert_eq!(decoded, packet);
     }
 
-    fn packet_id(&self) -> u8 {
-        MESSAGE
+    #[test]
+    fn unknown_enum_codes_fail_to_decode() {
+        let mut encoder = Encoder::new();
+        WelcomePacket::default().serialize(&mut encoder);
+        let mut payload = encoder.take_bytes().to_vec();
+        payload[0] = 3;
+
+        let mut decoded = WelcomePacket::default();
+        assert_eq!(
+            decoded.deserialize(&mut Decoder::new(payload.into())),
+            Err(CodingError::UnknownDiscriminant {
+                kind: "handshake status",
+                value: 3,
+                offset: 0
+            })
+        );
+        assert!(serde_json::from_str::<HandshakeStatus>("3").is_err());
     }
 }
@@ -2,35 +2,66 @@ use std::fmt;
 
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use crate::{
-    coding::{Decoder, Encoder},
-    types,
-};
+use crate::coding::{varint::encode_varint32, CodingError, Decoder, Encoder};
 
 use super::{
+    compression::{Compression, CompressionSettings, COMPRESSION_FLAGS},
     error::NetworkingError,
     packet::Packet,
-    packet_type::{PacketData, MESSAGE},
+    packet_type::PacketData,
 };
 
-// pub const PACKET_HEADER_SIZE: usize = 4 + 1 + 1;
+/// Envelope version + flags + packet type. The request id, when present, follows as a varint.
+pub const PACKET_HEADER_SIZE: usize = 1 + 1 + 1;
 pub const MAX_PACKET_SIZE: usize = 1024 * 1024 * 12; // 12 mB
 
-#[derive(Debug, PartialEq, Eq)]
+/// The version of the envelope header written by this server
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// The envelope carries a request id after the packet type
+pub const FLAG_REQUEST_ID: u8 = 0x01;
+
+/// Every flag understood by this server, packets with any other flag set are rejected
+const KNOWN_FLAGS: u8 = FLAG_REQUEST_ID | COMPRESSION_FLAGS;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RawPacket {
     pub packet_type: u8,
+
+    /// Set by clients on requests they expect an answer for. The server echoes it on the response.
+    pub request_id: Option<u32>,
+
     pub payload: Bytes,
 }
 
 impl RawPacket {
     /// Encodes this packet to a byte buffer
     pub fn encode(&self) -> Bytes {
+        self.encode_with(&CompressionSettings::disabled())
+    }
+
+    /// Encodes this packet to a byte buffer, compressing the payload when the settings allow it and it
+    /// actually gets smaller.
+    pub fn encode_with(&self, compression: &CompressionSettings) -> Bytes {
+        let (compression, payload) = match compression.algorithm_for(self.payload.len()) {
+            Compression::None => (Compression::None, self.payload.clone()),
+            algorithm => match algorithm.compress(&self.payload) {
+                compressed if compressed.len() < self.payload.len() => (algorithm, compressed),
+                _ => (Compression::None, self.payload.clone()),
+            },
+        };
+
         // Create a BytesMut buffer with the exact required capacity
-        let mut buffer = BytesMut::with_capacity(1 + self.payload.len());
+        let mut buffer = BytesMut::with_capacity(PACKET_HEADER_SIZE + 5 + payload.len());
 
         // Write fields to the buffer
+        buffer.put_u8(ENVELOPE_VERSION);
+        buffer.put_u8(self.flags() | compression.flag());
         buffer.put_u8(self.packet_type);
-        buffer.put_slice(&self.payload);
+        if let Some(request_id) = self.request_id {
+            encode_varint32(request_id, &mut buffer);
+        }
+        buffer.put_slice(&payload);
 
         // Convert BytesMut to Bytes for immutability
         buffer.freeze()
@@ -40,54 +71,100 @@ impl RawPacket {
     pub fn new(packet_type: u8, payload: Bytes) -> Self {
         Self {
             packet_type,
+            request_id: None,
             payload,
         }
     }
 
+    /// Marks this packet as a request, or as the response to one.
+    pub fn with_request_id(mut self, request_id: Option<u32>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    /// The flags byte of the envelope header, without the compression flags which are only known once encoded
+    pub fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.request_id.is_some() {
+            flags |= FLAG_REQUEST_ID;
+        }
+
+        flags
+    }
+
     /// Creates a new packet with the given packet_type.
-    pub fn new_from_type(packet_type: Box<dyn PacketData>) -> Self {
+    pub fn new_from_type(packet_type: &dyn PacketData) -> Self {
         let mut encoder = Encoder::new();
         packet_type.serialize(&mut encoder);
 
-        Self {
-            packet_type: packet_type.packet_id(),
-            payload: encoder.take_bytes(),
-        }
+        Self::new(packet_type.packet_id(), encoder.take_bytes())
     }
 
     pub fn from(packet: Packet) -> Self {
-        let mut encoder = Encoder::new();
-        match packet {
-            Packet::Message(message_packet) => {
-                message_packet.serialize(&mut encoder);
-                return RawPacket::new(MESSAGE, encoder.take_bytes());
-            }
-        }
+        RawPacket::new_from_type(packet.data())
     }
 
     /// Creates a new packet from Bytes, parsing the contents.
-    pub fn decode(buffer: Bytes) -> types::Result<RawPacket> {
-        if !buffer.has_remaining() {
-            return Err(NetworkingError::InvalidPacketFormat.into());
+    ///
+    /// The payload shares the given buffer, so no bytes are copied unless it is compressed. Compressed payloads
+    /// may not expand past `MAX_PACKET_SIZE`. Any compression is accepted, see [`RawPacket::decode_with`].
+    pub fn decode(buffer: Bytes) -> Result<RawPacket, NetworkingError> {
+        Self::decode_accepting(buffer, None)
+    }
+
+    /// Creates a new packet from Bytes like [`RawPacket::decode`], rejecting payloads compressed with another
+    /// algorithm than the negotiated one.
+    pub fn decode_with(
+        buffer: Bytes,
+        compression: &CompressionSettings,
+    ) -> Result<RawPacket, NetworkingError> {
+        Self::decode_accepting(buffer, Some(compression))
+    }
+
+    fn decode_accepting(
+        buffer: Bytes,
+        accepted: Option<&CompressionSettings>,
+    ) -> Result<RawPacket, NetworkingError> {
+        if buffer.remaining() < PACKET_HEADER_SIZE {
+            return Err(NetworkingError::InvalidPacketFormat);
         }
 
-        let packet_type = buffer[0];
-        let payload = &buffer[1..];
+        let mut decoder = Decoder::new(buffer);
+        let version = decoder.read_u8()?;
+        if version != ENVELOPE_VERSION {
+            return Err(NetworkingError::UnsupportedEnvelopeVersion { version });
+        }
+
+        let flags = decoder.read_u8()?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(NetworkingError::UnsupportedFlags { flags });
+        }
+        let compression = Compression::from_flags(flags)?;
+        if accepted.is_some_and(|accepted| !accepted.accepts(compression)) {
+            return Err(NetworkingError::UnsupportedCompression { compression });
+        }
+
+        let packet_type = decoder.read_u8()?;
+        let request_id = match flags & FLAG_REQUEST_ID {
+            0 => None,
+            _ => Some(decoder.read_varint()?),
+        };
 
         Ok(RawPacket {
             packet_type,
-            payload: Bytes::copy_from_slice(payload),
+            request_id,
+            payload: compression.decompress(decoder.into_remaining(), MAX_PACKET_SIZE)?,
         })
     }
 
-    /// Returns the total size of this packet, considering the payload.
+    // Returns the total size of this packet, considering the payload.
     // pub fn total_size(&self) -> usize {
     //     PACKET_HEADER_SIZE + self.payload.len()
     // }
 
     /// Converts the packet payload to the given PacketType
-    pub fn receive_payload<T: PacketData>(&self, packet_type: &mut T) -> types::Result<()> {
-        let mut decoder = Decoder::new(&self.payload);
+    pub fn receive_payload<T: PacketData>(&self, packet_type: &mut T) -> Result<(), CodingError> {
+        let mut decoder = Decoder::new(self.payload.clone());
         packet_type.deserialize(&mut decoder)?;
         Ok(())
     }
@@ -95,15 +172,18 @@ impl RawPacket {
 
 impl fmt::Display for RawPacket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Packet\n")?;
-        write!(f, "    - Packet Type: {}\n", self.packet_type)?;
-        write!(f, "    - Payload Size: {}\n", self.payload.len())?;
+        writeln!(f, "Packet")?;
+        writeln!(f, "    - Packet Type: {}", self.packet_type)?;
+        if let Some(request_id) = self.request_id {
+            writeln!(f, "    - Request Id: {}", request_id)?;
+        }
+        writeln!(f, "    - Payload Size: {}", self.payload.len())?;
 
         write!(f, "    - Payload: ")?;
         for i in 0..std::cmp::min(50, self.payload.len()) {
             write!(f, "{} ", self.payload[i])?;
         }
-        write!(f, "\n")?;
+        writeln!(f)?;
 
         write!(f, "    - String Payload: ")?;
         let utf8_payload = std::str::from_utf8(&self.payload);
@@ -118,12 +198,14 @@ impl fmt::Display for RawPacket {
 mod test {
 
     use super::*;
+    use crate::networking::packet_type::MESSAGE;
 
     #[test]
     fn packet_parse() {
         let payload = Bytes::from("hello world!");
         let packet1 = RawPacket {
             packet_type: 1,
+            request_id: None,
             payload,
         };
 
@@ -134,4 +216,133 @@ mod test {
         let packet2 = packet.unwrap();
         assert_eq!(packet1, packet2);
     }
+
+    #[test]
+    fn packet_parse_request_id() {
+        let packet1 =
+            RawPacket::new(MESSAGE, Bytes::from("hello world!")).with_request_id(Some(300));
+
+        let buffer = packet1.encode();
+        assert_eq!(
+            &buffer[..5],
+            &[ENVELOPE_VERSION, FLAG_REQUEST_ID, MESSAGE, 0xAC, 0x02]
+        );
+
+        let packet2 = RawPacket::decode(buffer).unwrap();
+        assert_eq!(packet1, packet2);
+    }
+
+    #[test]
+    fn packet_parse_rejects_unknown_envelopes() {
+        let buffer = Bytes::from_static(&[ENVELOPE_VERSION + 1, 0, MESSAGE]);
+        assert!(matches!(
+            RawPacket::decode(buffer),
+            Err(NetworkingError::UnsupportedEnvelopeVersion { .. })
+        ));
+
+        let buffer = Bytes::from_static(&[ENVELOPE_VERSION, 0x80, MESSAGE]);
+        assert!(matches!(
+            RawPacket::decode(buffer),
+            Err(NetworkingError::UnsupportedFlags { flags: 0x80 })
+        ));
+    }
+
+    #[test]
+    fn packet_parse_compressed() {
+        let payload = Bytes::from("hello world! ".repeat(200));
+        let packet1 = RawPacket::new(MESSAGE, payload.clone()).with_request_id(Some(7));
+
+        for algorithm in [Compression::Deflate, Compression::Zstd] {
+            let settings = CompressionSettings {
+                algorithm,
+                threshold: 1024,
+            };
+            let buffer = packet1.encode_with(&settings);
+            assert_eq!(buffer[1], FLAG_REQUEST_ID | algorithm.flag());
+            assert!(buffer.len() < payload.len());
+
+            let packet2 = RawPacket::decode(buffer).unwrap();
+            assert_eq!(packet1, packet2);
+        }
+
+        // below the threshold the payload is sent as it is
+        let settings = CompressionSettings {
+            algorithm: Compression::Zstd,
+            threshold: payload.len() + 1,
+        };
+        let buffer = packet1.encode_with(&settings);
+        assert_eq!(buffer[1], FLAG_REQUEST_ID);
+    }
+
+    #[test]
+    fn packet_parse_rejects_compression_that_was_not_negotiated() {
+        let packet = RawPacket::new(MESSAGE, Bytes::from("hello world! ".repeat(200)));
+        let zstd = CompressionSettings {
+            algorithm: Compression::Zstd,
+            threshold: 1024,
+        };
+        let buffer = packet.encode_with(&zstd);
+
+        assert!(matches!(
+            RawPacket::decode_with(buffer.clone(), &CompressionSettings::disabled()),
+            Err(NetworkingError::UnsupportedCompression {
+                compression: Compression::Zstd
+            })
+        ));
+        assert!(matches!(
+            RawPacket::decode_with(
+                buffer.clone(),
+                &CompressionSettings {
+                    algorithm: Compression::Deflate,
+                    ..zstd
+                }
+            ),
+            Err(NetworkingError::UnsupportedCompression { .. })
+        ));
+        assert_eq!(RawPacket::decode_with(buffer, &zstd).unwrap(), packet);
+
+        // small payloads are not compressed, whatever was negotiated
+        assert_eq!(
+            RawPacket::decode_with(packet.encode(), &zstd).unwrap(),
+            packet
+        );
+    }
+
+    #[test]
+    fn packet_parse_rejects_compression_bombs() {
+        let bomb = Compression::Zstd.compress(&vec![0; MAX_PACKET_SIZE + 1]);
+        assert!(bomb.len() < 4096);
+
+        let mut buffer = BytesMut::new();
+        buffer.put_slice(&[ENVELOPE_VERSION, Compression::Zstd.flag(), MESSAGE]);
+        buffer.put_slice(&bomb);
+        assert!(matches!(
+            RawPacket::decode(buffer.freeze()),
+            Err(NetworkingError::DecompressedTooLarge { .. })
+        ));
+
+        let buffer = Bytes::from_static(&[ENVELOPE_VERSION, COMPRESSION_FLAGS, MESSAGE]);
+        assert!(matches!(
+            RawPacket::decode(buffer),
+            Err(NetworkingError::UnsupportedFlags { .. })
+        ));
+    }
+
+    #[test]
+    fn packet_decode_shares_buffer() {
+        let mut encoder = Encoder::new();
+        encoder.write_bytes(&Bytes::from(vec![7; 1024]));
+        let buffer = RawPacket::new(MESSAGE, encoder.take_bytes()).encode();
+
+        let packet = RawPacket::decode(buffer.clone()).unwrap();
+        assert_eq!(
+            packet.payload.as_ptr(),
+            buffer[PACKET_HEADER_SIZE..].as_ptr()
+        );
+
+        let mut decoder = Decoder::new(packet.payload.clone());
+        let bytes = decoder.read_bytes().unwrap();
+        assert_eq!(bytes.len(), 1024);
+        assert_eq!(bytes.as_ptr(), packet.payload[2..].as_ptr());
+    }
 }
@@ -1,35 +1,90 @@
-use std::{collections::HashMap, sync::Arc};
+use std::collections::HashMap;
 
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
 use crate::{
-    networking::{message_payload::MessagePayload, packet::Packet, packet_type::MessagePacket},
+    networking::{
+        error::NetworkingError,
+        packet::Packet,
+        packet_type::{DestinationType, MessagePacket, MessagePayload},
+    },
     types::types,
 };
 
-use super::user::User;
+use super::user::UserHandle;
 
 pub struct ServerChannel {
-    subscribers: HashMap<Uuid, Arc<RwLock<User>>>,
+    /// The destination of the messages sent to the channel
+    id: i32,
+    subscribers: RwLock<HashMap<Uuid, UserHandle>>,
 }
 
 impl ServerChannel {
-    pub fn new() -> Self {
+    pub fn new(id: i32) -> Self {
         Self {
-            subscribers: HashMap::new(),
+            id,
+            subscribers: RwLock::new(HashMap::new()),
         }
     }
 
-    pub async fn add_subscriber(&mut self, user: Arc<RwLock<User>>) {
-        let user_id = user.read().await.id();
-        self.subscribers.insert(user_id, user);
+    pub fn id(&self) -> i32 {
+        self.id
     }
 
-    pub async fn broadcast(&mut self, message: MessagePayload) -> types::Result<()> {
-        for (key, value) in &self.subscribers {
-            let user = value.write().await;
-            user.send_packet(Packet::Message(MessagePacket {}))
+    pub async fn add_subscriber(&self, user: UserHandle) {
+        self.subscribers.write().await.insert(user.id(), user);
+    }
+
+    pub async fn remove_subscriber(&self, id: &Uuid) {
+        self.subscribers.write().await.remove(id);
+    }
+
+    pub async fn is_subscribed(&self, id: &Uuid) -> bool {
+        self.subscribers.read().await.contains_key(id)
+    }
+
+    /// Queues the message for every subscriber, subscribers that disconnected are dropped
+    pub async fn broadcast(&self, message: MessagePayload) -> types::Result<()> {
+        self.send_to_subscribers(None, message).await
+    }
+
+    /// Queues a message sent by the user `sender` for every other subscriber
+    pub async fn broadcast_from(&self, sender: Uuid, message: MessagePayload) -> types::Result<()> {
+        self.send_to_subscribers(Some(sender), message).await
+    }
+
+    async fn send_to_subscribers(
+        &self,
+        sender: Option<Uuid>,
+        message: MessagePayload,
+    ) -> types::Result<()> {
+        let subscribers: Vec<UserHandle> = self
+            .subscribers
+            .read()
+            .await
+            .values()
+            .filter(|user| Some(user.id()) != sender)
+            .cloned()
+            .collect();
+
+        let mut disconnected = Vec::new();
+        for user in subscribers {
+            let packet = Packet::Message(MessagePacket {
+                destination: self.id,
+                destination_type: DestinationType::Channel,
+                message_payload: message.clone(),
+            });
+
+            match user.send_packet(packet).await {
+                Ok(()) => {}
+                Err(NetworkingError::ConnectionClosed) => disconnected.push(user.id()),
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        for id in disconnected {
+            self.remove_subscriber(&id).await;
         }
 
         Ok(())
@@ -1,112 +1,584 @@
-use std::net::SocketAddr;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
-use futures::SinkExt;
-use tokio::net::TcpStream;
+use bytes::{Bytes, BytesMut};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    Sink, SinkExt, Stream,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+#[cfg(unix)]
+use tokio::net::unix::UCred;
 use tokio_stream::StreamExt;
-use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 use tokio_util::codec::*;
 
-use crate::{networking::raw_packet::RawPacket, networking::raw_packet::MAX_PACKET_SIZE, types};
+use crate::networking::{
+    compression::CompressionSettings,
+    error::NetworkingError,
+    json::{self, JsonPackets},
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+};
 
 use super::framed_websocket::WebSocketAdapter;
 
+pub mod http;
+pub mod memory;
+pub mod udp;
+
+/// The half of a connection packets are read from
+#[async_trait]
+pub trait PacketReader: Send {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError>;
+
+    /// Changes which compression the packets read from now on may use, the others fail with
+    /// [`NetworkingError::UnsupportedCompression`]. Until it is called only uncompressed packets are read.
+    fn accept_compression(&mut self, settings: CompressionSettings);
+
+    /// The address the client is connected from now, only known for connections that can change it while they are
+    /// open
+    fn current_socket(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// The half of a connection packets are written to
 #[async_trait]
-pub trait ConnectionHandle {
-    async fn read_packet(&mut self) -> types::Result<RawPacket>;
-    async fn write_packet(&mut self, packet: RawPacket) -> types::Result<()>;
+pub trait PacketWriter: Send {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError>;
+
+    /// Changes how the packets written from now on are compressed
+    fn set_compression(&mut self, settings: CompressionSettings);
+}
+
+pub trait ConnectionHandle: PacketReader + PacketWriter {
     fn socket(&self) -> SocketAddr;
+
+    /// The certificate the client authenticated with, only set for TLS connections with client authentication
+    fn client_certificate(&self) -> Option<&CertificateDer<'static>> {
+        None
+    }
+
+    /// The user and process of the client, only set for Unix socket connections
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> Option<UCred> {
+        None
+    }
+
+    /// Splits the connection into halves that can be driven by different tasks, so packets can be written while
+    /// the reader waits for the next one
+    fn split(self: Box<Self>) -> (Box<dyn PacketReader>, Box<dyn PacketWriter>);
+}
+
+/// A byte stream the stream based connections can run on
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static {
+    fn client_certificate(&self) -> Option<&CertificateDer<'static>> {
+        None
+    }
+
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> Option<UCred> {
+        None
+    }
+}
+
+impl Transport for TcpStream {}
+
+/// Connections without a network address, over Unix sockets or in memory, report the loopback address instead
+pub const LOOPBACK_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// The framing shared by every stream transport: a big-endian u32 length followed by the packet
+pub fn packet_codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder()
+        .big_endian()
+        .max_frame_length(MAX_PACKET_SIZE)
+        .length_field_type::<u32>()
+        .length_adjustment(0)
+        .length_field_offset(0)
+        .length_field_length(4)
+        .new_codec()
+}
+
+/// Reads the packets of a stream of length delimited frames
+#[derive(Debug)]
+pub struct FrameReader<S> {
+    stream: S,
+    compression: CompressionSettings,
+}
+
+#[async_trait]
+impl<S> PacketReader for FrameReader<S>
+where
+    S: Stream<Item = io::Result<BytesMut>> + Unpin + Send,
+{
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        let result: Option<BytesMut> = self.stream.try_next().await?;
+        if let Some(buffer) = result {
+            return RawPacket::decode_with(buffer.freeze(), &self.compression);
+        }
+
+        Err(NetworkingError::ConnectionClosed)
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+    }
 }
 
+/// Writes packets to a sink of length delimited frames
 #[derive(Debug)]
-pub struct TcpConnection {
-    stream: Framed<TcpStream, LengthDelimitedCodec>,
+pub struct FrameWriter<S> {
+    sink: S,
+    compression: CompressionSettings,
+}
+
+#[async_trait]
+impl<S> PacketWriter for FrameWriter<S>
+where
+    S: Sink<Bytes, Error = io::Error> + Unpin + Send,
+{
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        let buffer = packet.encode_with(&self.compression);
+        self.sink.send(buffer).await?;
+        Ok(())
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+    }
+}
+
+type Frames<S> = Framed<S, LengthDelimitedCodec>;
+
+/// The read half of a transport framed with the packet codec
+pub type FramedReader<S> = FrameReader<SplitStream<Frames<S>>>;
+
+/// The write half of a transport framed with the packet codec
+pub type FramedWriter<S> = FrameWriter<SplitSink<Frames<S>, Bytes>>;
+
+/// Splits a framed stream into its packet reader and writer
+fn frame_halves<S: AsyncRead + AsyncWrite + Unpin>(
+    transport: S,
+) -> (FramedReader<S>, FramedWriter<S>) {
+    let (sink, stream) = futures::StreamExt::split(Framed::new(transport, packet_codec()));
+    (
+        FrameReader {
+            stream,
+            compression: CompressionSettings::disabled(),
+        },
+        FrameWriter {
+            sink,
+            compression: CompressionSettings::disabled(),
+        },
+    )
+}
+
+#[derive(Debug)]
+pub struct TcpConnection<S = TcpStream> {
+    reader: FramedReader<S>,
+    writer: FramedWriter<S>,
     address: SocketAddr,
+    client_certificate: Option<CertificateDer<'static>>,
+    #[cfg(unix)]
+    peer_credentials: Option<UCred>,
 }
 
-impl TcpConnection {
-    pub fn new(address: SocketAddr, transport: TcpStream) -> Self {
-        let codec = LengthDelimitedCodec::builder()
-            .big_endian()
-            .max_frame_length(MAX_PACKET_SIZE)
-            .length_field_type::<u32>()
-            .length_adjustment(0)
-            .length_field_offset(0)
-            .length_field_length(4)
-            .new_framed(transport);
+impl<S: Transport> TcpConnection<S> {
+    pub fn new(address: SocketAddr, transport: S) -> Self {
+        let client_certificate = transport.client_certificate().cloned();
+        #[cfg(unix)]
+        let peer_credentials = transport.peer_credentials();
+        let (reader, writer) = frame_halves(transport);
 
         Self {
             address,
-            stream: codec,
+            reader,
+            writer,
+            client_certificate,
+            #[cfg(unix)]
+            peer_credentials,
         }
     }
 }
 
 #[async_trait]
-impl ConnectionHandle for TcpConnection {
-    async fn read_packet(&mut self) -> types::Result<RawPacket> {
-        let result: Option<bytes::BytesMut> = self.stream.try_next().await?;
-        if let Some(buffer) = result {
-            return Ok(RawPacket::decode(buffer.freeze())?);
-        }
+impl<S: Transport> PacketReader for TcpConnection<S> {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        self.reader.read_packet().await
+    }
 
-        Err("no data available".into())
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.reader.accept_compression(settings);
     }
+}
 
-    async fn write_packet(&mut self, packet: RawPacket) -> types::Result<()> {
-        let buffer = packet.encode();
-        self.stream.send(buffer).await?;
-        Ok(())
+#[async_trait]
+impl<S: Transport> PacketWriter for TcpConnection<S> {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.writer.write_packet(packet).await
     }
 
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.writer.set_compression(settings);
+    }
+}
+
+impl<S: Transport> ConnectionHandle for TcpConnection<S> {
     fn socket(&self) -> SocketAddr {
         self.address
     }
+
+    fn client_certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.client_certificate.as_ref()
+    }
+
+    #[cfg(unix)]
+    fn peer_credentials(&self) -> Option<UCred> {
+        self.peer_credentials
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn PacketReader>, Box<dyn PacketWriter>) {
+        (Box::new(self.reader), Box::new(self.writer))
+    }
 }
 
 #[derive(Debug)]
-pub struct WebSocketConnection {
-    stream: Framed<WebSocketAdapter, LengthDelimitedCodec>,
+pub struct WebSocketConnection<S = TcpStream> {
+    reader: FramedReader<WebSocketAdapter<S>>,
+    writer: FramedWriter<WebSocketAdapter<S>>,
     address: SocketAddr,
+    client_certificate: Option<CertificateDer<'static>>,
 }
 
-impl WebSocketConnection {
-    pub fn new(address: SocketAddr, transport: WebSocketStream<TcpStream>) -> Self {
-        let transport = WebSocketAdapter::new(transport);
-        let codec = LengthDelimitedCodec::builder()
-            .big_endian()
-            .max_frame_length(MAX_PACKET_SIZE)
-            .length_field_type::<u32>()
-            .length_adjustment(0)
-            .length_field_offset(0)
-            .length_field_length(4)
-            .new_framed(transport);
+impl<S: Transport> WebSocketConnection<S> {
+    pub fn new(address: SocketAddr, transport: WebSocketStream<S>) -> Self {
+        let client_certificate = transport.get_ref().client_certificate().cloned();
+        let (reader, writer) = frame_halves(WebSocketAdapter::new(transport));
 
         Self {
             address,
-            stream: codec,
+            reader,
+            writer,
+            client_certificate,
         }
     }
 }
 
 #[async_trait]
-impl ConnectionHandle for WebSocketConnection {
-    async fn read_packet(&mut self) -> types::Result<RawPacket> {
-        let result: Option<bytes::BytesMut> = self.stream.try_next().await?;
-        if let Some(buffer) = result {
-            return Ok(RawPacket::from(buffer.freeze())?);
-        }
+impl<S: Transport> PacketReader for WebSocketConnection<S> {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        self.reader.read_packet().await
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.reader.accept_compression(settings);
+    }
+}
+
+#[async_trait]
+impl<S: Transport> PacketWriter for WebSocketConnection<S> {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.writer.write_packet(packet).await
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.writer.set_compression(settings);
+    }
+}
 
-        Err("no data available".into())
+impl<S: Transport> ConnectionHandle for WebSocketConnection<S> {
+    fn socket(&self) -> SocketAddr {
+        self.address
+    }
+
+    fn client_certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.client_certificate.as_ref()
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn PacketReader>, Box<dyn PacketWriter>) {
+        (Box::new(self.reader), Box::new(self.writer))
+    }
+}
+
+/// Reads the packets of a WebSocket speaking the JSON subprotocol
+#[derive(Debug)]
+pub struct JsonReader<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    packets: Arc<dyn JsonPackets>,
+}
+
+#[async_trait]
+impl<S: Transport> PacketReader for JsonReader<S> {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Message::Text(text))) => return json::decode(&*self.packets, &text),
+                Some(Ok(Message::Binary(_))) => return Err(NetworkingError::InvalidPacketFormat),
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(NetworkingError::ConnectionClosed)
+                }
+
+                // pings are answered by tungstenite itself
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Err(io::Error::other(err).into()),
+            }
+        }
     }
 
-    async fn write_packet(&mut self, packet: RawPacket) -> types::Result<()> {
-        let buffer = packet.encode();
-        self.stream.send(buffer).await?;
+    /// JSON frames are never compressed
+    fn accept_compression(&mut self, _settings: CompressionSettings) {}
+}
+
+/// Writes packets to a WebSocket speaking the JSON subprotocol
+#[derive(Debug)]
+pub struct JsonWriter<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+    packets: Arc<dyn JsonPackets>,
+}
+
+#[async_trait]
+impl<S: Transport> PacketWriter for JsonWriter<S> {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        let text = json::encode(&*self.packets, packet)?;
+        self.sink
+            .send(Message::text(text))
+            .await
+            .map_err(io::Error::other)?;
         Ok(())
     }
 
+    /// JSON frames are never compressed
+    fn set_compression(&mut self, _settings: CompressionSettings) {}
+}
+
+/// A WebSocket client speaking the JSON subprotocol, every text frame carries one packet
+#[derive(Debug)]
+pub struct JsonWebSocketConnection<S = TcpStream> {
+    reader: JsonReader<S>,
+    writer: JsonWriter<S>,
+    address: SocketAddr,
+    client_certificate: Option<CertificateDer<'static>>,
+}
+
+impl<S: Transport> JsonWebSocketConnection<S> {
+    /// Creates the connection, translating the packet types known to `packets` from and to JSON
+    pub fn new(
+        address: SocketAddr,
+        transport: WebSocketStream<S>,
+        packets: Arc<dyn JsonPackets>,
+    ) -> Self {
+        let client_certificate = transport.get_ref().client_certificate().cloned();
+        let (sink, stream) = futures::StreamExt::split(transport);
+
+        Self {
+            address,
+            reader: JsonReader {
+                stream,
+                packets: packets.clone(),
+            },
+            writer: JsonWriter { sink, packets },
+            client_certificate,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Transport> PacketReader for JsonWebSocketConnection<S> {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        self.reader.read_packet().await
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.reader.accept_compression(settings);
+    }
+}
+
+#[async_trait]
+impl<S: Transport> PacketWriter for JsonWebSocketConnection<S> {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.writer.write_packet(packet).await
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.writer.set_compression(settings);
+    }
+}
+
+impl<S: Transport> ConnectionHandle for JsonWebSocketConnection<S> {
     fn socket(&self) -> SocketAddr {
         self.address
     }
+
+    fn client_certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.client_certificate.as_ref()
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn PacketReader>, Box<dyn PacketWriter>) {
+        (Box::new(self.reader), Box::new(self.writer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::networking::{
+        compression::Compression,
+        handshake::Capabilities,
+        packet::Packet,
+        packet_type::{ErrorCode, ErrorPacket, MESSAGE},
+    };
+    use crate::server::{connection::memory::MemoryConnection, registry::PacketRegistry, testing};
+
+    #[tokio::test]
+    async fn stream_connections_carry_packets_until_closed() {
+        let (mut server, client) = MemoryConnection::pair();
+        let mut client = Framed::new(client, packet_codec());
+
+        let packet = RawPacket::new(MESSAGE, Bytes::from_static(b"hello"));
+        client.send(packet.encode()).await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), packet);
+
+        server.write_packet(packet.clone()).await.unwrap();
+        let frame = client.next().await.unwrap().unwrap();
+        assert_eq!(RawPacket::decode(frame.freeze()).unwrap(), packet);
+
+        drop(client);
+        assert!(matches!(
+            server.read_packet().await,
+            Err(NetworkingError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn tcp_connection_compresses_negotiated_packets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = TcpStream::connect(address).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let mut server = TcpConnection::new(address, server);
+        let mut client = Framed::new(client, LengthDelimitedCodec::new());
+        let compression = CompressionSettings::negotiate(Capabilities::COMPRESSION_ZSTD);
+        server.set_compression(compression);
+
+        let packet = RawPacket::new(MESSAGE, Bytes::from("hello world! ".repeat(200)));
+        server.write_packet(packet.clone()).await.unwrap();
+
+        let frame = client.next().await.unwrap().unwrap().freeze();
+        assert_eq!(frame[1], Compression::Zstd.flag());
+        assert!(frame.len() < packet.payload.len());
+
+        // the client may only answer compressed once the compression was negotiated
+        client.send(frame.clone()).await.unwrap();
+        assert!(matches!(
+            server.read_packet().await,
+            Err(NetworkingError::UnsupportedCompression {
+                compression: Compression::Zstd
+            })
+        ));
+
+        server.accept_compression(compression);
+        client.send(frame).await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), packet);
+    }
+
+    async fn websocket_pair() -> (
+        SocketAddr,
+        WebSocketStream<TcpStream>,
+        WebSocketStream<TcpStream>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(
+            async {
+                let stream = TcpStream::connect(address).await.unwrap();
+                let url = format!("ws://{address}");
+                tokio_tungstenite::client_async(url, stream)
+                    .await
+                    .unwrap()
+                    .0
+            },
+            async {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio_tungstenite::accept_async(stream).await.unwrap()
+            },
+        );
+
+        (address, client, server)
+    }
+
+    #[tokio::test]
+    async fn websocket_connection_round_trips_compressed_packets() {
+        let (address, client, server) = websocket_pair().await;
+        let mut server = WebSocketConnection::new(address, server);
+        let mut client = WebSocketConnection::new(address, client);
+        let packet = RawPacket::new(MESSAGE, Bytes::from("hello world! ".repeat(200)));
+
+        for algorithm in [Compression::Deflate, Compression::Zstd] {
+            let compression = CompressionSettings {
+                algorithm,
+                threshold: 1024,
+            };
+            server.set_compression(compression);
+            server.accept_compression(compression);
+            client.set_compression(compression);
+            client.accept_compression(compression);
+
+            server.write_packet(packet.clone()).await.unwrap();
+            assert_eq!(client.read_packet().await.unwrap(), packet);
+
+            client.write_packet(packet.clone()).await.unwrap();
+            assert_eq!(server.read_packet().await.unwrap(), packet);
+        }
+    }
+
+    #[tokio::test]
+    async fn json_websocket_connection_speaks_text_frames() {
+        let (address, mut client, server) = websocket_pair().await;
+        let packets = Arc::new(PacketRegistry::default());
+        let mut server = JsonWebSocketConnection::new(address, server, packets);
+
+        let hello = r#"{"type":4,"requestId":1,"data":{"protocolVersion":1,"clientName":"browser","clientVersion":"0.1.0","capabilities":0}}"#;
+        client.send(Message::text(hello)).await.unwrap();
+        assert_eq!(
+            server.read_packet().await.unwrap(),
+            RawPacket::from(testing::hello("browser")).with_request_id(Some(1))
+        );
+
+        let error = ErrorPacket {
+            code: ErrorCode::MalformedPacket,
+            packet_type: 3,
+            message: "bad".into(),
+        };
+        server
+            .write_packet(RawPacket::from(Packet::Error(error)))
+            .await
+            .unwrap();
+        let text = client.next().await.unwrap().unwrap().into_text().unwrap();
+        assert_eq!(
+            text.as_str(),
+            r#"{"type":6,"data":{"code":2,"packetType":3,"message":"bad"}}"#
+        );
+
+        client
+            .send(Message::binary(b"\x01".to_vec()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            server.read_packet().await,
+            Err(NetworkingError::InvalidPacketFormat)
+        ));
+    }
 }
@@ -0,0 +1,776 @@
+//! A fallback for clients behind proxies that block WebSocket upgrades, served on the TCP listener next to the other
+//! transports. Each connection is an HTTP session:
+//!
+//! - `POST /rustchat` opens a session and answers with its id
+//! - `POST /rustchat/<id>` delivers the packets in the body, length delimited like on TCP
+//! - `GET /rustchat/<id>` long-polls the packets sent to the client, or streams them as Server-Sent Events when the
+//!   request accepts `text/event-stream`
+//! - `DELETE /rustchat/<id>` closes the session
+//!
+//! Every request gets its own TCP connection, the server closes it after answering.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_util::{
+    codec::{Decoder, Encoder},
+    sync::CancellationToken,
+};
+use uuid::Uuid;
+
+use crate::networking::{
+    compression::CompressionSettings,
+    error::NetworkingError,
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+};
+
+use super::{packet_codec, ConnectionHandle, PacketReader, PacketWriter, Transport};
+
+/// The path every fallback request starts with
+const PATH: &str = "/rustchat";
+
+/// How long a client has to send a whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const MAX_HEAD_SIZE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 32;
+
+/// A body carries at least one packet of the largest size
+const MAX_BODY_SIZE: usize = MAX_PACKET_SIZE + 4;
+
+/// How long a long-poll waits for a packet before answering with no content
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// How long an event stream may stay quiet before a comment is sent, so proxies do not close it
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a packet waits for the client to poll it before the connection is considered lost
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many packets may wait in each direction of a session
+const CAPACITY: usize = 64;
+
+/// The first bytes of fallback requests
+const REQUEST_PREFIXES: [&[u8]; 4] = [b"POST ", b"DELETE ", POLL_PREFIX, b"OPTIONS "];
+
+/// The first bytes of polls, shared by WebSocket upgrades to the same path. Only the headers tell them apart.
+const POLL_PREFIX: &[u8] = b"GET /rustchat";
+
+/// Whether the first bytes of a connection are the start of a fallback request
+pub fn is_fallback_request(peeked: &[u8]) -> bool {
+    REQUEST_PREFIXES
+        .iter()
+        .any(|prefix| peeked.starts_with(prefix))
+        && !(peeked.starts_with(POLL_PREFIX) && is_websocket_upgrade(peeked))
+}
+
+/// Whether the first bytes of a connection could still become a fallback request once more of them arrive
+pub fn is_partial_fallback_request(peeked: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let partial_poll = peeked.starts_with(POLL_PREFIX)
+        && peeked.len() < MAX_HEAD_SIZE
+        && matches!(
+            httparse::Request::new(&mut headers).parse(peeked),
+            Ok(httparse::Status::Partial)
+        );
+
+    partial_poll
+        || REQUEST_PREFIXES
+            .iter()
+            .any(|prefix| prefix.len() > peeked.len() && prefix.starts_with(peeked))
+}
+
+/// Whether a whole request head asks to upgrade the connection to a WebSocket
+fn is_websocket_upgrade(head: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    matches!(request.parse(head), Ok(httparse::Status::Complete(_)))
+        && request.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("upgrade")
+                && header.value.eq_ignore_ascii_case(b"websocket")
+        })
+}
+
+/// The encoded packets written to a session, locked by the request polling them
+type Outbound = Arc<tokio::sync::Mutex<OutboundQueue>>;
+
+/// The packets written to a session and not written to a response yet
+struct OutboundQueue {
+    packets: mpsc::Receiver<Bytes>,
+
+    /// Packets taken by a request that could not write them, the next request writes them first
+    unsent: VecDeque<Bytes>,
+}
+
+impl OutboundQueue {
+    /// Waits for the next packet, returning `None` once the session is closed
+    async fn recv(&mut self) -> Option<Bytes> {
+        match self.unsent.pop_front() {
+            Some(packet) => Some(packet),
+            None => self.packets.recv().await,
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<Bytes> {
+        self.unsent
+            .pop_front()
+            .or_else(|| self.packets.try_recv().ok())
+    }
+
+    /// Puts back packets that could not be written, in front of the others
+    fn unsend(&mut self, packets: Vec<Bytes>) {
+        for packet in packets.into_iter().rev() {
+            self.unsent.push_front(packet);
+        }
+    }
+}
+
+/// The client side of a session, used by the requests
+struct HttpSession {
+    /// The frames posted by the client, read by the user
+    inbound: mpsc::Sender<BytesMut>,
+
+    /// The encoded packets written by the user, taken by the request polling them
+    outbound: Outbound,
+
+    /// Cancelled when another request starts polling the session
+    poller: CancellationToken,
+}
+
+/// The open HTTP sessions, shared by the requests of every client
+#[derive(Default)]
+pub struct HttpSessions {
+    sessions: Mutex<HashMap<Uuid, HttpSession>>,
+}
+
+/// A parsed request, with its whole body
+struct Request {
+    method: String,
+    path: String,
+    accepts_events: bool,
+    body: BytesMut,
+}
+
+impl HttpSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many sessions are open
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Answers a single request. Returns the connection of the session it opened, if it did.
+    pub async fn handle<S: Transport>(
+        self: &Arc<Self>,
+        mut stream: S,
+        socket: SocketAddr,
+    ) -> io::Result<Option<HttpConnection>> {
+        let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) => {
+                respond(&mut stream, "400 Bad Request", b"malformed request").await?;
+                return Ok(None);
+            }
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+
+        let id = match request.path.strip_prefix(PATH) {
+            Some("" | "/") => None,
+            Some(id) => match id.strip_prefix('/').and_then(|id| Uuid::parse_str(id).ok()) {
+                Some(id) => Some(id),
+                None => {
+                    respond(&mut stream, "404 Not Found", b"unknown path").await?;
+                    return Ok(None);
+                }
+            },
+            None => {
+                respond(&mut stream, "404 Not Found", b"unknown path").await?;
+                return Ok(None);
+            }
+        };
+
+        match (request.method.as_str(), id) {
+            ("OPTIONS", _) => respond(&mut stream, "204 No Content", b"").await?,
+            ("POST", None) => {
+                let connection = self.open(socket);
+                respond_with(
+                    &mut stream,
+                    "201 Created",
+                    "text/plain",
+                    connection.id.to_string().as_bytes(),
+                )
+                .await?;
+                return Ok(Some(connection));
+            }
+            ("POST", Some(id)) => self.deliver(&mut stream, id, request.body).await?,
+            ("GET", Some(id)) if request.accepts_events => {
+                self.stream_events(&mut stream, id).await?
+            }
+            ("GET", Some(id)) => self.poll(&mut stream, id).await?,
+            ("DELETE", Some(id)) => {
+                self.sessions.lock().unwrap().remove(&id);
+                respond(&mut stream, "204 No Content", b"").await?;
+            }
+            _ => respond(&mut stream, "405 Method Not Allowed", b"").await?,
+        }
+        Ok(None)
+    }
+
+    fn open(self: &Arc<Self>, socket: SocketAddr) -> HttpConnection {
+        let id = Uuid::new_v4();
+        let (inbound, frames) = mpsc::channel(CAPACITY);
+        let (packets, outbound) = mpsc::channel(CAPACITY);
+        self.sessions.lock().unwrap().insert(
+            id,
+            HttpSession {
+                inbound,
+                outbound: Arc::new(tokio::sync::Mutex::new(OutboundQueue {
+                    packets: outbound,
+                    unsent: VecDeque::new(),
+                })),
+                poller: CancellationToken::new(),
+            },
+        );
+
+        HttpConnection {
+            id,
+            socket,
+            reader: HttpReader {
+                id,
+                frames,
+                compression: CompressionSettings::disabled(),
+                sessions: self.clone(),
+            },
+            writer: HttpWriter {
+                packets,
+                compression: CompressionSettings::disabled(),
+            },
+        }
+    }
+
+    /// Passes the frames of a POST body to the user of the session
+    async fn deliver<S: Transport>(
+        &self,
+        stream: &mut S,
+        id: Uuid,
+        mut body: BytesMut,
+    ) -> io::Result<()> {
+        let Some(inbound) = self.session(id, |session| session.inbound.clone()) else {
+            return respond(stream, "404 Not Found", b"unknown session").await;
+        };
+
+        let mut codec = packet_codec();
+        let mut frames = Vec::new();
+        loop {
+            match codec.decode(&mut body) {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) if body.is_empty() => break,
+                Ok(None) | Err(_) => {
+                    return respond(
+                        stream,
+                        "400 Bad Request",
+                        b"the body must be length delimited packets",
+                    )
+                    .await
+                }
+            }
+        }
+
+        for frame in frames {
+            if inbound.send(frame).await.is_err() {
+                return respond(stream, "410 Gone", b"the session is closed").await;
+            }
+        }
+        respond(stream, "204 No Content", b"").await
+    }
+
+    /// Answers with the packets waiting for the client, waiting for one if there are none. Packets that could not be
+    /// written are answered to the next request.
+    async fn poll<S: Transport>(&self, stream: &mut S, id: Uuid) -> io::Result<()> {
+        let Some((outbound, poller)) = self.take_poller(id) else {
+            return respond(stream, "404 Not Found", b"unknown session").await;
+        };
+        let mut outbound = outbound.lock().await;
+
+        let first = tokio::select! {
+            packet = outbound.recv() => packet,
+            _ = sleep(POLL_TIMEOUT) => return respond(stream, "204 No Content", b"").await,
+            _ = poller.cancelled() => return respond(stream, "204 No Content", b"").await,
+        };
+        let Some(first) = first else {
+            return respond(stream, "410 Gone", b"the session is closed").await;
+        };
+
+        let mut codec = packet_codec();
+        let mut body = BytesMut::new();
+        let mut packets = Vec::new();
+        let mut next = Some(first);
+        while let Some(packet) = next {
+            codec.encode(packet.clone(), &mut body)?;
+            packets.push(packet);
+            next = if body.len() < MAX_BODY_SIZE {
+                outbound.try_recv()
+            } else {
+                None
+            };
+        }
+
+        let written = respond_with(stream, "200 OK", "application/octet-stream", &body).await;
+        if written.is_err() {
+            outbound.unsend(packets);
+        }
+        written
+    }
+
+    /// Streams the packets sent to the client as events carrying them in base64, until another request polls the
+    /// session or it is closed. A packet whose event could not be written is answered to the next request.
+    async fn stream_events<S: Transport>(&self, stream: &mut S, id: Uuid) -> io::Result<()> {
+        let Some((outbound, poller)) = self.take_poller(id) else {
+            return respond(stream, "404 Not Found", b"unknown session").await;
+        };
+        let mut outbound = outbound.lock().await;
+
+        stream
+            .write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                Content-Type: text/event-stream\r\n\
+                Cache-Control: no-store\r\n\
+                X-Accel-Buffering: no\r\n\
+                Access-Control-Allow-Origin: *\r\n\
+                Connection: close\r\n\r\n",
+            )
+            .await?;
+        stream.flush().await?;
+
+        loop {
+            let (event, packet) = tokio::select! {
+                packet = outbound.recv() => match packet {
+                    Some(packet) => (format!("data: {}\n\n", STANDARD.encode(&packet)), Some(packet)),
+                    None => ("event: close\ndata:\n\n".into(), None),
+                },
+                _ = sleep(KEEP_ALIVE_INTERVAL) => (": keep-alive\n\n".into(), None),
+                _ = poller.cancelled() => return Ok(()),
+            };
+
+            let written = async {
+                stream.write_all(event.as_bytes()).await?;
+                stream.flush().await
+            };
+            if let Err(err) = written.await {
+                outbound.unsend(packet.into_iter().collect());
+                return Err(err);
+            }
+            if event.starts_with("event: close") {
+                return Ok(());
+            }
+        }
+    }
+
+    fn session<T>(&self, id: Uuid, f: impl FnOnce(&mut HttpSession) -> T) -> Option<T> {
+        self.sessions.lock().unwrap().get_mut(&id).map(f)
+    }
+
+    /// Makes the calling request the one polling the session, stopping the previous one
+    fn take_poller(&self, id: Uuid) -> Option<(Outbound, CancellationToken)> {
+        self.session(id, |session| {
+            session.poller.cancel();
+            session.poller = CancellationToken::new();
+            (session.outbound.clone(), session.poller.clone())
+        })
+    }
+}
+
+/// Reads the head and the body of a request, or returns `None` when it is not valid HTTP
+async fn read_request<S: Transport>(stream: &mut S) -> io::Result<Option<Request>> {
+    let mut buffer = BytesMut::with_capacity(1024);
+    let (head_len, content_length, mut request) = loop {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut head = httparse::Request::new(&mut headers);
+        match head.parse(&buffer) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                let header = |name: &str| {
+                    head.headers
+                        .iter()
+                        .find(|header| header.name.eq_ignore_ascii_case(name))
+                        .and_then(|header| std::str::from_utf8(header.value).ok())
+                };
+                let accepts_events =
+                    header("accept").is_some_and(|accept| accept.contains("text/event-stream"));
+                let content_length = match header("content-length").map(str::parse::<usize>) {
+                    Some(Ok(length)) => length,
+                    Some(Err(_)) => return Ok(None),
+                    None => 0,
+                };
+                let path = head.path.unwrap_or_default();
+                let path = path.split_once('?').map_or(path, |(path, _)| path);
+
+                let request = Request {
+                    method: head.method.unwrap_or_default().to_owned(),
+                    path: path.to_owned(),
+                    accepts_events,
+                    body: BytesMut::new(),
+                };
+                break (head_len, content_length, request);
+            }
+            Ok(httparse::Status::Partial) if buffer.len() < MAX_HEAD_SIZE => continue,
+            Ok(httparse::Status::Partial) | Err(_) => return Ok(None),
+        }
+    };
+
+    if content_length > MAX_BODY_SIZE {
+        return Ok(None);
+    }
+    buffer.advance(head_len);
+    while buffer.len() < content_length {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    buffer.truncate(content_length);
+    request.body = buffer;
+    Ok(Some(request))
+}
+
+async fn respond<S: Transport>(stream: &mut S, status: &str, body: &[u8]) -> io::Result<()> {
+    respond_with(stream, status, "text/plain", body).await
+}
+
+async fn respond_with<S: Transport>(
+    stream: &mut S,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: {content_type}\r\n\
+        Content-Length: {}\r\n\
+        Cache-Control: no-store\r\n\
+        Access-Control-Allow-Origin: *\r\n\
+        Access-Control-Allow-Methods: GET, POST, DELETE\r\n\
+        Access-Control-Allow-Headers: Content-Type, Accept\r\n\
+        Connection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.shutdown().await
+}
+
+/// A connection made of the requests of an HTTP session
+pub struct HttpConnection {
+    id: Uuid,
+    socket: SocketAddr,
+    reader: HttpReader,
+    writer: HttpWriter,
+}
+
+impl HttpConnection {
+    /// The id the client sends its requests to
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+/// Reads the frames posted to a session, which is closed once the reader is dropped
+pub struct HttpReader {
+    id: Uuid,
+    frames: mpsc::Receiver<BytesMut>,
+    compression: CompressionSettings,
+    sessions: Arc<HttpSessions>,
+}
+
+#[async_trait]
+impl PacketReader for HttpReader {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        match self.frames.recv().await {
+            Some(frame) => RawPacket::decode_with(frame.freeze(), &self.compression),
+            None => Err(NetworkingError::ConnectionClosed),
+        }
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+    }
+}
+
+impl Drop for HttpReader {
+    fn drop(&mut self) {
+        self.sessions.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Queues the packets for the requests polling a session
+pub struct HttpWriter {
+    packets: mpsc::Sender<Bytes>,
+    compression: CompressionSettings,
+}
+
+#[async_trait]
+impl PacketWriter for HttpWriter {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        // a client that stopped polling is gone, like a peer that stopped reading its socket
+        self.packets
+            .send_timeout(packet.encode_with(&self.compression), DELIVERY_TIMEOUT)
+            .await
+            .map_err(|_| NetworkingError::ConnectionClosed)
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+    }
+}
+
+#[async_trait]
+impl PacketReader for HttpConnection {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        self.reader.read_packet().await
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.reader.accept_compression(settings);
+    }
+}
+
+#[async_trait]
+impl PacketWriter for HttpConnection {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.writer.write_packet(packet).await
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.writer.set_compression(settings);
+    }
+}
+
+impl ConnectionHandle for HttpConnection {
+    fn socket(&self) -> SocketAddr {
+        self.socket
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn PacketReader>, Box<dyn PacketWriter>) {
+        (Box::new(self.reader), Box::new(self.writer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+    use crate::{
+        networking::packet_type::{MESSAGE, PING},
+        server::connection::LOOPBACK_ADDRESS,
+    };
+
+    /// Sends a request to the sessions and returns the response, with the connection it opened if any
+    async fn request(
+        sessions: &Arc<HttpSessions>,
+        head: &str,
+        body: &[u8],
+    ) -> (String, Option<HttpConnection>) {
+        let (server, mut client) = duplex(64 * 1024);
+        let handled = tokio::spawn({
+            let sessions = sessions.clone();
+            async move { sessions.handle(server, LOOPBACK_ADDRESS).await.unwrap() }
+        });
+
+        client
+            .write_all(format!("{head}\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes())
+            .await
+            .unwrap();
+        client.write_all(body).await.unwrap();
+        let response = read_to_end(&mut client).await;
+        (response, handled.await.unwrap())
+    }
+
+    async fn read_to_end(client: &mut DuplexStream) -> String {
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    fn frames(packets: &[RawPacket]) -> BytesMut {
+        let mut body = BytesMut::new();
+        for packet in packets {
+            packet_codec().encode(packet.encode(), &mut body).unwrap();
+        }
+        body
+    }
+
+    #[tokio::test]
+    async fn sessions_carry_packets_both_ways() {
+        let sessions = Arc::new(HttpSessions::new());
+        let (response, connection) = request(&sessions, "POST /rustchat HTTP/1.1", b"").await;
+        assert!(response.starts_with("HTTP/1.1 201 Created"));
+        let connection = connection.unwrap();
+        let id = connection.id();
+        assert!(response.ends_with(&id.to_string()));
+        let (mut reader, mut writer) = Box::new(connection).split();
+
+        let posted = [
+            RawPacket::new(PING, Bytes::from_static(&[1])),
+            RawPacket::new(MESSAGE, Bytes::from_static(b"hello")),
+        ];
+        let (response, _) = request(
+            &sessions,
+            &format!("POST /rustchat/{id} HTTP/1.1"),
+            &frames(&posted),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content"));
+        for packet in posted {
+            assert_eq!(reader.read_packet().await.unwrap(), packet);
+        }
+
+        // a long-poll takes every packet waiting
+        let written = [
+            RawPacket::new(MESSAGE, Bytes::from_static(b"first")),
+            RawPacket::new(MESSAGE, Bytes::from_static(b"second")),
+        ];
+        for packet in &written {
+            writer.write_packet(packet.clone()).await.unwrap();
+        }
+        let (response, _) = request(&sessions, &format!("GET /rustchat/{id} HTTP/1.1"), b"").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let body = response.split_once("\r\n\r\n").unwrap().1.as_bytes();
+        assert_eq!(body, &frames(&written)[..]);
+
+        let (response, _) =
+            request(&sessions, &format!("DELETE /rustchat/{id} HTTP/1.1"), b"").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content"));
+        assert!(writer.write_packet(written[0].clone()).await.is_err());
+        assert!(sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn event_streams_carry_packets_in_base64() {
+        let sessions = Arc::new(HttpSessions::new());
+        let (_, connection) = request(&sessions, "POST /rustchat HTTP/1.1", b"").await;
+        let connection = connection.unwrap();
+        let id = connection.id();
+        let (reader, mut writer) = Box::new(connection).split();
+
+        let (server, mut client) = duplex(64 * 1024);
+        let streaming = tokio::spawn({
+            let sessions = sessions.clone();
+            async move { sessions.handle(server, LOOPBACK_ADDRESS).await.unwrap() }
+        });
+        client
+            .write_all(
+                format!("GET /rustchat/{id} HTTP/1.1\r\nAccept: text/event-stream\r\n\r\n")
+                    .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        // wait for the stream to start before closing the session
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(client.read_u8().await.unwrap());
+        }
+        let packet = RawPacket::new(MESSAGE, Bytes::from_static(b"hello"));
+        writer.write_packet(packet.clone()).await.unwrap();
+
+        // the stream ends once the session is closed
+        drop((reader, writer));
+        streaming.await.unwrap();
+        let response = String::from_utf8(head).unwrap() + &read_to_end(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: text/event-stream"));
+        let data = format!("data: {}\n\n", STANDARD.encode(packet.encode()));
+        assert!(response.contains(&data));
+        assert!(response.ends_with("event: close\ndata:\n\n"));
+    }
+
+    #[tokio::test]
+    async fn packets_are_kept_when_the_response_can_not_be_written() {
+        let sessions = Arc::new(HttpSessions::new());
+        let (_, connection) = request(&sessions, "POST /rustchat HTTP/1.1", b"").await;
+        let connection = connection.unwrap();
+        let id = connection.id();
+        let (_reader, mut writer) = Box::new(connection).split();
+        let packets = [
+            RawPacket::new(MESSAGE, Bytes::from_static(b"first")),
+            RawPacket::new(MESSAGE, Bytes::from_static(b"second")),
+        ];
+        for packet in &packets {
+            writer.write_packet(packet.clone()).await.unwrap();
+        }
+
+        // the client goes away before the packets are written
+        for accept in ["", "Accept: text/event-stream\r\n"] {
+            let (server, mut client) = duplex(64 * 1024);
+            client
+                .write_all(format!("GET /rustchat/{id} HTTP/1.1\r\n{accept}\r\n").as_bytes())
+                .await
+                .unwrap();
+            drop(client);
+            assert!(sessions.handle(server, LOOPBACK_ADDRESS).await.is_err());
+        }
+
+        let (response, _) = request(&sessions, &format!("GET /rustchat/{id} HTTP/1.1"), b"").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        let body = response.split_once("\r\n\r\n").unwrap().1.as_bytes();
+        assert_eq!(body, &frames(&packets)[..]);
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_and_bodies_are_rejected() {
+        let sessions = Arc::new(HttpSessions::new());
+        let id = Uuid::new_v4();
+        let (response, _) = request(&sessions, &format!("GET /rustchat/{id} HTTP/1.1"), b"").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+        let (_, connection) = request(&sessions, "POST /rustchat HTTP/1.1", b"").await;
+        let connection = connection.unwrap();
+        let id = connection.id();
+        let (response, _) = request(
+            &sessions,
+            &format!("POST /rustchat/{id} HTTP/1.1"),
+            b"\0\0\0\x05ab",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+
+        assert!(is_fallback_request(b"POST /rustchat"));
+        assert!(is_fallback_request(b"GET /rustchat/"));
+        assert!(!is_fallback_request(b"GET / HTTP/1.1"));
+        assert!(is_partial_fallback_request(b"GET /rust"));
+        assert!(!is_partial_fallback_request(b"GET /chat"));
+        assert!(!is_partial_fallback_request(b"POST /rustchat"));
+
+        // a poll is only told apart from a WebSocket upgrade once its head is complete
+        assert!(is_partial_fallback_request(b"GET /rustchat"));
+        assert!(!is_partial_fallback_request(
+            b"GET /rustchat HTTP/1.1\r\n\r\n"
+        ));
+        let upgrade =
+            b"GET /rustchat HTTP/1.1\r\nUpgrade: WebSocket\r\nConnection: Upgrade\r\n\r\n";
+        assert!(!is_fallback_request(upgrade));
+    }
+}
@@ -0,0 +1,44 @@
+//! Connections over an in-memory pipe, so users and the server can be exercised without opening sockets. Packets are
+//! framed like on TCP.
+
+use std::net::SocketAddr;
+
+use tokio::io::{duplex, DuplexStream};
+
+use super::{TcpConnection, Transport, LOOPBACK_ADDRESS};
+
+pub type MemoryConnection = TcpConnection<DuplexStream>;
+
+/// How many bytes may be buffered in each direction of a memory connection before writes wait for the reader
+const BUFFER_SIZE: usize = 64 * 1024;
+
+impl Transport for DuplexStream {}
+
+impl MemoryConnection {
+    /// Creates the server side of a connection and the stream its client writes to and reads from
+    pub fn pair() -> (Self, DuplexStream) {
+        Self::pair_from(LOOPBACK_ADDRESS)
+    }
+
+    /// Creates a connection pair like [`MemoryConnection::pair`], as if its client connected from `address`
+    pub fn pair_from(address: SocketAddr) -> (Self, DuplexStream) {
+        let (server, client) = duplex(BUFFER_SIZE);
+        (Self::new(address, server), client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::connection::ConnectionHandle;
+
+    #[test]
+    fn memory_connections_report_the_address_of_their_client() {
+        let (server, _client) = MemoryConnection::pair();
+        assert_eq!(server.socket(), LOOPBACK_ADDRESS);
+
+        let address = "203.0.113.9:51000".parse().unwrap();
+        let (server, _client) = MemoryConnection::pair_from(address);
+        assert_eq!(server.socket(), address);
+    }
+}
@@ -0,0 +1,1279 @@
+//! A reliable, ordered transport over UDP.
+//!
+//! Packets are split into numbered segments of at most [`MAX_DATAGRAM_SIZE`] bytes. The receiver answers every data
+//! datagram with an ack carrying the next sequence number it expects plus a bitmap of the 64 segments after it that
+//! already arrived, so the sender only retransmits what was actually lost. Lost segments are detected by a timer
+//! derived from the measured round trip time, or earlier when three later segments were acked. The congestion window
+//! grows while segments are acked and shrinks on loss, so a congested link is not flooded with retransmissions.
+//!
+//! Every datagram starts with the protocol version and its kind:
+//!
+//! ```text
+//! DATA   version u8 | 0x01 | flags u8 | sequence u32 | payload
+//! ACK    version u8 | 0x02 | next sequence u32 | selective acks u64
+//! HELLO  version u8 | 0x03 | zeros up to the size of a COOKIE
+//! COOKIE version u8 | 0x04 | cookie [u8; 16]
+//! OPEN   version u8 | 0x05 | cookie [u8; 16]
+//! ```
+//!
+//! The flag 0x01 marks the last segment of a packet, and 0x02 an empty segment closing the connection, so closing is
+//! retransmitted like any other segment. A connection starts with the segment numbered 0.
+//!
+//! Before a connection is opened, the client proves it receives what is sent to its address: it sends HELLO, the
+//! listener answers with a COOKIE derived from the address without keeping any state, and the client sends it back in
+//! OPEN. The listener confirms the connection with an ACK of nothing. HELLO and OPEN are retransmitted like segments.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use sha2::{Digest, Sha256};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, error::TrySendError},
+    time::{sleep, sleep_until, timeout_at, Instant},
+};
+use uuid::Uuid;
+
+use crate::networking::{
+    compression::CompressionSettings,
+    error::NetworkingError,
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+};
+
+use super::{ConnectionHandle, PacketReader, PacketWriter};
+
+/// The largest datagram sent, small enough to avoid IP fragmentation on common links
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+const VERSION: u8 = 1;
+const KIND_DATA: u8 = 0x01;
+const KIND_ACK: u8 = 0x02;
+const KIND_HELLO: u8 = 0x03;
+const KIND_COOKIE: u8 = 0x04;
+const KIND_OPEN: u8 = 0x05;
+const FLAG_END: u8 = 0x01;
+const FLAG_CLOSE: u8 = 0x02;
+
+const DATA_HEADER_SIZE: usize = 7;
+const MAX_SEGMENT_PAYLOAD: usize = MAX_DATAGRAM_SIZE - DATA_HEADER_SIZE;
+
+/// How many segments past the acknowledged one can be in flight, and buffered by the receiver
+const WINDOW: usize = 256;
+
+const INITIAL_WINDOW: f64 = 4.0;
+const INITIAL_RTO: Duration = Duration::from_millis(250);
+const MIN_RTO: Duration = Duration::from_millis(50);
+const MAX_RTO: Duration = Duration::from_secs(10);
+
+/// A segment retransmitted this many times means the peer is gone
+const MAX_RETRANSMITS: u32 = 8;
+
+/// Acks of this many later segments mark the oldest unacknowledged one as lost before its timer expires
+const FAST_RETRANSMIT_THRESHOLD: usize = 3;
+
+/// How many packets are queued between a connection and its driver task
+const CHANNEL_SIZE: usize = 64;
+
+/// How many connections a listener keeps open at once, OPEN segments past it are dropped
+pub const MAX_PEERS: usize = 4096;
+
+const COOKIE_SIZE: usize = 16;
+
+/// How long a cookie is valid, a cookie issued just before the end of a period is valid during the next one too
+const COOKIE_PERIOD: Duration = Duration::from_secs(60);
+
+/// How long the listener waits before receiving again after its socket failed
+const RECEIVE_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+type Cookie = [u8; COOKIE_SIZE];
+
+/// Where datagrams are sent to and received from, implemented by `UdpSocket` and replaceable in tests
+#[async_trait]
+pub trait DatagramSocket: Send + Sync {
+    async fn send_to(&self, datagram: &[u8], target: SocketAddr) -> io::Result<()>;
+    async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+#[async_trait]
+impl DatagramSocket for UdpSocket {
+    async fn send_to(&self, datagram: &[u8], target: SocketAddr) -> io::Result<()> {
+        UdpSocket::send_to(self, datagram, target).await.map(|_| ())
+    }
+
+    async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Data { seq: u32, flags: u8, payload: Bytes },
+    Ack { next: u32, selective: u64 },
+    Hello,
+    Cookie(Cookie),
+    Open(Cookie),
+}
+
+impl Segment {
+    fn encode(&self) -> Bytes {
+        let mut buffer = BytesMut::with_capacity(MAX_DATAGRAM_SIZE);
+        buffer.put_u8(VERSION);
+        match self {
+            Segment::Data {
+                seq,
+                flags,
+                payload,
+            } => {
+                buffer.put_u8(KIND_DATA);
+                buffer.put_u8(*flags);
+                buffer.put_u32(*seq);
+                buffer.put_slice(payload);
+            }
+            Segment::Ack { next, selective } => {
+                buffer.put_u8(KIND_ACK);
+                buffer.put_u32(*next);
+                buffer.put_u64(*selective);
+            }
+            Segment::Hello => {
+                // as large as the answer, so a forged source address can not be used to amplify traffic
+                buffer.put_u8(KIND_HELLO);
+                buffer.put_bytes(0, COOKIE_SIZE);
+            }
+            Segment::Cookie(cookie) => {
+                buffer.put_u8(KIND_COOKIE);
+                buffer.put_slice(cookie);
+            }
+            Segment::Open(cookie) => {
+                buffer.put_u8(KIND_OPEN);
+                buffer.put_slice(cookie);
+            }
+        }
+
+        buffer.freeze()
+    }
+
+    /// Decodes a datagram, returning `None` for anything that is not a valid segment
+    fn decode(mut datagram: Bytes) -> Option<Segment> {
+        if datagram.remaining() < 2 || datagram.get_u8() != VERSION {
+            return None;
+        }
+
+        match datagram.get_u8() {
+            KIND_DATA if datagram.remaining() >= DATA_HEADER_SIZE - 2 => Some(Segment::Data {
+                flags: datagram.get_u8(),
+                seq: datagram.get_u32(),
+                payload: datagram,
+            }),
+            KIND_ACK if datagram.remaining() >= 12 => Some(Segment::Ack {
+                next: datagram.get_u32(),
+                selective: datagram.get_u64(),
+            }),
+            KIND_HELLO if datagram.remaining() >= COOKIE_SIZE => Some(Segment::Hello),
+            KIND_COOKIE if datagram.remaining() >= COOKIE_SIZE => {
+                Some(Segment::Cookie(read_cookie(&mut datagram)))
+            }
+            KIND_OPEN if datagram.remaining() >= COOKIE_SIZE => {
+                Some(Segment::Open(read_cookie(&mut datagram)))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn read_cookie(datagram: &mut Bytes) -> Cookie {
+    let mut cookie = [0; COOKIE_SIZE];
+    datagram.copy_to_slice(&mut cookie);
+    cookie
+}
+
+/// Issues the cookies peers send back to open a connection. A cookie is a keyed hash of the address of the peer and
+/// the current period, so the listener can check it without remembering who it was issued to.
+struct Cookies {
+    secret: [u8; 32],
+    started: Instant,
+}
+
+impl Cookies {
+    fn new() -> Self {
+        let mut secret = [0; 32];
+        secret[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+        secret[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+        Self {
+            secret,
+            started: Instant::now(),
+        }
+    }
+
+    fn period(&self) -> u64 {
+        (self.started.elapsed().as_secs() / COOKIE_PERIOD.as_secs()) + 1
+    }
+
+    fn issue(&self, peer: SocketAddr) -> Cookie {
+        self.cookie(peer, self.period())
+    }
+
+    fn verify(&self, peer: SocketAddr, cookie: &Cookie) -> bool {
+        let period = self.period();
+        *cookie == self.cookie(peer, period) || *cookie == self.cookie(peer, period - 1)
+    }
+
+    fn cookie(&self, peer: SocketAddr, period: u64) -> Cookie {
+        // the digest is truncated, so it can not be extended into a valid cookie for another input
+        let mut hasher = Sha256::new();
+        hasher.update(self.secret);
+        match peer.ip() {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(peer.port().to_be_bytes());
+        hasher.update(period.to_be_bytes());
+        hasher.finalize()[..COOKIE_SIZE].try_into().unwrap()
+    }
+}
+
+#[derive(Debug)]
+struct InFlight {
+    payload: Bytes,
+    flags: u8,
+
+    /// Acked selectively, the cumulative ack has not reached it yet
+    acked: bool,
+    sent_at: Instant,
+    retransmits: u32,
+}
+
+/// The protocol state of one side of a connection, without any IO so it can be driven by a task or a test
+#[derive(Debug)]
+struct Reliable {
+    /// The sequence number of the oldest segment in flight
+    send_base: u32,
+
+    /// Segments sent but not acked cumulatively, the first one has sequence number `send_base`
+    in_flight: VecDeque<InFlight>,
+
+    /// Segments waiting for room in the congestion window
+    queued: VecDeque<(Bytes, u8)>,
+    fast_retransmitted: bool,
+
+    congestion_window: f64,
+    slow_start_threshold: f64,
+    smoothed_rtt: Option<Duration>,
+    rtt_variance: Duration,
+    rto: Duration,
+
+    /// The sequence number of the next segment to deliver
+    receive_next: u32,
+
+    /// Out of order segments, index `i` holds sequence number `receive_next + i`
+    received: VecDeque<Option<(Bytes, u8)>>,
+
+    /// The segments of the packet being reassembled
+    message: BytesMut,
+    message_too_large: bool,
+
+    /// Datagrams to send to the peer
+    transmit: Vec<Bytes>,
+
+    /// Messages received in order, decoded by the reader, or the reason they could not be reassembled
+    delivered: VecDeque<Result<Bytes, NetworkingError>>,
+
+    /// The peer closed the connection
+    closed: bool,
+}
+
+impl Reliable {
+    fn new() -> Self {
+        Self {
+            send_base: 0,
+            in_flight: VecDeque::new(),
+            queued: VecDeque::new(),
+            fast_retransmitted: false,
+            congestion_window: INITIAL_WINDOW,
+            slow_start_threshold: WINDOW as f64,
+            smoothed_rtt: None,
+            rtt_variance: Duration::ZERO,
+            rto: INITIAL_RTO,
+            receive_next: 0,
+            received: VecDeque::new(),
+            message: BytesMut::new(),
+            message_too_large: false,
+            transmit: Vec::new(),
+            delivered: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    /// Splits an encoded packet into segments and sends as many as the congestion window allows
+    fn send(&mut self, mut packet: Bytes, now: Instant) {
+        while packet.len() > MAX_SEGMENT_PAYLOAD {
+            self.queued
+                .push_back((packet.split_to(MAX_SEGMENT_PAYLOAD), 0));
+        }
+        self.queued.push_back((packet, FLAG_END));
+        self.send_queued(now);
+    }
+
+    /// Tells the peer no more packets follow, once everything sent before was acked
+    fn close(&mut self, now: Instant) {
+        self.queued.push_back((Bytes::new(), FLAG_CLOSE));
+        self.send_queued(now);
+    }
+
+    fn send_queued(&mut self, now: Instant) {
+        let window = (self.congestion_window as usize).clamp(1, WINDOW);
+        while self.in_flight.len() < window {
+            let Some((payload, flags)) = self.queued.pop_front() else {
+                break;
+            };
+
+            let seq = self.send_base.wrapping_add(self.in_flight.len() as u32);
+            self.transmit.push(
+                Segment::Data {
+                    seq,
+                    flags,
+                    payload: payload.clone(),
+                }
+                .encode(),
+            );
+            self.in_flight.push_back(InFlight {
+                payload,
+                flags,
+                acked: false,
+                sent_at: now,
+                retransmits: 0,
+            });
+        }
+    }
+
+    fn handle_datagram(&mut self, datagram: Bytes, now: Instant) {
+        match Segment::decode(datagram) {
+            Some(Segment::Data {
+                seq,
+                flags,
+                payload,
+            }) => self.handle_data(seq, flags, payload),
+            Some(Segment::Ack { next, selective }) => self.handle_ack(next, selective, now),
+
+            // the handshake is over once the connection runs
+            _ => {}
+        }
+    }
+
+    fn handle_data(&mut self, seq: u32, flags: u8, payload: Bytes) {
+        let offset = seq.wrapping_sub(self.receive_next) as usize;
+
+        // anything outside the window is a duplicate of a delivered segment, the ack below tells the peer
+        if offset < WINDOW {
+            if self.received.len() <= offset {
+                self.received.resize(offset + 1, None);
+            }
+            self.received[offset] = Some((payload, flags));
+        }
+
+        self.advance();
+        self.acknowledge();
+    }
+
+    /// Reassembles the segments received in order, until `CHANNEL_SIZE` packets wait for the reader. The receive
+    /// window stops moving meanwhile, so the peer stops sending new segments until the reader catches up. Returns
+    /// whether it moved.
+    fn advance(&mut self) -> bool {
+        let receive_next = self.receive_next;
+        while self.delivered.len() < CHANNEL_SIZE {
+            let Some(Some(_)) = self.received.front() else {
+                break;
+            };
+
+            let (payload, flags) = self.received.pop_front().flatten().unwrap();
+            self.receive_next = self.receive_next.wrapping_add(1);
+            if flags & FLAG_CLOSE != 0 {
+                self.closed = true;
+            } else {
+                self.reassemble(payload, flags & FLAG_END != 0);
+            }
+        }
+
+        self.receive_next != receive_next
+    }
+
+    /// Tells the peer which segments arrived
+    fn acknowledge(&mut self) {
+        let selective = self
+            .received
+            .iter()
+            .skip(1)
+            .take(64)
+            .enumerate()
+            .filter(|(_, segment)| segment.is_some())
+            .fold(0u64, |bits, (i, _)| bits | 1 << i);
+        self.transmit.push(
+            Segment::Ack {
+                next: self.receive_next,
+                selective,
+            }
+            .encode(),
+        );
+    }
+
+    fn reassemble(&mut self, payload: Bytes, end: bool) {
+        if self.message.len() + payload.len() > MAX_PACKET_SIZE {
+            self.message_too_large = true;
+            self.message.clear();
+        } else if !self.message_too_large {
+            self.message.put_slice(&payload);
+        }
+
+        if end {
+            let packet = match self.message_too_large {
+                true => Err(NetworkingError::InvalidPacketFormat),
+                false => Ok(self.message.split().freeze()),
+            };
+            self.message_too_large = false;
+            self.delivered.push_back(packet);
+        }
+    }
+
+    fn handle_ack(&mut self, next: u32, selective: u64, now: Instant) {
+        let acked = next.wrapping_sub(self.send_base) as usize;
+        if acked > self.in_flight.len() {
+            // acks a segment that was never sent
+            return;
+        }
+
+        // the newest segment acked by this ack is the one that made the peer send it, older segments may have waited
+        // for a gap to be filled and would inflate the round trip time
+        let mut newest_sent = None;
+        let mut newly_acked = 0;
+        for segment in self.in_flight.drain(..acked) {
+            if !segment.acked {
+                newly_acked += 1;
+                newest_sent = Some((segment.sent_at, segment.retransmits));
+            }
+        }
+        if acked > 0 {
+            self.send_base = next;
+            self.fast_retransmitted = false;
+        }
+
+        for (i, segment) in self.in_flight.iter_mut().skip(1).take(64).enumerate() {
+            if selective & (1 << i) != 0 && !segment.acked {
+                segment.acked = true;
+                newly_acked += 1;
+                newest_sent = Some((segment.sent_at, segment.retransmits));
+            }
+        }
+
+        // Karn's algorithm, the ack of a retransmitted segment could be for any of its copies
+        if let Some((sent_at, 0)) = newest_sent {
+            self.sample_rtt(now.saturating_duration_since(sent_at));
+        }
+
+        for _ in 0..newly_acked {
+            if self.congestion_window < self.slow_start_threshold {
+                self.congestion_window += 1.0;
+            } else {
+                self.congestion_window += 1.0 / self.congestion_window;
+            }
+        }
+
+        let later_acked = self.in_flight.iter().filter(|s| s.acked).count();
+        if !self.fast_retransmitted && later_acked >= FAST_RETRANSMIT_THRESHOLD {
+            self.fast_retransmitted = true;
+            self.reduce_window(false);
+            self.retransmit(0, now);
+        }
+
+        self.send_queued(now);
+    }
+
+    fn sample_rtt(&mut self, sample: Duration) {
+        let srtt = match self.smoothed_rtt {
+            None => {
+                self.rtt_variance = sample / 2;
+                sample
+            }
+            Some(srtt) => {
+                self.rtt_variance = (self.rtt_variance * 3 + srtt.abs_diff(sample)) / 4;
+                (srtt * 7 + sample) / 8
+            }
+        };
+        self.smoothed_rtt = Some(srtt);
+        self.rto =
+            (srtt + (self.rtt_variance * 4).max(Duration::from_millis(10))).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// When the oldest unacknowledged segment has to be retransmitted
+    fn next_timeout(&self) -> Option<Instant> {
+        self.in_flight
+            .iter()
+            .filter(|segment| !segment.acked)
+            .map(|segment| segment.sent_at + self.rto)
+            .min()
+    }
+
+    /// Retransmits every segment whose timer expired, failing once one was retransmitted too often
+    fn handle_timeout(&mut self, now: Instant) -> Result<(), NetworkingError> {
+        let expired: Vec<usize> = (0..self.in_flight.len())
+            .filter(|&i| !self.in_flight[i].acked && self.in_flight[i].sent_at + self.rto <= now)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+
+        for i in expired {
+            if self.in_flight[i].retransmits >= MAX_RETRANSMITS {
+                return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+            }
+            self.retransmit(i, now);
+        }
+
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.reduce_window(true);
+        Ok(())
+    }
+
+    /// Halves the congestion window after a loss, a timeout means the link is badly congested so it restarts from one
+    fn reduce_window(&mut self, timeout: bool) {
+        self.slow_start_threshold = (self.congestion_window / 2.0).max(2.0);
+        self.congestion_window = match timeout {
+            true => 1.0,
+            false => self.slow_start_threshold,
+        };
+    }
+
+    fn retransmit(&mut self, index: usize, now: Instant) {
+        let seq = self.send_base.wrapping_add(index as u32);
+        let segment = &mut self.in_flight[index];
+        segment.sent_at = now;
+        segment.retransmits += 1;
+        self.transmit.push(
+            Segment::Data {
+                seq,
+                flags: segment.flags,
+                payload: segment.payload.clone(),
+            }
+            .encode(),
+        );
+    }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight.is_empty() && self.queued.is_empty()
+    }
+}
+
+/// A connection using the reliable UDP transport. The protocol runs in a separate task, so segments are acked and
+/// retransmitted even while nobody reads or writes packets.
+#[derive(Debug)]
+pub struct UdpConnection {
+    address: SocketAddr,
+    reader: UdpReader,
+    writer: UdpWriter,
+}
+
+/// Receives the packets the protocol task delivered in order
+#[derive(Debug)]
+pub struct UdpReader {
+    incoming: mpsc::Receiver<Result<Bytes, NetworkingError>>,
+    compression: CompressionSettings,
+}
+
+/// Hands packets to the protocol task
+#[derive(Debug)]
+pub struct UdpWriter {
+    outgoing: mpsc::Sender<Bytes>,
+    compression: CompressionSettings,
+}
+
+impl UdpConnection {
+    /// Connects to a server listening with [`UdpListener`]
+    pub async fn connect(address: SocketAddr) -> io::Result<Self> {
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        Ok(Self::connect_with(
+            Arc::new(UdpSocket::bind(local).await?),
+            address,
+        ))
+    }
+
+    /// Connects to a server through the given socket, which must not be used for anything else
+    pub fn connect_with(socket: Arc<dyn DatagramSocket>, address: SocketAddr) -> Self {
+        let (datagrams, receiver) = mpsc::channel(CHANNEL_SIZE);
+        let connection = Self::spawn(socket.clone(), address, receiver, Side::Client);
+
+        tokio::spawn(async move {
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                let (len, peer) = match socket.recv_from(&mut buffer).await {
+                    Ok(received) => received,
+                    Err(_) if datagrams.is_closed() => return,
+                    Err(err) => {
+                        println!("could not receive a datagram: {}", err);
+                        sleep(RECEIVE_ERROR_BACKOFF).await;
+                        continue;
+                    }
+                };
+                if peer != address {
+                    continue;
+                }
+                if datagrams
+                    .send(Bytes::copy_from_slice(&buffer[..len]))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+
+        connection
+    }
+
+    /// Starts the task running the protocol for the peer at `address`, fed with the datagrams it sends
+    fn spawn(
+        socket: Arc<dyn DatagramSocket>,
+        address: SocketAddr,
+        mut datagrams: mpsc::Receiver<Bytes>,
+        side: Side,
+    ) -> Self {
+        let (outgoing, outgoing_receiver) = mpsc::channel(CHANNEL_SIZE);
+        let (incoming_sender, incoming) = mpsc::channel(CHANNEL_SIZE);
+        tokio::spawn(async move {
+            let mut state = Reliable::new();
+            match side {
+                Side::Client => match open(&*socket, address, &mut datagrams).await {
+                    Ok(first) => {
+                        if let Some(datagram) = first {
+                            state.handle_datagram(datagram, Instant::now());
+                        }
+                    }
+                    Err(err) => {
+                        let _ = incoming_sender.send(Err(err)).await;
+                        return;
+                    }
+                },
+                Side::Listener(_) => {}
+            }
+
+            drive(
+                socket,
+                address,
+                state,
+                datagrams,
+                outgoing_receiver,
+                incoming_sender,
+            )
+            .await;
+
+            // the datagrams of the peer are no longer received, the listener can forget it
+            if let Side::Listener(closed) = side {
+                let _ = closed.send(address);
+            }
+        });
+
+        Self {
+            address,
+            reader: UdpReader {
+                incoming,
+                compression: CompressionSettings::disabled(),
+            },
+            writer: UdpWriter {
+                outgoing,
+                compression: CompressionSettings::disabled(),
+            },
+        }
+    }
+}
+
+/// Which end of a connection a protocol task runs
+enum Side {
+    /// Opens the connection with a handshake
+    Client,
+
+    /// Was opened by a peer, and tells the listener routing its datagrams once it is closed
+    Listener(mpsc::UnboundedSender<SocketAddr>),
+}
+
+/// Sends HELLO, then OPEN with the cookie of the listener, until the listener confirms the connection. Returns the
+/// first segment of the connection when it arrives before the confirmation.
+async fn open(
+    socket: &dyn DatagramSocket,
+    address: SocketAddr,
+    datagrams: &mut mpsc::Receiver<Bytes>,
+) -> Result<Option<Bytes>, NetworkingError> {
+    let mut request = Segment::Hello;
+    let mut rto = INITIAL_RTO;
+
+    'attempts: for _ in 0..=MAX_RETRANSMITS {
+        let _ = socket.send_to(&request.encode(), address).await;
+
+        let deadline = Instant::now() + rto;
+        while let Ok(datagram) = timeout_at(deadline, datagrams.recv()).await {
+            let datagram = datagram.ok_or(NetworkingError::ConnectionClosed)?;
+            match (Segment::decode(datagram.clone()), &request) {
+                // the listener waits for it, so it is sent right away
+                (Some(Segment::Cookie(cookie)), Segment::Hello) => {
+                    request = Segment::Open(cookie);
+                    continue 'attempts;
+                }
+                (Some(Segment::Ack { .. }), Segment::Open(_)) => return Ok(None),
+
+                // the confirmation was lost, but the connection is open
+                (Some(Segment::Data { .. }), Segment::Open(_)) => return Ok(Some(datagram)),
+                _ => {}
+            }
+        }
+        rto = (rto * 2).min(MAX_RTO);
+    }
+
+    Err(io::Error::from(io::ErrorKind::TimedOut).into())
+}
+
+#[async_trait]
+impl PacketReader for UdpReader {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        let message = self
+            .incoming
+            .recv()
+            .await
+            .unwrap_or(Err(NetworkingError::ConnectionClosed))?;
+        RawPacket::decode_with(message, &self.compression)
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+    }
+}
+
+#[async_trait]
+impl PacketWriter for UdpWriter {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.outgoing
+            .send(packet.encode_with(&self.compression))
+            .await
+            .map_err(|_| NetworkingError::ConnectionClosed)
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+    }
+}
+
+#[async_trait]
+impl PacketReader for UdpConnection {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        self.reader.read_packet().await
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.reader.accept_compression(settings);
+    }
+}
+
+#[async_trait]
+impl PacketWriter for UdpConnection {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.writer.write_packet(packet).await
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.writer.set_compression(settings);
+    }
+}
+
+impl ConnectionHandle for UdpConnection {
+    fn socket(&self) -> SocketAddr {
+        self.address
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn PacketReader>, Box<dyn PacketWriter>) {
+        (Box::new(self.reader), Box::new(self.writer))
+    }
+}
+
+/// Runs the protocol of one connection until either side closes it or the peer stops acking
+async fn drive(
+    socket: Arc<dyn DatagramSocket>,
+    address: SocketAddr,
+    mut state: Reliable,
+    mut datagrams: mpsc::Receiver<Bytes>,
+    mut outgoing: mpsc::Receiver<Bytes>,
+    incoming: mpsc::Sender<Result<Bytes, NetworkingError>>,
+) {
+    // the connection was dropped, what was already written is still delivered before closing
+    let mut closing = false;
+
+    loop {
+        deliver(&mut state, &incoming);
+        for datagram in state.transmit.drain(..) {
+            // a datagram that could not be sent is handled like a lost one
+            let _ = socket.send_to(&datagram, address).await;
+        }
+
+        if state.closed && state.delivered.is_empty() {
+            return;
+        }
+        if closing && state.is_idle() {
+            return;
+        }
+
+        let timeout = state.next_timeout();
+        let result = tokio::select! {
+            datagram = datagrams.recv() => match datagram {
+                Some(datagram) => {
+                    state.handle_datagram(datagram, Instant::now());
+                    Ok(())
+                }
+                None => return,
+            },
+            packet = outgoing.recv(), if !closing && state.queued.len() < WINDOW => {
+                match packet {
+                    Some(packet) => state.send(packet, Instant::now()),
+                    None => {
+                        state.close(Instant::now());
+                        closing = true;
+                    }
+                }
+                Ok(())
+            },
+            _ = sleep_until(timeout.unwrap_or_else(Instant::now)), if timeout.is_some() => {
+                state.handle_timeout(Instant::now())
+            }
+
+            // the reader made room, the next packets are handed to it above
+            _ = incoming.reserve(), if !state.delivered.is_empty() => Ok(()),
+        };
+
+        if let Err(err) = result {
+            let _ = incoming.send(Err(err)).await;
+            return;
+        }
+    }
+}
+
+/// Hands the reassembled packets to the reader without waiting for it, so segments are still acked and retransmitted
+/// while it is busy. The packets it has no room for stay in the state, which stops the receive window.
+fn deliver(state: &mut Reliable, incoming: &mpsc::Sender<Result<Bytes, NetworkingError>>) {
+    let mut advanced = false;
+    while let Some(packet) = state.delivered.pop_front() {
+        match incoming.try_send(packet) {
+            // nobody reads the packets anymore, but the peer may still be waiting for acks
+            Ok(()) | Err(TrySendError::Closed(_)) => {}
+            Err(TrySendError::Full(packet)) => {
+                state.delivered.push_front(packet);
+                break;
+            }
+        }
+        advanced |= state.advance();
+    }
+
+    // the peer waits for the window to move before sending more
+    if advanced {
+        state.acknowledge();
+    }
+}
+
+/// Accepts reliable UDP connections on a socket, routing the datagrams of every peer to its connection
+pub struct UdpListener {
+    local_addr: SocketAddr,
+    connections: mpsc::Receiver<UdpConnection>,
+}
+
+impl UdpListener {
+    pub async fn bind(address: &str) -> io::Result<Self> {
+        Self::with_socket(Arc::new(UdpSocket::bind(address).await?))
+    }
+
+    pub fn with_socket(socket: Arc<dyn DatagramSocket>) -> io::Result<Self> {
+        Self::start(socket, MAX_PEERS)
+    }
+
+    fn start(socket: Arc<dyn DatagramSocket>, max_peers: usize) -> io::Result<Self> {
+        let local_addr = socket.local_addr()?;
+        let (new_connections, connections) = mpsc::channel(CHANNEL_SIZE);
+        tokio::spawn(route(socket, new_connections, max_peers));
+
+        Ok(Self {
+            local_addr,
+            connections,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Waits for a peer to open a connection
+    pub async fn accept(&mut self) -> io::Result<UdpConnection> {
+        self.connections
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+async fn route(
+    socket: Arc<dyn DatagramSocket>,
+    connections: mpsc::Sender<UdpConnection>,
+    max_peers: usize,
+) {
+    let cookies = Cookies::new();
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+    let (closed_sender, mut closed) = mpsc::unbounded_channel();
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received,
+            Some(peer) = closed.recv() => {
+                // the peer may have opened another connection since
+                if peers.get(&peer).is_some_and(mpsc::Sender::is_closed) {
+                    peers.remove(&peer);
+                }
+                continue;
+            }
+        };
+        let (len, peer) = match received {
+            Ok(received) => received,
+            Err(err) => {
+                println!("could not receive a datagram: {}", err);
+                sleep(RECEIVE_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let datagram = Bytes::copy_from_slice(&buffer[..len]);
+
+        let open = peers.get(&peer).filter(|sender| !sender.is_closed());
+        match (open, Segment::decode(datagram.clone())) {
+            // the confirmation was lost
+            (Some(_), Some(Segment::Open(_))) => {}
+            (Some(sender), _) => {
+                // a full channel drops the datagram like a congested link would
+                let _ = sender.try_send(datagram);
+                continue;
+            }
+            (None, Some(Segment::Hello)) => {
+                let cookie = Segment::Cookie(cookies.issue(peer));
+                let _ = socket.send_to(&cookie.encode(), peer).await;
+                continue;
+            }
+            (None, Some(Segment::Open(cookie))) if cookies.verify(peer, &cookie) => {
+                if peers.len() >= max_peers {
+                    continue;
+                }
+
+                let permit = match connections.try_reserve() {
+                    Ok(permit) => permit,
+
+                    // the accept loop is behind, the peer sends OPEN again
+                    Err(TrySendError::Full(())) => continue,
+
+                    // the listener was dropped
+                    Err(TrySendError::Closed(())) => return,
+                };
+                let (sender, datagrams) = mpsc::channel(CHANNEL_SIZE);
+                let side = Side::Listener(closed_sender.clone());
+                permit.send(UdpConnection::spawn(socket.clone(), peer, datagrams, side));
+                peers.insert(peer, sender);
+            }
+
+            // what is left of a connection that is already gone, or a peer that did not prove its address
+            _ => continue,
+        }
+
+        let confirmation = Segment::Ack {
+            next: 0,
+            selective: 0,
+        };
+        let _ = socket.send_to(&confirmation.encode(), peer).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::networking::packet_type::MESSAGE;
+
+    /// Drops every `drop_every`th datagram it sends and delays every `delay_every`th one, so the datagrams after it
+    /// overtake it
+    struct FaultySocket {
+        socket: Arc<UdpSocket>,
+        sent: AtomicUsize,
+        drop_every: usize,
+        delay_every: usize,
+    }
+
+    impl FaultySocket {
+        async fn bind(drop_every: usize, delay_every: usize) -> Arc<Self> {
+            Arc::new(Self {
+                socket: Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+                sent: AtomicUsize::new(0),
+                drop_every,
+                delay_every,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl DatagramSocket for FaultySocket {
+        async fn send_to(&self, datagram: &[u8], target: SocketAddr) -> io::Result<()> {
+            let sent = self.sent.fetch_add(1, Ordering::Relaxed) + 1;
+            if sent.is_multiple_of(self.drop_every) {
+                return Ok(());
+            }
+            if sent.is_multiple_of(self.delay_every) {
+                let socket = self.socket.clone();
+                let datagram = datagram.to_vec();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    let _ = socket.send_to(&datagram, target).await;
+                });
+                return Ok(());
+            }
+
+            self.socket.send_to(datagram, target).await.map(|_| ())
+        }
+
+        async fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.socket.recv_from(buffer).await
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.local_addr()
+        }
+    }
+
+    fn packet(i: usize) -> RawPacket {
+        // every fourth packet spans several segments
+        let size = if i.is_multiple_of(4) { 5000 } else { 10 };
+        RawPacket::new(MESSAGE, Bytes::from(vec![i as u8; size]))
+    }
+
+    #[test]
+    fn segment_encoding() {
+        let segments = [
+            Segment::Data {
+                seq: u32::MAX,
+                flags: FLAG_END,
+                payload: Bytes::from_static(b"hello"),
+            },
+            Segment::Ack {
+                next: 7,
+                selective: 0b101,
+            },
+            Segment::Hello,
+            Segment::Cookie([1; COOKIE_SIZE]),
+            Segment::Open([2; COOKIE_SIZE]),
+        ];
+        for segment in segments {
+            assert_eq!(Segment::decode(segment.encode()), Some(segment));
+        }
+
+        assert_eq!(Segment::decode(Bytes::from_static(&[2, KIND_ACK])), None);
+        assert_eq!(
+            Segment::decode(Bytes::from_static(&[VERSION, KIND_ACK, 0])),
+            None
+        );
+
+        // a HELLO is as large as the COOKIE answering it
+        assert_eq!(
+            Segment::Hello.encode().len(),
+            Segment::Cookie([0; COOKIE_SIZE]).encode().len()
+        );
+        assert_eq!(
+            Segment::decode(Bytes::from_static(&[VERSION, KIND_HELLO])),
+            None
+        );
+    }
+
+    #[test]
+    fn cookies_are_bound_to_the_address_of_the_peer() {
+        let cookies = Cookies::new();
+        let peer: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let cookie = cookies.issue(peer);
+        assert!(cookies.verify(peer, &cookie));
+        assert!(!cookies.verify("192.0.2.1:4001".parse().unwrap(), &cookie));
+        assert!(!Cookies::new().verify(peer, &cookie));
+    }
+
+    #[test]
+    fn selective_acks_retransmit_only_lost_segments() {
+        let now = Instant::now();
+        let mut sender = Reliable::new();
+        let mut receiver = Reliable::new();
+        sender.congestion_window = 8.0;
+        sender.send(Bytes::from(vec![1; MAX_SEGMENT_PAYLOAD * 6 + 1]), now);
+        let datagrams: Vec<Bytes> = sender.transmit.drain(..).collect();
+        assert_eq!(datagrams.len(), 7);
+
+        // the second segment is lost
+        for (i, datagram) in datagrams.into_iter().enumerate() {
+            if i != 1 {
+                receiver.handle_datagram(datagram, now);
+            }
+        }
+        assert!(receiver.delivered.is_empty());
+        for ack in receiver.transmit.drain(..) {
+            sender.handle_datagram(ack, now);
+        }
+
+        // three later segments were acked, so the lost one is sent again right away and nothing else is
+        let retransmitted: Vec<Bytes> = sender.transmit.drain(..).collect();
+        assert_eq!(retransmitted.len(), 1);
+        assert!(matches!(
+            Segment::decode(retransmitted[0].clone()),
+            Some(Segment::Data { seq: 1, .. })
+        ));
+        assert!(sender.congestion_window < 8.0);
+
+        receiver.handle_datagram(retransmitted[0].clone(), now);
+        assert_eq!(receiver.delivered.len(), 1);
+        for ack in receiver.transmit.drain(..) {
+            sender.handle_datagram(ack, now);
+        }
+        assert!(sender.is_idle());
+        assert_eq!(sender.next_timeout(), None);
+    }
+
+    #[test]
+    fn receive_window_waits_for_slow_readers() {
+        let now = Instant::now();
+        let mut sender = Reliable::new();
+        let mut receiver = Reliable::new();
+        sender.congestion_window = WINDOW as f64;
+        for _ in 0..CHANNEL_SIZE + 2 {
+            sender.send(Bytes::from_static(b"packet"), now);
+        }
+        for datagram in sender.transmit.drain(..) {
+            receiver.handle_datagram(datagram, now);
+        }
+
+        // every segment is acked, but those the reader has no room for are not acked cumulatively
+        assert_eq!(receiver.delivered.len(), CHANNEL_SIZE);
+        assert_eq!(receiver.transmit.len(), CHANNEL_SIZE + 2);
+        let Some(Segment::Ack { next, selective }) =
+            Segment::decode(receiver.transmit.last().unwrap().clone())
+        else {
+            panic!("expected an ACK");
+        };
+        assert_eq!((next, selective), (CHANNEL_SIZE as u32, 0b1));
+
+        // once the reader took a packet the window moves again
+        receiver.transmit.clear();
+        receiver.delivered.pop_front();
+        assert!(receiver.advance());
+        assert_eq!(receiver.receive_next, CHANNEL_SIZE as u32 + 1);
+    }
+
+    #[tokio::test]
+    async fn udp_listeners_only_open_connections_for_verified_peers() {
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_address = server_socket.local_addr().unwrap();
+        let mut listener = UdpListener::start(server_socket, 1).unwrap();
+        let accept_timeout = Duration::from_millis(200);
+
+        // the first segment of a connection, or an OPEN with a forged cookie, is not enough
+        let forger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let data = Segment::Data {
+            seq: 0,
+            flags: FLAG_END,
+            payload: packet(1).encode(),
+        };
+        for segment in [data, Segment::Open([0; COOKIE_SIZE])] {
+            forger
+                .send_to(&segment.encode(), server_address)
+                .await
+                .unwrap();
+        }
+        assert!(tokio::time::timeout(accept_timeout, listener.accept())
+            .await
+            .is_err());
+
+        let mut first = UdpConnection::connect(server_address).await.unwrap();
+        first.write_packet(packet(1)).await.unwrap();
+        let mut server = listener.accept().await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), packet(1));
+
+        // the listener is full until the first connection is closed
+        let mut second = UdpConnection::connect(server_address).await.unwrap();
+        second.write_packet(packet(2)).await.unwrap();
+        assert!(tokio::time::timeout(accept_timeout, listener.accept())
+            .await
+            .is_err());
+
+        drop(first);
+        assert!(matches!(
+            server.read_packet().await,
+            Err(NetworkingError::ConnectionClosed)
+        ));
+        let mut server = listener.accept().await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), packet(2));
+    }
+
+    #[test]
+    fn timeouts_back_off_and_give_up() {
+        let mut now = Instant::now();
+        let mut sender = Reliable::new();
+        sender.send(Bytes::from_static(b"lost"), now);
+        sender.transmit.clear();
+
+        for _ in 0..MAX_RETRANSMITS {
+            let rto = sender.rto;
+            now = sender.next_timeout().unwrap();
+            sender.handle_timeout(now).unwrap();
+            assert_eq!(sender.transmit.drain(..).count(), 1);
+            assert_eq!(sender.rto, (rto * 2).min(MAX_RTO));
+            assert_eq!(sender.congestion_window, 1.0);
+        }
+
+        now = sender.next_timeout().unwrap();
+        assert!(matches!(
+            sender.handle_timeout(now),
+            Err(NetworkingError::Io { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn udp_connection_survives_loss_and_reordering() {
+        let server_socket = FaultySocket::bind(7, 5).await;
+        let server_address = server_socket.local_addr().unwrap();
+        let mut listener = UdpListener::with_socket(server_socket).unwrap();
+        let mut client =
+            UdpConnection::connect_with(FaultySocket::bind(6, 4).await, server_address);
+
+        for i in 0..40 {
+            client.write_packet(packet(i)).await.unwrap();
+        }
+
+        let mut server = listener.accept().await.unwrap();
+        for i in 0..40 {
+            assert_eq!(server.read_packet().await.unwrap(), packet(i));
+            server.write_packet(packet(i)).await.unwrap();
+        }
+        for i in 0..40 {
+            assert_eq!(client.read_packet().await.unwrap(), packet(i));
+        }
+
+        // dropping the client closes the connection once everything it wrote is acked
+        drop(client);
+        assert!(matches!(
+            server.read_packet().await,
+            Err(NetworkingError::ConnectionClosed)
+        ));
+    }
+}
@@ -1,14 +1,49 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc,
+    },
+};
 
+use sha2::{Digest, Sha256};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use super::{channel::ServerChannel, user::User};
+use super::{channel::ServerChannel, user::UserHandle};
+
+/// A username, registered by the first user signing in with it
+struct Account {
+    id: i32,
+    salt: Uuid,
+    password: [u8; 32],
+}
+
+impl Account {
+    fn hash(salt: &Uuid, password: &str) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(salt.as_bytes())
+            .chain_update(password.as_bytes())
+            .finalize()
+            .into()
+    }
+}
 
 /// Acts as a simple server database
 pub struct Database {
-    clients: RwLock<HashMap<Uuid, Arc<RwLock<User>>>>,
-    channels: RwLock<HashMap<Uuid, Arc<ServerChannel>>>,
+    clients: RwLock<HashMap<Uuid, UserHandle>>,
+    channels: RwLock<HashMap<i32, Arc<ServerChannel>>>,
+    accounts: RwLock<HashMap<String, Account>>,
+
+    /// The users signed in to an account, by account id
+    signed_in: RwLock<HashMap<i32, UserHandle>>,
+    next_account_id: AtomicI32,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Database {
@@ -16,21 +51,89 @@ impl Database {
         Self {
             channels: RwLock::new(HashMap::new()),
             clients: RwLock::new(HashMap::new()),
+            accounts: RwLock::new(HashMap::new()),
+            signed_in: RwLock::new(HashMap::new()),
+            next_account_id: AtomicI32::new(1),
         }
     }
 
-    pub async fn add_client(self: &Arc<Self>, id: &Uuid, user: Arc<RwLock<User>>) {
-        self.clients.write().await.insert(*id, user);
+    pub async fn add_client(self: &Arc<Self>, user: UserHandle) {
+        self.clients.write().await.insert(user.id(), user);
     }
 
+    /// Forgets a client that disconnected, signing it out and unsubscribing it from every channel
     pub async fn remove_client(self: &Arc<Self>, id: &Uuid) {
         self.clients.write().await.remove(id);
+        self.signed_in
+            .write()
+            .await
+            .retain(|_, user| user.id() != *id);
+
+        let channels: Vec<_> = self.channels.read().await.values().cloned().collect();
+        for channel in channels {
+            channel.remove_subscriber(id).await;
+        }
     }
 
-    pub async fn get_channel(self: &Arc<Self>, id: Uuid) -> Option<Arc<ServerChannel>> {
+    /// Handles to every connected client
+    pub async fn clients(self: &Arc<Self>) -> Vec<UserHandle> {
+        self.clients.read().await.values().cloned().collect()
+    }
+
+    /// A handle to send packets to a connected client
+    pub async fn get_client(self: &Arc<Self>, id: &Uuid) -> Option<UserHandle> {
+        self.clients.read().await.get(id).cloned()
+    }
+
+    /// Signs a client in to the account of `username`, registering it with `password` the first time. Returns the id
+    /// of the account, or `None` when the password does not match.
+    pub async fn sign_in(
+        self: &Arc<Self>,
+        username: &str,
+        password: &str,
+        user: UserHandle,
+    ) -> Option<i32> {
+        let id = {
+            let mut accounts = self.accounts.write().await;
+            match accounts.get(username) {
+                Some(account) if account.password == Account::hash(&account.salt, password) => {
+                    account.id
+                }
+                Some(_) => return None,
+                None => {
+                    let id = self.next_account_id.fetch_add(1, Ordering::Relaxed);
+                    let salt = Uuid::new_v4();
+                    let password = Account::hash(&salt, password);
+                    accounts.insert(username.to_owned(), Account { id, salt, password });
+                    id
+                }
+            }
+        };
+
+        // messages to the account go to the client that signed in last
+        self.signed_in.write().await.insert(id, user);
+        Some(id)
+    }
+
+    /// A handle to send packets to the client signed in to an account
+    pub async fn get_user(self: &Arc<Self>, id: i32) -> Option<UserHandle> {
+        self.signed_in.read().await.get(&id).cloned()
+    }
+
+    pub async fn get_channel(self: &Arc<Self>, id: i32) -> Option<Arc<ServerChannel>> {
         self.channels.read().await.get(&id).cloned()
     }
 
+    /// Creates an empty channel, or returns the existing one with that id
+    pub async fn create_channel(self: &Arc<Self>, id: i32) -> Arc<ServerChannel> {
+        self.channels
+            .write()
+            .await
+            .entry(id)
+            .or_insert_with(|| Arc::new(ServerChannel::new(id)))
+            .clone()
+    }
+
     pub async fn total_clients(self: &Arc<Self>) -> usize {
         self.clients.read().await.len()
     }
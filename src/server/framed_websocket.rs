@@ -1,84 +1,362 @@
-use std::{io, task::Poll};
+//! Runs the length delimited packet stream over WebSocket binary messages. Messages do not have to line up with
+//! packets: a message may carry several packets or only part of one.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
 
 use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncWrite, ReadBuf},
     net::TcpStream,
+    time::{interval_at, Instant, Interval, MissedTickBehavior},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error, Message,
+    },
+    WebSocketStream,
 };
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+/// How often the peer is pinged while the connection is quiet
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keepalive intervals without any frame from the peer before the connection is considered dead
+const KEEPALIVE_MISSED: u32 = 3;
 
 #[derive(Debug)]
-pub struct WebSocketAdapter {
-    web_socket: WebSocketStream<TcpStream>,
+pub struct WebSocketAdapter<S = TcpStream> {
+    web_socket: WebSocketStream<S>,
+
+    /// The part of the last binary message that was not read yet
+    pending: Bytes,
+
+    keepalive: Interval,
+    keepalive_timeout: Duration,
+
+    /// The keepalive ticked but the ping could not be queued yet
+    ping_due: bool,
+
+    /// When the peer last sent a frame
+    last_seen: Instant,
+
+    /// The frame the connection is being closed with, once it is
+    close: Option<CloseFrame>,
+    close_sent: bool,
 }
 
-impl WebSocketAdapter {
-    pub fn new(stream: WebSocketStream<TcpStream>) -> Self {
-        Self { web_socket: stream }
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketAdapter<S> {
+    pub fn new(stream: WebSocketStream<S>) -> Self {
+        Self::with_keepalive(stream, KEEPALIVE_INTERVAL)
+    }
+
+    /// Creates an adapter pinging the peer every `interval`, a peer that stays silent for a few intervals is
+    /// disconnected
+    pub fn with_keepalive(stream: WebSocketStream<S>, interval: Duration) -> Self {
+        let mut keepalive = interval_at(Instant::now() + interval, interval);
+        keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            web_socket: stream,
+            pending: Bytes::new(),
+            keepalive,
+            keepalive_timeout: interval * KEEPALIVE_MISSED,
+            ping_due: false,
+            last_seen: Instant::now(),
+            close: None,
+            close_sent: false,
+        }
+    }
+
+    /// The stream the WebSocket runs on
+    pub fn get_ref(&self) -> &S {
+        self.web_socket.get_ref()
+    }
+
+    /// Pings the peer when the keepalive ticks, failing when it has been silent for too long
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        while self.keepalive.poll_tick(cx).is_ready() {
+            if self.last_seen.elapsed() >= self.keepalive_timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "the WebSocket peer stopped answering pings",
+                ));
+            }
+            self.ping_due = true;
+        }
+
+        if self.ping_due {
+            if let Poll::Ready(ready) = self.web_socket.poll_ready_unpin(cx) {
+                ready.map_err(io::Error::other)?;
+                self.web_socket
+                    .start_send_unpin(Message::Ping(Bytes::new()))
+                    .map_err(io::Error::other)?;
+                self.ping_due = false;
+
+                // a ping that cannot be flushed now goes out with the next read or write
+                if let Poll::Ready(Err(err)) = self.web_socket.poll_flush_unpin(cx) {
+                    return Err(io::Error::other(err));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the close frame once and waits for it to be flushed
+    fn poll_send_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.close_sent {
+            ready!(self.web_socket.poll_ready_unpin(cx)).map_err(io::Error::other)?;
+            self.web_socket
+                .start_send_unpin(Message::Close(self.close.clone()))
+                .map_err(io::Error::other)?;
+            self.close_sent = true;
+        }
+
+        match ready!(self.web_socket.poll_flush_unpin(cx)) {
+            Ok(()) | Err(Error::ConnectionClosed | Error::AlreadyClosed) => Poll::Ready(Ok(())),
+            Err(err) => Poll::Ready(Err(io::Error::other(err))),
+        }
     }
 }
 
-impl AsyncRead for WebSocketAdapter {
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketAdapter<S> {
     fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        match self.web_socket.poll_next_unpin(cx) {
-            Poll::Ready(Some(Ok(msg))) => match msg {
-                Message::Binary(data) => {
-                    buf.put_slice(&data);
-                    Poll::Ready(Ok(()))
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            // the peer broke the protocol, it is told why before the error is reported
+            if let Some(close) = this.close.as_ref().filter(|c| c.code != CloseCode::Normal) {
+                let reason = close.reason.to_string();
+                ready!(this.poll_send_close(cx))?;
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, reason)));
+            }
+
+            if !this.pending.is_empty() {
+                let read = this.pending.len().min(buf.remaining());
+                buf.put_slice(&this.pending.split_to(read));
+                return Poll::Ready(Ok(()));
+            }
+
+            this.poll_keepalive(cx)?;
+
+            // a read without any bytes is the end of the stream
+            let message = match ready!(this.web_socket.poll_next_unpin(cx)) {
+                Some(Ok(message)) => message,
+                None | Some(Err(Error::ConnectionClosed | Error::AlreadyClosed)) => {
+                    return Poll::Ready(Ok(()))
                 }
-                // Message::Close(_) => Poll::Ready(Ok(())),
-                _ => Poll::Ready(Ok(())),
-            },
-            Poll::Ready(Some(Err(e))) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
-            Poll::Ready(None) => Poll::Ready(Ok(())),
-            Poll::Pending => Poll::Pending,
+                Some(Err(err)) => return Poll::Ready(Err(io::Error::other(err))),
+            };
+            this.last_seen = Instant::now();
+
+            match message {
+                Message::Binary(data) => this.pending = data,
+                Message::Text(_) => {
+                    this.close = Some(CloseFrame {
+                        code: CloseCode::Unsupported,
+                        reason: "packets are sent in binary frames".into(),
+                    });
+                }
+
+                // tungstenite answers the close frame itself
+                Message::Close(_) => return Poll::Ready(Ok(())),
+
+                // pings are answered by tungstenite, pongs only tell the peer is alive
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            }
         }
     }
 }
 
-impl AsyncWrite for WebSocketAdapter {
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketAdapter<S> {
     fn poll_write(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        match self.web_socket.poll_ready_unpin(cx) {
-            Poll::Ready(Ok(())) => match self
-                .web_socket
-                .start_send_unpin(Message::Binary(Bytes::copy_from_slice(buf)))
-            {
-                Ok(()) => Poll::Ready(Ok(buf.len())),
-                Err(e) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
-            },
-            Poll::Ready(Err(e)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
-            Poll::Pending => Poll::Pending,
-        }
+        ready!(self.web_socket.poll_ready_unpin(cx)).map_err(io::Error::other)?;
+        self.web_socket
+            .start_send_unpin(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(io::Error::other)?;
+        Poll::Ready(Ok(buf.len()))
     }
 
-    fn poll_flush(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> Poll<Result<(), io::Error>> {
-        match self.web_socket.poll_flush_unpin(cx) {
-            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
-            Poll::Pending => Poll::Pending,
-        }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.web_socket
+            .poll_flush_unpin(cx)
+            .map_err(io::Error::other)
     }
 
+    /// Closes the connection normally, unless it is already being closed for another reason
     fn poll_shutdown(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
-        match self.web_socket.poll_close_unpin(cx) {
-            Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
-            Poll::Pending => Poll::Pending,
+        if self.close.is_none() {
+            self.close = Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "".into(),
+            });
+        }
+        self.poll_send_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::{
+        networking::{packet_type::MESSAGE, raw_packet::RawPacket},
+        server::connection::packet_codec,
+    };
+
+    type Server = Framed<WebSocketAdapter, tokio_util::codec::LengthDelimitedCodec>;
+
+    async fn pair(keepalive: Duration) -> (WebSocketStream<TcpStream>, Server) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(
+            async {
+                let stream = TcpStream::connect(address).await.unwrap();
+                tokio_tungstenite::client_async(format!("ws://{address}"), stream)
+                    .await
+                    .unwrap()
+                    .0
+            },
+            async {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio_tungstenite::accept_async(stream).await.unwrap()
+            },
+        );
+
+        let server = WebSocketAdapter::with_keepalive(server, keepalive);
+        (client, Framed::new(server, packet_codec()))
+    }
+
+    /// A packet with its length prefix
+    fn frame(packet: &RawPacket) -> BytesMut {
+        let encoded = packet.encode();
+        let mut frame = BytesMut::new();
+        frame.put_u32(encoded.len() as u32);
+        frame.put_slice(&encoded);
+        frame
+    }
+
+    async fn read(server: &mut Server) -> RawPacket {
+        RawPacket::decode(server.next().await.unwrap().unwrap().freeze()).unwrap()
+    }
+
+    async fn close_code(client: &mut WebSocketStream<TcpStream>) -> CloseCode {
+        loop {
+            match client.next().await {
+                Some(Ok(Message::Close(Some(frame)))) => return frame.code,
+                Some(Ok(_)) => continue,
+                other => panic!("expected a close frame, got {:?}", other),
+            }
         }
     }
+
+    #[tokio::test]
+    async fn packets_are_split_across_and_merged_into_messages() {
+        let (mut client, mut server) = pair(KEEPALIVE_INTERVAL).await;
+        let packets: Vec<RawPacket> = [10, 20, 100_000]
+            .into_iter()
+            .map(|size| RawPacket::new(MESSAGE, Bytes::from(vec![7; size])))
+            .collect();
+
+        // the first message holds two packets and the start of a third, larger than any read buffer
+        let mut stream: BytesMut =
+            packets
+                .iter()
+                .map(frame)
+                .fold(BytesMut::new(), |mut all, frame| {
+                    all.put(frame);
+                    all
+                });
+        let first = stream.split_to(stream.len() / 2);
+        client.send(Message::binary(first.freeze())).await.unwrap();
+
+        // the rest arrives one byte at a time
+        for byte in stream {
+            client.send(Message::binary(vec![byte])).await.unwrap();
+        }
+
+        for packet in &packets {
+            assert_eq!(&read(&mut server).await, packet);
+        }
+    }
+
+    #[tokio::test]
+    async fn control_frames_are_not_mistaken_for_the_end_of_the_stream() {
+        let (mut client, mut server) = pair(KEEPALIVE_INTERVAL).await;
+        let packet = RawPacket::new(MESSAGE, Bytes::from_static(b"hello"));
+
+        client.send(Message::Ping("ping".into())).await.unwrap();
+        client.send(Message::Pong(Bytes::new())).await.unwrap();
+        client
+            .send(Message::binary(frame(&packet).freeze()))
+            .await
+            .unwrap();
+        assert_eq!(read(&mut server).await, packet);
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Pong("ping".into())
+        );
+
+        client.close(None).await.unwrap();
+        assert!(server.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn text_frames_close_the_connection() {
+        let (mut client, mut server) = pair(KEEPALIVE_INTERVAL).await;
+
+        client.send(Message::text("hello")).await.unwrap();
+        let err = server.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(close_code(&mut client).await, CloseCode::Unsupported);
+    }
+
+    #[tokio::test]
+    async fn closing_sends_a_normal_close_frame() {
+        let (mut client, mut server) = pair(KEEPALIVE_INTERVAL).await;
+
+        let (code, closed) = tokio::join!(
+            close_code(&mut client),
+            SinkExt::<Bytes>::close(&mut server)
+        );
+        closed.unwrap();
+        assert_eq!(code, CloseCode::Normal);
+    }
+
+    #[tokio::test]
+    async fn keepalive_pings_and_drops_silent_peers() {
+        let (mut client, mut server) = pair(Duration::from_millis(20)).await;
+
+        // the server only pings while it is waiting for packets
+        let (message, read) = tokio::select! {
+            message = client.next() => (message, None),
+            read = server.next() => (None, Some(read)),
+        };
+        assert!(read.is_none(), "the server stopped reading: {:?}", read);
+        assert!(matches!(message, Some(Ok(Message::Ping(_)))));
+
+        // the client is not polled anymore, so it never answers
+        let err = server.next().await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
 }
@@ -0,0 +1,388 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::{
+    networking::{
+        error::NetworkingError,
+        handshake::Capabilities,
+        packet::Packet,
+        packet_type::{
+            DestinationType, ErrorCode, FileAbortPacket, FileAckPacket, FileBeginPacket,
+            FileChunkPacket, FileCommitPacket, HelloPacket, JoinPacket, LoginPacket, MessagePacket,
+            MessagePayload, PingPacket, PongPacket, ResumePacket, SessionPacket, SignedInPacket,
+            FILE_COMMIT, JOIN, MESSAGE, SIGN_IN,
+        },
+    },
+    types::types,
+};
+
+use super::{
+    registry::PacketHandler,
+    session::SessionStore,
+    transfer::{TransferError, TransferStore},
+    user::User,
+};
+
+/// HELLO is consumed by the handshake, so any later one is ignored
+pub struct HelloHandler;
+
+#[async_trait]
+impl PacketHandler<HelloPacket> for HelloHandler {
+    async fn handle(&self, user: &mut User, _packet: HelloPacket) -> types::Result<()> {
+        println!("Ignoring HELLO from {} after the handshake", user.socket());
+        Ok(())
+    }
+}
+
+/// Answers the PINGs of clients checking that the server is still there
+pub struct PingHandler;
+
+#[async_trait]
+impl PacketHandler<PingPacket> for PingHandler {
+    async fn handle(&self, user: &mut User, packet: PingPacket) -> types::Result<()> {
+        user.respond(Packet::Pong(PongPacket {
+            nonce: packet.nonce,
+        }))
+        .await?;
+        Ok(())
+    }
+}
+
+/// PONG only has to reach the user to count as a heartbeat, there is nothing else to do with it
+pub struct PongHandler;
+
+#[async_trait]
+impl PacketHandler<PongPacket> for PongHandler {
+    async fn handle(&self, _user: &mut User, _packet: PongPacket) -> types::Result<()> {
+        Ok(())
+    }
+}
+
+/// Opens sessions, and hands the connection of a client resuming one to the user owning it
+pub struct ResumeHandler {
+    store: Arc<SessionStore>,
+}
+
+impl ResumeHandler {
+    pub fn new(store: Arc<SessionStore>) -> Self {
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl PacketHandler<ResumePacket> for ResumeHandler {
+    async fn handle(&self, user: &mut User, packet: ResumePacket) -> types::Result<()> {
+        if user.session().is_some() {
+            println!(
+                "Ignoring RESUME from {}, it is already in a session",
+                user.socket()
+            );
+            return Ok(());
+        }
+
+        if !packet.token.is_empty() {
+            match self.store.find(&packet.token, packet.last_sequence) {
+                Ok(resumable) => {
+                    println!(
+                        "Client {} resumes the session of {}",
+                        user.socket(),
+                        resumable.owner
+                    );
+                    user.respond(Packet::Session(SessionPacket {
+                        token: packet.token,
+                        resumed: true,
+                        next_sequence: resumable.next_sequence,
+                        grace_period: self.store.settings().grace_period_secs(),
+                    }))
+                    .await?;
+                    user.hand_over(resumable.resumes, packet.last_sequence)
+                        .await?;
+                    return Ok(());
+                }
+
+                // the client starts over in a new session
+                Err(err) => println!(
+                    "Client {} could not resume its session: {}",
+                    user.socket(),
+                    err
+                ),
+            }
+        }
+
+        let session = self.store.open(user.id(), user.outbox());
+        user.start_session(session)?;
+        Ok(())
+    }
+}
+
+/// Signs users in to their account, registering the usernames signing in for the first time
+pub struct LoginHandler;
+
+#[async_trait]
+impl PacketHandler<LoginPacket> for LoginHandler {
+    async fn handle(&self, user: &mut User, packet: LoginPacket) -> types::Result<()> {
+        if user.account().is_some() {
+            println!(
+                "Ignoring SIGN_IN from {}, it is already signed in",
+                user.id()
+            );
+            return Ok(());
+        }
+
+        let database = user.database().clone();
+        match database
+            .sign_in(&packet.username, &packet.password, user.handle())
+            .await
+        {
+            Some(user_id) => {
+                println!("Client {} signed in as {}", user.id(), packet.username);
+                user.set_account(user_id);
+                user.respond(Packet::SignedIn(SignedInPacket { user_id }))
+                    .await?;
+            }
+            None => {
+                user.send_error(
+                    ErrorCode::InvalidCredentials,
+                    SIGN_IN,
+                    format!("wrong password for {}", packet.username),
+                )
+                .await
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Subscribes signed in users to channels
+pub struct JoinHandler;
+
+#[async_trait]
+impl PacketHandler<JoinPacket> for JoinHandler {
+    async fn handle(&self, user: &mut User, packet: JoinPacket) -> types::Result<()> {
+        if user.account().is_none() {
+            user.send_error(
+                ErrorCode::NotSignedIn,
+                JOIN,
+                "sign in to join channels".into(),
+            )
+            .await;
+            return Ok(());
+        }
+
+        let channel = user.database().create_channel(packet.channel).await;
+        channel.add_subscriber(user.handle()).await;
+        Ok(())
+    }
+}
+
+/// Delivers the messages of signed in users to their destination
+pub struct MessageHandler;
+
+#[async_trait]
+impl PacketHandler<MessagePacket> for MessageHandler {
+    async fn handle(&self, user: &mut User, packet: MessagePacket) -> types::Result<()> {
+        deliver(user, MESSAGE, packet).await
+    }
+}
+
+/// Sends a message of the user to the signed in user or to the subscribers of the channel it is addressed to. The
+/// client is told with an ERROR about `packet_type` when it can not be delivered.
+async fn deliver(user: &mut User, packet_type: u8, message: MessagePacket) -> types::Result<()> {
+    if user.account().is_none() {
+        user.send_error(
+            ErrorCode::NotSignedIn,
+            packet_type,
+            "sign in to send messages".into(),
+        )
+        .await;
+        return Ok(());
+    }
+
+    let database = user.database().clone();
+    let (destination_type, destination) = (message.destination_type, message.destination);
+    let delivered = match destination_type {
+        DestinationType::User => match database.get_user(destination).await {
+            Some(recipient) => match recipient.send_packet(Packet::Message(message)).await {
+                Ok(()) => true,
+                Err(NetworkingError::ConnectionClosed) => false,
+                Err(err) => return Err(err.into()),
+            },
+            None => false,
+        },
+        DestinationType::Channel => match database.get_channel(destination).await {
+            Some(channel) => {
+                channel
+                    .broadcast_from(user.id(), message.message_payload)
+                    .await?;
+                true
+            }
+            None => false,
+        },
+        DestinationType::Unknown => false,
+    };
+
+    if !delivered {
+        user.send_error(
+            ErrorCode::UnknownDestination,
+            packet_type,
+            format!(
+                "no {:?} {} to deliver the message to",
+                destination_type, destination
+            ),
+        )
+        .await;
+    }
+    Ok(())
+}
+
+/// Handles the FILE_* packets of chunked uploads. Registered once per packet type, sharing the same store.
+#[derive(Clone)]
+pub struct FileTransferHandler {
+    store: Arc<TransferStore>,
+}
+
+impl FileTransferHandler {
+    pub fn new(store: Arc<TransferStore>) -> Self {
+        Self { store }
+    }
+
+    /// Answers a failed upload operation with FILE_ABORT. Storage errors are the server fault, so they are
+    /// returned to be reported as an internal error instead.
+    async fn reject(
+        &self,
+        user: &mut User,
+        transfer_id: Uuid,
+        err: TransferError,
+    ) -> types::Result<()> {
+        if let TransferError::Storage { .. } = err {
+            return Err(err.into());
+        }
+
+        println!(
+            "Rejecting file transfer {} of {}: {}",
+            transfer_id,
+            user.id(),
+            err
+        );
+        user.respond(Packet::FileAbort(FileAbortPacket {
+            transfer_id,
+            reason: err.to_string(),
+        }))
+        .await?;
+        Ok(())
+    }
+
+    /// Answers with FILE_ABORT unless file chunking was negotiated, returning whether the packet can be handled
+    async fn negotiated(&self, user: &mut User, transfer_id: Uuid) -> types::Result<bool> {
+        if user.capabilities().contains(Capabilities::FILE_CHUNKING) {
+            return Ok(true);
+        }
+
+        user.respond(Packet::FileAbort(FileAbortPacket {
+            transfer_id,
+            reason: "file chunking was not negotiated".into(),
+        }))
+        .await?;
+        Ok(false)
+    }
+
+    async fn acknowledge(
+        &self,
+        user: &mut User,
+        transfer_id: Uuid,
+        result: Result<u64, TransferError>,
+    ) -> types::Result<()> {
+        match result {
+            Ok(offset) => {
+                user.respond(Packet::FileAck(FileAckPacket {
+                    transfer_id,
+                    offset,
+                }))
+                .await?;
+                Ok(())
+            }
+            Err(err) => self.reject(user, transfer_id, err).await,
+        }
+    }
+}
+
+#[async_trait]
+impl PacketHandler<FileBeginPacket> for FileTransferHandler {
+    async fn handle(&self, user: &mut User, packet: FileBeginPacket) -> types::Result<()> {
+        let transfer_id = packet.transfer_id;
+        if !self.negotiated(user, transfer_id).await? {
+            return Ok(());
+        }
+
+        let result = self.store.begin(user.id(), packet).await;
+        self.acknowledge(user, transfer_id, result).await
+    }
+
+    // the handler is registered for every FILE_* packet, only this registration flushes the shared store
+    async fn flush(&self) -> types::Result<()> {
+        self.store.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PacketHandler<FileChunkPacket> for FileTransferHandler {
+    async fn handle(&self, user: &mut User, packet: FileChunkPacket) -> types::Result<()> {
+        if !self.negotiated(user, packet.transfer_id).await? {
+            return Ok(());
+        }
+
+        let result = self
+            .store
+            .write_chunk(user.id(), packet.transfer_id, packet.offset, &packet.data)
+            .await;
+        self.acknowledge(user, packet.transfer_id, result).await
+    }
+}
+
+#[async_trait]
+impl PacketHandler<FileCommitPacket> for FileTransferHandler {
+    async fn handle(&self, user: &mut User, packet: FileCommitPacket) -> types::Result<()> {
+        if !self.negotiated(user, packet.transfer_id).await? {
+            return Ok(());
+        }
+
+        let file = match self.store.commit(user.id(), packet.transfer_id).await {
+            Ok(file) => file,
+            Err(err) => return self.reject(user, packet.transfer_id, err).await,
+        };
+
+        println!(
+            "Stored file {} ({} bytes) from {} at {}",
+            file.name,
+            file.size,
+            user.id(),
+            file.path.display()
+        );
+
+        let message = || MessagePacket {
+            destination: file.destination,
+            destination_type: file.destination_type,
+            message_payload: MessagePayload::FileReference(file.id),
+        };
+        user.respond(Packet::Message(message())).await?;
+        deliver(user, FILE_COMMIT, message()).await
+    }
+}
+
+#[async_trait]
+impl PacketHandler<FileAbortPacket> for FileTransferHandler {
+    async fn handle(&self, user: &mut User, packet: FileAbortPacket) -> types::Result<()> {
+        if let Err(err) = self.store.abort(user.id(), packet.transfer_id).await {
+            println!(
+                "Ignoring FILE_ABORT of {} from {}: {}",
+                packet.transfer_id,
+                user.id(),
+                err
+            );
+        }
+        Ok(())
+    }
+}
@@ -2,5 +2,18 @@ pub mod channel;
 pub mod connection;
 pub mod database;
 pub mod framed_websocket;
+pub mod handlers;
+pub mod outgoing;
+pub mod proxy;
+pub mod quic;
+pub mod registry;
+#[allow(clippy::module_inception)]
 pub mod server;
+pub mod session;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod tls;
+pub mod transfer;
+#[cfg(unix)]
+pub mod unix;
 pub mod user;
@@ -0,0 +1,375 @@
+//! The queue of packets waiting to be written to a user. Senders never wait for the client, so the queue is bounded
+//! in packets and bytes, and what happens once a slow client fills it is decided by an [`OverflowPolicy`].
+
+use std::{collections::VecDeque, pin::pin, sync::Mutex};
+
+use tokio::sync::Notify;
+
+use crate::networking::{
+    compression::CompressionSettings,
+    error::NetworkingError,
+    packet::Packet,
+    packet_type::{DisconnectPacket, DisconnectReason, MESSAGE, PING, PONG},
+    raw_packet::RawPacket,
+};
+
+/// What to do with a packet that does not fit in the queue of a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drops the oldest queued packets until the new one fits
+    DropOldest,
+
+    /// Drops the oldest packets the client can do without, unsolicited messages and heartbeats, until the new one
+    /// fits. A non-critical packet that still does not fit is dropped itself, a critical one disconnects the client.
+    DropNonCritical,
+
+    /// Disconnects the client, telling it that it is too slow
+    Disconnect,
+}
+
+/// How many packets, and how many bytes of payload, may wait to be written to a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutgoingLimits {
+    pub max_packets: usize,
+    pub max_bytes: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for OutgoingLimits {
+    fn default() -> Self {
+        Self {
+            max_packets: 1024,
+            max_bytes: 16 * 1024 * 1024,
+            policy: OverflowPolicy::Disconnect,
+        }
+    }
+}
+
+/// The depth of the queue of a user, and how it has been since it connected
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutgoingStats {
+    pub packets: usize,
+    pub bytes: usize,
+    pub peak_packets: usize,
+    pub peak_bytes: usize,
+    pub dropped_packets: u64,
+}
+
+/// What the writer task of a user does, in the order it was asked
+#[derive(Debug)]
+pub enum Outgoing {
+    Packet(RawPacket),
+    Compression(CompressionSettings),
+
+    /// Writes the SESSION packet opening a session, then numbers the packets written after it, keeping the last
+    /// `max_replay` of them for the client to resume
+    StartSequence {
+        answer: RawPacket,
+        max_replay: usize,
+    },
+}
+
+impl Outgoing {
+    fn bytes(&self) -> usize {
+        match self {
+            Outgoing::Packet(packet) => packet.payload.len(),
+            Outgoing::Compression(_) | Outgoing::StartSequence { .. } => 0,
+        }
+    }
+
+    /// Whether the item may be dropped by `policy` to make room
+    fn is_droppable(&self, policy: OverflowPolicy) -> bool {
+        match (self, policy) {
+            (Outgoing::Packet(_), OverflowPolicy::DropOldest) => true,
+            (Outgoing::Packet(packet), OverflowPolicy::DropNonCritical) => !is_critical(packet),
+            _ => false,
+        }
+    }
+}
+
+/// Packets that can not be lost without breaking the protocol: answers to requests and every control packet
+fn is_critical(packet: &RawPacket) -> bool {
+    packet.request_id.is_some() || !matches!(packet.packet_type, MESSAGE | PING | PONG)
+}
+
+#[derive(Debug, Default)]
+struct State {
+    items: VecDeque<Outgoing>,
+    stats: OutgoingStats,
+
+    /// No more packets are accepted, the writer stops once the queue is empty
+    closed: bool,
+}
+
+impl State {
+    fn overflows(&self, limits: &OutgoingLimits, bytes: usize) -> bool {
+        // a packet is always accepted by an empty queue, however large it is
+        self.stats.packets > 0
+            && (self.stats.packets + 1 > limits.max_packets
+                || self.stats.bytes + bytes > limits.max_bytes)
+    }
+
+    fn push(&mut self, item: Outgoing) {
+        if let Outgoing::Packet(_) = item {
+            self.stats.packets += 1;
+            self.stats.bytes += item.bytes();
+            self.stats.peak_packets = self.stats.peak_packets.max(self.stats.packets);
+            self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.bytes);
+        }
+        self.items.push_back(item);
+    }
+
+    fn pop(&mut self) -> Option<Outgoing> {
+        let item = self.items.pop_front()?;
+        if let Outgoing::Packet(_) = item {
+            self.stats.packets -= 1;
+            self.stats.bytes -= item.bytes();
+        }
+        Some(item)
+    }
+
+    /// Drops the oldest item `policy` allows to drop, returning false when there is none
+    fn drop_oldest(&mut self, policy: OverflowPolicy) -> bool {
+        let Some(index) = self.items.iter().position(|item| item.is_droppable(policy)) else {
+            return false;
+        };
+
+        let item = self.items.remove(index).expect("the index was just found");
+        self.stats.packets -= 1;
+        self.stats.bytes -= item.bytes();
+        self.stats.dropped_packets += 1;
+        true
+    }
+
+    /// Replaces everything queued with a last packet, and accepts nothing after it
+    fn close_with(&mut self, packet: Option<RawPacket>) {
+        self.stats.dropped_packets += self.stats.packets as u64;
+        self.stats.packets = 0;
+        self.stats.bytes = 0;
+        self.items.clear();
+        if let Some(packet) = packet {
+            self.push(Outgoing::Packet(packet));
+        }
+        self.closed = true;
+    }
+}
+
+/// The queue between everyone sending to a user and the task writing to its connection
+#[derive(Debug)]
+pub struct OutgoingQueue {
+    limits: OutgoingLimits,
+    state: Mutex<State>,
+
+    /// Wakes the writer when something is queued or the queue is closed
+    queued: Notify,
+
+    /// Wakes the tasks waiting for the queue to be closed
+    closed: Notify,
+}
+
+impl OutgoingQueue {
+    pub fn new(limits: OutgoingLimits) -> Self {
+        Self {
+            limits,
+            state: Mutex::new(State::default()),
+            queued: Notify::new(),
+            closed: Notify::new(),
+        }
+    }
+
+    /// Queues a packet, applying the overflow policy when the queue is full.
+    /// Fails with `ConnectionClosed` when the queue is closed, or when the client is disconnected for being too slow.
+    pub fn push_packet(&self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.push(Outgoing::Packet(packet))
+    }
+
+    pub fn push(&self, item: Outgoing) -> Result<(), NetworkingError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(NetworkingError::ConnectionClosed);
+        }
+
+        let bytes = item.bytes();
+        let policy = self.limits.policy;
+        while state.overflows(&self.limits, bytes) && state.drop_oldest(policy) {}
+
+        if state.overflows(&self.limits, bytes) {
+            if item.is_droppable(policy) {
+                state.stats.dropped_packets += 1;
+                return Ok(());
+            }
+
+            state.close_with(Some(RawPacket::from(Packet::Disconnect(
+                DisconnectPacket {
+                    reason: DisconnectReason::TooSlow,
+                    message: "too many packets are waiting to be sent".into(),
+                },
+            ))));
+            drop(state);
+            self.queued.notify_one();
+            self.closed.notify_waiters();
+            return Err(NetworkingError::ConnectionClosed);
+        }
+
+        state.push(item);
+        drop(state);
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    /// Takes the next item to write, waiting for one. Returns `None` once the queue is closed and empty.
+    pub async fn pop(&self) -> Option<Outgoing> {
+        loop {
+            // a single task pops, so the permit stored by `notify_one` is never taken by someone else
+            let queued = self.queued.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(item) = state.pop() {
+                    return Some(item);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            queued.await;
+        }
+    }
+
+    /// Stops accepting packets, what is already queued is still written
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.queued.notify_one();
+        self.closed.notify_waiters();
+    }
+
+    /// Stops accepting packets and drops what is queued, the connection can not be written to anymore
+    pub fn abort(&self) {
+        self.state.lock().unwrap().close_with(None);
+        self.queued.notify_one();
+        self.closed.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Waits until the queue is closed
+    pub async fn closed(&self) {
+        let mut closed = pin!(self.closed.notified());
+        closed.as_mut().enable();
+        if self.is_closed() {
+            return;
+        }
+        closed.await;
+    }
+
+    pub fn stats(&self) -> OutgoingStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::networking::packet_type::{DISCONNECT, ERROR};
+
+    fn packet(packet_type: u8, len: usize) -> RawPacket {
+        RawPacket::new(packet_type, Bytes::from(vec![0; len]))
+    }
+
+    fn limited_queue(policy: OverflowPolicy) -> OutgoingQueue {
+        OutgoingQueue::new(OutgoingLimits {
+            max_packets: 3,
+            max_bytes: 100,
+            policy,
+        })
+    }
+
+    /// Closes the queue and returns what the writer would still write
+    async fn drain(queue: &OutgoingQueue) -> Vec<RawPacket> {
+        queue.close();
+        let mut packets = Vec::new();
+        while let Some(item) = queue.pop().await {
+            if let Outgoing::Packet(packet) = item {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_latest_packets() {
+        let queue = limited_queue(OverflowPolicy::DropOldest);
+        for len in [10, 20, 30, 40] {
+            queue.push_packet(packet(ERROR, len)).unwrap();
+        }
+
+        // the byte limit applies too
+        queue.push_packet(packet(ERROR, 60)).unwrap();
+
+        let stats = queue.stats();
+        assert_eq!((stats.packets, stats.bytes), (2, 100));
+        assert_eq!((stats.peak_packets, stats.dropped_packets), (3, 3));
+        let lens: Vec<_> = drain(&queue)
+            .await
+            .iter()
+            .map(|p| p.payload.len())
+            .collect();
+        assert_eq!(lens, [40, 60]);
+    }
+
+    #[tokio::test]
+    async fn non_critical_packets_are_dropped_first() {
+        let queue = limited_queue(OverflowPolicy::DropNonCritical);
+        queue.push_packet(packet(ERROR, 10)).unwrap();
+        queue.push_packet(packet(MESSAGE, 10)).unwrap();
+        queue
+            .push_packet(packet(MESSAGE, 10).with_request_id(Some(1)))
+            .unwrap();
+
+        // the unsolicited message makes room for the error
+        queue.push_packet(packet(ERROR, 20)).unwrap();
+        let lens: Vec<_> = drain(&queue)
+            .await
+            .iter()
+            .map(|p| p.payload.len())
+            .collect();
+        assert_eq!(lens, [10, 10, 20]);
+
+        // with only critical packets queued, another message is dropped but an error disconnects
+        let queue = limited_queue(OverflowPolicy::DropNonCritical);
+        for _ in 0..3 {
+            queue.push_packet(packet(ERROR, 10)).unwrap();
+        }
+        queue.push_packet(packet(PING, 0)).unwrap();
+        assert_eq!(queue.stats().dropped_packets, 1);
+        assert!(matches!(
+            queue.push_packet(packet(ERROR, 10)),
+            Err(NetworkingError::ConnectionClosed)
+        ));
+        assert!(queue.is_closed());
+    }
+
+    #[tokio::test]
+    async fn slow_clients_are_disconnected() {
+        let queue = limited_queue(OverflowPolicy::Disconnect);
+        queue.push_packet(packet(MESSAGE, 90)).unwrap();
+        assert!(matches!(
+            queue.push_packet(packet(MESSAGE, 20)),
+            Err(NetworkingError::ConnectionClosed)
+        ));
+        queue.closed().await;
+
+        // the queued packets are replaced by the reason of the disconnection
+        let packets = drain(&queue).await;
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].packet_type, DISCONNECT);
+        let Ok(Packet::Disconnect(disconnect)) = Packet::from(packets[0].clone()) else {
+            panic!("expected a DISCONNECT");
+        };
+        assert_eq!(disconnect.reason, DisconnectReason::TooSlow);
+        assert_eq!(queue.stats().dropped_packets, 1);
+    }
+}
@@ -0,0 +1,364 @@
+//! The PROXY protocol, versions 1 and 2, sent by load balancers in front of the TCP listener so the server knows the
+//! address of the client rather than the one of the load balancer. Only connections from trusted proxies are expected
+//! to start with a header, anyone else could claim any address.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a trusted proxy has to send the header once connected
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest version 1 header, line ending included
+const MAX_V1_HEADER: usize = 107;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The 4 bits of version 2 headers after the signature, followed by the command
+const V2_VERSION: u8 = 0x2;
+const V2_LOCAL: u8 = 0x0;
+const V2_PROXY: u8 = 0x1;
+
+/// The address families of version 2 headers, followed by the transport
+const V2_INET: u8 = 0x1;
+const V2_INET6: u8 = 0x2;
+
+#[derive(Debug, Snafu)]
+pub enum ProxyError {
+    #[snafu(display("invalid CIDR block {block:?}"))]
+    InvalidCidr { block: String },
+
+    #[snafu(display("the connection does not start with a PROXY protocol header"))]
+    MissingHeader,
+
+    #[snafu(display("invalid PROXY protocol header: {reason}"))]
+    InvalidHeader { reason: &'static str },
+
+    #[snafu(display("could not read the PROXY protocol header: {source}"))]
+    Read { source: io::Error },
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(err: ProxyError) -> Self {
+        match err {
+            ProxyError::Read { source } => source,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// A block of IPv4 or IPv6 addresses, like `10.0.0.0/8`. A single address is a block of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // dual stack listeners report IPv4 clients as IPv4-mapped IPv6 addresses
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ProxyError;
+
+    fn from_str(block: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidrSnafu { block };
+        let (network, prefix) = match block.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (block, None),
+        };
+
+        let network = IpAddr::from_str(network).ok().with_context(invalid)?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().with_context(invalid)?,
+            None => max_prefix,
+        };
+        ensure!(prefix <= max_prefix, invalid());
+
+        // clients are matched by their canonical address, so IPv4-mapped blocks become IPv4 blocks
+        if let (IpAddr::V6(_), IpAddr::V4(mapped)) = (network, network.to_canonical()) {
+            ensure!(prefix >= 96, invalid());
+            return Ok(Cidr {
+                network: IpAddr::V4(mapped),
+                prefix: prefix - 96,
+            });
+        }
+
+        Ok(Cidr { network, prefix })
+    }
+}
+
+/// The load balancers whose connections start with a PROXY protocol header
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies {
+    blocks: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    pub fn new(blocks: Vec<Cidr>) -> Self {
+        Self { blocks }
+    }
+
+    /// Parses a comma separated list of CIDR blocks, like `10.0.0.0/8, fd00::/8`
+    pub fn parse(list: &str) -> Result<Self, ProxyError> {
+        let blocks = list
+            .split(',')
+            .map(str::trim)
+            .filter(|block| !block.is_empty())
+            .map(Cidr::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(Self::new(blocks))
+    }
+
+    pub fn is_trusted(&self, address: IpAddr) -> bool {
+        self.blocks.iter().any(|block| block.contains(address))
+    }
+}
+
+/// Reads the PROXY protocol header a connection starts with, leaving the stream at the first byte of the client.
+/// Returns the address of the client, or `None` when the proxy connected on its own behalf, like for health checks.
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<SocketAddr>, ProxyError> {
+    let mut start = [0; V1_PREFIX.len()];
+    stream.read_exact(&mut start).await.context(ReadSnafu)?;
+
+    if start == V1_PREFIX {
+        read_v1(stream).await
+    } else if V2_SIGNATURE.starts_with(&start) {
+        read_v2(stream).await
+    } else {
+        MissingHeaderSnafu.fail()
+    }
+}
+
+/// Reads the rest of a `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n` line
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError> {
+    // the line is read a byte at a time, so nothing after it is consumed
+    let mut line = Vec::with_capacity(MAX_V1_HEADER - V1_PREFIX.len());
+    while !line.ends_with(b"\r\n") {
+        ensure!(
+            line.len() < MAX_V1_HEADER - V1_PREFIX.len(),
+            InvalidHeaderSnafu {
+                reason: "the line is too long"
+            }
+        );
+        line.push(stream.read_u8().await.context(ReadSnafu)?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .ok()
+        .context(InvalidHeaderSnafu {
+            reason: "the line is not ASCII",
+        })?;
+    let fields: Vec<_> = line.split(' ').collect();
+    let (source, port) = match fields[..] {
+        ["UNKNOWN", ..] => return Ok(None),
+        ["TCP4", source, _, port, _] => (source.parse().map(IpAddr::V4), port),
+        ["TCP6", source, _, port, _] => (source.parse().map(IpAddr::V6), port),
+        _ => {
+            return InvalidHeaderSnafu {
+                reason: "unknown protocol or missing fields",
+            }
+            .fail()
+        }
+    };
+
+    let source = source.ok().context(InvalidHeaderSnafu {
+        reason: "invalid source address",
+    })?;
+    let port = port.parse().ok().context(InvalidHeaderSnafu {
+        reason: "invalid source port",
+    })?;
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+/// Reads the rest of a binary header, after the first bytes of its signature
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>, ProxyError> {
+    let mut head = [0; 16 - V1_PREFIX.len()];
+    stream.read_exact(&mut head).await.context(ReadSnafu)?;
+    ensure!(
+        head[..V2_SIGNATURE.len() - V1_PREFIX.len()] == V2_SIGNATURE[V1_PREFIX.len()..],
+        MissingHeaderSnafu
+    );
+
+    let [.., version_command, family_transport, len_high, len_low] = head;
+    ensure!(
+        version_command >> 4 == V2_VERSION,
+        InvalidHeaderSnafu {
+            reason: "unsupported version"
+        }
+    );
+
+    // the addresses are followed by optional TLVs, which are read with them so the stream is left after the header
+    let mut addresses = vec![0; u16::from_be_bytes([len_high, len_low]) as usize];
+    stream.read_exact(&mut addresses).await.context(ReadSnafu)?;
+
+    match version_command & 0x0f {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => {
+            return InvalidHeaderSnafu {
+                reason: "unknown command",
+            }
+            .fail()
+        }
+    }
+
+    let too_short = InvalidHeaderSnafu {
+        reason: "the addresses are truncated",
+    };
+    match family_transport >> 4 {
+        V2_INET => {
+            ensure!(addresses.len() >= 12, too_short);
+            let source: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(source).into(), port)))
+        }
+        V2_INET6 => {
+            ensure!(addresses.len() >= 36, too_short);
+            let source: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(source).into(), port)))
+        }
+
+        // unspecified or Unix addresses say nothing useful about the client
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    async fn read(header: &[u8]) -> (Result<Option<SocketAddr>, ProxyError>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(header).await.unwrap();
+        client.write_all(b"rest").await.unwrap();
+        drop(client);
+
+        let result = read_header(&mut server).await;
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        (result, rest)
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(V2_VERSION << 4 | command);
+        header.push(family << 4 | 0x1);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[test]
+    fn cidr_blocks_contain_their_addresses() {
+        let block: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(block.contains("10.1.200.3".parse().unwrap()));
+        assert!(block.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!block.contains("10.2.0.1".parse().unwrap()));
+        assert!(!block.contains("::1".parse().unwrap()));
+
+        let proxies = TrustedProxies::parse("192.168.1.7, fd00::/8").unwrap();
+        assert!(proxies.is_trusted("192.168.1.7".parse().unwrap()));
+        assert!(!proxies.is_trusted("192.168.1.8".parse().unwrap()));
+        assert!(proxies.is_trusted("fd12::1".parse().unwrap()));
+        assert!(TrustedProxies::parse("0.0.0.0/0")
+            .unwrap()
+            .is_trusted("8.8.8.8".parse().unwrap()));
+
+        let mapped: Cidr = "::ffff:10.0.0.0/104".parse().unwrap();
+        assert_eq!(mapped, "10.0.0.0/8".parse().unwrap());
+        assert!(mapped.contains("::ffff:10.3.2.1".parse().unwrap()));
+        assert!(mapped.contains("10.3.2.1".parse().unwrap()));
+        assert!(!mapped.contains("11.0.0.1".parse().unwrap()));
+
+        for block in [
+            "10.0.0.0/33",
+            "10.0.0/8",
+            "fd00::/129",
+            "10.0.0.0/",
+            "::ffff:0:0/80",
+        ] {
+            assert!(
+                matches!(block.parse::<Cidr>(), Err(ProxyError::InvalidCidr { .. })),
+                "{block} was accepted"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn version_1_headers_carry_the_client_address() {
+        let (address, rest) = read(b"PROXY TCP4 203.0.113.9 10.0.0.1 51000 7878\r\n").await;
+        assert_eq!(address.unwrap(), Some("203.0.113.9:51000".parse().unwrap()));
+        assert_eq!(rest, b"rest");
+
+        let (address, _) = read(b"PROXY TCP6 2001:db8::1 ::1 51000 7878\r\n").await;
+        assert_eq!(
+            address.unwrap(),
+            Some("[2001:db8::1]:51000".parse().unwrap())
+        );
+
+        let (address, rest) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(address.unwrap(), None);
+        assert_eq!(rest, b"rest");
+
+        let (address, _) = read(b"PROXY TCP4 203.0.113.9 10.0.0.1 51000\r\n").await;
+        assert!(matches!(address, Err(ProxyError::InvalidHeader { .. })));
+        let (address, _) = read(&[b'P'; MAX_V1_HEADER]).await;
+        assert!(matches!(address, Err(ProxyError::MissingHeader)));
+        let (address, _) = read(b"GET / HTTP/1.1\r\n").await;
+        assert!(matches!(address, Err(ProxyError::MissingHeader)));
+    }
+
+    #[tokio::test]
+    async fn version_2_headers_carry_the_client_address() {
+        // a TLV follows the addresses
+        let mut addresses = vec![203, 0, 113, 9, 10, 0, 0, 1, 0xc7, 0x38, 0x1e, 0xc6];
+        addresses.extend([0x04, 0x00, 0x01, 0xff]);
+        let (address, rest) = read(&v2(V2_PROXY, V2_INET, &addresses)).await;
+        assert_eq!(address.unwrap(), Some("203.0.113.9:51000".parse().unwrap()));
+        assert_eq!(rest, b"rest");
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend(Ipv6Addr::LOCALHOST.octets());
+        addresses.extend([0xc7, 0x38, 0x1e, 0xc6]);
+        let (address, _) = read(&v2(V2_PROXY, V2_INET6, &addresses)).await;
+        assert_eq!(
+            address.unwrap(),
+            Some("[2001:db8::1]:51000".parse().unwrap())
+        );
+
+        // health checks of the proxy itself
+        let (address, rest) = read(&v2(V2_LOCAL, 0, &[])).await;
+        assert_eq!(address.unwrap(), None);
+        assert_eq!(rest, b"rest");
+
+        let (address, _) = read(&v2(V2_PROXY, V2_INET, &[203, 0, 113, 9])).await;
+        assert!(matches!(address, Err(ProxyError::InvalidHeader { .. })));
+    }
+}
@@ -0,0 +1,356 @@
+//! The QUIC transport. A client opens a bidirectional control stream first and frames packets on it exactly like on
+//! TCP. File transfers may go through a second bidirectional stream, so a large upload does not hold back the chat
+//! packets behind it.
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures::SinkExt;
+use quinn::{
+    crypto::rustls::QuicServerConfig,
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer},
+    },
+    Connection, ConnectionError, RecvStream, SendStream,
+};
+use tokio::{sync::mpsc, time::timeout};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+use crate::networking::{
+    compression::CompressionSettings,
+    error::NetworkingError,
+    packet_type::{FILE_ABORT, FILE_ACK, FILE_BEGIN, FILE_CHUNK, FILE_COMMIT},
+    raw_packet::RawPacket,
+};
+
+use super::connection::{packet_codec, ConnectionHandle, PacketReader, PacketWriter};
+
+/// The ALPN protocol clients have to request
+pub const ALPN_PROTOCOL: &[u8] = b"rustchat";
+
+/// How long a client has to open its control stream after connecting
+const CONTROL_STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds the QUIC server configuration for a certificate chain and its private key
+pub fn server_config(
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<quinn::ServerConfig, Box<dyn std::error::Error>> {
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+    crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+    let mut config =
+        quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+
+    // clients keep their connection when their address changes, e.g. when a phone switches from wifi to cellular
+    config.migration(true);
+    Ok(config)
+}
+
+type PacketReadStream = FramedRead<RecvStream, LengthDelimitedCodec>;
+type PacketWriteStream = FramedWrite<SendStream, LengthDelimitedCodec>;
+
+#[derive(Debug)]
+pub struct QuicConnection {
+    connection: Connection,
+    reader: QuicReader,
+    writer: QuicWriter,
+}
+
+/// Reads the packets of the control stream and of the transfer stream, accepting the latter when the client opens it
+#[derive(Debug)]
+pub struct QuicReader {
+    connection: Connection,
+    control: PacketReadStream,
+
+    /// The stream of file transfer packets, opened by the client when it first needs it
+    transfers: Option<PacketReadStream>,
+
+    /// Hands the sending side of the transfer stream to the writer, `None` once the client finished it
+    transfer_writers: mpsc::UnboundedSender<Option<PacketWriteStream>>,
+    compression: CompressionSettings,
+}
+
+/// Writes packets to the control stream, and file transfer packets to the transfer stream while it is open
+#[derive(Debug)]
+pub struct QuicWriter {
+    control: PacketWriteStream,
+    transfers: Option<PacketWriteStream>,
+    transfer_writers: mpsc::UnboundedReceiver<Option<PacketWriteStream>>,
+    compression: CompressionSettings,
+}
+
+impl QuicConnection {
+    /// Waits for the client to open its control stream
+    pub async fn accept(connection: Connection) -> Result<Self, NetworkingError> {
+        let (send, recv) = timeout(CONTROL_STREAM_TIMEOUT, connection.accept_bi())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+            .map_err(connection_error)?;
+        let (transfer_writers, transfer_writers_receiver) = mpsc::unbounded_channel();
+
+        Ok(Self {
+            connection: connection.clone(),
+            reader: QuicReader {
+                connection,
+                control: FramedRead::new(recv, packet_codec()),
+                transfers: None,
+                transfer_writers,
+                compression: CompressionSettings::disabled(),
+            },
+            writer: QuicWriter {
+                control: FramedWrite::new(send, packet_codec()),
+                transfers: None,
+                transfer_writers: transfer_writers_receiver,
+                compression: CompressionSettings::disabled(),
+            },
+        })
+    }
+}
+
+#[async_trait]
+impl PacketReader for QuicReader {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        loop {
+            let frame = tokio::select! {
+                frame = self.control.next() => match frame {
+                    Some(frame) => frame?,
+                    None => return Err(NetworkingError::ConnectionClosed),
+                },
+                frame = next_frame(self.transfers.as_mut()) => match frame {
+                    Some(frame) => frame?,
+
+                    // the client finished its transfer stream, it can open a new one later
+                    None => {
+                        self.transfers = None;
+                        let _ = self.transfer_writers.send(None);
+                        continue;
+                    }
+                },
+                stream = self.connection.accept_bi(), if self.transfers.is_none() => {
+                    let (send, recv) = stream.map_err(connection_error)?;
+                    self.transfers = Some(FramedRead::new(recv, packet_codec()));
+                    let _ = self.transfer_writers.send(Some(FramedWrite::new(send, packet_codec())));
+                    continue;
+                }
+            };
+
+            return RawPacket::decode_with(frame.freeze(), &self.compression);
+        }
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+    }
+
+    /// The address changes when the connection migrates
+    fn current_socket(&self) -> Option<SocketAddr> {
+        Some(self.connection.remote_address())
+    }
+}
+
+#[async_trait]
+impl PacketWriter for QuicWriter {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        while let Ok(transfers) = self.transfer_writers.try_recv() {
+            self.transfers = transfers;
+        }
+
+        let stream = match &mut self.transfers {
+            Some(transfers) if is_transfer_packet(packet.packet_type) => transfers,
+            _ => &mut self.control,
+        };
+
+        stream.send(packet.encode_with(&self.compression)).await?;
+        Ok(())
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.compression = settings;
+    }
+}
+
+#[async_trait]
+impl PacketReader for QuicConnection {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        self.reader.read_packet().await
+    }
+
+    fn accept_compression(&mut self, settings: CompressionSettings) {
+        self.reader.accept_compression(settings);
+    }
+
+    fn current_socket(&self) -> Option<SocketAddr> {
+        self.reader.current_socket()
+    }
+}
+
+#[async_trait]
+impl PacketWriter for QuicConnection {
+    async fn write_packet(&mut self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.writer.write_packet(packet).await
+    }
+
+    fn set_compression(&mut self, settings: CompressionSettings) {
+        self.writer.set_compression(settings);
+    }
+}
+
+impl ConnectionHandle for QuicConnection {
+    /// The current address of the client, which changes when the connection migrates
+    fn socket(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    fn split(self: Box<Self>) -> (Box<dyn PacketReader>, Box<dyn PacketWriter>) {
+        (Box::new(self.reader), Box::new(self.writer))
+    }
+}
+
+async fn next_frame(
+    stream: Option<&mut PacketReadStream>,
+) -> Option<Result<bytes::BytesMut, io::Error>> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn is_transfer_packet(packet_type: u8) -> bool {
+    matches!(
+        packet_type,
+        FILE_BEGIN | FILE_CHUNK | FILE_ACK | FILE_COMMIT | FILE_ABORT
+    )
+}
+
+fn connection_error(err: ConnectionError) -> NetworkingError {
+    match err {
+        ConnectionError::ApplicationClosed(_)
+        | ConnectionError::ConnectionClosed(_)
+        | ConnectionError::LocallyClosed => NetworkingError::ConnectionClosed,
+        err => io::Error::from(err).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use quinn::{crypto::rustls::QuicClientConfig, Endpoint};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    use super::*;
+    use crate::networking::packet_type::{HELLO, MESSAGE, WELCOME};
+
+    /// A bidirectional stream opened by the client
+    struct PacketStream {
+        reader: PacketReadStream,
+        writer: PacketWriteStream,
+    }
+
+    impl PacketStream {
+        fn new((send, recv): (SendStream, RecvStream)) -> Self {
+            Self {
+                reader: FramedRead::new(recv, packet_codec()),
+                writer: FramedWrite::new(send, packet_codec()),
+            }
+        }
+    }
+
+    /// A server endpoint with a self-signed certificate and a client connected to it over loopback
+    async fn connect() -> (Endpoint, Connection, Connection) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let certificate = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()).into();
+
+        let config = server_config(vec![certificate.clone()], key).unwrap();
+        let server = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(certificate).unwrap();
+        let mut crypto = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(crypto).unwrap(),
+        )));
+
+        let address = server.local_addr().unwrap();
+        let (client_connection, server_connection) = tokio::join!(
+            async { client.connect(address, "localhost").unwrap().await.unwrap() },
+            async { server.accept().await.unwrap().await.unwrap() },
+        );
+
+        (client, client_connection, server_connection)
+    }
+
+    async fn open_stream(connection: &Connection, packet: RawPacket) -> PacketStream {
+        let mut stream = PacketStream::new(connection.open_bi().await.unwrap());
+        stream.writer.send(packet.encode()).await.unwrap();
+        stream
+    }
+
+    async fn read(stream: &mut PacketStream) -> RawPacket {
+        let frame = stream.reader.next().await.unwrap().unwrap();
+        RawPacket::decode(frame.freeze()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn quic_connection_separates_transfer_stream() {
+        let (_endpoint, client, server) = connect().await;
+
+        let hello = RawPacket::new(HELLO, Bytes::from_static(b"hello"));
+        let mut control = open_stream(&client, hello.clone()).await;
+        let mut server = QuicConnection::accept(server).await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), hello);
+
+        let chunk = RawPacket::new(FILE_CHUNK, Bytes::from_static(b"chunk"));
+        let mut transfers = open_stream(&client, chunk.clone()).await;
+        assert_eq!(server.read_packet().await.unwrap(), chunk);
+
+        let ack = RawPacket::new(FILE_ACK, Bytes::from_static(b"ack"));
+        let welcome = RawPacket::new(WELCOME, Bytes::from_static(b"welcome"));
+        server.write_packet(ack.clone()).await.unwrap();
+        server.write_packet(welcome.clone()).await.unwrap();
+        assert_eq!(read(&mut transfers).await, ack);
+        assert_eq!(read(&mut control).await, welcome);
+
+        client.close(0u32.into(), b"bye");
+        assert!(matches!(
+            server.read_packet().await,
+            Err(NetworkingError::ConnectionClosed | NetworkingError::Io { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn quic_connection_survives_migration() {
+        let (endpoint, client, server) = connect().await;
+
+        let hello = RawPacket::new(HELLO, Bytes::from_static(b"hello"));
+        let mut control = open_stream(&client, hello.clone()).await;
+        let mut server = QuicConnection::accept(server).await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), hello);
+        let address = server.socket();
+
+        // the client moves to another socket, as if its network changed
+        endpoint
+            .rebind(std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+            .unwrap();
+
+        let message = RawPacket::new(MESSAGE, Bytes::from_static(b"still here"));
+        control.writer.send(message.encode()).await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), message);
+        assert_ne!(server.socket(), address);
+
+        // users keep reading the address from the read half once the connection is split
+        let (reader, _) = Box::new(server).split();
+        assert_ne!(reader.current_socket(), Some(address));
+        assert!(reader.current_socket().is_some());
+    }
+}
@@ -0,0 +1,418 @@
+use std::{any::type_name, collections::HashMap, fmt, marker::PhantomData, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use crate::{
+    coding::Decoder,
+    networking::{
+        error::{HandlerSnafu, InvalidJsonSnafu, NetworkingError},
+        json::JsonPackets,
+        packet_type::{
+            DisconnectPacket, ErrorPacket, FileAbortPacket, FileAckPacket, FileBeginPacket,
+            FileChunkPacket, FileCommitPacket, PacketData, ResumePacket, ServerShutdownPacket,
+            SessionPacket, SignedInPacket, WelcomePacket,
+        },
+        raw_packet::RawPacket,
+    },
+    types::types,
+};
+
+use super::{
+    handlers::{
+        FileTransferHandler, HelloHandler, JoinHandler, LoginHandler, MessageHandler, PingHandler,
+        PongHandler, ResumeHandler,
+    },
+    session::SessionStore,
+    transfer::TransferStore,
+    user::User,
+};
+
+/// Handles every decoded packet of type `P` received from a user
+#[async_trait]
+pub trait PacketHandler<P>: Send + Sync {
+    async fn handle(&self, user: &mut User, packet: P) -> types::Result<()>;
+
+    /// Persists what the handler keeps in memory or in buffers, called before the server exits
+    async fn flush(&self) -> types::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum RegistryError {
+    #[snafu(display("packet type {packet_type} is already registered by {existing}"))]
+    DuplicatePacketType {
+        packet_type: u8,
+        existing: &'static str,
+    },
+}
+
+/// Maps packet ids to the decoder and handler of their packet type.
+///
+/// The server builds one at startup and every user dispatches the packets it receives through it,
+/// so new packet types can be added by registering them instead of editing the `Packet` enum.
+///
+/// It also decodes the handshake and translates packets for JSON clients, so it knows the packets the server only
+/// sends as well, see [`PacketRegistry::declare`].
+pub struct PacketRegistry {
+    packets: HashMap<u8, Box<dyn RegisteredPacket>>,
+
+    /// Every known packet type, with or without a handler
+    codecs: HashMap<u8, Box<dyn PacketCodec>>,
+}
+
+impl PacketRegistry {
+    /// Creates a registry without any packet type, clients can only complete their handshake once HELLO is registered
+    pub fn new() -> Self {
+        Self {
+            packets: HashMap::new(),
+            codecs: HashMap::new(),
+        }
+    }
+
+    /// Registers the handler for the packet type `P`, failing if its id is already taken.
+    pub fn register<P, H>(&mut self, handler: H) -> Result<(), RegistryError>
+    where
+        P: PacketData + Default + Serialize + DeserializeOwned + 'static,
+        H: PacketHandler<P> + 'static,
+    {
+        let packet_type = self.insert_codec::<P>()?;
+        self.packets.insert(
+            packet_type,
+            Box::new(Registration {
+                handler,
+                packet: PhantomData::<fn() -> P>,
+            }),
+        );
+
+        Ok(())
+    }
+
+    /// Declares the packet type `P` without a handler, for packets the server sends but never receives
+    pub fn declare<P>(&mut self) -> Result<(), RegistryError>
+    where
+        P: PacketData + Default + Serialize + DeserializeOwned + 'static,
+    {
+        self.insert_codec::<P>().map(|_| ())
+    }
+
+    fn insert_codec<P>(&mut self) -> Result<u8, RegistryError>
+    where
+        P: PacketData + Default + Serialize + DeserializeOwned + 'static,
+    {
+        let packet_type = P::default().packet_id();
+        if let Some(existing) = self.codecs.get(&packet_type) {
+            return DuplicatePacketTypeSnafu {
+                packet_type,
+                existing: existing.name(),
+            }
+            .fail();
+        }
+
+        self.codecs
+            .insert(packet_type, Box::new(PhantomData::<fn() -> P>));
+        Ok(packet_type)
+    }
+
+    /// Registers the FILE_* packets of chunked uploads, storing the files in the given store
+    pub fn register_file_transfers(
+        &mut self,
+        store: Arc<TransferStore>,
+    ) -> Result<(), RegistryError> {
+        let handler = FileTransferHandler::new(store);
+        self.register::<FileBeginPacket, _>(handler.clone())?;
+        self.register::<FileChunkPacket, _>(handler.clone())?;
+        self.register::<FileCommitPacket, _>(handler.clone())?;
+        self.register::<FileAbortPacket, _>(handler)
+    }
+
+    /// Registers RESUME, opening and resuming sessions in the given store
+    pub fn register_sessions(&mut self, store: Arc<SessionStore>) -> Result<(), RegistryError> {
+        self.register::<ResumePacket, _>(ResumeHandler::new(store))
+    }
+
+    /// Whether a handler is registered for the packet type
+    pub fn contains(&self, packet_type: u8) -> bool {
+        self.packets.contains_key(&packet_type)
+    }
+
+    /// Decodes the packet if it is of type `P`, returning `None` for packets of any other known type
+    pub fn decode<P>(&self, raw_packet: &RawPacket) -> Result<Option<P>, NetworkingError>
+    where
+        P: PacketData + Default,
+    {
+        let packet_type = raw_packet.packet_type;
+        if !self.codecs.contains_key(&packet_type) {
+            return Err(NetworkingError::UnknownPacketType { packet_type });
+        }
+
+        let mut packet = P::default();
+        if packet.packet_id() != packet_type {
+            return Ok(None);
+        }
+        raw_packet.receive_payload(&mut packet)?;
+        Ok(Some(packet))
+    }
+
+    /// Flushes every registered handler, failing with the first error once all of them were flushed
+    pub async fn flush(&self) -> types::Result<()> {
+        let mut result = Ok(());
+        for packet in self.packets.values() {
+            if let Err(err) = packet.flush().await {
+                println!("Could not flush the handler of {}: {}", packet.name(), err);
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    /// Decodes the packet and passes it to the handler registered for its type.
+    pub async fn dispatch(
+        &self,
+        user: &mut User,
+        raw_packet: RawPacket,
+    ) -> Result<(), NetworkingError> {
+        let packet_type = raw_packet.packet_type;
+        match self.packets.get(&packet_type) {
+            Some(packet) => {
+                packet
+                    .dispatch(user, Decoder::new(raw_packet.payload))
+                    .await
+            }
+            None => Err(NetworkingError::UnknownPacketType { packet_type }),
+        }
+    }
+}
+
+impl Default for PacketRegistry {
+    /// Creates a registry with the built in packet types
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry
+            .register(HelloHandler)
+            .expect("built in packet types must have unique ids");
+        registry
+            .register(LoginHandler)
+            .expect("built in packet types must have unique ids");
+        registry
+            .register(JoinHandler)
+            .expect("built in packet types must have unique ids");
+        registry
+            .register(MessageHandler)
+            .expect("built in packet types must have unique ids");
+        registry
+            .register(PingHandler)
+            .expect("built in packet types must have unique ids");
+        registry
+            .register(PongHandler)
+            .expect("built in packet types must have unique ids");
+        registry
+            .register_file_transfers(Arc::new(TransferStore::default()))
+            .expect("built in packet types must have unique ids");
+        registry
+            .register_sessions(Arc::new(SessionStore::default()))
+            .expect("built in packet types must have unique ids");
+
+        registry
+            .declare::<WelcomePacket>()
+            .expect("built in packet types must have unique ids");
+        registry
+            .declare::<ErrorPacket>()
+            .expect("built in packet types must have unique ids");
+        registry
+            .declare::<FileAckPacket>()
+            .expect("built in packet types must have unique ids");
+        registry
+            .declare::<DisconnectPacket>()
+            .expect("built in packet types must have unique ids");
+        registry
+            .declare::<ServerShutdownPacket>()
+            .expect("built in packet types must have unique ids");
+        registry
+            .declare::<SessionPacket>()
+            .expect("built in packet types must have unique ids");
+        registry
+            .declare::<SignedInPacket>()
+            .expect("built in packet types must have unique ids");
+        registry
+    }
+}
+
+impl fmt::Debug for PacketRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut packet_types: Vec<_> = self.codecs.keys().collect();
+        packet_types.sort();
+        f.debug_struct("PacketRegistry")
+            .field("packet_types", &packet_types)
+            .finish()
+    }
+}
+
+impl JsonPackets for PacketRegistry {
+    fn decode_json(
+        &self,
+        packet_type: u8,
+        data: serde_json::Value,
+    ) -> Result<RawPacket, NetworkingError> {
+        match self.codecs.get(&packet_type) {
+            Some(codec) => codec.decode_json(data),
+            None => Err(NetworkingError::UnknownPacketType { packet_type }),
+        }
+    }
+
+    fn encode_json(&self, raw_packet: RawPacket) -> Result<serde_json::Value, NetworkingError> {
+        let packet_type = raw_packet.packet_type;
+        match self.codecs.get(&packet_type) {
+            Some(codec) => codec.encode_json(raw_packet.payload),
+            None => Err(NetworkingError::UnknownPacketType { packet_type }),
+        }
+    }
+}
+
+/// The JSON encoding of a known packet type, with its concrete type erased
+trait PacketCodec: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn decode_json(&self, data: serde_json::Value) -> Result<RawPacket, NetworkingError>;
+    fn encode_json(&self, payload: Bytes) -> Result<serde_json::Value, NetworkingError>;
+}
+
+impl<P> PacketCodec for PhantomData<fn() -> P>
+where
+    P: PacketData + Default + Serialize + DeserializeOwned + 'static,
+{
+    fn name(&self) -> &'static str {
+        type_name::<P>()
+    }
+
+    fn decode_json(&self, data: serde_json::Value) -> Result<RawPacket, NetworkingError> {
+        let packet: P = serde_json::from_value(data).context(InvalidJsonSnafu)?;
+        Ok(RawPacket::new_from_type(&packet))
+    }
+
+    fn encode_json(&self, payload: Bytes) -> Result<serde_json::Value, NetworkingError> {
+        let mut packet = P::default();
+        packet.deserialize(&mut Decoder::new(payload))?;
+        serde_json::to_value(packet).context(InvalidJsonSnafu)
+    }
+}
+
+/// A registered packet type with its concrete type erased
+#[async_trait]
+trait RegisteredPacket: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn dispatch(&self, user: &mut User, decoder: Decoder) -> Result<(), NetworkingError>;
+    async fn flush(&self) -> types::Result<()>;
+}
+
+struct Registration<P, H> {
+    handler: H,
+    packet: PhantomData<fn() -> P>,
+}
+
+#[async_trait]
+impl<P, H> RegisteredPacket for Registration<P, H>
+where
+    P: PacketData + Default + 'static,
+    H: PacketHandler<P>,
+{
+    fn name(&self) -> &'static str {
+        type_name::<P>()
+    }
+
+    async fn dispatch(&self, user: &mut User, mut decoder: Decoder) -> Result<(), NetworkingError> {
+        let mut packet = P::default();
+        packet.deserialize(&mut decoder)?;
+
+        let packet_type = packet.packet_id();
+        self.handler
+            .handle(user, packet)
+            .await
+            .context(HandlerSnafu { packet_type })
+    }
+
+    async fn flush(&self) -> types::Result<()> {
+        self.handler.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::packet_type::{
+        HelloPacket, PingPacket, FILE_ACK, FILE_CHUNK, HELLO, JOIN, MESSAGE, RESUME, SESSION,
+        SIGNED_IN, SIGN_IN, WELCOME,
+    };
+
+    struct NoopHandler;
+
+    #[async_trait]
+    impl PacketHandler<HelloPacket> for NoopHandler {
+        async fn handle(&self, _user: &mut User, _packet: HelloPacket) -> types::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn default_registers_built_in_packets() {
+        let registry = PacketRegistry::default();
+        assert!(registry.contains(HELLO));
+        assert!(registry.contains(SIGN_IN));
+        assert!(registry.contains(JOIN));
+        assert!(registry.contains(MESSAGE));
+        assert!(registry.contains(FILE_CHUNK));
+        assert!(registry.contains(RESUME));
+        assert!(!registry.contains(WELCOME));
+        assert!(!registry.contains(SESSION));
+        assert!(!registry.contains(SIGNED_IN));
+        assert!(!registry.contains(FILE_ACK));
+    }
+
+    #[test]
+    fn register_rejects_duplicate_ids() {
+        let mut registry = PacketRegistry::default();
+        let err = registry.register(NoopHandler).unwrap_err();
+
+        assert!(matches!(
+            err,
+            RegistryError::DuplicatePacketType {
+                packet_type: HELLO,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn declared_packets_are_known_without_a_handler() {
+        let mut registry = PacketRegistry::default();
+        assert!(matches!(
+            registry.declare::<WelcomePacket>(),
+            Err(RegistryError::DuplicatePacketType {
+                packet_type: WELCOME,
+                ..
+            })
+        ));
+
+        let welcome = RawPacket::new_from_type(&WelcomePacket::default());
+        assert!(registry.encode_json(welcome.clone()).is_ok());
+        assert!(matches!(registry.decode::<HelloPacket>(&welcome), Ok(None)));
+
+        let hello = RawPacket::new_from_type(&HelloPacket::default());
+        assert!(matches!(
+            registry.decode::<HelloPacket>(&hello),
+            Ok(Some(_))
+        ));
+
+        let registry = PacketRegistry::new();
+        assert!(matches!(
+            registry.decode::<HelloPacket>(&hello),
+            Err(NetworkingError::UnknownPacketType { packet_type: HELLO })
+        ));
+        assert!(matches!(
+            registry.encode_json(RawPacket::new_from_type(&PingPacket::default())),
+            Err(NetworkingError::UnknownPacketType { .. })
+        ));
+    }
+}
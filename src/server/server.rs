@@ -1,95 +1,334 @@
-use std::{sync::Arc, time::Duration};
+use std::{future::Future, io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
 
 use tokio::{
-    net::{unix::uid_t, TcpListener},
-    sync::RwLock,
-    time::sleep,
+    io::AsyncBufReadExt,
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
 };
-use tokio_tungstenite::accept_async;
+use tokio_rustls::{rustls, TlsAcceptor};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{Request, Response},
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue},
+    },
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::types::types::{self};
+use crate::{
+    networking::{json::JSON_SUBPROTOCOL, packet::Packet, packet_type::ServerShutdownPacket},
+    types::types::{self},
+};
 
 use super::{
-    connection::{ConnectionHandle, TcpConnection, WebSocketConnection},
+    connection::{
+        http::{self, HttpSessions},
+        udp::UdpListener,
+        ConnectionHandle, JsonWebSocketConnection, TcpConnection, Transport, WebSocketConnection,
+    },
     database::Database,
-    user::User,
+    outgoing::OutgoingLimits,
+    proxy::{self, TrustedProxies},
+    quic::QuicConnection,
+    registry::PacketRegistry,
+    tls,
+    user::{Heartbeat, User, UserHandle, UserSettings},
 };
 
+#[cfg(unix)]
+use super::{
+    connection::LOOPBACK_ADDRESS,
+    unix::{self, UnixConnection},
+};
+
+/// How long a TLS client has to finish its handshake and send its first bytes
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client has to send the first bytes telling what it speaks
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait before peeking again, when the first bytes do not tell what the client speaks yet
+const SNIFF_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many bytes are peeked at most, enough for the head of a poll that has to be told apart from a WebSocket upgrade
+const SNIFF_SIZE: usize = http::MAX_HEAD_SIZE;
+
+/// How the server stops
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownSettings {
+    /// Sent to every client in the SERVER_SHUTDOWN packet
+    pub reason: String,
+
+    /// When the server is expected back, so clients know when to reconnect
+    pub reconnect_after: Option<Duration>,
+
+    /// How long connections have to write what is queued for them before the server exits anyway
+    pub drain_deadline: Duration,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            reason: "the server is shutting down".into(),
+            reconnect_after: None,
+            drain_deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What every accepted connection needs to be served
+#[derive(Clone)]
+struct Context {
+    db: Arc<Database>,
+    registry: Arc<PacketRegistry>,
+    settings: UserSettings,
+
+    /// The sessions of the clients connected over the HTTP fallback
+    http: Arc<HttpSessions>,
+
+    /// Cancelled when the server shuts down, stopping the listeners and the users
+    shutdown: CancellationToken,
+
+    /// The tasks serving connections, awaited before the server exits
+    connections: TaskTracker,
+}
+
 /// Server is meant to be a singleton that maintains the actual server state
 pub struct Server {
-    listener: TcpListener,
+    listener: Option<TcpListener>,
+    quic: Option<quinn::Endpoint>,
+    udp: Option<UdpListener>,
+    #[cfg(unix)]
+    unix: Option<tokio::net::UnixListener>,
+    tls: Option<TlsAcceptor>,
+
+    /// The load balancers whose connections start with a PROXY protocol header
+    proxies: Option<Arc<TrustedProxies>>,
+    user_settings: UserSettings,
+    shutdown_settings: ShutdownSettings,
+    shutdown: CancellationToken,
+    connections: TaskTracker,
+    http: Arc<HttpSessions>,
     db: Arc<Database>,
+    registry: Arc<PacketRegistry>,
 }
 
 impl Server {
     pub async fn new(endpoint: &str) -> Result<Server, Box<dyn std::error::Error>> {
+        Self::with_registry(endpoint, PacketRegistry::default()).await
+    }
+
+    /// Creates a server that handles the packet types of the given registry
+    pub async fn with_registry(
+        endpoint: &str,
+        registry: PacketRegistry,
+    ) -> Result<Server, Box<dyn std::error::Error>> {
         let listener = TcpListener::bind(endpoint).await?;
 
         Ok(Server {
-            listener,
-            db: Arc::new(Database::new()),
+            listener: Some(listener),
+            ..Self::without_listener(registry)
         })
     }
 
+    /// Creates a server that only serves the connections passed to [`Server::connect`] and the listeners added later
+    pub fn without_listener(registry: PacketRegistry) -> Server {
+        Server {
+            listener: None,
+            quic: None,
+            udp: None,
+            #[cfg(unix)]
+            unix: None,
+            tls: None,
+            proxies: None,
+            user_settings: UserSettings::default(),
+            shutdown_settings: ShutdownSettings::default(),
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
+            http: Arc::new(HttpSessions::new()),
+            db: Arc::new(Database::new()),
+            registry: Arc::new(registry),
+        }
+    }
+
     pub async fn run(&mut self) -> types::Result<()> {
-        println!("Running on {}", self.listener.local_addr().unwrap());
+        self.run_until(std::future::pending()).await
+    }
+
+    /// Serves clients until `signal` completes, then shuts down: the listeners stop, every client is sent a
+    /// SERVER_SHUTDOWN and disconnected once what is queued for it is written, and the handlers are flushed.
+    pub async fn run_until(&mut self, signal: impl Future<Output = ()>) -> types::Result<()> {
+        if let Some(listener) = &self.listener {
+            println!("Running on {}", listener.local_addr()?);
+        }
         self.stats();
+        let context = self.context();
+
+        if let Some(endpoint) = self.quic.clone() {
+            println!("Running QUIC on {}", endpoint.local_addr()?);
+            tokio::spawn(accept_quic(endpoint, context.clone()));
+        }
 
-        loop {
-            // accept tcp connection
-            let (stream, socket) = self.listener.accept().await?;
+        if let Some(listener) = self.udp.take() {
+            println!("Running reliable UDP on {}", listener.local_addr());
+            tokio::spawn(accept_udp(listener, context.clone()));
+        }
 
-            // determine the source of the connection
-            let mut buf = [0; 3];
+        #[cfg(unix)]
+        if let Some(listener) = self.unix.take() {
+            println!("Running on Unix socket {:?}", listener.local_addr()?);
+            tokio::spawn(accept_unix(listener, context.clone()));
+        }
 
-            // Peek first 4 bytes to check if its a GET (websocket connection)
-            let is_websocket: bool = match stream.peek(&mut buf).await {
-                Ok(read) => read >= 3 && &buf[..3] == b"GET",
-                Err(_) => false,
+        let accept = async {
+            let Some(listener) = &self.listener else {
+                return std::future::pending().await;
             };
 
-            let connection_handle: Box<dyn ConnectionHandle + Send + Sync>;
-            if is_websocket {
-                match accept_async(stream).await {
-                    Ok(ws) => {
-                        connection_handle = Box::new(WebSocketConnection::new(socket, ws));
-                    }
-                    Err(err) => {
-                        println!("could not connect via websocket: {}", err);
+            loop {
+                // accept tcp connection
+                let (stream, socket) = listener.accept().await?;
+                let tls = self.tls.clone();
+                let proxies = self.proxies.clone();
+                let context = context.clone();
+
+                // the handshakes are awaited in their own task, so a slow client does not block others
+                self.connections.spawn(async move {
+                    let accepted = accept_tcp(stream, socket, tls, proxies.as_deref(), &context);
+                    match accepted.await {
+                        Ok(Some(connection_handle)) => {
+                            serve(&context, connection_handle).await;
+                        }
 
-                        // continue to the next loop to avoid using connection_handle
-                        // if its not initialized
-                        continue;
+                        // an HTTP request that did not open a session
+                        Ok(None) => {}
+                        Err(err) => println!("could not connect from {}: {}", socket, err),
                     }
-                }
-            } else {
-                connection_handle = Box::new(TcpConnection::new(socket, stream));
+                });
             }
+        };
 
-            // create a client and spawn a new task to handle it
-            println!("Client connected from {}", socket);
-            let user = User::new(connection_handle);
-            let uid = &user.id();
-            let client = Arc::new(RwLock::new(user));
-            self.db.add_client(uid, client.clone()).await;
-
-            // Clone server metadata to give it to the tokio task
-            let db = self.db.clone();
-            tokio::spawn(async move {
-                // TODO: If an error is encountered, log it.
-
-                // Process the connection.
-                let mut client = client.write().await;
-                if let Err(err) = client.run().await {
-                    println!("An error occurred handling user: {}", err);
-                }
+        tokio::select! {
+            result = accept => return result,
+            _ = signal => {}
+        }
+
+        self.shutdown().await
+    }
 
-                println!("Client {} disconnected.", socket);
-                db.remove_client(&client.id()).await;
-            });
+    /// Serves a connection that was accepted elsewhere, like the listeners do, returning a handle to its user
+    pub async fn connect(&self, connection: Box<dyn ConnectionHandle + Send + Sync>) -> UserHandle {
+        serve(&self.context(), connection).await
+    }
+
+    pub fn database(&self) -> &Arc<Database> {
+        &self.db
+    }
+
+    fn context(&self) -> Context {
+        Context {
+            db: self.db.clone(),
+            registry: self.registry.clone(),
+            settings: self.user_settings,
+            http: self.http.clone(),
+            shutdown: self.shutdown.clone(),
+            connections: self.connections.clone(),
         }
     }
 
+    /// Tells every client that the server is going away, then waits until the drain deadline for the connections to
+    /// write what is queued for them. [`Server::run_until`] calls it once its signal completes.
+    pub async fn shutdown(&mut self) -> types::Result<()> {
+        let clients = self.db.clients().await;
+        println!("Shutting down, disconnecting {} clients", clients.len());
+
+        let settings = &self.shutdown_settings;
+        for client in &clients {
+            let notice = ServerShutdownPacket {
+                reason: settings.reason.clone(),
+                reconnect_after: settings
+                    .reconnect_after
+                    .map(|delay| delay.as_secs().try_into().unwrap_or(u32::MAX)),
+            };
+
+            // a client that is already gone does not need to know
+            let _ = client.send_packet(Packet::ServerShutdown(notice)).await;
+        }
+
+        self.shutdown.cancel();
+        self.connections.close();
+        if timeout(settings.drain_deadline, self.connections.wait())
+            .await
+            .is_err()
+        {
+            println!(
+                "{} connections were still writing after {:?}",
+                self.connections.len(),
+                settings.drain_deadline
+            );
+        }
+
+        if let Some(endpoint) = &self.quic {
+            endpoint.close(0u32.into(), b"server shutdown");
+        }
+
+        self.registry.flush().await
+    }
+
+    /// The address of the TCP listener, if the server has one
+    pub fn local_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.listener.as_ref().map(TcpListener::local_addr)
+    }
+
+    /// Changes the SERVER_SHUTDOWN sent to clients and how long they have to drain when the server stops
+    pub fn set_shutdown(&mut self, settings: ShutdownSettings) {
+        self.shutdown_settings = settings;
+    }
+
+    /// Changes how often quiet clients are pinged and how many pings they may miss before being disconnected
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.user_settings.heartbeat = heartbeat;
+    }
+
+    /// Changes how many packets may wait to be written to a slow client, and what happens once there are more
+    pub fn set_outgoing_limits(&mut self, limits: OutgoingLimits) {
+        self.user_settings.outgoing = limits;
+    }
+
+    /// Accepts connections on a Unix socket once the server runs, for clients on the same host
+    #[cfg(unix)]
+    pub fn listen_unix(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.unix = Some(unix::bind(path)?);
+        Ok(())
+    }
+
+    /// Accepts TLS connections on the TCP listener next to plaintext ones, for raw packet streams and WebSockets
+    pub fn enable_tls(&mut self, config: Arc<rustls::ServerConfig>) {
+        self.tls = Some(TlsAcceptor::from(config));
+    }
+
+    /// Reads the address of the client from the PROXY protocol header, version 1 or 2, that connections from these
+    /// load balancers start with. Connections from anywhere else are taken as they are.
+    pub fn trust_proxies(&mut self, proxies: TrustedProxies) {
+        self.proxies = Some(Arc::new(proxies));
+    }
+
+    /// Accepts QUIC connections on the given UDP endpoint once the server runs, next to the TCP listener
+    pub fn listen_quic(
+        &mut self,
+        endpoint: &str,
+        config: quinn::ServerConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.quic = Some(quinn::Endpoint::server(config, endpoint.parse()?)?);
+        Ok(())
+    }
+
+    /// Accepts reliable UDP connections on the given endpoint once the server runs, next to the TCP listener
+    pub async fn listen_udp(&mut self, endpoint: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.udp = Some(UdpListener::bind(endpoint).await?);
+        Ok(())
+    }
+
     pub fn stats(&self) {
         let db = self.db.clone();
         tokio::spawn(async move {
@@ -97,8 +336,608 @@ impl Server {
                 sleep(Duration::from_secs(10)).await;
                 println!("---------- Server Stats ----------");
                 println!("Connected users: {}", db.total_clients().await);
+
+                // a client that does not keep up shows in the depth of its outgoing queue
+                let clients = db.clients().await;
+                if let Some((client, queue)) = clients
+                    .iter()
+                    .map(|client| (client, client.outgoing_stats()))
+                    .max_by_key(|(_, queue)| queue.bytes)
+                {
+                    println!(
+                        "Deepest outgoing queue: {} packets, {} bytes for {}",
+                        queue.packets,
+                        queue.bytes,
+                        client.socket()
+                    );
+                }
                 println!("----------------------------------");
             }
         });
     }
 }
+
+/// Creates a user for the connection and spawns a task handling it until it disconnects
+async fn serve(
+    context: &Context,
+    connection_handle: Box<dyn ConnectionHandle + Send + Sync>,
+) -> UserHandle {
+    let socket = connection_handle.socket();
+    println!("Client connected from {}", socket);
+    let mut client = User::with_settings(
+        connection_handle,
+        context.registry.clone(),
+        context.settings,
+    )
+    .with_shutdown(context.shutdown.clone())
+    .with_database(context.db.clone());
+    let handle = client.handle();
+    let db = context.db.clone();
+    db.add_client(handle.clone()).await;
+
+    context.connections.spawn(async move {
+        // TODO: If an error is encountered, log it.
+
+        // Process the connection, other tasks send to the client through its handle meanwhile
+        if let Err(err) = client.run().await {
+            println!("An error occurred handling user: {}", err);
+        }
+
+        println!("Client {} disconnected.", socket);
+        db.remove_client(&client.id()).await;
+        client.disconnect().await;
+    });
+
+    handle
+}
+
+#[cfg(unix)]
+async fn accept_unix(listener: tokio::net::UnixListener, context: Context) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.cancelled() => return,
+        };
+
+        match accepted {
+            Ok((stream, _)) => {
+                let connection = UnixConnection::new(LOOPBACK_ADDRESS, stream);
+                serve(&context, Box::new(connection)).await;
+            }
+            Err(err) => println!("could not accept Unix socket connection: {}", err),
+        }
+    }
+}
+
+/// What a client connecting to the TCP listener speaks, told apart by its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Packets,
+    WebSocket,
+    Http,
+}
+
+impl Protocol {
+    fn sniff(peeked: &[u8]) -> Self {
+        if http::is_fallback_request(peeked) {
+            Protocol::Http
+        } else if peeked.starts_with(b"GET") {
+            Protocol::WebSocket
+        } else {
+            Protocol::Packets
+        }
+    }
+
+    /// Whether the first bytes are too few to tell what the client speaks. Every client sends at least 4 bytes, the
+    /// length of a frame or the start of an HTTP request.
+    fn is_undecided(peeked: &[u8]) -> bool {
+        peeked.len() < 4 || http::is_partial_fallback_request(peeked)
+    }
+}
+
+/// Peeks at the first bytes of a connection until they tell what the client speaks, returning how many there are
+async fn peek_first_bytes(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    let mut previous = 0;
+    loop {
+        let read = stream.peek(buf).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if read == buf.len() || !Protocol::is_undecided(&buf[..read]) {
+            return Ok(read);
+        }
+
+        // peeking returns the same bytes until more arrive
+        if read == previous {
+            sleep(SNIFF_INTERVAL).await;
+        }
+        previous = read;
+    }
+}
+
+/// Tells apart the kinds of clients connecting to the TCP listener and completes their handshakes. Returns `None` for
+/// HTTP requests that were answered without opening a session.
+async fn accept_tcp(
+    mut stream: TcpStream,
+    mut socket: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    proxies: Option<&TrustedProxies>,
+    context: &Context,
+) -> io::Result<Option<Box<dyn ConnectionHandle + Send + Sync>>> {
+    // a load balancer tells who the client is before the client says anything
+    if proxies.is_some_and(|proxies| proxies.is_trusted(socket.ip())) {
+        let header = timeout(proxy::HEADER_TIMEOUT, proxy::read_header(&mut stream))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        // without a client, like for health checks, the connection is the load balancer's own
+        if let Some(client) = header {
+            socket = client;
+        }
+    }
+
+    // determine the source of the connection
+    let mut buf = vec![0; SNIFF_SIZE];
+
+    // Peek the first bytes to check if its a TLS ClientHello, an HTTP request or a GET (websocket connection)
+    let read = timeout(SNIFF_TIMEOUT, peek_first_bytes(&stream, &mut buf))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let peeked = &buf[..read];
+
+    match tls {
+        Some(acceptor) if tls::is_client_hello(peeked) => {
+            let handshake = async {
+                let mut stream = tls::accept(&acceptor, stream).await?;
+                // the first decrypted record holds the whole of the first write of the client
+                let protocol = Protocol::sniff(stream.fill_buf().await?);
+                Ok::<_, io::Error>((stream, protocol))
+            };
+            let (stream, protocol) = timeout(TLS_HANDSHAKE_TIMEOUT, handshake)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+            accept_transport(stream, socket, protocol, context).await
+        }
+        _ => accept_transport(stream, socket, Protocol::sniff(peeked), context).await,
+    }
+}
+
+/// Creates the connection for a plaintext or TLS stream, completing the WebSocket handshake first if there is one
+async fn accept_transport<S: Transport>(
+    stream: S,
+    socket: SocketAddr,
+    protocol: Protocol,
+    context: &Context,
+) -> io::Result<Option<Box<dyn ConnectionHandle + Send + Sync>>> {
+    match protocol {
+        Protocol::Packets => return Ok(Some(Box::new(TcpConnection::new(socket, stream)))),
+        Protocol::Http => {
+            let connection = context.http.handle(stream, socket).await?;
+            return Ok(connection.map(|connection| Box::new(connection) as _));
+        }
+        Protocol::WebSocket => {}
+    }
+
+    let mut json = false;
+    // the error response type is chosen by tungstenite
+    #[allow(clippy::result_large_err)]
+    let select_subprotocol = |request: &Request, mut response: Response| {
+        if requests_json(request) {
+            json = true;
+            response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(JSON_SUBPROTOCOL),
+            );
+        }
+        Ok(response)
+    };
+
+    let ws = accept_hdr_async(stream, select_subprotocol)
+        .await
+        .map_err(|err| io::Error::other(format!("could not connect via websocket: {}", err)))?;
+    if json {
+        let packets = context.registry.clone();
+        Ok(Some(Box::new(JsonWebSocketConnection::new(
+            socket, ws, packets,
+        ))))
+    } else {
+        Ok(Some(Box::new(WebSocketConnection::new(socket, ws))))
+    }
+}
+
+async fn accept_quic(endpoint: quinn::Endpoint, context: Context) {
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = context.shutdown.cancelled() => {
+                // new clients are refused, the connections already accepted stay open until they drain
+                endpoint.set_server_config(None);
+                return;
+            }
+        };
+        let Some(incoming) = incoming else {
+            return;
+        };
+        let context = context.clone();
+
+        // the TLS handshake and control stream are awaited in their own task, so a slow client does not block others
+        context.connections.clone().spawn(async move {
+            let socket = incoming.remote_address();
+            let connection = match incoming.await {
+                Ok(connection) => QuicConnection::accept(connection).await,
+                Err(err) => Err(io::Error::from(err).into()),
+            };
+
+            match connection {
+                Ok(connection) => {
+                    serve(&context, Box::new(connection)).await;
+                }
+                Err(err) => println!("could not connect via QUIC from {}: {}", socket, err),
+            }
+        });
+    }
+}
+
+async fn accept_udp(mut listener: UdpListener, context: Context) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.cancelled() => return,
+        };
+
+        match accepted {
+            Ok(connection) => {
+                serve(&context, Box::new(connection)).await;
+            }
+
+            // the task routing the datagrams is gone, nothing will be accepted anymore
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                println!("UDP listener stopped: {}", err);
+                return;
+            }
+            Err(err) => println!("could not accept UDP connection: {}", err),
+        }
+    }
+}
+
+/// Whether a WebSocket client asked for the JSON subprotocol, binary frames are used otherwise
+fn requests_json(request: &Request) -> bool {
+    request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == JSON_SUBPROTOCOL)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, Bytes, BytesMut};
+    use futures::{SinkExt, StreamExt};
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+        task::JoinHandle,
+    };
+    use tokio_rustls::{
+        client,
+        rustls::{
+            pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+            ClientConfig, RootCertStore,
+        },
+        TlsConnector,
+    };
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::{
+        networking::{packet_type::MESSAGE, raw_packet::RawPacket},
+        server::{connection::packet_codec, testing},
+    };
+
+    struct Certificates {
+        server: Arc<rustls::ServerConfig>,
+        client_certificate: CertificateDer<'static>,
+        client: ClientConfig,
+        anonymous_client: ClientConfig,
+    }
+
+    /// A certificate authority issuing the server certificate and a client certificate
+    fn certificates() -> Certificates {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let authority = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(authority.der().clone()).unwrap();
+
+        let issue = |name: &str| {
+            let key = KeyPair::generate().unwrap();
+            let certificate = CertificateParams::new(vec![name.into()])
+                .unwrap()
+                .signed_by(&key, &authority)
+                .unwrap();
+            let key = PrivatePkcs8KeyDer::from(key.serialize_der()).into();
+            (certificate.der().clone(), key)
+        };
+        let (server_certificate, server_key) = issue("localhost");
+        let (client_certificate, client_key) = issue("bot");
+
+        Certificates {
+            server: tls::server_config(vec![server_certificate], server_key, Some(roots.clone()))
+                .unwrap(),
+            client: ClientConfig::builder()
+                .with_root_certificates(roots.clone())
+                .with_client_auth_cert(vec![client_certificate.clone()], client_key)
+                .unwrap(),
+            anonymous_client: ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+            client_certificate,
+        }
+    }
+
+    /// Accepts the next client of a listener like the server does
+    async fn listen(
+        tls: &Arc<rustls::ServerConfig>,
+    ) -> (
+        SocketAddr,
+        JoinHandle<io::Result<Box<dyn ConnectionHandle + Send + Sync>>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let acceptor = TlsAcceptor::from(tls.clone());
+        let accepted = tokio::spawn(async move {
+            let (stream, socket) = listener.accept().await?;
+            let context = Server::without_listener(PacketRegistry::default()).context();
+            let connection = accept_tcp(stream, socket, Some(acceptor), None, &context).await?;
+            Ok(connection.expect("not an HTTP request"))
+        });
+
+        (address, accepted)
+    }
+
+    async fn connect_tls(
+        address: SocketAddr,
+        config: ClientConfig,
+    ) -> client::TlsStream<TcpStream> {
+        let stream = TcpStream::connect(address).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    /// A packet with its length prefix, as written on stream transports
+    fn frame(packet: &RawPacket) -> Vec<u8> {
+        let encoded = packet.encode();
+        let mut frame = BytesMut::new();
+        frame.put_u32(encoded.len() as u32);
+        frame.put_slice(&encoded);
+        frame.to_vec()
+    }
+
+    #[tokio::test]
+    async fn tls_clients_are_told_apart_from_plaintext() {
+        let certificates = certificates();
+        let packet = RawPacket::new(MESSAGE, "hello".into());
+
+        let (address, accepted) = listen(&certificates.server).await;
+        let mut client = connect_tls(address, certificates.client).await;
+        client.write_all(&frame(&packet)).await.unwrap();
+        let mut server = accepted.await.unwrap().unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), packet);
+        assert_eq!(
+            server.client_certificate(),
+            Some(&certificates.client_certificate)
+        );
+
+        // TLS is optional, plaintext clients still connect to the same listener
+        let (address, accepted) = listen(&certificates.server).await;
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&frame(&packet)).await.unwrap();
+        let mut server = accepted.await.unwrap().unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), packet);
+        assert_eq!(server.client_certificate(), None);
+    }
+
+    #[tokio::test]
+    async fn secure_websocket_clients_are_accepted() {
+        let certificates = certificates();
+        let packet = RawPacket::new(MESSAGE, "hello".into());
+
+        let (address, accepted) = listen(&certificates.server).await;
+        let stream = connect_tls(address, certificates.anonymous_client).await;
+        let (mut client, _) = tokio_tungstenite::client_async("wss://localhost", stream)
+            .await
+            .unwrap();
+        let mut server = accepted.await.unwrap().unwrap();
+
+        client.send(Message::binary(frame(&packet))).await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), packet);
+        assert_eq!(server.client_certificate(), None);
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrades_are_told_apart_from_polls() {
+        let certificates = certificates();
+        let packet = RawPacket::new(MESSAGE, "hello".into());
+
+        // the path of the fallback does not make an upgrade a poll
+        let (address, accepted) = listen(&certificates.server).await;
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut client, _) = tokio_tungstenite::client_async("ws://localhost/rustchat", stream)
+            .await
+            .unwrap();
+        let mut server = accepted.await.unwrap().unwrap();
+        client.send(Message::binary(frame(&packet))).await.unwrap();
+        assert_eq!(server.read_packet().await.unwrap(), packet);
+
+        let poll = format!("GET /rustchat/{} HTTP/1.1\r\n\r\n", uuid::Uuid::new_v4());
+        assert_eq!(Protocol::sniff(poll.as_bytes()), Protocol::Http);
+        assert!(Protocol::is_undecided(
+            b"GET /rustchat HTTP/1.1\r\nUpgrade: websocket"
+        ));
+    }
+
+    /// Sends an HTTP request over its own connection, returning the head and the body of the response
+    async fn http_request(address: SocketAddr, head: &str, body: &[u8]) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let head = format!("{head}\r\nContent-Length: {}\r\n\r\n", body.len());
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let body = response.split_off(end + 4);
+        (String::from_utf8(response).unwrap(), body)
+    }
+
+    #[tokio::test]
+    async fn http_clients_are_served_on_the_tcp_listener() {
+        let mut server = Server::new("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().unwrap();
+        tokio::spawn(async move { server.run().await.map_err(|err| err.to_string()) });
+
+        let (head, id) = http_request(address, "POST /rustchat HTTP/1.1", b"").await;
+        assert!(head.starts_with("HTTP/1.1 201 Created"));
+        let path = format!("/rustchat/{}", String::from_utf8(id).unwrap());
+
+        let hello = RawPacket::from(testing::hello("tester"));
+        let (head, _) =
+            http_request(address, &format!("POST {path} HTTP/1.1"), &frame(&hello)).await;
+        assert!(head.starts_with("HTTP/1.1 204 No Content"));
+
+        let (head, body) = http_request(address, &format!("GET {path} HTTP/1.1"), b"").await;
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        let welcome = RawPacket::decode(Bytes::copy_from_slice(&body[4..])).unwrap();
+        assert!(matches!(Packet::from(welcome), Ok(Packet::Welcome(_))));
+    }
+
+    #[tokio::test]
+    async fn first_bytes_are_awaited_until_they_tell_the_protocol() {
+        let mut server = Server::new("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().unwrap();
+        tokio::spawn(async move { server.run().await.map_err(|err| err.to_string()) });
+
+        // the start of a fallback request that could be a WebSocket upgrade as well
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"GET /rust").await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        let rest = format!("chat/{} HTTP/1.1\r\n\r\n", uuid::Uuid::new_v4());
+        stream.write_all(rest.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(response.ends_with("unknown session"));
+
+        // clients leaving before saying anything are not served
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(TcpStream::connect(address).await.unwrap());
+        let (stream, socket) = listener.accept().await.unwrap();
+        let context = Server::without_listener(PacketRegistry::default()).context();
+        let accepted = accept_tcp(stream, socket, None, None, &context).await;
+        assert_eq!(
+            accepted.err().map(|err| err.kind()),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[tokio::test]
+    async fn proxied_clients_are_known_by_their_own_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let context = Server::without_listener(PacketRegistry::default()).context();
+        let packet = RawPacket::new(MESSAGE, "hello".into());
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 203.0.113.9 127.0.0.1 51000 7878\r\n")
+            .await
+            .unwrap();
+        client.write_all(&frame(&packet)).await.unwrap();
+        let (stream, socket) = listener.accept().await.unwrap();
+        let proxies = TrustedProxies::parse("127.0.0.0/8").unwrap();
+        let mut server = accept_tcp(stream, socket, None, Some(&proxies), &context)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.socket(), "203.0.113.9:51000".parse().unwrap());
+        assert_eq!(server.read_packet().await.unwrap(), packet);
+
+        // anyone else could claim any address
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 203.0.113.9 127.0.0.1 51000 7878\r\n")
+            .await
+            .unwrap();
+        let (stream, socket) = listener.accept().await.unwrap();
+        let proxies = TrustedProxies::parse("10.0.0.0/8").unwrap();
+        let server = accept_tcp(stream, socket, None, Some(&proxies), &context)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(server.socket(), socket);
+
+        // trusted proxies have to send the header
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&frame(&packet)).await.unwrap();
+        let (stream, socket) = listener.accept().await.unwrap();
+        let proxies = TrustedProxies::parse("127.0.0.1").unwrap();
+        let accepted = accept_tcp(stream, socket, None, Some(&proxies), &context).await;
+        assert_eq!(
+            accepted.err().map(|err| err.kind()),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_and_drains_clients() {
+        let mut server = Server::new("127.0.0.1:0").await.unwrap();
+        server.set_shutdown(ShutdownSettings {
+            reconnect_after: Some(Duration::from_secs(5)),
+            ..ShutdownSettings::default()
+        });
+        let address = server.local_addr().unwrap().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = tokio::spawn(async move {
+            let signal = async {
+                let _ = stopped.await;
+            };
+            server
+                .run_until(signal)
+                .await
+                .map_err(|err| err.to_string())
+        });
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut client = Framed::new(stream, packet_codec());
+        let hello = RawPacket::from(testing::hello("tester"));
+        client.send(hello.encode()).await.unwrap();
+        let mut read = async || {
+            let frame = client.next().await?.unwrap();
+            Some(Packet::from(RawPacket::decode(frame.freeze()).unwrap()).unwrap())
+        };
+        assert!(matches!(read().await, Some(Packet::Welcome(_))));
+
+        stop.send(()).unwrap();
+        let Some(Packet::ServerShutdown(notice)) = read().await else {
+            panic!("expected a SERVER_SHUTDOWN");
+        };
+        assert_eq!(notice.reconnect_after, Some(5));
+        assert!(read().await.is_none());
+        running.await.unwrap().unwrap();
+
+        // the listener is closed
+        assert!(TcpStream::connect(address).await.is_err());
+    }
+}
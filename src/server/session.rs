@@ -0,0 +1,331 @@
+//! Sessions let a client that lost its connection reconnect as the same user. The packets written in a session are
+//! numbered and the last ones are kept, so those the client missed are written again once it resumes.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use snafu::{ensure, OptionExt, Snafu};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::networking::{handshake::Handshake, raw_packet::RawPacket};
+
+use super::connection::{PacketReader, PacketWriter};
+
+/// How long sessions outlive their connection, and how much of them can be replayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionSettings {
+    /// How long a session waits for its client to reconnect
+    pub grace_period: Duration,
+
+    /// How many of the last packets written in a session are kept for replay
+    pub max_replay: usize,
+}
+
+impl SessionSettings {
+    /// The grace period as sent to clients in SESSION
+    pub fn grace_period_secs(&self) -> u32 {
+        self.grace_period.as_secs().try_into().unwrap_or(u32::MAX)
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::from_secs(60),
+            max_replay: 256,
+        }
+    }
+}
+
+#[derive(Debug, Snafu)]
+pub enum SessionError {
+    #[snafu(display("no session has this token, it may have expired"))]
+    UnknownSession,
+
+    #[snafu(display("the packets after sequence number {last_sequence} are no longer kept"))]
+    ReplayUnavailable { last_sequence: u64 },
+}
+
+/// The packets written to a user, numbered once it opened a session
+#[derive(Debug, Default)]
+pub struct Outbox {
+    /// Packets are only numbered and kept in a session
+    recording: bool,
+
+    /// How many packets are kept
+    capacity: usize,
+
+    /// The sequence number of the first kept packet
+    first_sequence: u64,
+    packets: VecDeque<RawPacket>,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Numbers the packets written from now on, starting at 1, and keeps the last `capacity` of them
+    pub fn start(&mut self, capacity: usize) {
+        self.recording = true;
+        self.capacity = capacity;
+        self.first_sequence = 1;
+        self.packets.clear();
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Numbers a packet about to be written. It is kept before it is written, so a packet lost with the connection
+    /// is replayed too.
+    pub fn record(&mut self, packet: &RawPacket) {
+        if !self.recording {
+            return;
+        }
+
+        self.packets.push_back(packet.clone());
+        if self.packets.len() > self.capacity {
+            self.packets.pop_front();
+            self.first_sequence += 1;
+        }
+    }
+
+    /// The sequence number of the next packet written
+    pub fn next_sequence(&self) -> u64 {
+        self.first_sequence + self.packets.len() as u64
+    }
+
+    /// The packets written after `last_sequence`, or `None` when some of them are no longer kept or the client claims
+    /// to have received packets that were never written
+    pub fn replay(&self, last_sequence: u64) -> Option<Vec<RawPacket>> {
+        // the sequence number comes from the client, it may be anything
+        let next_sequence = last_sequence.checked_add(1)?;
+        if !self.recording
+            || next_sequence < self.first_sequence
+            || last_sequence >= self.next_sequence()
+        {
+            return None;
+        }
+
+        let skipped = (next_sequence - self.first_sequence) as usize;
+        Some(self.packets.iter().skip(skipped).cloned().collect())
+    }
+}
+
+/// A session a client may resume, as found by [`SessionStore::find`]
+pub struct Resumable {
+    /// The user owning the session
+    pub owner: Uuid,
+
+    /// Where to send the user the new connection
+    pub resumes: mpsc::Sender<Resume>,
+
+    /// The sequence number of the first packet replayed to the client
+    pub next_sequence: u64,
+}
+
+/// A new connection taking over a session, sent to the user that owns it
+pub struct Resume {
+    pub reader: Box<dyn PacketReader>,
+    pub writer: Box<dyn PacketWriter>,
+    pub socket: SocketAddr,
+
+    /// What was agreed with the client on the new connection
+    pub handshake: Handshake,
+
+    /// The last packet the client received, those after it are replayed
+    pub last_sequence: u64,
+}
+
+struct Entry {
+    user: Uuid,
+    outbox: Arc<Mutex<Outbox>>,
+    resumes: mpsc::Sender<Resume>,
+}
+
+/// The open sessions, found by their token when a client resumes one.
+///
+/// A session belongs to its user, which keeps running while its client is away and is handed the new connection.
+pub struct SessionStore {
+    settings: SessionSettings,
+    sessions: Mutex<HashMap<String, Entry>>,
+}
+
+impl SessionStore {
+    pub fn new(settings: SessionSettings) -> Self {
+        Self {
+            settings,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn settings(&self) -> SessionSettings {
+        self.settings
+    }
+
+    /// Opens a session for a user, whose written packets are numbered in `outbox`
+    pub fn open(self: &Arc<Self>, user: Uuid, outbox: Arc<Mutex<Outbox>>) -> Session {
+        let token = Uuid::new_v4().simple().to_string();
+        let (resumes, receiver) = mpsc::channel(1);
+        self.sessions.lock().unwrap().insert(
+            token.clone(),
+            Entry {
+                user,
+                outbox,
+                resumes,
+            },
+        );
+
+        Session {
+            token,
+            settings: self.settings,
+            store: self.clone(),
+            resumes: receiver,
+        }
+    }
+
+    /// Finds the session with the token, checking that what the client missed can be replayed
+    pub fn find(&self, token: &str, last_sequence: u64) -> Result<Resumable, SessionError> {
+        let next_sequence = last_sequence
+            .checked_add(1)
+            .context(ReplayUnavailableSnafu { last_sequence })?;
+
+        let sessions = self.sessions.lock().unwrap();
+        let entry = sessions.get(token).context(UnknownSessionSnafu)?;
+        ensure!(
+            entry.outbox.lock().unwrap().replay(last_sequence).is_some(),
+            ReplayUnavailableSnafu { last_sequence }
+        );
+        Ok(Resumable {
+            owner: entry.user,
+            resumes: entry.resumes.clone(),
+            next_sequence,
+        })
+    }
+
+    /// How many sessions are open, attached to a connection or not
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        Self::new(SessionSettings::default())
+    }
+}
+
+/// The session of a user, closed when it is dropped
+pub struct Session {
+    token: String,
+    settings: SessionSettings,
+    store: Arc<SessionStore>,
+
+    /// The connections resuming the session
+    resumes: mpsc::Receiver<Resume>,
+}
+
+impl Session {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    pub fn settings(&self) -> SessionSettings {
+        self.settings
+    }
+
+    /// Waits for a connection resuming the session
+    pub async fn resumed(&mut self) -> Resume {
+        match self.resumes.recv().await {
+            Some(resume) => resume,
+
+            // the store keeps a sender until the session is dropped
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.store.sessions.lock().unwrap().remove(&self.token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::packet_type::MESSAGE;
+
+    fn packets(outbox: &mut Outbox, count: u8) {
+        for index in 0..count {
+            outbox.record(&RawPacket::new(MESSAGE, vec![index].into()));
+        }
+    }
+
+    fn payloads(replay: Option<Vec<RawPacket>>) -> Option<Vec<u8>> {
+        replay.map(|packets| packets.iter().map(|packet| packet.payload[0]).collect())
+    }
+
+    #[test]
+    fn outboxes_replay_the_kept_packets_after_a_sequence_number() {
+        let mut outbox = Outbox::new();
+        packets(&mut outbox, 2);
+        assert_eq!(
+            outbox.replay(0),
+            None,
+            "packets are only numbered in a session"
+        );
+
+        outbox.start(3);
+        packets(&mut outbox, 5);
+        assert_eq!(outbox.next_sequence(), 6);
+
+        // packets 3 to 5 are kept
+        assert_eq!(payloads(outbox.replay(2)), Some(vec![2, 3, 4]));
+        assert_eq!(payloads(outbox.replay(4)), Some(vec![4]));
+        assert_eq!(payloads(outbox.replay(5)), Some(vec![]));
+        assert_eq!(payloads(outbox.replay(1)), None);
+        assert_eq!(payloads(outbox.replay(6)), None);
+        assert_eq!(payloads(outbox.replay(u64::MAX)), None);
+    }
+
+    #[test]
+    fn sessions_are_closed_when_dropped() {
+        let store = Arc::new(SessionStore::default());
+        let outbox = Arc::new(Mutex::new(Outbox::new()));
+        outbox.lock().unwrap().start(1);
+        let user = Uuid::new_v4();
+
+        let session = store.open(user, outbox);
+        let found = store.find(session.token(), 0).unwrap();
+        assert_eq!((found.owner, found.next_sequence), (user, 1));
+        assert!(matches!(
+            store.find(session.token(), 1),
+            Err(SessionError::ReplayUnavailable { last_sequence: 1 })
+        ));
+        assert!(matches!(
+            store.find(session.token(), u64::MAX),
+            Err(SessionError::ReplayUnavailable {
+                last_sequence: u64::MAX
+            })
+        ));
+
+        let token = session.token().to_owned();
+        drop(session);
+        assert!(store.is_empty());
+        assert!(matches!(
+            store.find(&token, 0),
+            Err(SessionError::UnknownSession)
+        ));
+    }
+}
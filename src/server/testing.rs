@@ -0,0 +1,550 @@
+//! End-to-end fixtures: a [`TestServer`] without listeners, and [`TestClient`]s connected to it over memory
+//! connections, speaking the protocol like real clients do.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::{io::DuplexStream, time::timeout};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use uuid::Uuid;
+
+use crate::networking::{
+    handshake::{Capabilities, PROTOCOL_VERSION},
+    packet::Packet,
+    packet_type::{
+        DestinationType, HelloPacket, JoinPacket, LoginPacket, MessagePacket, MessagePayload,
+        PingPacket, SignedInPacket,
+    },
+    raw_packet::RawPacket,
+};
+
+use super::{
+    connection::{memory::MemoryConnection, packet_codec, LOOPBACK_ADDRESS},
+    database::Database,
+    registry::PacketRegistry,
+    server::Server,
+    user::UserHandle,
+};
+
+/// How long a client waits for a packet before the test fails
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The password every test client signs in with
+const PASSWORD: &str = "secret";
+
+/// The HELLO of a client speaking the current protocol version without any capability
+pub fn hello(client_name: &str) -> Packet {
+    hello_with(client_name, Capabilities::empty())
+}
+
+/// The HELLO of a client speaking the current protocol version with the given capabilities
+pub fn hello_with(client_name: &str, capabilities: Capabilities) -> Packet {
+    Packet::Hello(HelloPacket {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: client_name.into(),
+        client_version: "0.1.0".into(),
+        capabilities,
+    })
+}
+
+pub struct TestServer {
+    server: Server,
+}
+
+impl TestServer {
+    pub fn new() -> Self {
+        Self::with_registry(PacketRegistry::default())
+    }
+
+    pub fn with_registry(registry: PacketRegistry) -> Self {
+        Self {
+            server: Server::without_listener(registry),
+        }
+    }
+
+    /// Connects a client and completes the handshake
+    pub async fn connect(&self) -> TestClient {
+        self.connect_from(LOOPBACK_ADDRESS).await
+    }
+
+    /// Connects a client from the given address and completes the handshake
+    pub async fn connect_from(&self, address: SocketAddr) -> TestClient {
+        self.handshake(address, hello("test-client")).await
+    }
+
+    /// Connects a client and completes the handshake, agreeing on the given capabilities
+    pub async fn connect_with(&self, capabilities: Capabilities) -> TestClient {
+        self.handshake(LOOPBACK_ADDRESS, hello_with("test-client", capabilities))
+            .await
+    }
+
+    async fn handshake(&self, address: SocketAddr, hello: Packet) -> TestClient {
+        let mut client = self.connect_raw_from(address).await;
+        client.send(hello).await;
+        match client.recv().await {
+            Packet::Welcome(_) => client,
+            packet => panic!("expected a WELCOME, got {:?}", packet),
+        }
+    }
+
+    /// Connects a client from the given address that has not sent HELLO yet
+    pub async fn connect_raw_from(&self, address: SocketAddr) -> TestClient {
+        let (connection, stream) = MemoryConnection::pair_from(address);
+        let handle = self.server.connect(Box::new(connection)).await;
+        TestClient {
+            handle,
+            stream: Framed::new(stream, packet_codec()),
+        }
+    }
+
+    pub fn database(&self) -> &Arc<Database> {
+        self.server.database()
+    }
+
+    pub async fn shutdown(mut self) {
+        self.server.shutdown().await.unwrap();
+    }
+}
+
+pub struct TestClient {
+    handle: UserHandle,
+    stream: Framed<DuplexStream, LengthDelimitedCodec>,
+}
+
+impl TestClient {
+    /// The id the server gave to this client
+    pub fn id(&self) -> Uuid {
+        self.handle.id()
+    }
+
+    pub async fn send(&mut self, packet: Packet) {
+        self.send_raw(RawPacket::from(packet)).await;
+    }
+
+    pub async fn send_raw(&mut self, packet: RawPacket) {
+        self.stream.send(packet.encode()).await.unwrap();
+    }
+
+    /// Signs in to the account of `username`, registering it the first time, and returns the id of the account
+    pub async fn login(&mut self, username: &str) -> i32 {
+        self.send(Packet::SignIn(LoginPacket {
+            username: username.into(),
+            password: PASSWORD.into(),
+        }))
+        .await;
+        match self.recv().await {
+            Packet::SignedIn(SignedInPacket { user_id }) => user_id,
+            packet => panic!("expected a SIGNED_IN, got {:?}", packet),
+        }
+    }
+
+    /// Subscribes to a channel, returning once the server handled it
+    pub async fn join(&mut self, channel: i32) {
+        self.send(Packet::Join(JoinPacket { channel })).await;
+        self.sync().await;
+    }
+
+    /// Waits until the server handled every packet sent before, packets of a connection are handled in order
+    pub async fn sync(&mut self) {
+        self.send(Packet::Ping(PingPacket { nonce: 0 })).await;
+        match self.recv().await {
+            Packet::Pong(_) => {}
+            packet => panic!("expected a PONG, got {:?}", packet),
+        }
+    }
+
+    pub async fn send_message(
+        &mut self,
+        destination_type: DestinationType,
+        destination: i32,
+        text: &str,
+    ) {
+        self.send(Packet::Message(MessagePacket {
+            destination,
+            destination_type,
+            message_payload: MessagePayload::Text(text.into()),
+        }))
+        .await;
+    }
+
+    /// Waits for the next packet, failing the test if none arrives in time
+    pub async fn recv(&mut self) -> Packet {
+        let raw = self.recv_raw().await;
+        Packet::from(raw).unwrap()
+    }
+
+    pub async fn recv_raw(&mut self) -> RawPacket {
+        let frame = timeout(RECEIVE_TIMEOUT, self.stream.next())
+            .await
+            .expect("no packet received in time")
+            .expect("the connection was closed")
+            .unwrap();
+        RawPacket::decode(frame.freeze()).unwrap()
+    }
+
+    /// Fails the test if a packet arrives within a short delay
+    pub async fn assert_silent(&mut self) {
+        if let Ok(frame) = timeout(Duration::from_millis(100), self.stream.next()).await {
+            panic!("expected no packet, got {:?}", frame);
+        }
+    }
+
+    /// Waits for the server to close the connection, failing the test if a packet arrives first
+    pub async fn expect_closed(&mut self) {
+        let frame = timeout(RECEIVE_TIMEOUT, self.stream.next())
+            .await
+            .expect("the connection was not closed in time");
+        assert!(frame.is_none(), "expected the connection to be closed");
+    }
+
+    /// Closes the connection like a client going away
+    pub async fn disconnect(mut self) {
+        SinkExt::<Bytes>::close(&mut self.stream).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::networking::packet_type::{
+        ErrorCode, FileBeginPacket, FileChunkPacket, FileCommitPacket, ResumePacket, SessionPacket,
+        JOIN, MESSAGE, SIGN_IN,
+    };
+
+    #[tokio::test]
+    async fn messages_reach_the_subscribers_of_their_channel() {
+        let server = TestServer::new();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        alice.login("alice").await;
+        bob.login("bob").await;
+        alice.join(1).await;
+        bob.join(2).await;
+
+        let mut carol = server.connect().await;
+        carol.login("carol").await;
+        carol
+            .send_message(DestinationType::Channel, 1, "hello")
+            .await;
+
+        let Packet::Message(message) = alice.recv().await else {
+            panic!("expected a MESSAGE");
+        };
+        assert_eq!(message.destination, 1);
+        assert_eq!(message.destination_type, DestinationType::Channel);
+        assert!(matches!(message.message_payload, MessagePayload::Text(text) if text == "hello"));
+        bob.assert_silent().await;
+
+        // subscribers do not get their own messages back
+        carol.join(1).await;
+        carol
+            .send_message(DestinationType::Channel, 1, "again")
+            .await;
+        assert_text(alice.recv().await, "again");
+        carol.assert_silent().await;
+    }
+
+    #[tokio::test]
+    async fn messages_reach_signed_in_users() {
+        let server = TestServer::new();
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        let alice_id = alice.login("alice").await;
+        bob.login("bob").await;
+
+        bob.send_message(DestinationType::User, alice_id, "hi alice")
+            .await;
+        let Packet::Message(message) = alice.recv().await else {
+            panic!("expected a MESSAGE");
+        };
+        assert_eq!(message.destination, alice_id);
+        assert_eq!(message.destination_type, DestinationType::User);
+        assert_text(Packet::Message(message), "hi alice");
+
+        // signing in again with the same password gives the same account
+        let mut other = server.connect().await;
+        assert_eq!(other.login("alice").await, alice_id);
+    }
+
+    fn assert_error(packet: Packet, code: ErrorCode, packet_type: u8) {
+        let Packet::Error(error) = packet else {
+            panic!("expected an ERROR, got {:?}", packet);
+        };
+        assert_eq!(error.code, code);
+        assert_eq!(error.packet_type, packet_type);
+    }
+
+    #[tokio::test]
+    async fn messages_need_a_signed_in_sender_and_a_known_destination() {
+        let server = TestServer::new();
+        let mut client = server.connect().await;
+        client
+            .send_message(DestinationType::Channel, 1, "hello")
+            .await;
+        assert_error(client.recv().await, ErrorCode::NotSignedIn, MESSAGE);
+        client.send(Packet::Join(JoinPacket { channel: 1 })).await;
+        assert_error(client.recv().await, ErrorCode::NotSignedIn, JOIN);
+
+        client.login("alice").await;
+        client
+            .send_message(DestinationType::Channel, 1, "hello")
+            .await;
+        assert_error(client.recv().await, ErrorCode::UnknownDestination, MESSAGE);
+        client
+            .send_message(DestinationType::User, 99, "hello")
+            .await;
+        assert_error(client.recv().await, ErrorCode::UnknownDestination, MESSAGE);
+
+        let mut other = server.connect().await;
+        other
+            .send(Packet::SignIn(LoginPacket {
+                username: "alice".into(),
+                password: "wrong".into(),
+            }))
+            .await;
+        assert_error(other.recv().await, ErrorCode::InvalidCredentials, SIGN_IN);
+    }
+
+    #[tokio::test]
+    async fn clients_are_told_about_packets_the_server_can_not_handle() {
+        let server = TestServer::new();
+        let mut client = server.connect().await;
+
+        client
+            .send_raw(RawPacket::new(200, Bytes::new()).with_request_id(Some(7)))
+            .await;
+        let error = client.recv_raw().await;
+        assert_eq!(error.request_id, Some(7));
+        let Ok(Packet::Error(error)) = Packet::from(error) else {
+            panic!("expected an ERROR");
+        };
+        assert_eq!(error.code, ErrorCode::UnknownPacketType);
+        assert_eq!(error.packet_type, 200);
+    }
+
+    #[tokio::test]
+    async fn file_transfers_need_file_chunking() {
+        let server = TestServer::new();
+        let mut client = server.connect().await;
+        let transfer_id = Uuid::new_v4();
+
+        let packets = [
+            Packet::FileChunk(FileChunkPacket {
+                transfer_id,
+                offset: 0,
+                data: Bytes::from_static(b"hello"),
+            }),
+            Packet::FileCommit(FileCommitPacket { transfer_id }),
+        ];
+        for packet in packets {
+            client.send(packet).await;
+            let Packet::FileAbort(abort) = client.recv().await else {
+                panic!("expected a FILE_ABORT");
+            };
+            assert_eq!(abort.transfer_id, transfer_id);
+            assert_eq!(abort.reason, "file chunking was not negotiated");
+        }
+    }
+
+    #[tokio::test]
+    async fn committed_files_are_delivered_to_their_destination() {
+        let server = TestServer::new();
+        let mut uploader = server.connect_with(Capabilities::FILE_CHUNKING).await;
+        let mut alice = server.connect().await;
+        let mut bob = server.connect().await;
+        uploader.login("uploader").await;
+        alice.login("alice").await;
+        bob.login("bob").await;
+        alice.join(1).await;
+
+        let transfer_id = Uuid::new_v4();
+        let content = b"shared with the channel";
+        let packets = [
+            Packet::FileBegin(FileBeginPacket {
+                transfer_id,
+                destination: 1,
+                destination_type: DestinationType::Channel,
+                name: "notes.txt".into(),
+                size: content.len() as u64,
+                sha256: Bytes::copy_from_slice(&Sha256::digest(content)),
+            }),
+            Packet::FileChunk(FileChunkPacket {
+                transfer_id,
+                offset: 0,
+                data: Bytes::from_static(content),
+            }),
+        ];
+        for packet in packets {
+            uploader.send(packet).await;
+            assert!(matches!(uploader.recv().await, Packet::FileAck(_)));
+        }
+        uploader
+            .send(Packet::FileCommit(FileCommitPacket { transfer_id }))
+            .await;
+
+        // the uploader gets the reference as the answer, the subscribers of the channel get it as a message
+        for client in [&mut uploader, &mut alice] {
+            let Packet::Message(message) = client.recv().await else {
+                panic!("expected a MESSAGE");
+            };
+            assert_eq!(message.destination, 1);
+            assert_eq!(message.destination_type, DestinationType::Channel);
+            assert!(
+                matches!(message.message_payload, MessagePayload::FileReference(id) if id == transfer_id)
+            );
+        }
+        uploader.assert_silent().await;
+        bob.assert_silent().await;
+    }
+
+    #[tokio::test]
+    async fn disconnected_clients_leave_the_database_and_their_channels() {
+        let server = TestServer::new();
+        let mut client = server.connect().await;
+        let id = client.id();
+        let account = client.login("alice").await;
+        client.join(1).await;
+        assert_eq!(server.database().total_clients().await, 1);
+
+        client.disconnect().await;
+        timeout(RECEIVE_TIMEOUT, async {
+            while server.database().get_client(&id).await.is_some() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the client was not removed");
+        let channel = server.database().get_channel(1).await.unwrap();
+        assert!(!channel.is_subscribed(&id).await);
+        assert!(server.database().get_user(account).await.is_none());
+    }
+
+    fn assert_text(packet: Packet, expected: &str) {
+        let Packet::Message(message) = packet else {
+            panic!("expected a MESSAGE, got {:?}", packet);
+        };
+        assert!(matches!(message.message_payload, MessagePayload::Text(text) if text == expected));
+    }
+
+    async fn resume(client: &mut TestClient, token: &str, last_sequence: u64) -> SessionPacket {
+        client
+            .send(Packet::Resume(ResumePacket {
+                token: token.into(),
+                last_sequence,
+            }))
+            .await;
+        match client.recv().await {
+            Packet::Session(session) => session,
+            packet => panic!("expected a SESSION, got {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn resumed_sessions_replay_what_the_client_missed() {
+        let server = TestServer::new();
+        let mut client = server.connect().await;
+        let session = resume(&mut client, "", 0).await;
+        assert!(!session.resumed);
+        assert_eq!(session.next_sequence, 1);
+
+        let id = client.id();
+        let handle = client.handle.clone();
+        client.login("alice").await;
+        client.join(1).await;
+        let mut sender = server.connect().await;
+        sender.login("bob").await;
+        for text in ["first", "second"] {
+            sender.send_message(DestinationType::Channel, 1, text).await;
+        }
+        assert_text(client.recv().await, "first");
+
+        // the user outlives its connection, and keeps receiving packets while the client is away
+        client.disconnect().await;
+        sender
+            .send_message(DestinationType::Channel, 1, "third")
+            .await;
+        sender.sync().await;
+        let channel = server.database().get_channel(1).await.unwrap();
+        assert!(channel.is_subscribed(&id).await);
+
+        let address = "192.0.2.1:4000".parse().unwrap();
+        let mut client = server.connect_from(address).await;
+        // SIGNED_IN and the PONG of the join came before the first message
+        let resumed = resume(&mut client, &session.token, 3).await;
+        assert!(resumed.resumed);
+        assert_eq!(resumed.next_sequence, 4);
+        for text in ["second", "third"] {
+            assert_text(client.recv().await, text);
+        }
+
+        // everyone holding the user sees where its client is now
+        assert_eq!(handle.socket(), address);
+
+        // the same user is subscribed to the channel
+        sender
+            .send_message(DestinationType::Channel, 1, "fourth")
+            .await;
+        assert_text(client.recv().await, "fourth");
+
+        // the connection was handed to the user of the session, the one created for it is gone
+        timeout(RECEIVE_TIMEOUT, async {
+            while server.database().total_clients().await > 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the user created for the resuming connection was not removed");
+        assert!(server.database().get_client(&id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn sequence_numbers_past_the_last_one_are_not_resumed() {
+        let server = TestServer::new();
+        let mut client = server.connect().await;
+        let session = resume(&mut client, "", 0).await;
+
+        // a sequence number that overflows is refused like any other that was never written
+        let mut other = server.connect().await;
+        let replaced = resume(&mut other, &session.token, u64::MAX).await;
+        assert!(!replaced.resumed);
+        assert_ne!(replaced.token, session.token);
+
+        // the sessions still work for everyone else
+        let mut client = server.connect().await;
+        let resumed = resume(&mut client, &session.token, 0).await;
+        assert!(resumed.resumed);
+        assert_eq!(resumed.next_sequence, 1);
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_are_replaced_by_new_ones() {
+        let server = TestServer::new();
+        let mut client = server.connect().await;
+        let session = resume(&mut client, "expired", 3).await;
+        assert!(!session.resumed);
+        assert_ne!(session.token, "expired");
+
+        // a connection belongs to a single session
+        client
+            .send(Packet::Resume(ResumePacket {
+                token: String::new(),
+                last_sequence: 0,
+            }))
+            .await;
+        client.assert_silent().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_clients_and_closes_their_connections() {
+        let server = TestServer::new();
+        let mut client = server.connect().await;
+
+        let shutdown = tokio::spawn(server.shutdown());
+        assert!(matches!(client.recv().await, Packet::ServerShutdown(_)));
+        client.expect_closed().await;
+        shutdown.await.unwrap();
+    }
+}
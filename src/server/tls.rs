@@ -0,0 +1,91 @@
+//! TLS termination for the TCP listener. Raw packet streams and WebSocket connections may both be wrapped in TLS, the
+//! server tells them apart from plaintext by the first bytes the client sends.
+
+use std::{io, path::Path, sync::Arc};
+
+use tokio::{io::BufReader, net::TcpStream};
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        server::WebPkiClientVerifier,
+        RootCertStore,
+    },
+    server,
+};
+
+use super::connection::{JsonWebSocketConnection, TcpConnection, Transport, WebSocketConnection};
+
+/// A TLS stream from a client. It is buffered, so the server can look at the first decrypted bytes to tell a WebSocket
+/// handshake from a packet.
+pub type TlsStream = BufReader<server::TlsStream<TcpStream>>;
+
+pub type TlsConnection = TcpConnection<TlsStream>;
+pub type TlsWebSocketConnection = WebSocketConnection<TlsStream>;
+pub type TlsJsonWebSocketConnection = JsonWebSocketConnection<TlsStream>;
+
+/// The TLS record type of a handshake message, the ClientHello is the first one a client sends
+const HANDSHAKE_RECORD: u8 = 0x16;
+
+/// The major version of every TLS record, from TLS 1.0 to 1.3
+const TLS_MAJOR_VERSION: u8 = 0x03;
+
+impl Transport for TlsStream {
+    fn client_certificate(&self) -> Option<&CertificateDer<'static>> {
+        let (_, connection) = self.get_ref().get_ref();
+        connection.peer_certificates()?.first()
+    }
+}
+
+/// Builds the TLS configuration for a certificate chain and its private key. When `client_roots` is set, clients may
+/// authenticate with a certificate issued by one of them, clients without a certificate are still accepted.
+pub fn server_config(
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<RootCertStore>,
+) -> Result<Arc<rustls::ServerConfig>, Box<dyn std::error::Error>> {
+    let builder = rustls::ServerConfig::builder();
+    let builder = match client_roots {
+        Some(roots) => builder.with_client_cert_verifier(
+            WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()?,
+        ),
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(builder.with_single_cert(certificates, key)?))
+}
+
+/// Reads a PEM certificate chain and private key
+pub fn load_pem(
+    certificate: &Path,
+    key: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Box<dyn std::error::Error>> {
+    let certificates =
+        CertificateDer::pem_file_iter(certificate)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    Ok((certificates, key))
+}
+
+/// Reads the PEM certificates of the authorities client certificates have to be issued by
+pub fn load_roots(path: &Path) -> Result<RootCertStore, Box<dyn std::error::Error>> {
+    let mut roots = RootCertStore::empty();
+    for certificate in CertificateDer::pem_file_iter(path)? {
+        roots.add(certificate?)?;
+    }
+    Ok(roots)
+}
+
+/// Whether the first bytes of a connection are the start of a TLS ClientHello
+pub fn is_client_hello(peeked: &[u8]) -> bool {
+    matches!(peeked, [HANDSHAKE_RECORD, TLS_MAJOR_VERSION, ..])
+}
+
+/// Completes the TLS handshake of a client
+pub async fn accept(
+    acceptor: &tokio_rustls::TlsAcceptor,
+    stream: TcpStream,
+) -> io::Result<TlsStream> {
+    Ok(BufReader::new(acceptor.accept(stream).await?))
+}
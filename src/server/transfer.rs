@@ -0,0 +1,568 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use uuid::Uuid;
+
+use crate::networking::packet_type::{DestinationType, FileBeginPacket};
+
+/// The largest file a client can upload
+pub const MAX_FILE_SIZE: u64 = 1 << 30;
+
+/// Uploads without any progress for this long are dropped the next time an upload begins
+pub const TRANSFER_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How many uploads a user may have in progress at once
+pub const MAX_TRANSFERS_PER_USER: usize = 4;
+
+/// How many uploads may be in progress at once across every user, each of them may take up to [`MAX_FILE_SIZE`]
+pub const MAX_TRANSFERS: usize = 64;
+
+const SHA256_LEN: usize = 32;
+
+#[derive(Debug, Snafu)]
+pub enum TransferError {
+    #[snafu(display("unknown file transfer {transfer_id}"))]
+    UnknownTransfer { transfer_id: Uuid },
+
+    #[snafu(display("file transfer {transfer_id} was started for another file"))]
+    TransferMismatch { transfer_id: Uuid },
+
+    #[snafu(display("file transfer {transfer_id} belongs to another user"))]
+    NotOwner { transfer_id: Uuid },
+
+    #[snafu(display("{limit} of your file transfers are already in progress"))]
+    TooManyTransfers { limit: usize },
+
+    #[snafu(display("the server already has {limit} file transfers in progress"))]
+    ServerBusy { limit: usize },
+
+    #[snafu(display("the file is {size} bytes, the limit is {limit} bytes"))]
+    FileTooLarge { size: u64, limit: u64 },
+
+    #[snafu(display(
+        "the file hash must be a {SHA256_LEN} bytes SHA-256 digest, got {len} bytes"
+    ))]
+    InvalidHash { len: usize },
+
+    #[snafu(display(
+        "a chunk of {len} bytes at offset {offset} does not fit in a file of {size} bytes"
+    ))]
+    ChunkOutOfBounds { offset: u64, len: usize, size: u64 },
+
+    #[snafu(display("only {received} of {size} bytes were received"))]
+    Incomplete { received: u64, size: u64 },
+
+    #[snafu(display("the file hash does not match"))]
+    HashMismatch,
+
+    #[snafu(display("could not store the file: {source}"))]
+    Storage { source: io::Error },
+}
+
+/// A file whose upload was committed and verified
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredFile {
+    pub id: Uuid,
+    pub name: String,
+    pub size: u64,
+    pub destination: i32,
+    pub destination_type: DestinationType,
+    pub path: PathBuf,
+}
+
+struct Transfer {
+    begin: FileBeginPacket,
+    file: File,
+
+    /// Every byte before this offset is written to the file
+    received: u64,
+    updated_at: Instant,
+}
+
+/// A transfer and the user it belongs to, only that user can continue it
+#[derive(Clone)]
+struct Upload {
+    owner: Uuid,
+    transfer: Arc<Mutex<Transfer>>,
+}
+
+/// Reassembles chunked uploads into files under a directory.
+///
+/// Uploads are not tied to a connection but to the user that began them, so a client that resumes its session can
+/// send the same FILE_BEGIN again and continue from the acknowledged offset. Partial uploads are stored as
+/// `<transfer id>.part` and renamed to `<transfer id>` once committed.
+pub struct TransferStore {
+    directory: PathBuf,
+    transfers: Mutex<HashMap<Uuid, Upload>>,
+    max_transfers: usize,
+    max_transfers_per_user: usize,
+}
+
+impl TransferStore {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            transfers: Mutex::new(HashMap::new()),
+            max_transfers: MAX_TRANSFERS,
+            max_transfers_per_user: MAX_TRANSFERS_PER_USER,
+        }
+    }
+
+    /// Starts or resumes an upload of `owner`, returning the offset the client has to continue from
+    pub async fn begin(&self, owner: Uuid, packet: FileBeginPacket) -> Result<u64, TransferError> {
+        ensure!(
+            packet.size <= MAX_FILE_SIZE,
+            FileTooLargeSnafu {
+                size: packet.size,
+                limit: MAX_FILE_SIZE
+            }
+        );
+        ensure!(
+            packet.sha256.len() == SHA256_LEN,
+            InvalidHashSnafu {
+                len: packet.sha256.len()
+            }
+        );
+
+        let mut transfers = self.transfers.lock().await;
+        self.drop_expired(&mut transfers).await;
+
+        if let Some(upload) = transfers.get(&packet.transfer_id).cloned() {
+            ensure!(
+                upload.owner == owner,
+                NotOwnerSnafu {
+                    transfer_id: packet.transfer_id
+                }
+            );
+
+            // never wait for a transfer while holding the map, commit locks them the other way around
+            drop(transfers);
+            let mut transfer = upload.transfer.lock().await;
+            ensure!(
+                transfer.begin == packet,
+                TransferMismatchSnafu {
+                    transfer_id: packet.transfer_id
+                }
+            );
+
+            transfer.updated_at = Instant::now();
+            return Ok(transfer.received);
+        }
+
+        let owned = transfers
+            .values()
+            .filter(|upload| upload.owner == owner)
+            .count();
+        ensure!(
+            owned < self.max_transfers_per_user,
+            TooManyTransfersSnafu {
+                limit: self.max_transfers_per_user
+            }
+        );
+        ensure!(
+            transfers.len() < self.max_transfers,
+            ServerBusySnafu {
+                limit: self.max_transfers
+            }
+        );
+
+        fs::create_dir_all(&self.directory)
+            .await
+            .context(StorageSnafu)?;
+        let file = File::create(self.partial_path(packet.transfer_id))
+            .await
+            .context(StorageSnafu)?;
+
+        let transfer = Transfer {
+            begin: packet,
+            file,
+            received: 0,
+            updated_at: Instant::now(),
+        };
+        transfers.insert(
+            transfer.begin.transfer_id,
+            Upload {
+                owner,
+                transfer: Arc::new(Mutex::new(transfer)),
+            },
+        );
+
+        Ok(0)
+    }
+
+    /// Appends a chunk to an upload, returning the acknowledged offset.
+    ///
+    /// A chunk that does not start at the acknowledged offset is ignored, the returned offset tells the client where
+    /// to continue from.
+    pub async fn write_chunk(
+        &self,
+        owner: Uuid,
+        transfer_id: Uuid,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<u64, TransferError> {
+        let transfer = self.get(owner, transfer_id).await?;
+        let mut transfer = transfer.lock().await;
+        if offset != transfer.received {
+            return Ok(transfer.received);
+        }
+
+        let size = transfer.begin.size;
+        ensure!(
+            offset + data.len() as u64 <= size,
+            ChunkOutOfBoundsSnafu {
+                offset,
+                len: data.len(),
+                size
+            }
+        );
+
+        transfer.file.write_all(data).await.context(StorageSnafu)?;
+        transfer.file.flush().await.context(StorageSnafu)?;
+        transfer.received += data.len() as u64;
+        transfer.updated_at = Instant::now();
+
+        Ok(transfer.received)
+    }
+
+    /// Verifies the hash of a complete upload and moves it to its final path.
+    ///
+    /// An upload whose hash does not match is deleted, an incomplete one can still be resumed.
+    pub async fn commit(
+        &self,
+        owner: Uuid,
+        transfer_id: Uuid,
+    ) -> Result<StoredFile, TransferError> {
+        let transfer = self.get(owner, transfer_id).await?;
+        let transfer = transfer.lock().await;
+        ensure!(
+            transfer.received == transfer.begin.size,
+            IncompleteSnafu {
+                received: transfer.received,
+                size: transfer.begin.size
+            }
+        );
+
+        let partial_path = self.partial_path(transfer_id);
+        let digest = hash_file(&partial_path).await.context(StorageSnafu)?;
+        if digest[..] != transfer.begin.sha256[..] {
+            self.remove(transfer_id).await;
+            return HashMismatchSnafu.fail();
+        }
+
+        let path = self.directory.join(transfer_id.to_string());
+        fs::rename(&partial_path, &path)
+            .await
+            .context(StorageSnafu)?;
+        self.transfers.lock().await.remove(&transfer_id);
+
+        let begin = &transfer.begin;
+        Ok(StoredFile {
+            id: transfer_id,
+            name: begin.name.clone(),
+            size: begin.size,
+            destination: begin.destination,
+            destination_type: begin.destination_type,
+            path,
+        })
+    }
+
+    /// Writes what was received of every upload to the disk
+    pub async fn flush(&self) -> Result<(), TransferError> {
+        let uploads: Vec<_> = self.transfers.lock().await.values().cloned().collect();
+        for upload in uploads {
+            upload
+                .transfer
+                .lock()
+                .await
+                .file
+                .sync_all()
+                .await
+                .context(StorageSnafu)?;
+        }
+        Ok(())
+    }
+
+    /// Cancels an upload and deletes what was received, unknown transfers are ignored
+    pub async fn abort(&self, owner: Uuid, transfer_id: Uuid) -> Result<(), TransferError> {
+        match self.get(owner, transfer_id).await {
+            Ok(_) => self.remove(transfer_id).await,
+            Err(TransferError::UnknownTransfer { .. }) => {}
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        owner: Uuid,
+        transfer_id: Uuid,
+    ) -> Result<Arc<Mutex<Transfer>>, TransferError> {
+        let upload = self
+            .transfers
+            .lock()
+            .await
+            .get(&transfer_id)
+            .cloned()
+            .context(UnknownTransferSnafu { transfer_id })?;
+        ensure!(upload.owner == owner, NotOwnerSnafu { transfer_id });
+        Ok(upload.transfer)
+    }
+
+    async fn remove(&self, transfer_id: Uuid) {
+        if self.transfers.lock().await.remove(&transfer_id).is_some() {
+            let _ = fs::remove_file(self.partial_path(transfer_id)).await;
+        }
+    }
+
+    async fn drop_expired(&self, transfers: &mut HashMap<Uuid, Upload>) {
+        let expired: Vec<Uuid> = transfers
+            .iter()
+            .filter(|(_, upload)| {
+                // a locked transfer is in use, so it is not expired
+                upload
+                    .transfer
+                    .try_lock()
+                    .is_ok_and(|transfer| transfer.updated_at.elapsed() > TRANSFER_TTL)
+            })
+            .map(|(transfer_id, _)| *transfer_id)
+            .collect();
+
+        for transfer_id in expired {
+            transfers.remove(&transfer_id);
+            let _ = fs::remove_file(self.partial_path(transfer_id)).await;
+        }
+    }
+
+    fn partial_path(&self, transfer_id: Uuid) -> PathBuf {
+        self.directory.join(format!("{transfer_id}.part"))
+    }
+}
+
+impl Default for TransferStore {
+    /// Stores uploads in the system temporary directory
+    fn default() -> Self {
+        Self::new(std::env::temp_dir().join("rustchat-transfers"))
+    }
+}
+
+async fn hash_file(path: &PathBuf) -> io::Result<[u8; SHA256_LEN]> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer).await? {
+            0 => return Ok(hasher.finalize().into()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn begin(transfer_id: Uuid, content: &[u8]) -> FileBeginPacket {
+        FileBeginPacket {
+            transfer_id,
+            destination: 42,
+            destination_type: DestinationType::Channel,
+            name: "notes.txt".into(),
+            size: content.len() as u64,
+            sha256: Bytes::copy_from_slice(&Sha256::digest(content)),
+        }
+    }
+
+    const OWNER: Uuid = Uuid::from_u128(1);
+
+    fn store() -> TransferStore {
+        TransferStore::new(std::env::temp_dir().join(format!("rustchat-test-{}", Uuid::new_v4())))
+    }
+
+    #[tokio::test]
+    async fn upload_resumes_from_acknowledged_offset() {
+        let store = store();
+        let transfer_id = Uuid::new_v4();
+        let content = b"hello world, this file is uploaded in chunks";
+
+        assert_eq!(
+            store
+                .begin(OWNER, begin(transfer_id, content))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            store
+                .write_chunk(OWNER, transfer_id, 0, &content[..10])
+                .await
+                .unwrap(),
+            10
+        );
+
+        // a chunk past the acknowledged offset is ignored, a reconnecting client resumes from the ack
+        assert_eq!(
+            store
+                .write_chunk(OWNER, transfer_id, 20, &content[20..])
+                .await
+                .unwrap(),
+            10
+        );
+        assert_eq!(
+            store
+                .begin(OWNER, begin(transfer_id, content))
+                .await
+                .unwrap(),
+            10
+        );
+        assert!(matches!(
+            store.commit(OWNER, transfer_id).await,
+            Err(TransferError::Incomplete { received: 10, .. })
+        ));
+
+        let size = content.len() as u64;
+        assert_eq!(
+            store
+                .write_chunk(OWNER, transfer_id, 10, &content[10..])
+                .await
+                .unwrap(),
+            size
+        );
+        let file = store.commit(OWNER, transfer_id).await.unwrap();
+        assert_eq!(file.size, size);
+        assert_eq!(fs::read(&file.path).await.unwrap(), content);
+        assert!(matches!(
+            store.write_chunk(OWNER, transfer_id, size, b"").await,
+            Err(TransferError::UnknownTransfer { .. })
+        ));
+
+        fs::remove_dir_all(&store.directory).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn commit_rejects_mismatched_hash() {
+        let store = store();
+        let transfer_id = Uuid::new_v4();
+
+        store
+            .begin(OWNER, begin(transfer_id, b"expected"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.write_chunk(OWNER, transfer_id, 0, b"too large").await,
+            Err(TransferError::ChunkOutOfBounds { .. })
+        ));
+        store
+            .write_chunk(OWNER, transfer_id, 0, b"tampered")
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.commit(OWNER, transfer_id).await,
+            Err(TransferError::HashMismatch)
+        ));
+        assert!(!store.partial_path(transfer_id).exists());
+
+        let mut other = begin(transfer_id, b"expected");
+        other.name = "other.txt".into();
+        store
+            .begin(OWNER, begin(transfer_id, b"expected"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.begin(OWNER, other).await,
+            Err(TransferError::TransferMismatch { .. })
+        ));
+
+        fs::remove_dir_all(&store.directory).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfers_belong_to_the_user_that_began_them() {
+        let store = store();
+        let transfer_id = Uuid::new_v4();
+        let other = Uuid::from_u128(2);
+        store
+            .begin(OWNER, begin(transfer_id, b"mine"))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            store.begin(other, begin(transfer_id, b"mine")).await,
+            Err(TransferError::NotOwner { .. })
+        ));
+        assert!(matches!(
+            store.write_chunk(other, transfer_id, 0, b"mine").await,
+            Err(TransferError::NotOwner { .. })
+        ));
+        assert!(matches!(
+            store.commit(other, transfer_id).await,
+            Err(TransferError::NotOwner { .. })
+        ));
+        assert!(matches!(
+            store.abort(other, transfer_id).await,
+            Err(TransferError::NotOwner { .. })
+        ));
+
+        // the upload is left as it was for its owner
+        store
+            .write_chunk(OWNER, transfer_id, 0, b"mine")
+            .await
+            .unwrap();
+        store.commit(OWNER, transfer_id).await.unwrap();
+        store.abort(OWNER, transfer_id).await.unwrap();
+
+        fs::remove_dir_all(&store.directory).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn transfers_in_progress_are_limited() {
+        let mut store = store();
+        store.max_transfers_per_user = 1;
+        store.max_transfers = 2;
+
+        store
+            .begin(OWNER, begin(Uuid::new_v4(), b"first"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store.begin(OWNER, begin(Uuid::new_v4(), b"second")).await,
+            Err(TransferError::TooManyTransfers { limit: 1 })
+        ));
+
+        let transfer_id = Uuid::new_v4();
+        let other = Uuid::from_u128(2);
+        store
+            .begin(other, begin(transfer_id, b"other"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            store
+                .begin(Uuid::from_u128(3), begin(Uuid::new_v4(), b"third"))
+                .await,
+            Err(TransferError::ServerBusy { limit: 2 })
+        ));
+
+        // an aborted upload makes room for another
+        store.abort(other, transfer_id).await.unwrap();
+        store
+            .begin(Uuid::from_u128(3), begin(Uuid::new_v4(), b"third"))
+            .await
+            .unwrap();
+
+        fs::remove_dir_all(&store.directory).await.unwrap();
+    }
+}
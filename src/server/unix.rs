@@ -0,0 +1,65 @@
+//! A Unix domain socket listener for bots and tools running on the same host as the server. Packets are framed like on
+//! TCP, and the credentials of the peer process are available to trust local connections.
+
+use std::{fs, io, os::unix::fs::FileTypeExt, path::Path};
+
+use tokio::net::{unix::UCred, UnixListener, UnixStream};
+
+use super::connection::{TcpConnection, Transport};
+
+pub type UnixConnection = TcpConnection<UnixStream>;
+
+impl Transport for UnixStream {
+    fn peer_credentials(&self) -> Option<UCred> {
+        self.peer_cred().ok()
+    }
+}
+
+/// Listens on a socket path, replacing the socket a previous server left behind
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::from(io::ErrorKind::AlreadyExists)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    UnixListener::bind(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::server::connection::{ConnectionHandle, LOOPBACK_ADDRESS};
+
+    #[tokio::test]
+    async fn unix_connection_exposes_peer_credentials() {
+        let path = env::temp_dir().join(format!("rustchat-test-{}.sock", process::id()));
+
+        // a socket left behind is replaced
+        drop(bind(&path).unwrap());
+        let listener = bind(&path).unwrap();
+
+        let client = UnixStream::connect(&path).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let server = UnixConnection::new(LOOPBACK_ADDRESS, stream);
+
+        let credentials = server.peer_credentials().unwrap();
+        assert_eq!(credentials.pid(), Some(process::id() as i32));
+
+        drop(client);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_does_not_replace_other_files() {
+        let path = env::temp_dir().join(format!("rustchat-test-{}.file", process::id()));
+        fs::write(&path, b"not a socket").unwrap();
+
+        let err = bind(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,24 +1,239 @@
-use std::{any::Any, time::Instant};
+use std::{
+    mem,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
 
-use bytes::Bytes;
+#[cfg(unix)]
+use tokio::net::unix::UCred;
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, timeout, MissedTickBehavior},
+};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
-    networking::{packet::Packet, raw_packet::RawPacket},
+    coding::CodingError,
+    networking::{
+        compression::CompressionSettings,
+        error::NetworkingError,
+        handshake::{Capabilities, Handshake, HandshakeStatus},
+        packet::Packet,
+        packet_type::{
+            DisconnectPacket, DisconnectReason, ErrorCode, ErrorPacket, HelloPacket, PingPacket,
+            SessionPacket, WelcomePacket,
+        },
+        raw_packet::RawPacket,
+    },
     types::types,
 };
 
-use super::connection::ConnectionHandle;
+use super::{
+    connection::{ConnectionHandle, PacketReader, PacketWriter},
+    database::Database,
+    outgoing::{Outgoing, OutgoingLimits, OutgoingQueue, OutgoingStats},
+    registry::PacketRegistry,
+    session::{Outbox, Resume, Session},
+};
+
+/// How long a client has to send its HELLO packet after connecting
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a quiet client is pinged, and how many of those pings it may leave unanswered before it is disconnected.
+/// Any packet from the client counts as an answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            max_missed: 3,
+        }
+    }
+}
+
+/// How the server treats every user, configured on the server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserSettings {
+    pub heartbeat: Heartbeat,
+    pub outgoing: OutgoingLimits,
+}
 
 // User represents a person that is connected to the server
 pub struct User {
     id: Uuid,
 
+    /// What was agreed with the client during the handshake
+    handshake: Handshake,
+
     /// The stats of the user
     stats: UserStats,
 
-    /// The read/write stream of the user
-    connection: Box<dyn ConnectionHandle + Send + Sync>,
+    /// The packet types the user is able to send
+    registry: Arc<PacketRegistry>,
+
+    /// The request id of the packet being handled
+    request_id: Option<u32>,
+
+    heartbeat: Heartbeat,
+
+    /// The heartbeats that passed without a packet from the client
+    missed_heartbeats: u32,
+
+    /// The read half of the connection, the write half belongs to the writer task
+    reader: Box<dyn PacketReader>,
+
+    /// Queues packets for the writer task, shared with everyone that sends to the user
+    handle: UserHandle,
+
+    /// The task writing the queued packets, awaited by `disconnect`. It gives the write half back once the queue is
+    /// closed and drained.
+    writer: Option<JoinHandle<Option<Box<dyn PacketWriter>>>>,
+
+    /// Cancelled by the writer task when the connection can not be written to anymore
+    connection_lost: CancellationToken,
+
+    /// The packets written to the user, numbered by the writer task in a session
+    outbox: Arc<Mutex<Outbox>>,
+
+    /// Keeps the user around when its connection is lost, once the client opened a session with RESUME
+    session: Option<Session>,
+
+    /// Cancelled when the server shuts down
+    shutdown: CancellationToken,
+
+    /// The clients, accounts and channels messages are routed through
+    database: Arc<Database>,
+
+    /// The account the client signed in to, messages to it are delivered to this user
+    account: Option<i32>,
+
+    client_certificate: Option<CertificateDer<'static>>,
+    #[cfg(unix)]
+    peer_credentials: Option<UCred>,
+}
+
+/// Sends packets to a connected user from any task, so channels and other users can push to it while it is reading.
+/// Cloning it is cheap.
+#[derive(Debug, Clone)]
+pub struct UserHandle {
+    id: Uuid,
+
+    /// Follows the client when its QUIC connection migrates or it resumes its session from another connection
+    socket: Arc<Mutex<SocketAddr>>,
+    outgoing: Arc<OutgoingQueue>,
+}
+
+impl UserHandle {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn socket(&self) -> SocketAddr {
+        *self.socket.lock().unwrap()
+    }
+
+    fn set_socket(&self, socket: SocketAddr) {
+        *self.socket.lock().unwrap() = socket;
+    }
+
+    /// Queues a packet for the user. When its queue is full the overflow policy applies, which may disconnect it.
+    pub async fn send_packet(&self, packet: Packet) -> Result<(), NetworkingError> {
+        self.send_raw(RawPacket::from(packet)).await
+    }
+
+    pub async fn send_raw(&self, packet: RawPacket) -> Result<(), NetworkingError> {
+        self.outgoing.push_packet(packet)
+    }
+
+    /// Whether the user is still connected, packets sent to a disconnected user fail with `ConnectionClosed`
+    pub fn is_connected(&self) -> bool {
+        !self.outgoing.is_closed()
+    }
+
+    /// How many packets are waiting to be written to the user
+    pub fn outgoing_stats(&self) -> OutgoingStats {
+        self.outgoing.stats()
+    }
+}
+
+/// Writes the packets a resumed client missed, then the packets queued for the user until it disconnects or the
+/// connection fails. Returns the write half when the queue was closed and everything was written.
+async fn write_packets(
+    mut writer: Box<dyn PacketWriter>,
+    outgoing: Arc<OutgoingQueue>,
+    outbox: Arc<Mutex<Outbox>>,
+    socket: Arc<Mutex<SocketAddr>>,
+    connection_lost: CancellationToken,
+    replay: Vec<RawPacket>,
+) -> Option<Box<dyn PacketWriter>> {
+    let mut replay = replay.into_iter();
+
+    // what was queued before the user disconnected, like a last error, is still delivered
+    loop {
+        let result = match replay.next() {
+            // replayed packets were numbered when they were first written
+            Some(packet) => writer.write_packet(packet).await,
+            None => match outgoing.pop().await {
+                // closed and drained, the write half may be handed to another user
+                None => return Some(writer),
+                Some(Outgoing::Packet(packet)) => {
+                    outbox.lock().unwrap().record(&packet);
+                    writer.write_packet(packet).await
+                }
+                Some(Outgoing::Compression(settings)) => {
+                    writer.set_compression(settings);
+                    Ok(())
+                }
+                Some(Outgoing::StartSequence { answer, max_replay }) => {
+                    let result = writer.write_packet(answer).await;
+                    outbox.lock().unwrap().start(max_replay);
+                    result
+                }
+            },
+        };
+
+        if let Err(err) = result {
+            println!("Could not write to {}: {}", socket.lock().unwrap(), err);
+
+            // the queue of a user in a session is kept for the client to resume it
+            if !outbox.lock().unwrap().is_recording() {
+                outgoing.abort();
+            }
+            connection_lost.cancel();
+            return None;
+        }
+    }
+}
+
+/// Stands in for the connection of a user that lost it or handed it over
+struct Detached;
+
+#[async_trait]
+impl PacketReader for Detached {
+    async fn read_packet(&mut self) -> Result<RawPacket, NetworkingError> {
+        Err(NetworkingError::ConnectionClosed)
+    }
+
+    fn accept_compression(&mut self, _settings: CompressionSettings) {}
+}
+
+/// Waits for a connection resuming the session of a user, forever if it has none
+async fn next_resume(session: &mut Option<Session>) -> Resume {
+    match session {
+        Some(session) => session.resumed().await,
+        None => std::future::pending().await,
+    }
 }
 
 #[derive(Debug)]
@@ -30,102 +245,658 @@ pub struct UserStats {
     last_interaction: Option<Instant>,
 }
 
+impl UserStats {
+    pub fn join_at(&self) -> Instant {
+        self.join_at
+    }
+
+    pub fn last_interaction(&self) -> Option<Instant> {
+        self.last_interaction
+    }
+}
+
 impl User {
-    /// Creates a new user for the given Connection, which is borrowed and owned by User
-    pub fn new(connection: Box<dyn ConnectionHandle + Send + Sync>) -> Self {
-        User {
-            id: Uuid::new_v4(),
+    /// Creates a new user for the given Connection, which is borrowed and owned by User.
+    /// The connection is split: the user reads from it while a separate task writes the packets queued through its
+    /// [`UserHandle`].
+    pub fn new(
+        connection: Box<dyn ConnectionHandle + Send + Sync>,
+        registry: Arc<PacketRegistry>,
+    ) -> Self {
+        Self::with_settings(connection, registry, UserSettings::default())
+    }
+
+    /// Creates a user with the heartbeat and outgoing queue limits of `settings`
+    pub fn with_settings(
+        connection: Box<dyn ConnectionHandle + Send + Sync>,
+        registry: Arc<PacketRegistry>,
+        settings: UserSettings,
+    ) -> Self {
+        let id = Uuid::new_v4();
+        let socket = connection.socket();
+        let client_certificate = connection.client_certificate().cloned();
+        #[cfg(unix)]
+        let peer_credentials = connection.peer_credentials();
+
+        let (reader, writer) = connection.split();
+        let outgoing = Arc::new(OutgoingQueue::new(settings.outgoing));
+
+        let mut user = User {
+            id,
+            registry,
+            request_id: None,
+            heartbeat: settings.heartbeat,
+            missed_heartbeats: 0,
+            handshake: Handshake::default(),
             stats: UserStats {
                 join_at: Instant::now(),
                 last_interaction: None,
             },
-            connection,
-        }
+            reader,
+            handle: UserHandle {
+                id,
+                socket: Arc::new(Mutex::new(socket)),
+                outgoing,
+            },
+            writer: None,
+            connection_lost: CancellationToken::new(),
+            outbox: Arc::new(Mutex::new(Outbox::new())),
+            session: None,
+            shutdown: CancellationToken::new(),
+            database: Arc::new(Database::new()),
+            account: None,
+            client_certificate,
+            #[cfg(unix)]
+            peer_credentials,
+        };
+        user.spawn_writer(writer, Vec::new());
+        user
     }
 
     pub fn id(&self) -> Uuid {
-        return self.id;
+        self.id
+    }
+
+    pub fn socket(&self) -> SocketAddr {
+        self.handle.socket()
+    }
+
+    /// Stops the user once `shutdown` is cancelled, the packets already queued are still written
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Routes the messages of the user through the database of the server instead of one of its own
+    pub fn with_database(mut self, database: Arc<Database>) -> Self {
+        self.database = database;
+        self
+    }
+
+    pub fn database(&self) -> &Arc<Database> {
+        &self.database
+    }
+
+    /// The id of the account the client signed in to, if it did
+    pub fn account(&self) -> Option<i32> {
+        self.account
+    }
+
+    pub fn set_account(&mut self, account: i32) {
+        self.account = Some(account);
+    }
+
+    /// A handle other tasks can send packets to the user with
+    pub fn handle(&self) -> UserHandle {
+        self.handle.clone()
+    }
+
+    /// The user and process of a client connected through the Unix socket, local clients can be trusted with them
+    #[cfg(unix)]
+    pub fn peer_credentials(&self) -> Option<UCred> {
+        self.peer_credentials
+    }
+
+    /// The certificate the client authenticated with over TLS, if any
+    pub fn client_certificate(&self) -> Option<&CertificateDer<'static>> {
+        self.client_certificate.as_ref()
+    }
+
+    pub fn stats(&self) -> &UserStats {
+        &self.stats
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    /// The capabilities agreed with the client
+    pub fn capabilities(&self) -> Capabilities {
+        self.handshake.capabilities
     }
 
     /// Process a single connection
     pub async fn run(&mut self) -> types::Result<()> {
-        // while !self.shutdown.is_shutdown() {
+        let shutdown = self.shutdown.clone();
+        let accepted = tokio::select! {
+            accepted = self.accept_handshake() => accepted?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        self.request_id = None;
+        if !accepted {
+            return Ok(());
+        }
+
+        let mut heartbeat = time::interval_at(
+            time::Instant::now() + self.heartbeat.interval,
+            self.heartbeat.interval,
+        );
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
         loop {
-            // here tokio::select! is used to await until self.connection.read_packet() OR self.shutdown.recv() completes
+            // here tokio::select! is used to await until a packet is read OR the server shuts down
             let packet = tokio::select! {
-                res = self.connection.read_packet() => res,
-                // _ = self.shutdown.recv() => {
-                //     // If a shutdown signal is received, return from `run`.
-                //     // This will result in the task terminating.
-                //     return Ok(());
-                // }
+                res = self.reader.read_packet() => res,
+
+                // the writer task stopped, the connection is broken
+                _ = self.handle.outgoing.closed() => return Ok(()),
+                _ = self.connection_lost.cancelled() => Err(NetworkingError::ConnectionClosed),
+
+                // the client reconnected before its previous connection was found to be lost
+                resume = next_resume(&mut self.session) => {
+                    if self.attach(resume).await || self.wait_for_resume().await {
+                        continue;
+                    }
+                    return Ok(());
+                }
+
+                _ = heartbeat.tick() => {
+                    if self.check_heartbeat().await? {
+                        continue;
+                    }
+                    return Ok(());
+                }
+
+                // the server is shutting down, what is queued is written when the user is dropped
+                _ = self.shutdown.cancelled() => return Ok(()),
             };
 
-            match packet {
+            let (packet_type, result) = match packet {
                 Ok(raw_packet) => {
-                    println!(
-                        "Received packet [{}] from {}",
-                        raw_packet,
-                        self.connection.socket()
-                    );
-
-                    if let Ok(packet) = Packet::from(raw_packet) {
-                        self.handle_packet(packet);
+                    // a migrated QUIC connection keeps working from the new address of the client
+                    if let Some(socket) = self.reader.current_socket() {
+                        self.handle.set_socket(socket);
                     }
 
-                    // TODO: ignore invalid packets?
+                    println!("Received packet [{}] from {}", raw_packet, self.socket());
+                    self.stats.last_interaction = Some(Instant::now());
+                    self.missed_heartbeats = 0;
+
+                    let registry = self.registry.clone();
+                    let packet_type = raw_packet.packet_type;
+                    self.request_id = raw_packet.request_id;
+                    (packet_type, registry.dispatch(self, raw_packet).await)
+                }
+                Err(err) => (0, Err(err)),
+            };
 
-                    // packet.receive_payload(packet_type)
+            match result {
+                Ok(()) => {}
 
-                    // self.connection.write_packet(packet).await.unwrap();
-                    // println!("Response sent.");
+                // a client in a session may come back
+                Err(NetworkingError::ConnectionClosed | NetworkingError::Io { .. })
+                    if self.session.is_some() =>
+                {
+                    println!("Lost the connection to {}", self.socket());
+                    if !self.wait_for_resume().await {
+                        return Ok(());
+                    }
                 }
-                Err(err) => {
-                    println!("Failed to decode packet: {}", err);
 
-                    // TODO: check kind of error before disconnecting
-                    self.connection
-                        .write_packet(RawPacket::new(1, Bytes::from("wrong packet")))
-                        .await
-                        .unwrap_or_default();
-                    println!("Response sent.");
+                // the peer went away, there is no one left to answer
+                Err(NetworkingError::ConnectionClosed) => return Ok(()),
+                Err(err @ NetworkingError::Io { .. }) => return Err(err.into()),
+
+                // a varint longer than its type is never sent by a well behaved client, and
+                // a client using another envelope version would not understand our answers
+                Err(
+                    err @ (NetworkingError::Coding {
+                        source: CodingError::VarintOverflow { .. },
+                    }
+                    | NetworkingError::UnsupportedEnvelopeVersion { .. }),
+                ) => {
+                    println!("Failed to decode packet: {}", err);
+                    self.send_error(ErrorCode::MalformedPacket, packet_type, err.to_string())
+                        .await;
 
                     // break the loop and return to the caller
                     return Ok(());
                 }
+
+                // frames are length delimited, so a bad payload does not affect the packets after it
+                Err(err @ NetworkingError::UnknownPacketType { .. }) => {
+                    println!("Dropping packet from {}: {}", self.socket(), err);
+                    self.send_error(ErrorCode::UnknownPacketType, packet_type, err.to_string())
+                        .await;
+                }
+                Err(
+                    err @ (NetworkingError::InvalidPacketFormat
+                    | NetworkingError::UnsupportedFlags { .. }
+                    | NetworkingError::UnsupportedCompression { .. }
+                    | NetworkingError::InvalidCompressedPayload { .. }
+                    | NetworkingError::DecompressedTooLarge { .. }
+                    | NetworkingError::InvalidJson { .. }
+                    | NetworkingError::Coding { .. }),
+                ) => {
+                    println!("Dropping packet from {}: {}", self.socket(), err);
+                    self.send_error(ErrorCode::MalformedPacket, packet_type, err.to_string())
+                        .await;
+                }
+                Err(err @ NetworkingError::Handler { .. }) => {
+                    println!("Error handling packet from {}: {}", self.socket(), err);
+                    self.send_error(
+                        ErrorCode::InternalError,
+                        packet_type,
+                        "the server failed to handle the packet".into(),
+                    )
+                    .await;
+                }
             }
+
+            self.request_id = None;
         }
     }
 
-    pub async fn send_packet(&mut self, packet: Packet) -> types::Result<()> {
-        let raw_packet = RawPacket::from(packet);
-        match self.connection.write_packet(raw_packet).await {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err),
+    /// Sends a packet that is not an answer to any request
+    pub async fn send_packet(&mut self, packet: Packet) -> Result<(), NetworkingError> {
+        self.handle.send_packet(packet).await
+    }
+
+    /// Answers the packet being handled, echoing its request id so the client can match the response.
+    pub async fn respond(&mut self, packet: Packet) -> Result<(), NetworkingError> {
+        let raw_packet = RawPacket::from(packet).with_request_id(self.request_id);
+        self.handle.send_raw(raw_packet).await
+    }
+
+    /// Pings the client when it has been quiet for a whole heartbeat interval. Once it missed too many heartbeats it is
+    /// told why and false is returned, the connection has to be closed.
+    async fn check_heartbeat(&mut self) -> Result<bool, NetworkingError> {
+        let quiet = self
+            .stats
+            .last_interaction
+            .unwrap_or(self.stats.join_at)
+            .elapsed();
+        if quiet < self.heartbeat.interval {
+            return Ok(true);
+        }
+
+        if self.missed_heartbeats >= self.heartbeat.max_missed {
+            println!(
+                "Client {} missed {} heartbeats",
+                self.socket(),
+                self.missed_heartbeats
+            );
+            self.send_packet(Packet::Disconnect(DisconnectPacket {
+                reason: DisconnectReason::HeartbeatTimeout,
+                message: format!("no packet received for {} seconds", quiet.as_secs()),
+            }))
+            .await?;
+            return Ok(false);
         }
+
+        self.missed_heartbeats += 1;
+        self.send_packet(Packet::Ping(PingPacket {
+            nonce: self.missed_heartbeats.into(),
+        }))
+        .await?;
+        Ok(true)
     }
 
-    fn handle_packet(&self, packet: Packet) {
-        match packet {
-            Packet::Message(message_packet) => {}
+    /// The request id of the packet being handled, if the client set one
+    pub fn request_id(&self) -> Option<u32> {
+        self.request_id
+    }
+
+    /// Waits for the HELLO packet that must open every connection and answers it with a WELCOME.
+    /// Returns false when the client was rejected and the connection has to be closed.
+    async fn accept_handshake(&mut self) -> types::Result<bool> {
+        let packet = match timeout(HANDSHAKE_TIMEOUT, self.reader.read_packet()).await {
+            Ok(packet) => packet.and_then(|raw_packet| {
+                self.request_id = raw_packet.request_id;
+                self.registry.decode::<HelloPacket>(&raw_packet)
+            }),
+            Err(_) => {
+                println!("Client {} did not send HELLO in time", self.socket());
+                return Ok(false);
+            }
+        };
+
+        let hello = match packet {
+            Ok(Some(hello)) => hello,
+            Err(NetworkingError::ConnectionClosed) => return Ok(false),
+            Err(err @ NetworkingError::Io { .. }) => return Err(err.into()),
+            Ok(None) | Err(_) => {
+                let welcome = WelcomePacket::rejected(
+                    HandshakeStatus::HelloExpected,
+                    "the first packet must be HELLO".into(),
+                );
+                self.respond(Packet::Welcome(welcome)).await?;
+                return Ok(false);
+            }
+        };
+
+        match Handshake::negotiate(hello) {
+            Ok(handshake) => {
+                println!(
+                    "Client {} speaks protocol v{} using {} {}",
+                    self.socket(),
+                    handshake.protocol_version,
+                    handshake.client_name,
+                    handshake.client_version
+                );
+
+                // the WELCOME itself is never compressed, the client only learns the capabilities from it
+                self.respond(Packet::Welcome(handshake.welcome())).await?;
+                let compression = CompressionSettings::negotiate(handshake.capabilities);
+                self.reader.accept_compression(compression);
+                self.handle
+                    .outgoing
+                    .push(Outgoing::Compression(compression))?;
+                self.handshake = handshake;
+                Ok(true)
+            }
+            Err(welcome) => {
+                println!("Rejected client {}: {}", self.socket(), welcome.reason);
+                self.respond(Packet::Welcome(welcome)).await?;
+                Ok(false)
+            }
         }
     }
 
-    // Disconnects the user
-    // pub async fn disconnect(&mut self) -> Result<(), Error> {
-    //     if self.state != UserState::Join {
-    //         return Ok(());
-    //     }
-
-    //     let result = self.stream.shutdown().await;
-    //     match result {
-    //         Ok(_) => {
-    //             self.state = UserState::Disconnect;
-    //             self.stats.last_interaction = Some(Instant::now());
-    //             return Ok(());
-    //         }
-    //         Err(_) => result,
-    //     }
-    // }
+    /// The session the client opened with RESUME, if any
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// The packets written to the user, numbered once it is in a session
+    pub fn outbox(&self) -> Arc<Mutex<Outbox>> {
+        self.outbox.clone()
+    }
+
+    /// Puts the user in a session and answers the RESUME that opened it, the packets written after the answer are
+    /// numbered
+    pub fn start_session(&mut self, session: Session) -> Result<(), NetworkingError> {
+        let settings = session.settings();
+        let answer = RawPacket::from(Packet::Session(SessionPacket {
+            token: session.token().into(),
+            resumed: false,
+            next_sequence: 1,
+            grace_period: settings.grace_period_secs(),
+        }))
+        .with_request_id(self.request_id);
+
+        // queued as one item, so no packet sent by another task comes between the answer and the numbered ones
+        self.handle.outgoing.push(Outgoing::StartSequence {
+            answer,
+            max_replay: settings.max_replay,
+        })?;
+        self.session = Some(session);
+        Ok(())
+    }
+
+    /// Hands the connection to the user owning the session the client resumed, once what is queued is written.
+    /// This user has no connection left afterwards.
+    pub async fn hand_over(
+        &mut self,
+        resumes: mpsc::Sender<Resume>,
+        last_sequence: u64,
+    ) -> Result<(), NetworkingError> {
+        self.handle.outgoing.close();
+        let writer = match self.writer.take() {
+            Some(writer) => writer.await.ok().flatten(),
+            None => None,
+        };
+        let writer = writer.ok_or(NetworkingError::ConnectionClosed)?;
+
+        let resume = Resume {
+            reader: mem::replace(&mut self.reader, Box::new(Detached)),
+            writer,
+            socket: self.socket(),
+            handshake: self.handshake.clone(),
+            last_sequence,
+        };
+        resumes
+            .send(resume)
+            .await
+            .map_err(|_| NetworkingError::ConnectionClosed)
+    }
+
+    /// Keeps the session of a user whose connection was lost until its client resumes it. Returns false when the grace
+    /// period ends first, or the server shuts down, and the session is over.
+    async fn wait_for_resume(&mut self) -> bool {
+        let Some(grace_period) = self
+            .session
+            .as_ref()
+            .map(|session| session.settings().grace_period)
+        else {
+            return false;
+        };
+        self.detach().await;
+
+        println!("Keeping the session of {} for {:?}", self.id, grace_period);
+        let deadline = time::Instant::now() + grace_period;
+        loop {
+            let Some(session) = &mut self.session else {
+                return false;
+            };
+            let resume = tokio::select! {
+                resume = session.resumed() => resume,
+                _ = time::sleep_until(deadline) => {
+                    println!("The session of {} expired", self.id);
+                    return false;
+                }
+                _ = self.shutdown.cancelled() => return false,
+
+                // the queue overflowed while the client was away
+                _ = self.handle.outgoing.closed() => return false,
+            };
+
+            if self.attach(resume).await {
+                return true;
+            }
+        }
+    }
+
+    /// Moves the session to the connection resuming it, replaying what the client missed. Returns false when that is
+    /// no longer possible, the new connection is then closed.
+    async fn attach(&mut self, resume: Resume) -> bool {
+        self.detach().await;
+        let replay = self.outbox.lock().unwrap().replay(resume.last_sequence);
+        let Some(replay) = replay else {
+            println!(
+                "Could not resume the session of {} from {}, the packets after {} are no longer kept",
+                self.id, resume.socket, resume.last_sequence
+            );
+            return false;
+        };
+
+        println!(
+            "Client {} resumed the session of {}, replaying {} packets",
+            resume.socket,
+            self.id,
+            replay.len()
+        );
+        self.reader = resume.reader;
+        self.handle.set_socket(resume.socket);
+        self.handshake = resume.handshake;
+        self.stats.last_interaction = Some(Instant::now());
+        self.missed_heartbeats = 0;
+        self.spawn_writer(resume.writer, replay);
+        true
+    }
+
+    /// Drops the connection of the user, what is queued is kept for the next one
+    async fn detach(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            // packets are numbered before they are written, so one lost with the connection is replayed
+            writer.abort();
+            let _ = writer.await;
+        }
+        self.reader = Box::new(Detached);
+    }
+
+    /// Starts the task writing to the connection, beginning with the packets to replay
+    fn spawn_writer(&mut self, writer: Box<dyn PacketWriter>, replay: Vec<RawPacket>) {
+        self.connection_lost = CancellationToken::new();
+        self.writer = Some(tokio::spawn(write_packets(
+            writer,
+            self.handle.outgoing.clone(),
+            self.outbox.clone(),
+            self.handle.socket.clone(),
+            self.connection_lost.clone(),
+            replay,
+        )));
+    }
+
+    /// Tells the client that one of its packets could not be processed
+    pub async fn send_error(&mut self, code: ErrorCode, packet_type: u8, message: String) {
+        let packet = Packet::Error(ErrorPacket {
+            code,
+            packet_type,
+            message,
+        });
+
+        if let Err(err) = self.respond(packet).await {
+            println!("Could not send error to {}: {}", self.socket(), err);
+        }
+    }
+
+    /// Disconnects the user once the packets queued for it are written
+    pub async fn disconnect(mut self) {
+        self.handle.outgoing.close();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.await;
+        }
+    }
+}
+
+impl Drop for User {
+    /// Lets the writer task flush what is queued and close the connection
+    fn drop(&mut self) {
+        self.handle.outgoing.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    use super::*;
+    use crate::{
+        networking::packet_type::{DestinationType, MessagePacket, MessagePayload, PongPacket},
+        server::{
+            connection::{packet_codec, TcpConnection},
+            testing,
+        },
+    };
+
+    type Client = Framed<TcpStream, LengthDelimitedCodec>;
+
+    fn message(text: &str) -> Packet {
+        Packet::Message(MessagePacket {
+            destination: 1,
+            destination_type: DestinationType::Channel,
+            message_payload: MessagePayload::Text(text.into()),
+        })
+    }
+
+    async fn read(client: &mut Client) -> RawPacket {
+        let frame = client.next().await.unwrap().unwrap();
+        RawPacket::decode(frame.freeze()).unwrap()
+    }
+
+    /// Connects a client to a running user and completes the handshake
+    async fn connect(settings: UserSettings) -> (Client, UserHandle, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, socket) = listener.accept().await.unwrap();
+        let mut client = Framed::new(client, packet_codec());
+
+        let mut user = User::with_settings(
+            Box::new(TcpConnection::new(socket, stream)),
+            Arc::new(PacketRegistry::default()),
+            settings,
+        );
+        let handle = user.handle();
+        let running = tokio::spawn(async move { user.run().await.unwrap() });
+
+        let hello = RawPacket::from(testing::hello("tester"));
+        client.send(hello.encode()).await.unwrap();
+        assert!(matches!(
+            Packet::from(read(&mut client).await),
+            Ok(Packet::Welcome(_))
+        ));
+
+        (client, handle, running)
+    }
+
+    #[tokio::test]
+    async fn handles_send_while_the_user_reads() {
+        let (mut client, handle, running) = connect(UserSettings::default()).await;
+
+        // the user is blocked reading from the client, yet the packets are delivered
+        for text in ["first", "second"] {
+            handle.send_packet(message(text)).await.unwrap();
+            assert_eq!(read(&mut client).await, RawPacket::from(message(text)));
+        }
+
+        drop(client);
+        running.await.unwrap();
+        assert!(!handle.is_connected());
+        assert!(matches!(
+            handle.send_packet(message("late")).await,
+            Err(NetworkingError::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn quiet_clients_are_pinged_then_disconnected() {
+        let settings = UserSettings {
+            heartbeat: Heartbeat {
+                interval: Duration::from_millis(50),
+                max_missed: 2,
+            },
+            ..UserSettings::default()
+        };
+        let (mut client, _, running) = connect(settings).await;
+
+        // answering a ping resets the missed heartbeats
+        let Ok(Packet::Ping(ping)) = Packet::from(read(&mut client).await) else {
+            panic!("expected a PING");
+        };
+        assert_eq!(ping.nonce, 1);
+        let pong = Packet::Pong(PongPacket { nonce: ping.nonce });
+        client.send(RawPacket::from(pong).encode()).await.unwrap();
+
+        for nonce in 1..=2 {
+            let Ok(Packet::Ping(ping)) = Packet::from(read(&mut client).await) else {
+                panic!("expected a PING");
+            };
+            assert_eq!(ping.nonce, nonce);
+        }
+
+        let Ok(Packet::Disconnect(disconnect)) = Packet::from(read(&mut client).await) else {
+            panic!("expected a DISCONNECT");
+        };
+        assert_eq!(disconnect.reason, DisconnectReason::HeartbeatTimeout);
+        running.await.unwrap();
+        assert!(client.next().await.is_none());
+    }
 }
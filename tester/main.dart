@@ -3,6 +3,13 @@ import 'dart:io';
 import 'dart:math';
 import 'dart:typed_data';
 
+import 'protocol.dart';
+
+const kProtocolVersion = 1;
+
+/// Messages typed in the terminal are sent to this channel
+const kChannel = 1;
+
 Future<void> main() async {
   final destinationAddr = InternetAddress("127.0.0.1");
   final destinationPort = 7878;
@@ -14,18 +21,28 @@ Future<void> main() async {
     timeout: const Duration(seconds: 3),
   );
 
-  socket.listen(_onMessage);
+  final frames = FrameBuffer();
+  socket.listen((Uint8List buffer) => frames.add(buffer).forEach(_onFrame));
+
+  // HELLO must be the first packet on every connection
+  socket.add(encodeFrame(const HelloPacket(
+    protocolVersion: kProtocolVersion,
+    clientName: "dart-tester",
+    clientVersion: "0.1.0",
+    capabilities: 0,
+  )));
 
-  // final packet = Packet.message(message: "hi");
-  // print("Send packet: $packet");
-  // socket.add(packet.encode());
   stdin.transform(utf8.decoder).listen((input) {
     final message = input.trim();
     if (message.isNotEmpty) {
       try {
-        final packet = Packet.message(message: message);
+        final packet = MessagePacket(
+          destination: kChannel,
+          destinationType: DestinationType.channel,
+          messagePayload: MessagePayloadText(message),
+        );
         print("Send packet: $packet");
-        socket.add(packet.encode());
+        socket.add(encodeFrame(packet));
       } catch (err) {
         print(err);
       }
@@ -35,42 +52,10 @@ Future<void> main() async {
   await socket.done;
 }
 
-void _onMessage(Uint8List buffer) {
-  print("Message received: $buffer");
-}
-
-const kMaxPacketSize = 1024 * 1024 * 12;
-
-class Packet {
-  final PacketType type;
-  final Uint8List payload;
-
-  const Packet({required this.type, required this.payload});
-
-  factory Packet.message({required String message}) {
-    return Packet(type: PacketType.message, payload: utf8.encode(message));
+void _onFrame(Frame frame) {
+  try {
+    print("Packet received: ${frame.decode() ?? frame}");
+  } on ProtocolException catch (err) {
+    print("Invalid packet received: $err");
   }
-
-  Uint8List encode() {
-    final payloadSize = 1 + payload.lengthInBytes;
-    final buffer = Uint8List(5 + payload.lengthInBytes);
-
-    final byteData = ByteData.sublistView(buffer);
-    byteData.setUint32(0, payloadSize, Endian.big);
-
-    buffer[4] = type.index;
-
-    buffer.setRange(5, buffer.lengthInBytes, payload);
-
-    if (buffer.lengthInBytes > kMaxPacketSize) {
-      throw "Message too big.";
-    }
-
-    return buffer;
-  }
-
-  @override
-  String toString() => "Packet(type: $type, payload: ${payload.sublist(0, min(20, payload.length - 1))})";
 }
-
-enum PacketType { unknown, signIn, signOut, message }
@@ -0,0 +1,1264 @@
+// @generated by rustchat-codegen from protocol.toml, do not edit.
+
+import 'dart:convert';
+import 'dart:typed_data';
+
+const kEnvelopeVersion = 1;
+const kFlagRequestId = 0x01;
+const kMaxPacketSize = 1024 * 1024 * 12;
+
+class ProtocolException implements Exception {
+  final String message;
+
+  ProtocolException(this.message);
+
+  @override
+  String toString() => 'ProtocolException: $message';
+}
+
+class ProtocolWriter {
+  final BytesBuilder _builder = BytesBuilder(copy: false);
+  final ByteData _scratch = ByteData(8);
+
+  Uint8List takeBytes() => _builder.takeBytes();
+
+  void writeBool(bool value) => _builder.addByte(value ? 1 : 0);
+  void writeU8(int value) => _builder.addByte(value & 0xFF);
+  void writeI8(int value) => _builder.addByte(value & 0xFF);
+
+  void writeU16(int value) => _add(2, () => _scratch.setUint16(0, value));
+  void writeI16(int value) => _add(2, () => _scratch.setInt16(0, value));
+  void writeU32(int value) => _add(4, () => _scratch.setUint32(0, value));
+  void writeI32(int value) => _add(4, () => _scratch.setInt32(0, value));
+  void writeU64(int value) => _add(8, () => _scratch.setUint64(0, value));
+  void writeI64(int value) => _add(8, () => _scratch.setInt64(0, value));
+  void writeF32(double value) => _add(4, () => _scratch.setFloat32(0, value));
+  void writeF64(double value) => _add(8, () => _scratch.setFloat64(0, value));
+
+  void writeVarint(int value) => writeVarlong(value & 0xFFFFFFFF);
+
+  void writeVarlong(int value) {
+    while ((value & ~0x7F) != 0) {
+      _builder.addByte((value & 0x7F) | 0x80);
+      value = value >>> 7;
+    }
+    _builder.addByte(value);
+  }
+
+  void writeSvarint(int value) => writeVarint((value << 1) ^ (value >> 31));
+  void writeSvarlong(int value) => writeVarlong((value << 1) ^ (value >> 63));
+
+  void writeString(String value) => writeBytes(utf8.encode(value));
+
+  void writeBytes(Uint8List value) {
+    writeVarint(value.length);
+    _builder.add(value);
+  }
+
+  void writeUuid(String value) {
+    final hex = value.replaceAll('-', '');
+    if (hex.length != 32) {
+      throw ProtocolException('invalid uuid $value');
+    }
+    for (var i = 0; i < 32; i += 2) {
+      _builder.addByte(int.parse(hex.substring(i, i + 2), radix: 16));
+    }
+  }
+
+  void writeOption<T>(T? value, void Function(T) write) {
+    writeBool(value != null);
+    if (value != null) {
+      write(value);
+    }
+  }
+
+  void writeList<T>(List<T> values, void Function(T) write) {
+    writeVarint(values.length);
+    values.forEach(write);
+  }
+
+  void writeMap<K, V>(Map<K, V> values, void Function(K) writeKey, void Function(V) writeValue) {
+    writeVarint(values.length);
+    values.forEach((key, value) {
+      writeKey(key);
+      writeValue(value);
+    });
+  }
+
+  void _add(int length, void Function() write) {
+    write();
+    _builder.add(Uint8List.fromList(_scratch.buffer.asUint8List(0, length)));
+  }
+}
+
+class ProtocolReader {
+  final Uint8List _bytes;
+  final ByteData _data;
+  int _offset = 0;
+
+  ProtocolReader(Uint8List bytes)
+      : _bytes = bytes,
+        _data = ByteData.sublistView(bytes);
+
+  int get offset => _offset;
+  int get remaining => _bytes.length - _offset;
+
+  bool readBool() {
+    final offset = _offset;
+    final value = readU8();
+    if (value > 1) {
+      throw ProtocolException('invalid boolean byte $value at offset $offset');
+    }
+    return value == 1;
+  }
+
+  int readU8() => _read(1, () => _data.getUint8(_offset));
+  int readI8() => _read(1, () => _data.getInt8(_offset));
+  int readU16() => _read(2, () => _data.getUint16(_offset));
+  int readI16() => _read(2, () => _data.getInt16(_offset));
+  int readU32() => _read(4, () => _data.getUint32(_offset));
+  int readI32() => _read(4, () => _data.getInt32(_offset));
+  int readU64() => _read(8, () => _data.getUint64(_offset));
+  int readI64() => _read(8, () => _data.getInt64(_offset));
+  double readF32() => _read(4, () => _data.getFloat32(_offset));
+  double readF64() => _read(8, () => _data.getFloat64(_offset));
+
+  int readVarint() => _readVarint(32);
+  int readVarlong() => _readVarint(64);
+
+  int readSvarint() {
+    final value = readVarint();
+    return (value >>> 1) ^ -(value & 1);
+  }
+
+  int readSvarlong() {
+    final value = readVarlong();
+    return (value >>> 1) ^ -(value & 1);
+  }
+
+  String readString() {
+    final offset = _offset;
+    final bytes = readBytes();
+    try {
+      return utf8.decode(bytes);
+    } on FormatException {
+      throw ProtocolException('invalid utf-8 string at offset $offset');
+    }
+  }
+
+  Uint8List readBytes() {
+    final length = readVarint();
+    _ensure(length);
+    final bytes = Uint8List.sublistView(_bytes, _offset, _offset + length);
+    _offset += length;
+    return bytes;
+  }
+
+  String readUuid() {
+    _ensure(16);
+    final hex = StringBuffer();
+    for (var i = 0; i < 16; i++) {
+      if (i == 4 || i == 6 || i == 8 || i == 10) {
+        hex.write('-');
+      }
+      hex.write(_bytes[_offset + i].toRadixString(16).padLeft(2, '0'));
+    }
+    _offset += 16;
+    return hex.toString();
+  }
+
+  T? readOption<T>(T Function() read) => readBool() ? read() : null;
+
+  List<T> readList<T>(int maxLength, T Function() read) {
+    final length = _readLength(maxLength);
+    return List.generate(length, (_) => read());
+  }
+
+  Map<K, V> readMap<K, V>(int maxLength, K Function() readKey, V Function() readValue) {
+    final length = _readLength(maxLength);
+    final values = <K, V>{};
+    for (var i = 0; i < length; i++) {
+      final key = readKey();
+      values[key] = readValue();
+    }
+    return values;
+  }
+
+  /// Every element takes at least one byte, so a length larger than the remaining data is rejected early.
+  int _readLength(int maxLength) {
+    final offset = _offset;
+    final length = readVarint();
+    if (length > maxLength) {
+      throw ProtocolException(
+          'collection at offset $offset has $length elements, the maximum is $maxLength');
+    }
+    _ensure(length);
+    return length;
+  }
+
+  int _readVarint(int bits) {
+    final offset = _offset;
+    var result = 0;
+    for (var shift = 0; shift < bits; shift += 7) {
+      final byte = readU8();
+      result |= (byte & 0x7F) << shift;
+      if ((byte & 0x80) == 0) {
+        return result;
+      }
+    }
+    throw ProtocolException('varint at offset $offset does not fit in $bits bits');
+  }
+
+  T _read<T>(int length, T Function() read) {
+    _ensure(length);
+    final value = read();
+    _offset += length;
+    return value;
+  }
+
+  void _ensure(int needed) {
+    if (remaining < needed) {
+      throw ProtocolException(
+          'unexpected end of data at offset $_offset: needed $needed bytes but only $remaining remain');
+    }
+  }
+}
+
+abstract interface class PacketData {
+  int get packetId;
+
+  void encode(ProtocolWriter writer);
+}
+
+/// A packet envelope, without the length prefix
+class Frame {
+  final int packetType;
+
+  /// Set on requests that expect an answer, the server echoes it on the response.
+  final int? requestId;
+  final Uint8List payload;
+
+  const Frame({required this.packetType, this.requestId, required this.payload});
+
+  /// Decodes the payload, returns null when the packet type is unknown
+  PacketData? decode() => decodePacket(packetType, ProtocolReader(payload));
+
+  @override
+  String toString() => 'Frame(packetType: $packetType, requestId: $requestId, payload: ${payload.length} bytes)';
+}
+
+/// Encodes a packet with its length prefix and envelope header
+Uint8List encodeFrame(PacketData packet, {int? requestId}) {
+  final writer = ProtocolWriter();
+  writer.writeU8(kEnvelopeVersion);
+  writer.writeU8(requestId == null ? 0 : kFlagRequestId);
+  writer.writeU8(packet.packetId);
+  if (requestId != null) {
+    writer.writeVarint(requestId);
+  }
+  packet.encode(writer);
+
+  final body = writer.takeBytes();
+  if (body.length > kMaxPacketSize) {
+    throw ProtocolException('packet of ${body.length} bytes exceeds the maximum packet size');
+  }
+
+  final frame = Uint8List(4 + body.length);
+  ByteData.sublistView(frame).setUint32(0, body.length);
+  frame.setRange(4, frame.length, body);
+  return frame;
+}
+
+/// Decodes an envelope, the length prefix must already be stripped
+Frame decodeFrame(Uint8List body) {
+  final reader = ProtocolReader(body);
+  final version = reader.readU8();
+  if (version != kEnvelopeVersion) {
+    throw ProtocolException('unsupported envelope version $version');
+  }
+
+  final flags = reader.readU8();
+  if ((flags & ~kFlagRequestId) != 0) {
+    throw ProtocolException('unsupported envelope flags $flags');
+  }
+
+  final packetType = reader.readU8();
+  final requestId = (flags & kFlagRequestId) != 0 ? reader.readVarint() : null;
+  return Frame(
+    packetType: packetType,
+    requestId: requestId,
+    payload: Uint8List.sublistView(body, reader.offset),
+  );
+}
+
+/// Splits a stream of bytes into frames
+class FrameBuffer {
+  final BytesBuilder _pending = BytesBuilder();
+
+  /// Adds received bytes, returning every frame they complete
+  List<Frame> add(Uint8List bytes) {
+    _pending.add(bytes);
+    var buffer = _pending.takeBytes();
+
+    final frames = <Frame>[];
+    while (buffer.length >= 4) {
+      final length = ByteData.sublistView(buffer).getUint32(0);
+      if (buffer.length < 4 + length) {
+        break;
+      }
+      frames.add(decodeFrame(Uint8List.sublistView(buffer, 4, 4 + length)));
+      buffer = Uint8List.sublistView(buffer, 4 + length);
+    }
+
+    _pending.add(buffer);
+    return frames;
+  }
+}
+
+abstract final class PacketType {
+  /// A user trying to sign in to the server. A username is registered with the password it first signs in with. The server
+  /// answers with SIGNED_IN, or with an ERROR when the password does not match.
+  static const signIn = 1;
+  /// A user trying to sign out from the server.
+  static const signOut = 2;
+  /// A message from an user, delivered to the signed in user or to the subscribers of the channel it is sent to. Only signed
+  /// in users may send messages.
+  static const message = 3;
+  /// The first packet a client sends, announcing its protocol version and capabilities.
+  static const hello = 4;
+  /// The server answer to HELLO, accepting or rejecting the client.
+  static const welcome = 5;
+  /// Sent by the server when a packet could not be processed.
+  static const error = 6;
+  /// Starts uploading a file in chunks, or resumes the upload when the transfer id is already known.
+  /// The server answers with a FILE_ACK carrying the offset the client has to continue from.
+  static const fileBegin = 7;
+  /// A piece of a file being uploaded, answered with a FILE_ACK.
+  static const fileChunk = 8;
+  /// Sent by the server to acknowledge the stored part of an upload.
+  static const fileAck = 9;
+  /// Finishes an upload. Once the hash is verified the server answers with the MessagePacket referencing the file, and
+  /// delivers it to its destination like any other message.
+  static const fileCommit = 10;
+  /// Cancels an upload. Sent by the client to give up, or by the server when the upload can not continue.
+  static const fileAbort = 11;
+  /// Checks that the peer is still there. Sent by the server when the client has been quiet for a heartbeat interval, the
+  /// client may send it too. Either side answers with a PONG carrying the same nonce.
+  static const ping = 12;
+  /// The answer to a PING.
+  static const pong = 13;
+  /// Sent by the server right before it closes the connection.
+  static const disconnect = 14;
+  /// Sent by the server to every client when it stops. The packets queued before it are still delivered, then the connection
+  /// is closed.
+  static const serverShutdown = 15;
+  /// Opens a session, or resumes the session of a lost connection. Sent right after the handshake by clients that want to
+  /// survive reconnects, the server answers with SESSION.
+  static const resume = 16;
+  /// The answer to RESUME. Every packet the server sends after it is numbered, starting at next_sequence, so a client that
+  /// reconnects knows what it missed. When the session can not be resumed a new one is opened instead.
+  static const session = 17;
+  /// The answer to SIGN_IN.
+  static const signedIn = 18;
+  /// Subscribes a signed in user to a channel, creating the channel when it does not exist yet. Every message sent to the
+  /// channel is delivered to its subscribers.
+  static const join = 19;
+}
+
+/// Decodes the payload of a packet, returns null when the packet type is unknown
+PacketData? decodePacket(int packetType, ProtocolReader reader) {
+  return switch (packetType) {
+    PacketType.signIn => LoginPacket.decode(reader),
+    PacketType.signOut => LogoutPacket.decode(reader),
+    PacketType.message => MessagePacket.decode(reader),
+    PacketType.hello => HelloPacket.decode(reader),
+    PacketType.welcome => WelcomePacket.decode(reader),
+    PacketType.error => ErrorPacket.decode(reader),
+    PacketType.fileBegin => FileBeginPacket.decode(reader),
+    PacketType.fileChunk => FileChunkPacket.decode(reader),
+    PacketType.fileAck => FileAckPacket.decode(reader),
+    PacketType.fileCommit => FileCommitPacket.decode(reader),
+    PacketType.fileAbort => FileAbortPacket.decode(reader),
+    PacketType.ping => PingPacket.decode(reader),
+    PacketType.pong => PongPacket.decode(reader),
+    PacketType.disconnect => DisconnectPacket.decode(reader),
+    PacketType.serverShutdown => ServerShutdownPacket.decode(reader),
+    PacketType.resume => ResumePacket.decode(reader),
+    PacketType.session => SessionPacket.decode(reader),
+    PacketType.signedIn => SignedInPacket.decode(reader),
+    PacketType.join => JoinPacket.decode(reader),
+    _ => null,
+  };
+}
+
+/// The kind of recipient of a message
+enum DestinationType {
+  unknown,
+  user,
+  channel;
+
+  int get code => index;
+
+  static DestinationType decode(ProtocolReader reader) {
+    final offset = reader.offset;
+    final code = reader.readU8();
+    if (code >= values.length) {
+      throw ProtocolException('unknown destination type discriminant $code at offset $offset');
+    }
+    return values[code];
+  }
+}
+
+/// The outcome of a handshake, sent back to the client in the WELCOME packet
+enum HandshakeStatus {
+  accepted,
+  /// The client protocol version is outside the range supported by the server
+  unsupportedVersion,
+  /// The first packet sent by the client was not a HELLO
+  helloExpected;
+
+  int get code => index;
+
+  static HandshakeStatus decode(ProtocolReader reader) {
+    final offset = reader.offset;
+    final code = reader.readU8();
+    if (code >= values.length) {
+      throw ProtocolException('unknown handshake status discriminant $code at offset $offset');
+    }
+    return values[code];
+  }
+}
+
+/// Why the server could not process a packet
+enum ErrorCode {
+  unknown,
+  /// The packet type is not registered on the server
+  unknownPacketType,
+  /// The packet payload could not be decoded
+  malformedPacket,
+  /// The packet was decoded but the server failed to handle it
+  internalError,
+  /// The password does not match the one the username signed in with first
+  invalidCredentials,
+  /// The packet is only handled once the user signed in
+  notSignedIn,
+  /// No signed in user or channel has the destination of the message
+  unknownDestination;
+
+  int get code => index;
+
+  static ErrorCode decode(ProtocolReader reader) {
+    final offset = reader.offset;
+    final code = reader.readU8();
+    if (code >= values.length) {
+      throw ProtocolException('unknown error code discriminant $code at offset $offset');
+    }
+    return values[code];
+  }
+}
+
+/// Why the server closed the connection, sent in the DISCONNECT packet
+enum DisconnectReason {
+  unknown,
+  /// The client did not send anything, not even a PONG, for too many heartbeats
+  heartbeatTimeout,
+  /// The client did not read its packets fast enough, too many were waiting to be sent
+  tooSlow;
+
+  int get code => index;
+
+  static DisconnectReason decode(ProtocolReader reader) {
+    final offset = reader.offset;
+    final code = reader.readU8();
+    if (code >= values.length) {
+      throw ProtocolException('unknown disconnect reason discriminant $code at offset $offset');
+    }
+    return values[code];
+  }
+}
+
+/// A set of optional protocol features, advertised by the client in the HELLO packet.
+/// The server answers with the subset it also supports.
+abstract final class Capabilities {
+  /// Packet payloads above a size threshold may be deflate compressed
+  static const compressionDeflate = 1 << 0;
+  /// Files may be sent in chunks instead of a single MessagePacket
+  static const fileChunking = 1 << 1;
+  /// Packet payloads above a size threshold may be zstd compressed, preferred over deflate
+  static const compressionZstd = 1 << 2;
+}
+
+/// The content of a message. A FileReference carries the transfer id of a file uploaded in chunks, see FILE_BEGIN.
+sealed class MessagePayload {
+  const MessagePayload();
+
+  void encode(ProtocolWriter writer);
+
+  static MessagePayload decode(ProtocolReader reader) {
+    final offset = reader.offset;
+    final tag = reader.readI8();
+    return switch (tag) {
+      1 => MessagePayloadText(reader.readString()),
+      2 => MessagePayloadFile(reader.readBytes()),
+      3 => MessagePayloadFileReference(reader.readUuid()),
+      _ => throw ProtocolException('unknown message payload discriminant $tag at offset $offset'),
+    };
+  }
+}
+
+final class MessagePayloadText extends MessagePayload {
+  final String value;
+
+  const MessagePayloadText(this.value);
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeI8(1);
+    writer.writeString(value);
+  }
+
+  @override
+  String toString() => 'MessagePayloadText($value)';
+}
+
+final class MessagePayloadFile extends MessagePayload {
+  final Uint8List value;
+
+  const MessagePayloadFile(this.value);
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeI8(2);
+    writer.writeBytes(value);
+  }
+
+  @override
+  String toString() => 'MessagePayloadFile($value)';
+}
+
+final class MessagePayloadFileReference extends MessagePayload {
+  final String value;
+
+  const MessagePayloadFileReference(this.value);
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeI8(3);
+    writer.writeUuid(value);
+  }
+
+  @override
+  String toString() => 'MessagePayloadFileReference($value)';
+}
+
+/// A user trying to sign in to the server. A username is registered with the password it first signs in with. The server
+/// answers with SIGNED_IN, or with an ERROR when the password does not match.
+class LoginPacket implements PacketData {
+  static const id = PacketType.signIn;
+
+  final String username;
+
+  final String password;
+
+  const LoginPacket({
+    required this.username,
+    required this.password,
+  });
+
+  factory LoginPacket.decode(ProtocolReader reader) {
+    return LoginPacket(
+      username: reader.readString(),
+      password: reader.readString(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeString(username);
+    writer.writeString(password);
+  }
+
+  @override
+  String toString() => 'LoginPacket(username: $username, password: $password)';
+}
+
+/// A user trying to sign out from the server.
+class LogoutPacket implements PacketData {
+  static const id = PacketType.signOut;
+
+  final int sessionId;
+
+  const LogoutPacket({
+    required this.sessionId,
+  });
+
+  factory LogoutPacket.decode(ProtocolReader reader) {
+    return LogoutPacket(
+      sessionId: reader.readSvarlong(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeSvarlong(sessionId);
+  }
+
+  @override
+  String toString() => 'LogoutPacket(sessionId: $sessionId)';
+}
+
+/// A message from an user, delivered to the signed in user or to the subscribers of the channel it is sent to. Only signed
+/// in users may send messages.
+class MessagePacket implements PacketData {
+  static const id = PacketType.message;
+
+  /// The id of the destination. Can be an individual user or a channel
+  final int destination;
+
+  /// Indicates the destination type: Channel/User
+  final DestinationType destinationType;
+
+  /// The payload of the message
+  final MessagePayload messagePayload;
+
+  const MessagePacket({
+    required this.destination,
+    required this.destinationType,
+    required this.messagePayload,
+  });
+
+  factory MessagePacket.decode(ProtocolReader reader) {
+    return MessagePacket(
+      destination: reader.readI32(),
+      destinationType: DestinationType.decode(reader),
+      messagePayload: MessagePayload.decode(reader),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeI32(destination);
+    writer.writeU8(destinationType.code);
+    messagePayload.encode(writer);
+  }
+
+  @override
+  String toString() => 'MessagePacket(destination: $destination, destinationType: $destinationType, messagePayload: $messagePayload)';
+}
+
+/// The first packet a client sends, announcing its protocol version and capabilities.
+class HelloPacket implements PacketData {
+  static const id = PacketType.hello;
+
+  /// The protocol version the client speaks
+  final int protocolVersion;
+
+  final String clientName;
+
+  final String clientVersion;
+
+  /// The optional features the client supports
+  final int capabilities;
+
+  const HelloPacket({
+    required this.protocolVersion,
+    required this.clientName,
+    required this.clientVersion,
+    required this.capabilities,
+  });
+
+  factory HelloPacket.decode(ProtocolReader reader) {
+    return HelloPacket(
+      protocolVersion: reader.readVarint(),
+      clientName: reader.readString(),
+      clientVersion: reader.readString(),
+      capabilities: reader.readVarint(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeVarint(protocolVersion);
+    writer.writeString(clientName);
+    writer.writeString(clientVersion);
+    writer.writeVarint(capabilities);
+  }
+
+  @override
+  String toString() => 'HelloPacket(protocolVersion: $protocolVersion, clientName: $clientName, clientVersion: $clientVersion, capabilities: $capabilities)';
+}
+
+/// The server answer to HELLO, accepting or rejecting the client.
+class WelcomePacket implements PacketData {
+  static const id = PacketType.welcome;
+
+  final HandshakeStatus status;
+
+  /// The protocol version used for the rest of the connection, 0 if the client was rejected
+  final int protocolVersion;
+
+  /// The range of protocol versions the server accepts
+  final int minProtocolVersion;
+
+  final int maxProtocolVersion;
+
+  /// The capabilities both the client and the server support
+  final int capabilities;
+
+  /// A human readable explanation when the client is rejected
+  final String reason;
+
+  const WelcomePacket({
+    required this.status,
+    required this.protocolVersion,
+    required this.minProtocolVersion,
+    required this.maxProtocolVersion,
+    required this.capabilities,
+    required this.reason,
+  });
+
+  factory WelcomePacket.decode(ProtocolReader reader) {
+    return WelcomePacket(
+      status: HandshakeStatus.decode(reader),
+      protocolVersion: reader.readVarint(),
+      minProtocolVersion: reader.readVarint(),
+      maxProtocolVersion: reader.readVarint(),
+      capabilities: reader.readVarint(),
+      reason: reader.readString(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeU8(status.code);
+    writer.writeVarint(protocolVersion);
+    writer.writeVarint(minProtocolVersion);
+    writer.writeVarint(maxProtocolVersion);
+    writer.writeVarint(capabilities);
+    writer.writeString(reason);
+  }
+
+  @override
+  String toString() => 'WelcomePacket(status: $status, protocolVersion: $protocolVersion, minProtocolVersion: $minProtocolVersion, maxProtocolVersion: $maxProtocolVersion, capabilities: $capabilities, reason: $reason)';
+}
+
+/// Sent by the server when a packet could not be processed.
+class ErrorPacket implements PacketData {
+  static const id = PacketType.error;
+
+  final ErrorCode code;
+
+  /// The type of the packet that caused the error
+  final int packetType;
+
+  /// A human readable description of the error
+  final String message;
+
+  const ErrorPacket({
+    required this.code,
+    required this.packetType,
+    required this.message,
+  });
+
+  factory ErrorPacket.decode(ProtocolReader reader) {
+    return ErrorPacket(
+      code: ErrorCode.decode(reader),
+      packetType: reader.readU8(),
+      message: reader.readString(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeU8(code.code);
+    writer.writeU8(packetType);
+    writer.writeString(message);
+  }
+
+  @override
+  String toString() => 'ErrorPacket(code: $code, packetType: $packetType, message: $message)';
+}
+
+/// Starts uploading a file in chunks, or resumes the upload when the transfer id is already known.
+/// The server answers with a FILE_ACK carrying the offset the client has to continue from.
+class FileBeginPacket implements PacketData {
+  static const id = PacketType.fileBegin;
+
+  /// Chosen by the client, used to resume the upload and to reference the file
+  final String transferId;
+
+  /// Where the message referencing the file is delivered once committed
+  final int destination;
+
+  final DestinationType destinationType;
+
+  final String name;
+
+  /// The size of the whole file in bytes
+  final int size;
+
+  /// The SHA-256 digest of the whole file
+  final Uint8List sha256;
+
+  const FileBeginPacket({
+    required this.transferId,
+    required this.destination,
+    required this.destinationType,
+    required this.name,
+    required this.size,
+    required this.sha256,
+  });
+
+  factory FileBeginPacket.decode(ProtocolReader reader) {
+    return FileBeginPacket(
+      transferId: reader.readUuid(),
+      destination: reader.readI32(),
+      destinationType: DestinationType.decode(reader),
+      name: reader.readString(),
+      size: reader.readVarlong(),
+      sha256: reader.readBytes(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeUuid(transferId);
+    writer.writeI32(destination);
+    writer.writeU8(destinationType.code);
+    writer.writeString(name);
+    writer.writeVarlong(size);
+    writer.writeBytes(sha256);
+  }
+
+  @override
+  String toString() => 'FileBeginPacket(transferId: $transferId, destination: $destination, destinationType: $destinationType, name: $name, size: $size, sha256: $sha256)';
+}
+
+/// A piece of a file being uploaded, answered with a FILE_ACK.
+class FileChunkPacket implements PacketData {
+  static const id = PacketType.fileChunk;
+
+  final String transferId;
+
+  /// The position of the chunk in the file, chunks must be sent in order
+  final int offset;
+
+  final Uint8List data;
+
+  const FileChunkPacket({
+    required this.transferId,
+    required this.offset,
+    required this.data,
+  });
+
+  factory FileChunkPacket.decode(ProtocolReader reader) {
+    return FileChunkPacket(
+      transferId: reader.readUuid(),
+      offset: reader.readVarlong(),
+      data: reader.readBytes(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeUuid(transferId);
+    writer.writeVarlong(offset);
+    writer.writeBytes(data);
+  }
+
+  @override
+  String toString() => 'FileChunkPacket(transferId: $transferId, offset: $offset, data: $data)';
+}
+
+/// Sent by the server to acknowledge the stored part of an upload.
+class FileAckPacket implements PacketData {
+  static const id = PacketType.fileAck;
+
+  final String transferId;
+
+  /// Every byte before this offset is stored, the next chunk has to start here
+  final int offset;
+
+  const FileAckPacket({
+    required this.transferId,
+    required this.offset,
+  });
+
+  factory FileAckPacket.decode(ProtocolReader reader) {
+    return FileAckPacket(
+      transferId: reader.readUuid(),
+      offset: reader.readVarlong(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeUuid(transferId);
+    writer.writeVarlong(offset);
+  }
+
+  @override
+  String toString() => 'FileAckPacket(transferId: $transferId, offset: $offset)';
+}
+
+/// Finishes an upload. Once the hash is verified the server answers with the MessagePacket referencing the file, and
+/// delivers it to its destination like any other message.
+class FileCommitPacket implements PacketData {
+  static const id = PacketType.fileCommit;
+
+  final String transferId;
+
+  const FileCommitPacket({
+    required this.transferId,
+  });
+
+  factory FileCommitPacket.decode(ProtocolReader reader) {
+    return FileCommitPacket(
+      transferId: reader.readUuid(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeUuid(transferId);
+  }
+
+  @override
+  String toString() => 'FileCommitPacket(transferId: $transferId)';
+}
+
+/// Cancels an upload. Sent by the client to give up, or by the server when the upload can not continue.
+class FileAbortPacket implements PacketData {
+  static const id = PacketType.fileAbort;
+
+  final String transferId;
+
+  /// A human readable explanation, empty when sent by the client
+  final String reason;
+
+  const FileAbortPacket({
+    required this.transferId,
+    required this.reason,
+  });
+
+  factory FileAbortPacket.decode(ProtocolReader reader) {
+    return FileAbortPacket(
+      transferId: reader.readUuid(),
+      reason: reader.readString(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeUuid(transferId);
+    writer.writeString(reason);
+  }
+
+  @override
+  String toString() => 'FileAbortPacket(transferId: $transferId, reason: $reason)';
+}
+
+/// Checks that the peer is still there. Sent by the server when the client has been quiet for a heartbeat interval, the
+/// client may send it too. Either side answers with a PONG carrying the same nonce.
+class PingPacket implements PacketData {
+  static const id = PacketType.ping;
+
+  /// Chosen by the sender, echoed by the PONG
+  final int nonce;
+
+  const PingPacket({
+    required this.nonce,
+  });
+
+  factory PingPacket.decode(ProtocolReader reader) {
+    return PingPacket(
+      nonce: reader.readVarlong(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeVarlong(nonce);
+  }
+
+  @override
+  String toString() => 'PingPacket(nonce: $nonce)';
+}
+
+/// The answer to a PING.
+class PongPacket implements PacketData {
+  static const id = PacketType.pong;
+
+  /// The nonce of the PING being answered
+  final int nonce;
+
+  const PongPacket({
+    required this.nonce,
+  });
+
+  factory PongPacket.decode(ProtocolReader reader) {
+    return PongPacket(
+      nonce: reader.readVarlong(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeVarlong(nonce);
+  }
+
+  @override
+  String toString() => 'PongPacket(nonce: $nonce)';
+}
+
+/// Sent by the server right before it closes the connection.
+class DisconnectPacket implements PacketData {
+  static const id = PacketType.disconnect;
+
+  final DisconnectReason reason;
+
+  /// A human readable explanation
+  final String message;
+
+  const DisconnectPacket({
+    required this.reason,
+    required this.message,
+  });
+
+  factory DisconnectPacket.decode(ProtocolReader reader) {
+    return DisconnectPacket(
+      reason: DisconnectReason.decode(reader),
+      message: reader.readString(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeU8(reason.code);
+    writer.writeString(message);
+  }
+
+  @override
+  String toString() => 'DisconnectPacket(reason: $reason, message: $message)';
+}
+
+/// Sent by the server to every client when it stops. The packets queued before it are still delivered, then the connection
+/// is closed.
+class ServerShutdownPacket implements PacketData {
+  static const id = PacketType.serverShutdown;
+
+  /// A human readable explanation
+  final String reason;
+
+  /// When set, the server expects to be back after this many seconds
+  final int? reconnectAfter;
+
+  const ServerShutdownPacket({
+    required this.reason,
+    required this.reconnectAfter,
+  });
+
+  factory ServerShutdownPacket.decode(ProtocolReader reader) {
+    return ServerShutdownPacket(
+      reason: reader.readString(),
+      reconnectAfter: reader.readOption(() => reader.readU32()),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeString(reason);
+    writer.writeOption(reconnectAfter, (v0) => writer.writeU32(v0));
+  }
+
+  @override
+  String toString() => 'ServerShutdownPacket(reason: $reason, reconnectAfter: $reconnectAfter)';
+}
+
+/// Opens a session, or resumes the session of a lost connection. Sent right after the handshake by clients that want to
+/// survive reconnects, the server answers with SESSION.
+class ResumePacket implements PacketData {
+  static const id = PacketType.resume;
+
+  /// The token of the session to resume, empty to open a new one
+  final String token;
+
+  /// The sequence number of the last packet received in the session, 0 if none
+  final int lastSequence;
+
+  const ResumePacket({
+    required this.token,
+    required this.lastSequence,
+  });
+
+  factory ResumePacket.decode(ProtocolReader reader) {
+    return ResumePacket(
+      token: reader.readString(),
+      lastSequence: reader.readVarlong(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeString(token);
+    writer.writeVarlong(lastSequence);
+  }
+
+  @override
+  String toString() => 'ResumePacket(token: $token, lastSequence: $lastSequence)';
+}
+
+/// The answer to RESUME. Every packet the server sends after it is numbered, starting at next_sequence, so a client that
+/// reconnects knows what it missed. When the session can not be resumed a new one is opened instead.
+class SessionPacket implements PacketData {
+  static const id = PacketType.session;
+
+  /// Sent in RESUME to resume this session after a reconnect
+  final String token;
+
+  /// Whether the requested session was resumed
+  final bool resumed;
+
+  /// The sequence number of the next packet sent by the server
+  final int nextSequence;
+
+  /// How many seconds the session is kept after its connection is lost
+  final int gracePeriod;
+
+  const SessionPacket({
+    required this.token,
+    required this.resumed,
+    required this.nextSequence,
+    required this.gracePeriod,
+  });
+
+  factory SessionPacket.decode(ProtocolReader reader) {
+    return SessionPacket(
+      token: reader.readString(),
+      resumed: reader.readBool(),
+      nextSequence: reader.readVarlong(),
+      gracePeriod: reader.readU32(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeString(token);
+    writer.writeBool(resumed);
+    writer.writeVarlong(nextSequence);
+    writer.writeU32(gracePeriod);
+  }
+
+  @override
+  String toString() => 'SessionPacket(token: $token, resumed: $resumed, nextSequence: $nextSequence, gracePeriod: $gracePeriod)';
+}
+
+/// The answer to SIGN_IN.
+class SignedInPacket implements PacketData {
+  static const id = PacketType.signedIn;
+
+  /// The destination other users send messages to this user with
+  final int userId;
+
+  const SignedInPacket({
+    required this.userId,
+  });
+
+  factory SignedInPacket.decode(ProtocolReader reader) {
+    return SignedInPacket(
+      userId: reader.readI32(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeI32(userId);
+  }
+
+  @override
+  String toString() => 'SignedInPacket(userId: $userId)';
+}
+
+/// Subscribes a signed in user to a channel, creating the channel when it does not exist yet. Every message sent to the
+/// channel is delivered to its subscribers.
+class JoinPacket implements PacketData {
+  static const id = PacketType.join;
+
+  /// The destination messages are sent to the channel with
+  final int channel;
+
+  const JoinPacket({
+    required this.channel,
+  });
+
+  factory JoinPacket.decode(ProtocolReader reader) {
+    return JoinPacket(
+      channel: reader.readI32(),
+    );
+  }
+
+  @override
+  int get packetId => id;
+
+  @override
+  void encode(ProtocolWriter writer) {
+    writer.writeI32(channel);
+  }
+
+  @override
+  String toString() => 'JoinPacket(channel: $channel)';
+}
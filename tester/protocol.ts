@@ -0,0 +1,1127 @@
+// @generated by rustchat-codegen from protocol.toml, do not edit.
+
+export const ENVELOPE_VERSION = 1;
+export const FLAG_REQUEST_ID = 0x01;
+export const MAX_PACKET_SIZE = 1024 * 1024 * 12;
+
+const textEncoder = new TextEncoder();
+const textDecoder = new TextDecoder("utf-8", { fatal: true });
+
+export class ProtocolError extends Error {}
+
+export class ProtocolWriter {
+  private buffer = new Uint8Array(256);
+  private view = new DataView(this.buffer.buffer);
+  private length = 0;
+
+  takeBytes(): Uint8Array {
+    const bytes = this.buffer.slice(0, this.length);
+    this.length = 0;
+    return bytes;
+  }
+
+  writeBool(value: boolean): void {
+    this.writeU8(value ? 1 : 0);
+  }
+
+  writeU8(value: number): void {
+    this.view.setUint8(this.reserve(1), value);
+  }
+
+  writeI8(value: number): void {
+    this.view.setInt8(this.reserve(1), value);
+  }
+
+  writeU16(value: number): void {
+    this.view.setUint16(this.reserve(2), value);
+  }
+
+  writeI16(value: number): void {
+    this.view.setInt16(this.reserve(2), value);
+  }
+
+  writeU32(value: number): void {
+    this.view.setUint32(this.reserve(4), value);
+  }
+
+  writeI32(value: number): void {
+    this.view.setInt32(this.reserve(4), value);
+  }
+
+  writeU64(value: bigint): void {
+    this.view.setBigUint64(this.reserve(8), value);
+  }
+
+  writeI64(value: bigint): void {
+    this.view.setBigInt64(this.reserve(8), value);
+  }
+
+  writeF32(value: number): void {
+    this.view.setFloat32(this.reserve(4), value);
+  }
+
+  writeF64(value: number): void {
+    this.view.setFloat64(this.reserve(8), value);
+  }
+
+  writeVarint(value: number): void {
+    value >>>= 0;
+    while (value >= 0x80) {
+      this.writeU8((value & 0x7f) | 0x80);
+      value >>>= 7;
+    }
+    this.writeU8(value);
+  }
+
+  writeVarlong(value: bigint): void {
+    value = BigInt.asUintN(64, value);
+    while (value >= 0x80n) {
+      this.writeU8(Number(value & 0x7fn) | 0x80);
+      value >>= 7n;
+    }
+    this.writeU8(Number(value));
+  }
+
+  writeSvarint(value: number): void {
+    this.writeVarint((value << 1) ^ (value >> 31));
+  }
+
+  writeSvarlong(value: bigint): void {
+    value = BigInt.asIntN(64, value);
+    this.writeVarlong((value << 1n) ^ (value >> 63n));
+  }
+
+  writeString(value: string): void {
+    this.writeBytes(textEncoder.encode(value));
+  }
+
+  writeBytes(value: Uint8Array): void {
+    this.writeVarint(value.length);
+    this.buffer.set(value, this.reserve(value.length));
+  }
+
+  writeUuid(value: string): void {
+    const hex = value.replace(/-/g, "");
+    if (!/^[0-9a-fA-F]{32}$/.test(hex)) {
+      throw new ProtocolError(`invalid uuid ${value}`);
+    }
+    for (let i = 0; i < 32; i += 2) {
+      this.writeU8(parseInt(hex.substring(i, i + 2), 16));
+    }
+  }
+
+  writeOption<T>(value: T | null, write: (value: T) => void): void {
+    this.writeBool(value !== null);
+    if (value !== null) {
+      write(value);
+    }
+  }
+
+  writeList<T>(values: T[], write: (value: T) => void): void {
+    this.writeVarint(values.length);
+    values.forEach((value) => write(value));
+  }
+
+  writeMap<K, V>(values: Map<K, V>, writeKey: (key: K) => void, writeValue: (value: V) => void): void {
+    this.writeVarint(values.size);
+    values.forEach((value, key) => {
+      writeKey(key);
+      writeValue(value);
+    });
+  }
+
+  /** Grows the buffer when needed, returning the offset of the reserved bytes */
+  private reserve(length: number): number {
+    if (this.length + length > this.buffer.length) {
+      const buffer = new Uint8Array(Math.max(this.buffer.length * 2, this.length + length));
+      buffer.set(this.buffer.subarray(0, this.length));
+      this.buffer = buffer;
+      this.view = new DataView(buffer.buffer);
+    }
+
+    const offset = this.length;
+    this.length += length;
+    return offset;
+  }
+}
+
+export class ProtocolReader {
+  private readonly view: DataView;
+  offset = 0;
+
+  constructor(private readonly bytes: Uint8Array) {
+    this.view = new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength);
+  }
+
+  get remaining(): number {
+    return this.bytes.length - this.offset;
+  }
+
+  readBool(): boolean {
+    const offset = this.offset;
+    const value = this.readU8();
+    if (value > 1) {
+      throw new ProtocolError(`invalid boolean byte ${value} at offset ${offset}`);
+    }
+    return value === 1;
+  }
+
+  readU8(): number {
+    return this.view.getUint8(this.advance(1));
+  }
+
+  readI8(): number {
+    return this.view.getInt8(this.advance(1));
+  }
+
+  readU16(): number {
+    return this.view.getUint16(this.advance(2));
+  }
+
+  readI16(): number {
+    return this.view.getInt16(this.advance(2));
+  }
+
+  readU32(): number {
+    return this.view.getUint32(this.advance(4));
+  }
+
+  readI32(): number {
+    return this.view.getInt32(this.advance(4));
+  }
+
+  readU64(): bigint {
+    return this.view.getBigUint64(this.advance(8));
+  }
+
+  readI64(): bigint {
+    return this.view.getBigInt64(this.advance(8));
+  }
+
+  readF32(): number {
+    return this.view.getFloat32(this.advance(4));
+  }
+
+  readF64(): number {
+    return this.view.getFloat64(this.advance(8));
+  }
+
+  readVarint(): number {
+    const offset = this.offset;
+    let result = 0;
+    for (let shift = 0; shift < 32; shift += 7) {
+      const byte = this.readU8();
+      result |= (byte & 0x7f) << shift;
+      if ((byte & 0x80) === 0) {
+        return result >>> 0;
+      }
+    }
+    throw new ProtocolError(`varint at offset ${offset} does not fit in 32 bits`);
+  }
+
+  readVarlong(): bigint {
+    const offset = this.offset;
+    let result = 0n;
+    for (let shift = 0n; shift < 64n; shift += 7n) {
+      const byte = this.readU8();
+      result |= BigInt(byte & 0x7f) << shift;
+      if ((byte & 0x80) === 0) {
+        return BigInt.asUintN(64, result);
+      }
+    }
+    throw new ProtocolError(`varint at offset ${offset} does not fit in 64 bits`);
+  }
+
+  readSvarint(): number {
+    const value = this.readVarint();
+    return (value >>> 1) ^ -(value & 1);
+  }
+
+  readSvarlong(): bigint {
+    const value = this.readVarlong();
+    return (value >> 1n) ^ -(value & 1n);
+  }
+
+  readString(): string {
+    const offset = this.offset;
+    const bytes = this.readBytes();
+    try {
+      return textDecoder.decode(bytes);
+    } catch {
+      throw new ProtocolError(`invalid utf-8 string at offset ${offset}`);
+    }
+  }
+
+  readBytes(): Uint8Array {
+    const length = this.readVarint();
+    const offset = this.advance(length);
+    return this.bytes.subarray(offset, offset + length);
+  }
+
+  readUuid(): string {
+    const offset = this.advance(16);
+    const hex = Array.from(this.bytes.subarray(offset, offset + 16), (byte) =>
+      byte.toString(16).padStart(2, "0"),
+    ).join("");
+    return `${hex.slice(0, 8)}-${hex.slice(8, 12)}-${hex.slice(12, 16)}-${hex.slice(16, 20)}-${hex.slice(20)}`;
+  }
+
+  readOption<T>(read: () => T): T | null {
+    return this.readBool() ? read() : null;
+  }
+
+  readList<T>(maxLength: number, read: () => T): T[] {
+    const length = this.readLength(maxLength);
+    const values: T[] = [];
+    for (let i = 0; i < length; i++) {
+      values.push(read());
+    }
+    return values;
+  }
+
+  readMap<K, V>(maxLength: number, readKey: () => K, readValue: () => V): Map<K, V> {
+    const length = this.readLength(maxLength);
+    const values = new Map<K, V>();
+    for (let i = 0; i < length; i++) {
+      const key = readKey();
+      values.set(key, readValue());
+    }
+    return values;
+  }
+
+  /** Every element takes at least one byte, so a length larger than the remaining data is rejected early. */
+  private readLength(maxLength: number): number {
+    const offset = this.offset;
+    const length = this.readVarint();
+    if (length > maxLength) {
+      throw new ProtocolError(`collection at offset ${offset} has ${length} elements, the maximum is ${maxLength}`);
+    }
+    this.ensure(length);
+    return length;
+  }
+
+  /** Consumes `length` bytes, returning the offset they start at */
+  private advance(length: number): number {
+    this.ensure(length);
+    const offset = this.offset;
+    this.offset += length;
+    return offset;
+  }
+
+  private ensure(needed: number): void {
+    if (this.remaining < needed) {
+      throw new ProtocolError(
+        `unexpected end of data at offset ${this.offset}: needed ${needed} bytes but only ${this.remaining} remain`,
+      );
+    }
+  }
+}
+
+/** A packet envelope, without the length prefix */
+export interface Frame {
+  packetType: number;
+
+  /** Set on requests that expect an answer, the server echoes it on the response. */
+  requestId: number | null;
+  payload: Uint8Array;
+}
+
+/** Encodes a packet with its length prefix and envelope header */
+export function encodeFrame(packet: Packet, requestId: number | null = null): Uint8Array {
+  const writer = new ProtocolWriter();
+  writer.writeU32(0); // length prefix, filled once the body is written
+  writer.writeU8(ENVELOPE_VERSION);
+  writer.writeU8(requestId === null ? 0 : FLAG_REQUEST_ID);
+  writer.writeU8(packet.type);
+  if (requestId !== null) {
+    writer.writeVarint(requestId);
+  }
+  writePacket(writer, packet);
+
+  const frame = writer.takeBytes();
+  const length = frame.length - 4;
+  if (length > MAX_PACKET_SIZE) {
+    throw new ProtocolError(`packet of ${length} bytes exceeds the maximum packet size`);
+  }
+  new DataView(frame.buffer).setUint32(0, length);
+  return frame;
+}
+
+/** Decodes an envelope, the length prefix must already be stripped */
+export function decodeFrame(body: Uint8Array): Frame {
+  const reader = new ProtocolReader(body);
+  const version = reader.readU8();
+  if (version !== ENVELOPE_VERSION) {
+    throw new ProtocolError(`unsupported envelope version ${version}`);
+  }
+
+  const flags = reader.readU8();
+  if ((flags & ~FLAG_REQUEST_ID) !== 0) {
+    throw new ProtocolError(`unsupported envelope flags ${flags}`);
+  }
+
+  const packetType = reader.readU8();
+  const requestId = (flags & FLAG_REQUEST_ID) !== 0 ? reader.readVarint() : null;
+  return { packetType, requestId, payload: body.subarray(reader.offset) };
+}
+
+/** Splits a stream of bytes into frames */
+export class FrameBuffer {
+  private pending = new Uint8Array(0);
+
+  /** Adds received bytes, returning every frame they complete */
+  push(bytes: Uint8Array): Frame[] {
+    const buffer = new Uint8Array(this.pending.length + bytes.length);
+    buffer.set(this.pending);
+    buffer.set(bytes, this.pending.length);
+
+    const frames: Frame[] = [];
+    let offset = 0;
+    while (buffer.length - offset >= 4) {
+      const length = new DataView(buffer.buffer, offset).getUint32(0);
+      if (buffer.length - offset < 4 + length) {
+        break;
+      }
+      frames.push(decodeFrame(buffer.subarray(offset + 4, offset + 4 + length)));
+      offset += 4 + length;
+    }
+
+    this.pending = buffer.slice(offset);
+    return frames;
+  }
+}
+
+export enum PacketType {
+  /**
+   * A user trying to sign in to the server. A username is registered with the password it first signs in with. The server
+   * answers with SIGNED_IN, or with an ERROR when the password does not match.
+   */
+  SignIn = 1,
+  /** A user trying to sign out from the server. */
+  SignOut = 2,
+  /**
+   * A message from an user, delivered to the signed in user or to the subscribers of the channel it is sent to. Only signed
+   * in users may send messages.
+   */
+  Message = 3,
+  /** The first packet a client sends, announcing its protocol version and capabilities. */
+  Hello = 4,
+  /** The server answer to HELLO, accepting or rejecting the client. */
+  Welcome = 5,
+  /** Sent by the server when a packet could not be processed. */
+  Error = 6,
+  /**
+   * Starts uploading a file in chunks, or resumes the upload when the transfer id is already known.
+   * The server answers with a FILE_ACK carrying the offset the client has to continue from.
+   */
+  FileBegin = 7,
+  /** A piece of a file being uploaded, answered with a FILE_ACK. */
+  FileChunk = 8,
+  /** Sent by the server to acknowledge the stored part of an upload. */
+  FileAck = 9,
+  /**
+   * Finishes an upload. Once the hash is verified the server answers with the MessagePacket referencing the file, and
+   * delivers it to its destination like any other message.
+   */
+  FileCommit = 10,
+  /** Cancels an upload. Sent by the client to give up, or by the server when the upload can not continue. */
+  FileAbort = 11,
+  /**
+   * Checks that the peer is still there. Sent by the server when the client has been quiet for a heartbeat interval, the
+   * client may send it too. Either side answers with a PONG carrying the same nonce.
+   */
+  Ping = 12,
+  /** The answer to a PING. */
+  Pong = 13,
+  /** Sent by the server right before it closes the connection. */
+  Disconnect = 14,
+  /**
+   * Sent by the server to every client when it stops. The packets queued before it are still delivered, then the connection
+   * is closed.
+   */
+  ServerShutdown = 15,
+  /**
+   * Opens a session, or resumes the session of a lost connection. Sent right after the handshake by clients that want to
+   * survive reconnects, the server answers with SESSION.
+   */
+  Resume = 16,
+  /**
+   * The answer to RESUME. Every packet the server sends after it is numbered, starting at next_sequence, so a client that
+   * reconnects knows what it missed. When the session can not be resumed a new one is opened instead.
+   */
+  Session = 17,
+  /** The answer to SIGN_IN. */
+  SignedIn = 18,
+  /**
+   * Subscribes a signed in user to a channel, creating the channel when it does not exist yet. Every message sent to the
+   * channel is delivered to its subscribers.
+   */
+  Join = 19,
+}
+
+export type Packet =
+  | { type: PacketType.SignIn; data: LoginPacket }
+  | { type: PacketType.SignOut; data: LogoutPacket }
+  | { type: PacketType.Message; data: MessagePacket }
+  | { type: PacketType.Hello; data: HelloPacket }
+  | { type: PacketType.Welcome; data: WelcomePacket }
+  | { type: PacketType.Error; data: ErrorPacket }
+  | { type: PacketType.FileBegin; data: FileBeginPacket }
+  | { type: PacketType.FileChunk; data: FileChunkPacket }
+  | { type: PacketType.FileAck; data: FileAckPacket }
+  | { type: PacketType.FileCommit; data: FileCommitPacket }
+  | { type: PacketType.FileAbort; data: FileAbortPacket }
+  | { type: PacketType.Ping; data: PingPacket }
+  | { type: PacketType.Pong; data: PongPacket }
+  | { type: PacketType.Disconnect; data: DisconnectPacket }
+  | { type: PacketType.ServerShutdown; data: ServerShutdownPacket }
+  | { type: PacketType.Resume; data: ResumePacket }
+  | { type: PacketType.Session; data: SessionPacket }
+  | { type: PacketType.SignedIn; data: SignedInPacket }
+  | { type: PacketType.Join; data: JoinPacket };
+
+export function writePacket(writer: ProtocolWriter, packet: Packet): void {
+  switch (packet.type) {
+    case PacketType.SignIn:
+      return writeLoginPacket(writer, packet.data);
+    case PacketType.SignOut:
+      return writeLogoutPacket(writer, packet.data);
+    case PacketType.Message:
+      return writeMessagePacket(writer, packet.data);
+    case PacketType.Hello:
+      return writeHelloPacket(writer, packet.data);
+    case PacketType.Welcome:
+      return writeWelcomePacket(writer, packet.data);
+    case PacketType.Error:
+      return writeErrorPacket(writer, packet.data);
+    case PacketType.FileBegin:
+      return writeFileBeginPacket(writer, packet.data);
+    case PacketType.FileChunk:
+      return writeFileChunkPacket(writer, packet.data);
+    case PacketType.FileAck:
+      return writeFileAckPacket(writer, packet.data);
+    case PacketType.FileCommit:
+      return writeFileCommitPacket(writer, packet.data);
+    case PacketType.FileAbort:
+      return writeFileAbortPacket(writer, packet.data);
+    case PacketType.Ping:
+      return writePingPacket(writer, packet.data);
+    case PacketType.Pong:
+      return writePongPacket(writer, packet.data);
+    case PacketType.Disconnect:
+      return writeDisconnectPacket(writer, packet.data);
+    case PacketType.ServerShutdown:
+      return writeServerShutdownPacket(writer, packet.data);
+    case PacketType.Resume:
+      return writeResumePacket(writer, packet.data);
+    case PacketType.Session:
+      return writeSessionPacket(writer, packet.data);
+    case PacketType.SignedIn:
+      return writeSignedInPacket(writer, packet.data);
+    case PacketType.Join:
+      return writeJoinPacket(writer, packet.data);
+  }
+}
+
+/** Decodes the payload of a frame, returns null when the packet type is unknown */
+export function decodePacket(frame: Frame): Packet | null {
+  const reader = new ProtocolReader(frame.payload);
+  switch (frame.packetType) {
+    case PacketType.SignIn:
+      return { type: PacketType.SignIn, data: readLoginPacket(reader) };
+    case PacketType.SignOut:
+      return { type: PacketType.SignOut, data: readLogoutPacket(reader) };
+    case PacketType.Message:
+      return { type: PacketType.Message, data: readMessagePacket(reader) };
+    case PacketType.Hello:
+      return { type: PacketType.Hello, data: readHelloPacket(reader) };
+    case PacketType.Welcome:
+      return { type: PacketType.Welcome, data: readWelcomePacket(reader) };
+    case PacketType.Error:
+      return { type: PacketType.Error, data: readErrorPacket(reader) };
+    case PacketType.FileBegin:
+      return { type: PacketType.FileBegin, data: readFileBeginPacket(reader) };
+    case PacketType.FileChunk:
+      return { type: PacketType.FileChunk, data: readFileChunkPacket(reader) };
+    case PacketType.FileAck:
+      return { type: PacketType.FileAck, data: readFileAckPacket(reader) };
+    case PacketType.FileCommit:
+      return { type: PacketType.FileCommit, data: readFileCommitPacket(reader) };
+    case PacketType.FileAbort:
+      return { type: PacketType.FileAbort, data: readFileAbortPacket(reader) };
+    case PacketType.Ping:
+      return { type: PacketType.Ping, data: readPingPacket(reader) };
+    case PacketType.Pong:
+      return { type: PacketType.Pong, data: readPongPacket(reader) };
+    case PacketType.Disconnect:
+      return { type: PacketType.Disconnect, data: readDisconnectPacket(reader) };
+    case PacketType.ServerShutdown:
+      return { type: PacketType.ServerShutdown, data: readServerShutdownPacket(reader) };
+    case PacketType.Resume:
+      return { type: PacketType.Resume, data: readResumePacket(reader) };
+    case PacketType.Session:
+      return { type: PacketType.Session, data: readSessionPacket(reader) };
+    case PacketType.SignedIn:
+      return { type: PacketType.SignedIn, data: readSignedInPacket(reader) };
+    case PacketType.Join:
+      return { type: PacketType.Join, data: readJoinPacket(reader) };
+    default:
+      return null;
+  }
+}
+
+/** The kind of recipient of a message */
+export enum DestinationType {
+  Unknown = 0,
+  User = 1,
+  Channel = 2,
+}
+
+function readDestinationType(reader: ProtocolReader): DestinationType {
+  const offset = reader.offset;
+  const code = reader.readU8();
+  if (code >= 3) {
+    throw new ProtocolError(`unknown destination type discriminant ${code} at offset ${offset}`);
+  }
+  return code;
+}
+
+/** The outcome of a handshake, sent back to the client in the WELCOME packet */
+export enum HandshakeStatus {
+  Accepted = 0,
+  /** The client protocol version is outside the range supported by the server */
+  UnsupportedVersion = 1,
+  /** The first packet sent by the client was not a HELLO */
+  HelloExpected = 2,
+}
+
+function readHandshakeStatus(reader: ProtocolReader): HandshakeStatus {
+  const offset = reader.offset;
+  const code = reader.readU8();
+  if (code >= 3) {
+    throw new ProtocolError(`unknown handshake status discriminant ${code} at offset ${offset}`);
+  }
+  return code;
+}
+
+/** Why the server could not process a packet */
+export enum ErrorCode {
+  Unknown = 0,
+  /** The packet type is not registered on the server */
+  UnknownPacketType = 1,
+  /** The packet payload could not be decoded */
+  MalformedPacket = 2,
+  /** The packet was decoded but the server failed to handle it */
+  InternalError = 3,
+  /** The password does not match the one the username signed in with first */
+  InvalidCredentials = 4,
+  /** The packet is only handled once the user signed in */
+  NotSignedIn = 5,
+  /** No signed in user or channel has the destination of the message */
+  UnknownDestination = 6,
+}
+
+function readErrorCode(reader: ProtocolReader): ErrorCode {
+  const offset = reader.offset;
+  const code = reader.readU8();
+  if (code >= 7) {
+    throw new ProtocolError(`unknown error code discriminant ${code} at offset ${offset}`);
+  }
+  return code;
+}
+
+/** Why the server closed the connection, sent in the DISCONNECT packet */
+export enum DisconnectReason {
+  Unknown = 0,
+  /** The client did not send anything, not even a PONG, for too many heartbeats */
+  HeartbeatTimeout = 1,
+  /** The client did not read its packets fast enough, too many were waiting to be sent */
+  TooSlow = 2,
+}
+
+function readDisconnectReason(reader: ProtocolReader): DisconnectReason {
+  const offset = reader.offset;
+  const code = reader.readU8();
+  if (code >= 3) {
+    throw new ProtocolError(`unknown disconnect reason discriminant ${code} at offset ${offset}`);
+  }
+  return code;
+}
+
+/**
+ * A set of optional protocol features, advertised by the client in the HELLO packet.
+ * The server answers with the subset it also supports.
+ */
+export const Capabilities = {
+  /** Packet payloads above a size threshold may be deflate compressed */
+  COMPRESSION_DEFLATE: 1 << 0,
+  /** Files may be sent in chunks instead of a single MessagePacket */
+  FILE_CHUNKING: 1 << 1,
+  /** Packet payloads above a size threshold may be zstd compressed, preferred over deflate */
+  COMPRESSION_ZSTD: 1 << 2,
+} as const;
+
+/** The content of a message. A FileReference carries the transfer id of a file uploaded in chunks, see FILE_BEGIN. */
+export type MessagePayload =
+  | { kind: "Text"; value: string }
+  | { kind: "File"; value: Uint8Array }
+  | { kind: "FileReference"; value: string };
+
+export function writeMessagePayload(writer: ProtocolWriter, value: MessagePayload): void {
+  switch (value.kind) {
+    case "Text":
+      writer.writeI8(1);
+      writer.writeString(value.value);
+      return;
+    case "File":
+      writer.writeI8(2);
+      writer.writeBytes(value.value);
+      return;
+    case "FileReference":
+      writer.writeI8(3);
+      writer.writeUuid(value.value);
+      return;
+  }
+}
+
+export function readMessagePayload(reader: ProtocolReader): MessagePayload {
+  const offset = reader.offset;
+  const tag = reader.readI8();
+  switch (tag) {
+    case 1:
+      return { kind: "Text", value: reader.readString() };
+    case 2:
+      return { kind: "File", value: reader.readBytes() };
+    case 3:
+      return { kind: "FileReference", value: reader.readUuid() };
+    default:
+      throw new ProtocolError(`unknown message payload discriminant ${tag} at offset ${offset}`);
+  }
+}
+
+/**
+ * A user trying to sign in to the server. A username is registered with the password it first signs in with. The server
+ * answers with SIGNED_IN, or with an ERROR when the password does not match.
+ */
+export interface LoginPacket {
+  username: string;
+  password: string;
+}
+
+export function writeLoginPacket(writer: ProtocolWriter, packet: LoginPacket): void {
+  writer.writeString(packet.username);
+  writer.writeString(packet.password);
+}
+
+export function readLoginPacket(reader: ProtocolReader): LoginPacket {
+  return {
+    username: reader.readString(),
+    password: reader.readString(),
+  };
+}
+
+/** A user trying to sign out from the server. */
+export interface LogoutPacket {
+  sessionId: bigint;
+}
+
+export function writeLogoutPacket(writer: ProtocolWriter, packet: LogoutPacket): void {
+  writer.writeSvarlong(packet.sessionId);
+}
+
+export function readLogoutPacket(reader: ProtocolReader): LogoutPacket {
+  return {
+    sessionId: reader.readSvarlong(),
+  };
+}
+
+/**
+ * A message from an user, delivered to the signed in user or to the subscribers of the channel it is sent to. Only signed
+ * in users may send messages.
+ */
+export interface MessagePacket {
+  /** The id of the destination. Can be an individual user or a channel */
+  destination: number;
+  /** Indicates the destination type: Channel/User */
+  destinationType: DestinationType;
+  /** The payload of the message */
+  messagePayload: MessagePayload;
+}
+
+export function writeMessagePacket(writer: ProtocolWriter, packet: MessagePacket): void {
+  writer.writeI32(packet.destination);
+  writer.writeU8(packet.destinationType);
+  writeMessagePayload(writer, packet.messagePayload);
+}
+
+export function readMessagePacket(reader: ProtocolReader): MessagePacket {
+  return {
+    destination: reader.readI32(),
+    destinationType: readDestinationType(reader),
+    messagePayload: readMessagePayload(reader),
+  };
+}
+
+/** The first packet a client sends, announcing its protocol version and capabilities. */
+export interface HelloPacket {
+  /** The protocol version the client speaks */
+  protocolVersion: number;
+  clientName: string;
+  clientVersion: string;
+  /** The optional features the client supports */
+  capabilities: number;
+}
+
+export function writeHelloPacket(writer: ProtocolWriter, packet: HelloPacket): void {
+  writer.writeVarint(packet.protocolVersion);
+  writer.writeString(packet.clientName);
+  writer.writeString(packet.clientVersion);
+  writer.writeVarint(packet.capabilities);
+}
+
+export function readHelloPacket(reader: ProtocolReader): HelloPacket {
+  return {
+    protocolVersion: reader.readVarint(),
+    clientName: reader.readString(),
+    clientVersion: reader.readString(),
+    capabilities: reader.readVarint(),
+  };
+}
+
+/** The server answer to HELLO, accepting or rejecting the client. */
+export interface WelcomePacket {
+  status: HandshakeStatus;
+  /** The protocol version used for the rest of the connection, 0 if the client was rejected */
+  protocolVersion: number;
+  /** The range of protocol versions the server accepts */
+  minProtocolVersion: number;
+  maxProtocolVersion: number;
+  /** The capabilities both the client and the server support */
+  capabilities: number;
+  /** A human readable explanation when the client is rejected */
+  reason: string;
+}
+
+export function writeWelcomePacket(writer: ProtocolWriter, packet: WelcomePacket): void {
+  writer.writeU8(packet.status);
+  writer.writeVarint(packet.protocolVersion);
+  writer.writeVarint(packet.minProtocolVersion);
+  writer.writeVarint(packet.maxProtocolVersion);
+  writer.writeVarint(packet.capabilities);
+  writer.writeString(packet.reason);
+}
+
+export function readWelcomePacket(reader: ProtocolReader): WelcomePacket {
+  return {
+    status: readHandshakeStatus(reader),
+    protocolVersion: reader.readVarint(),
+    minProtocolVersion: reader.readVarint(),
+    maxProtocolVersion: reader.readVarint(),
+    capabilities: reader.readVarint(),
+    reason: reader.readString(),
+  };
+}
+
+/** Sent by the server when a packet could not be processed. */
+export interface ErrorPacket {
+  code: ErrorCode;
+  /** The type of the packet that caused the error */
+  packetType: number;
+  /** A human readable description of the error */
+  message: string;
+}
+
+export function writeErrorPacket(writer: ProtocolWriter, packet: ErrorPacket): void {
+  writer.writeU8(packet.code);
+  writer.writeU8(packet.packetType);
+  writer.writeString(packet.message);
+}
+
+export function readErrorPacket(reader: ProtocolReader): ErrorPacket {
+  return {
+    code: readErrorCode(reader),
+    packetType: reader.readU8(),
+    message: reader.readString(),
+  };
+}
+
+/**
+ * Starts uploading a file in chunks, or resumes the upload when the transfer id is already known.
+ * The server answers with a FILE_ACK carrying the offset the client has to continue from.
+ */
+export interface FileBeginPacket {
+  /** Chosen by the client, used to resume the upload and to reference the file */
+  transferId: string;
+  /** Where the message referencing the file is delivered once committed */
+  destination: number;
+  destinationType: DestinationType;
+  name: string;
+  /** The size of the whole file in bytes */
+  size: bigint;
+  /** The SHA-256 digest of the whole file */
+  sha256: Uint8Array;
+}
+
+export function writeFileBeginPacket(writer: ProtocolWriter, packet: FileBeginPacket): void {
+  writer.writeUuid(packet.transferId);
+  writer.writeI32(packet.destination);
+  writer.writeU8(packet.destinationType);
+  writer.writeString(packet.name);
+  writer.writeVarlong(packet.size);
+  writer.writeBytes(packet.sha256);
+}
+
+export function readFileBeginPacket(reader: ProtocolReader): FileBeginPacket {
+  return {
+    transferId: reader.readUuid(),
+    destination: reader.readI32(),
+    destinationType: readDestinationType(reader),
+    name: reader.readString(),
+    size: reader.readVarlong(),
+    sha256: reader.readBytes(),
+  };
+}
+
+/** A piece of a file being uploaded, answered with a FILE_ACK. */
+export interface FileChunkPacket {
+  transferId: string;
+  /** The position of the chunk in the file, chunks must be sent in order */
+  offset: bigint;
+  data: Uint8Array;
+}
+
+export function writeFileChunkPacket(writer: ProtocolWriter, packet: FileChunkPacket): void {
+  writer.writeUuid(packet.transferId);
+  writer.writeVarlong(packet.offset);
+  writer.writeBytes(packet.data);
+}
+
+export function readFileChunkPacket(reader: ProtocolReader): FileChunkPacket {
+  return {
+    transferId: reader.readUuid(),
+    offset: reader.readVarlong(),
+    data: reader.readBytes(),
+  };
+}
+
+/** Sent by the server to acknowledge the stored part of an upload. */
+export interface FileAckPacket {
+  transferId: string;
+  /** Every byte before this offset is stored, the next chunk has to start here */
+  offset: bigint;
+}
+
+export function writeFileAckPacket(writer: ProtocolWriter, packet: FileAckPacket): void {
+  writer.writeUuid(packet.transferId);
+  writer.writeVarlong(packet.offset);
+}
+
+export function readFileAckPacket(reader: ProtocolReader): FileAckPacket {
+  return {
+    transferId: reader.readUuid(),
+    offset: reader.readVarlong(),
+  };
+}
+
+/**
+ * Finishes an upload. Once the hash is verified the server answers with the MessagePacket referencing the file, and
+ * delivers it to its destination like any other message.
+ */
+export interface FileCommitPacket {
+  transferId: string;
+}
+
+export function writeFileCommitPacket(writer: ProtocolWriter, packet: FileCommitPacket): void {
+  writer.writeUuid(packet.transferId);
+}
+
+export function readFileCommitPacket(reader: ProtocolReader): FileCommitPacket {
+  return {
+    transferId: reader.readUuid(),
+  };
+}
+
+/** Cancels an upload. Sent by the client to give up, or by the server when the upload can not continue. */
+export interface FileAbortPacket {
+  transferId: string;
+  /** A human readable explanation, empty when sent by the client */
+  reason: string;
+}
+
+export function writeFileAbortPacket(writer: ProtocolWriter, packet: FileAbortPacket): void {
+  writer.writeUuid(packet.transferId);
+  writer.writeString(packet.reason);
+}
+
+export function readFileAbortPacket(reader: ProtocolReader): FileAbortPacket {
+  return {
+    transferId: reader.readUuid(),
+    reason: reader.readString(),
+  };
+}
+
+/**
+ * Checks that the peer is still there. Sent by the server when the client has been quiet for a heartbeat interval, the
+ * client may send it too. Either side answers with a PONG carrying the same nonce.
+ */
+export interface PingPacket {
+  /** Chosen by the sender, echoed by the PONG */
+  nonce: bigint;
+}
+
+export function writePingPacket(writer: ProtocolWriter, packet: PingPacket): void {
+  writer.writeVarlong(packet.nonce);
+}
+
+export function readPingPacket(reader: ProtocolReader): PingPacket {
+  return {
+    nonce: reader.readVarlong(),
+  };
+}
+
+/** The answer to a PING. */
+export interface PongPacket {
+  /** The nonce of the PING being answered */
+  nonce: bigint;
+}
+
+export function writePongPacket(writer: ProtocolWriter, packet: PongPacket): void {
+  writer.writeVarlong(packet.nonce);
+}
+
+export function readPongPacket(reader: ProtocolReader): PongPacket {
+  return {
+    nonce: reader.readVarlong(),
+  };
+}
+
+/** Sent by the server right before it closes the connection. */
+export interface DisconnectPacket {
+  reason: DisconnectReason;
+  /** A human readable explanation */
+  message: string;
+}
+
+export function writeDisconnectPacket(writer: ProtocolWriter, packet: DisconnectPacket): void {
+  writer.writeU8(packet.reason);
+  writer.writeString(packet.message);
+}
+
+export function readDisconnectPacket(reader: ProtocolReader): DisconnectPacket {
+  return {
+    reason: readDisconnectReason(reader),
+    message: reader.readString(),
+  };
+}
+
+/**
+ * Sent by the server to every client when it stops. The packets queued before it are still delivered, then the connection
+ * is closed.
+ */
+export interface ServerShutdownPacket {
+  /** A human readable explanation */
+  reason: string;
+  /** When set, the server expects to be back after this many seconds */
+  reconnectAfter: number | null;
+}
+
+export function writeServerShutdownPacket(writer: ProtocolWriter, packet: ServerShutdownPacket): void {
+  writer.writeString(packet.reason);
+  writer.writeOption(packet.reconnectAfter, (v0) => writer.writeU32(v0));
+}
+
+export function readServerShutdownPacket(reader: ProtocolReader): ServerShutdownPacket {
+  return {
+    reason: reader.readString(),
+    reconnectAfter: reader.readOption(() => reader.readU32()),
+  };
+}
+
+/**
+ * Opens a session, or resumes the session of a lost connection. Sent right after the handshake by clients that want to
+ * survive reconnects, the server answers with SESSION.
+ */
+export interface ResumePacket {
+  /** The token of the session to resume, empty to open a new one */
+  token: string;
+  /** The sequence number of the last packet received in the session, 0 if none */
+  lastSequence: bigint;
+}
+
+export function writeResumePacket(writer: ProtocolWriter, packet: ResumePacket): void {
+  writer.writeString(packet.token);
+  writer.writeVarlong(packet.lastSequence);
+}
+
+export function readResumePacket(reader: ProtocolReader): ResumePacket {
+  return {
+    token: reader.readString(),
+    lastSequence: reader.readVarlong(),
+  };
+}
+
+/**
+ * The answer to RESUME. Every packet the server sends after it is numbered, starting at next_sequence, so a client that
+ * reconnects knows what it missed. When the session can not be resumed a new one is opened instead.
+ */
+export interface SessionPacket {
+  /** Sent in RESUME to resume this session after a reconnect */
+  token: string;
+  /** Whether the requested session was resumed */
+  resumed: boolean;
+  /** The sequence number of the next packet sent by the server */
+  nextSequence: bigint;
+  /** How many seconds the session is kept after its connection is lost */
+  gracePeriod: number;
+}
+
+export function writeSessionPacket(writer: ProtocolWriter, packet: SessionPacket): void {
+  writer.writeString(packet.token);
+  writer.writeBool(packet.resumed);
+  writer.writeVarlong(packet.nextSequence);
+  writer.writeU32(packet.gracePeriod);
+}
+
+export function readSessionPacket(reader: ProtocolReader): SessionPacket {
+  return {
+    token: reader.readString(),
+    resumed: reader.readBool(),
+    nextSequence: reader.readVarlong(),
+    gracePeriod: reader.readU32(),
+  };
+}
+
+/** The answer to SIGN_IN. */
+export interface SignedInPacket {
+  /** The destination other users send messages to this user with */
+  userId: number;
+}
+
+export function writeSignedInPacket(writer: ProtocolWriter, packet: SignedInPacket): void {
+  writer.writeI32(packet.userId);
+}
+
+export function readSignedInPacket(reader: ProtocolReader): SignedInPacket {
+  return {
+    userId: reader.readI32(),
+  };
+}
+
+/**
+ * Subscribes a signed in user to a channel, creating the channel when it does not exist yet. Every message sent to the
+ * channel is delivered to its subscribers.
+ */
+export interface JoinPacket {
+  /** The destination messages are sent to the channel with */
+  channel: number;
+}
+
+export function writeJoinPacket(writer: ProtocolWriter, packet: JoinPacket): void {
+  writer.writeI32(packet.channel);
+}
+
+export function readJoinPacket(reader: ProtocolReader): JoinPacket {
+  return {
+    channel: reader.readI32(),
+  };
+}